@@ -0,0 +1,90 @@
+//! Packing a converted folder into a single archive file.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use walkdir::WalkDir;
+
+use crate::{hashes, lock, manifest::url_path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Recognizes `.tar`, `.tar.gz`, `.tgz`, `.tar.zst` and `.zip` file names
+    pub fn from_path(path: &Path) -> Option<ArchiveFormat> {
+        let name = path.file_name()?.to_string_lossy().to_lowercase();
+        if name.ends_with(".tar.zst") {
+            Some(ArchiveFormat::TarZst)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(ArchiveFormat::TarGz)
+        } else if name.ends_with(".tar") {
+            Some(ArchiveFormat::Tar)
+        } else if name.ends_with(".zip") {
+            Some(ArchiveFormat::Zip)
+        } else {
+            None
+        }
+    }
+}
+
+/// Writes every file in `dir` into `archive`, with paths relative to `dir`
+pub fn write_archive(dir: &Path, archive: &Path, format: ArchiveFormat) -> Result<()> {
+    let mut files = Vec::new();
+    for entry in WalkDir::new(dir).sort_by_file_name() {
+        let entry = entry?;
+        let is_state = [hashes::FILE_NAME, lock::FILE_NAME]
+            .iter()
+            .any(|name| entry.file_name() == *name);
+        if entry.file_type().is_file() && !is_state {
+            let relative = entry.path().strip_prefix(dir)?.to_path_buf();
+            files.push((entry.into_path(), relative));
+        }
+    }
+    let file = File::create(archive).wrap_err_with(|| format!("creating {}", archive.display()))?;
+    let writer = BufWriter::new(file);
+    match format {
+        ArchiveFormat::Tar => write_tar(writer, &files)?.flush()?,
+        ArchiveFormat::TarGz => {
+            let encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            write_tar(encoder, &files)?.finish()?.flush()?;
+        }
+        ArchiveFormat::TarZst => {
+            let encoder = zstd::Encoder::new(writer, 0)?;
+            write_tar(encoder, &files)?.finish()?.flush()?;
+        }
+        ArchiveFormat::Zip => {
+            let mut zip = zip::ZipWriter::new(writer);
+            for (path, relative) in &files {
+                // Without the time feature every entry is dated 1980, which
+                // keeps archives reproducible
+                zip.start_file(url_path(relative), zip::write::FileOptions::default())?;
+                std::io::copy(&mut File::open(path)?, &mut zip)?;
+            }
+            zip.finish()?.flush()?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the inner writer so compressors can be finished
+fn write_tar<W: Write>(writer: W, files: &[(PathBuf, PathBuf)]) -> Result<W> {
+    let mut tar = tar::Builder::new(writer);
+    // Leave out modification times and owners so identical outputs give
+    // identical archives
+    tar.mode(tar::HeaderMode::Deterministic);
+    for (path, relative) in files {
+        tar.append_path_with_name(path, relative)
+            .wrap_err_with(|| format!("adding {} to the archive", path.display()))?;
+    }
+    Ok(tar.into_inner()?)
+}
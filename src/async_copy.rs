@@ -0,0 +1,109 @@
+//! Copies pass-through files with tokio so that directory creation, metadata
+//! reads and copies of many small files overlap.
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::Sender,
+        Arc,
+    },
+    time::Instant,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use tokio::{sync::Semaphore, task::JoinSet};
+
+use crate::{pipeline, Job, JobKind, JobResult, Outcome};
+
+/// How many copies are in flight at the same time
+const CONCURRENCY: usize = 64;
+
+/// Copies every job and sends its result along with its index. No new copies
+/// are started once `cancelled` is set. Copies that are up to date are kept
+/// unless `clean` is set. With `preserve` the copies get the modification
+/// time and permissions of their source.
+pub(crate) fn copy_all(
+    jobs: Vec<(usize, Job)>,
+    cancelled: &AtomicBool,
+    clean: bool,
+    preserve: bool,
+    results: Sender<(usize, Result<JobResult>)>,
+) {
+    let runtime = match tokio::runtime::Builder::new_multi_thread().build() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            if let Some((i, _)) = jobs.first() {
+                let _ = results.send((*i, Err(eyre!(e).wrap_err("starting the copy runtime"))));
+            }
+            return;
+        }
+    };
+    runtime.block_on(async {
+        let limit = Arc::new(Semaphore::new(CONCURRENCY));
+        let mut tasks = JoinSet::new();
+        for (i, job) in jobs {
+            if cancelled.load(Ordering::Relaxed) {
+                break;
+            }
+            let permit = limit
+                .clone()
+                .acquire_owned()
+                .await
+                .expect("the semaphore is never closed");
+            let results = results.clone();
+            tasks.spawn(async move {
+                let start = Instant::now();
+                let copied = match copy(&job, clean).await {
+                    Ok(()) if preserve => pipeline::preserve_attributes(&job),
+                    copied => copied,
+                };
+                let outcome = match copied {
+                    Ok(()) => Outcome::Copied,
+                    Err(e) => Outcome::Failed(e),
+                };
+                let result = JobResult {
+                    job,
+                    outcome,
+                    duration: start.elapsed(),
+                };
+                let _ = results.send((i, Ok(result)));
+                drop(permit);
+            });
+        }
+        while tasks.join_next().await.is_some() {}
+    });
+}
+
+async fn copy(job: &Job, clean: bool) -> Result<()> {
+    let JobKind::Copy { destination } = &job.kind else {
+        return Err(eyre!("not a copy job: {}", job.source.display()));
+    };
+    if *destination == job.source {
+        // Copying a file to itself can lead to corruption
+        return Err(eyre!(
+            "source and destination paths are the same: {}",
+            job.source.display()
+        ));
+    }
+    if !clean && pipeline::is_copy_up_to_date(&job.source, destination) {
+        return Ok(());
+    }
+    if let Some(p) = destination.parent() {
+        tokio::fs::create_dir_all(p).await?;
+    }
+    // The destination may be a hard link to the source from an earlier run
+    match tokio::fs::remove_file(destination).await {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+        _ => {}
+    }
+    tokio::fs::copy(&job.source, destination)
+        .await
+        .wrap_err_with(|| {
+            format!(
+                "source: {}, destination: {}",
+                job.source.display(),
+                destination.display()
+            )
+        })?;
+    Ok(())
+}
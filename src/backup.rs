@@ -0,0 +1,94 @@
+//! Keeps the outputs replaced by a run, so that a bad setting doesn't destroy
+//! the only good copies. Every run that replaces outputs gets its own folder
+//! named by its start time in UTC, like `20240131T120000Z`, holding the
+//! replaced files at their path inside the destination.
+
+use std::{
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::SystemTime,
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use tracing::debug;
+
+use crate::s3::amz_date;
+
+#[derive(Debug)]
+pub struct Backup {
+    root: PathBuf,
+    destination: PathBuf,
+    started: SystemTime,
+    /// The folder of this run, created when the first output is replaced
+    folder: OnceLock<PathBuf>,
+}
+
+impl Backup {
+    /// Backs up outputs in `destination` to a new folder in `root`
+    pub fn new(root: impl Into<PathBuf>, destination: impl Into<PathBuf>) -> Backup {
+        Backup {
+            root: root.into(),
+            destination: destination.into(),
+            started: SystemTime::now(),
+            folder: OnceLock::new(),
+        }
+    }
+
+    fn folder(&self) -> &Path {
+        self.folder.get_or_init(|| {
+            let (_, timestamp) = amz_date(self.started);
+            // Runs started in the same second get their own folders
+            (1..)
+                .map(|n| match n {
+                    1 => self.root.join(&timestamp),
+                    n => self.root.join(format!("{timestamp}-{n}")),
+                })
+                .find(|folder| !folder.exists())
+                .expect("some folder name is free")
+        })
+    }
+
+    /// Moves `output` into the backup if it exists, before it is replaced
+    pub fn save(&self, output: &Path) -> Result<()> {
+        if !output.is_file() {
+            return Ok(());
+        }
+        let relative = output.strip_prefix(&self.destination).unwrap_or(output);
+        let backup = self.folder().join(relative);
+        if let Some(p) = backup.parent() {
+            std::fs::create_dir_all(p)
+                .wrap_err_with(|| format!("creating the folder {}", p.display()))?;
+        }
+        debug!("backing up {} to {}", output.display(), backup.display());
+        // Renaming fails across filesystems
+        if std::fs::rename(output, &backup).is_err() {
+            std::fs::copy(output, &backup)
+                .and_then(|_| std::fs::remove_file(output))
+                .wrap_err_with(|| {
+                    format!("backing up {} to {}", output.display(), backup.display())
+                })?;
+        }
+        Ok(())
+    }
+
+    /// Removes all but the `keep` newest backups in `root`
+    pub fn prune(root: &Path, keep: usize) -> Result<()> {
+        let Ok(entries) = std::fs::read_dir(root) else {
+            return Ok(());
+        };
+        let mut folders: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_dir())
+            .map(|entry| entry.path())
+            .collect();
+        // The names sort by time
+        folders.sort();
+        let remove = folders.len().saturating_sub(keep);
+        for folder in &folders[..remove] {
+            debug!("removing the old backup {}", folder.display());
+            std::fs::remove_dir_all(folder)
+                .wrap_err_with(|| format!("removing {}", folder.display()))?;
+        }
+        Ok(())
+    }
+}
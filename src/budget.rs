@@ -0,0 +1,72 @@
+//! The total size of the destination folder, checked against a budget to keep
+//! the weight of the site under control.
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+use walkdir::WalkDir;
+
+use crate::{hashes, journal, lock};
+
+/// How many of the largest files are listed
+const LARGEST: usize = 10;
+
+/// Files the tool keeps for itself, which aren't served with the site
+const INTERNAL_FILES: [&str; 3] = [hashes::FILE_NAME, journal::FILE_NAME, lock::FILE_NAME];
+
+#[derive(Debug, Clone, Default)]
+pub struct Usage {
+    /// The size of every file in the folder, in bytes
+    pub total: u64,
+    /// The largest files relative to the folder, largest first
+    pub largest: Vec<(PathBuf, u64)>,
+}
+
+/// Adds up the size of the files in `destination`
+pub fn measure(destination: &Path) -> Result<Usage> {
+    let mut usage = Usage::default();
+    let mut files = Vec::new();
+    for entry in WalkDir::new(destination) {
+        let entry = entry?;
+        if !entry.file_type().is_file()
+            || INTERNAL_FILES.iter().any(|name| entry.file_name() == *name)
+        {
+            continue;
+        }
+        let size = entry.metadata()?.len();
+        usage.total += size;
+        let relative = entry.path().strip_prefix(destination)?;
+        files.push((relative.to_path_buf(), size));
+    }
+    files.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    files.truncate(LARGEST);
+    usage.largest = files;
+    Ok(usage)
+}
+
+/// Parses sizes like "500MiB", "1.5 GB" or "2048" bytes, for the command line
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let split = size
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| format!("{size} doesn't start with a number"))?;
+    let multiplier = match unit.trim().to_ascii_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1 << 10,
+        "m" | "mb" => 1000_u64.pow(2),
+        "mib" => 1 << 20,
+        "g" | "gb" => 1000_u64.pow(3),
+        "gib" => 1 << 30,
+        unit => {
+            return Err(format!(
+                "unknown unit {unit}, use B, KB, KiB, MB, MiB, GB or GiB"
+            ))
+        }
+    };
+    Ok((number * multiplier as f64) as u64)
+}
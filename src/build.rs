@@ -0,0 +1,56 @@
+//! Helpers for running the pipeline from a Cargo build script.
+//!
+//! Call [`convert`] from the `main` function of `build.rs`:
+//!
+//! ```no_run
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! web_assets_converter::build::convert("assets", format!("{out_dir}/assets")).unwrap();
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::Result;
+
+use crate::{manifest::SkipReason, Outcome, Pipeline, PipelineBuilder, Report};
+
+/// Converts `source` into `destination` with the default settings and tells
+/// Cargo to rerun the build script when anything in `source` changes
+pub fn convert(source: impl Into<PathBuf>, destination: impl Into<PathBuf>) -> Result<Report> {
+    convert_with(Pipeline::builder(source, destination))
+}
+
+/// Like [`convert`], for a pipeline with custom settings
+pub fn convert_with(builder: PipelineBuilder) -> Result<Report> {
+    let pipeline = builder.build();
+    rerun_if_changed(pipeline.source());
+    let report = pipeline.run()?;
+    for result in &report.results {
+        if let Outcome::Failed(e) | Outcome::Corrupt(e) = &result.outcome {
+            // Warnings must fit on one line to be shown by Cargo
+            let message = format!("{}: {e:#}", result.job.source.display());
+            println!("cargo:warning={}", message.replace('\n', " "));
+        }
+    }
+    for (relative, size) in pipeline.too_large() {
+        println!(
+            "cargo:warning=left out {}, its {size} bytes are more than the maximum file size",
+            relative.display()
+        );
+    }
+    for (relative, _, reason) in pipeline.blocked() {
+        let why = match reason {
+            SkipReason::NotAllowed => "isn't allowed",
+            _ => "is blocked",
+        };
+        println!(
+            "cargo:warning=left out {}, its extension {why}",
+            relative.display()
+        );
+    }
+    Ok(report)
+}
+
+/// Cargo rescans a directory given to `rerun-if-changed` recursively
+fn rerun_if_changed(path: &Path) {
+    println!("cargo:rerun-if-changed={}", path.display());
+}
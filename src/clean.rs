@@ -0,0 +1,73 @@
+//! Finds and removes the files the tool wrote to a destination folder, leaving
+//! hand-placed files next to them alone.
+
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use walkdir::WalkDir;
+
+use crate::{contact_sheet, diff::STATE_FILES, gallery, headers, manifest, paths, Job};
+
+/// The existing files in `destination` that are outputs of `jobs`, were
+/// listed as outputs in its manifest, or hold the state of earlier runs
+pub fn generated_files(jobs: &[Job], destination: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = BTreeSet::new();
+    for job in jobs {
+        files.extend(
+            job.outputs()
+                .into_iter()
+                .map(|(_, path)| path.to_path_buf()),
+        );
+    }
+    // Outputs of sources that were removed since the manifest was written
+    let manifest_path = destination.join(manifest::FILE_NAME);
+    if let Ok(json) = std::fs::read_to_string(&manifest_path) {
+        let manifest: serde_json::Value = serde_json::from_str(&json)
+            .wrap_err_with(|| format!("parsing {}", manifest_path.display()))?;
+        let outputs = manifest["assets"]
+            .as_object()
+            .into_iter()
+            .flat_map(|assets| assets.values())
+            .filter_map(|variants| variants.as_object())
+            .flatten()
+            .filter_map(|(_, output)| output.as_str());
+        for output in outputs {
+            let relative = Path::new(output);
+            // Don't follow a tampered manifest out of the destination
+            if paths::is_plain_relative(relative) {
+                files.insert(destination.join(relative));
+            }
+        }
+    }
+    files.extend(contact_sheet::sheets(destination)?);
+    files.extend(STATE_FILES.iter().map(|name| destination.join(name)));
+    files.extend(
+        WalkDir::new(destination)
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| gallery::is_page(e.path()) || headers::is_headers_file(e.path()))
+            .map(|e| e.into_path()),
+    );
+    Ok(files.into_iter().filter(|path| path.is_file()).collect())
+}
+
+/// Removes `files` and then the folders in `destination` they leave empty
+pub fn remove(files: &[PathBuf], destination: &Path) -> Result<()> {
+    for file in files {
+        std::fs::remove_file(file).wrap_err_with(|| format!("removing {}", file.display()))?;
+        let folders = file
+            .ancestors()
+            .skip(1)
+            .take_while(|folder| folder.starts_with(destination) && *folder != destination);
+        for folder in folders {
+            // Fails if the folder isn't empty, e.g. holds hand-placed files
+            if std::fs::remove_dir(folder).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
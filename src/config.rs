@@ -0,0 +1,1077 @@
+//! The optional `web_assets_converter.toml` in the asset folder, for settings
+//! that don't fit on a command line. Flags given on the command line win.
+//!
+//! ```toml
+//! # The maximum size of copied non-image files in MiB
+//! max-file-size = 50
+//! # Files in the asset folder that are left out
+//! exclude = ["drafts/**", "**/*.psd"]
+//! # Files that are copied whatever their size
+//! force-copy = ["videos/intro.mp4"]
+//! # Extensions of files that never reach the destination, whatever their size
+//! # or rules, listed in skipped.json. Defaults to BLOCKED_EXTENSIONS, set to
+//! # [] to allow them all
+//! block = ["psd", "xcf", "ai", "blend", "xmp"]
+//! # Only process files with these extensions, leaving out the rest and listing
+//! # them in skipped.json
+//! allow = ["jpg", "png", "svg", "webp", "mp4", "woff2"]
+//! quality = 80
+//! # jpg, webp or png
+//! format = "webp"
+//! # Web safe output names like "cafe-menu.jpg" for "Café Menu.JPG"
+//! slug-names = true
+//! # Crop thumbnails to a square, keeping the center, or north, south-east...
+//! gravity = "center"
+//! # Tune images for their content: photo, artwork, screenshot or scan
+//! preset = "photo"
+//! # Convert PNG screenshots and diagrams with the screenshot preset, in
+//! # folders without a preset
+//! detect-graphics = true
+//! # The background of transparent images converted to JPEG
+//! flatten-color = "#1a1a2e"
+//! # Correct unedited photos before resizing: auto-level, white-balance
+//! # and contrast-stretch
+//! enhance = ["auto-level", "white-balance"]
+//! # Remove the noise of high-ISO photos before resizing, from 1 to 50
+//! # percent. Best set in the .assets.toml of a folder of night shots
+//! denoise = 5
+//! # Put every output directly in the destination, named like "photos-rally-a.jpg"
+//! flatten = true
+//! # Also copy the untouched sources of converted images into originals/
+//! keep-originals = true
+//! # Where the files left out for their size or extension are hosted, listed
+//! # with them in skipped.json. {path} is relative to the asset folder, {name}
+//! # the file name
+//! external-url = "https://media.example.com/originals/{path}"
+//! # Where the outputs are served, listed in the manifest. {path} is relative
+//! # to the destination, {hash} changes with the contents of the output
+//! output-url = "https://cdn.example.com/assets/{path}?v={hash}"
+//!
+//! [sizes]
+//! default = 1600
+//! high = 3200
+//! thumb = 400
+//!
+//! # The largest size in kB (1000 bytes) of JPEG and WebP variants, which
+//! # lowers their quality as far as needed
+//! [target-size]
+//! default = 300
+//! thumb = 40
+//!
+//! # Keep the metadata and color profile of high resolution downloads
+//! [strip]
+//! high = false
+//!
+//! # Output names of every variant, from {stem}, {ext}, {variant} and {width}
+//! [names]
+//! default = "{stem}-{width}w.{ext}"
+//! high = "{stem}-{width}w.{ext}"
+//! thumb = "{stem}-{width}w.{ext}"
+//!
+//! # Also convert another folder, relative to the asset folder, into a
+//! # folder called "shared" in the destination
+//! [[extra-sources]]
+//! path = "../shared-assets"
+//! prefix = "shared"
+//!
+//! # Keep transparency in PNGs
+//! [extensions.png]
+//! format = "png"
+//!
+//! # convert, copy or skip
+//! [extensions.tif]
+//! action = "convert"
+//!
+//! # Copy videos up to 100 MiB
+//! [extensions.mp4]
+//! max-file-size = 100
+//!
+//! # Rules for video, audio, document or design files, see
+//! # formats::Category. Rules for an extension take precedence
+//! [categories.video]
+//! max-file-size = 500
+//!
+//! [categories.design]
+//! action = "skip"
+//! ```
+//!
+//! Any folder inside the asset folder can hold a [`DIRECTORY_FILE_NAME`] with
+//! settings for the files in that folder and below. It can set everything
+//! except `max-file-size`, `force-copy`, `block`, `allow`, `external-url`,
+//! `output-url` and `extra-sources`. Its `exclude` globs are relative to that folder.
+//!
+//! ```toml
+//! # photos/rally/.assets.toml
+//! quality = 95
+//! thumbnails = false
+//! ```
+//!
+//! A single file can be given its own settings in a [`Sidecar`] next to it.
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+#[cfg(feature = "native")]
+use color_eyre::eyre::WrapErr;
+use color_eyre::eyre::{eyre, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    formats::{self, Category},
+    Variant, MIB,
+};
+
+/// The name of the config file in the asset folder
+pub const FILE_NAME: &str = "web_assets_converter.toml";
+
+/// The name of the config files that override settings for a single folder
+pub const DIRECTORY_FILE_NAME: &str = ".assets.toml";
+
+/// The extension appended to the name of a source for its [`Sidecar`]
+pub const SIDECAR_EXTENSION: &str = "toml";
+
+/// The name of the files that list files to leave out, with the same syntax as
+/// `.gitignore`. Like those they apply to their folder and everything below.
+pub const IGNORE_FILE_NAME: &str = ".assetignore";
+
+/// The context of errors in a config file, so they can be told apart from
+/// other errors, e.g. for the exit code
+#[derive(Debug)]
+pub struct ConfigError {
+    pub path: PathBuf,
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "config file {}", self.path.display())
+    }
+}
+
+/// The extensions of the working files of design and 3D tools and the
+/// sidecars of raw photo editors, which are blocked unless the config lists
+/// its own
+pub const BLOCKED_EXTENSIONS: [&str; 11] = [
+    "psd", "psb", "xcf", "ai", "kra", "afphoto", "afdesign", "blend", "xmp", "pp3", "dop",
+];
+
+/// Whether `extension` is in a list of extensions, which may start with a dot
+fn is_listed(list: &[String], extension: &str) -> bool {
+    list.iter()
+        .any(|e| e.trim_start_matches('.').eq_ignore_ascii_case(extension))
+}
+
+/// The quality of converted images unless the config sets one
+pub const DEFAULT_QUALITY: u8 = 85;
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// The maximum size of copied non-image files in MiB
+    pub max_file_size: Option<u64>,
+    /// Globs matched against the path of every file inside the asset folder
+    pub exclude: Vec<String>,
+    /// Globs of files that are copied even if they are larger than the
+    /// maximum file size. Only read from the config of the asset folder
+    pub force_copy: Vec<String>,
+    /// Extensions of files that are never copied or converted, even with
+    /// force-copy, a rule or a sidecar. Defaults to [`BLOCKED_EXTENSIONS`].
+    /// Only read from the config of the asset folder
+    pub block: Option<Vec<String>>,
+    /// The only extensions of files that are copied or converted, if set.
+    /// Blocked extensions stay blocked. Only read from the config of the
+    /// asset folder
+    pub allow: Option<Vec<String>>,
+    /// The quality of converted images, from 1 to 100
+    pub quality: Option<u8>,
+    /// The format images are converted to. Defaults to JPEG
+    pub format: Option<Format>,
+    /// Set to false to leave out the thumbnail variant
+    pub thumbnails: Option<bool>,
+    /// Crop thumbnails to a square, keeping this side of the image
+    pub gravity: Option<Gravity>,
+    /// Tunes the conversion for what the images show, see [`Preset`]
+    pub preset: Option<Preset>,
+    /// Convert sources without a preset that look like graphics with
+    /// [`Preset::Screenshot`], see [`crate::graphics`]
+    pub detect_graphics: Option<bool>,
+    /// The `#rrggbb` background transparent images are put on when they are
+    /// converted to JPEG, which has no transparency. Without one ImageMagick
+    /// drops the transparency, which often leaves transparent areas black
+    pub flatten_color: Option<String>,
+    /// Automatic corrections applied to converted images before they are
+    /// resized, in order. A folder config can turn them off with `[]`
+    pub enhance: Vec<Enhancement>,
+    /// The threshold of ImageMagick's wavelet denoise in percent, from 1 to
+    /// 50, applied to converted images before they are resized. Noise is
+    /// both ugly and costly to encode
+    pub denoise: Option<u8>,
+    /// Give outputs lowercase ASCII names without spaces, see [`crate::slug`]
+    pub slug_names: Option<bool>,
+    /// Put every output directly in the destination folder instead of
+    /// mirroring the folders of the asset folder
+    pub flatten: Option<bool>,
+    /// Also copy the sources of converted images into
+    /// [`crate::plan::ORIGINALS_DIR`] in the destination, leaving them out
+    /// of the manifest
+    pub keep_originals: Option<bool>,
+    /// A URL template for the files left out for being too large, see
+    /// [`crate::manifest::external_url`]. Only read from the config of the
+    /// asset folder
+    pub external_url: Option<String>,
+    /// A URL template for the outputs, listed with them in the manifest, see
+    /// [`crate::manifest::output_url`]. Only read from the config of the
+    /// asset folder
+    pub output_url: Option<String>,
+    pub sizes: Sizes,
+    pub strip: Strip,
+    pub target_size: TargetSizes,
+    pub names: Names,
+    /// Other folders converted along with the asset folder. Only read from
+    /// the config of the asset folder
+    pub extra_sources: Vec<ExtraSource>,
+    /// Settings for files with a certain extension, which take precedence
+    /// over the ones above. Extensions are matched case insensitively.
+    pub extensions: BTreeMap<String, ExtensionRule>,
+    /// Settings for the files in a category, which take precedence over the
+    /// ones for all files but not over the ones for an extension
+    pub categories: BTreeMap<Category, ExtensionRule>,
+}
+
+/// A folder converted along with the asset folder
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExtraSource {
+    /// Relative to the asset folder
+    pub path: PathBuf,
+    /// The folder in the destination its outputs are placed in. Empty to
+    /// merge it into the top of the destination
+    #[serde(default)]
+    pub prefix: PathBuf,
+}
+
+/// The file name template of every variant. `{stem}` is the name of the
+/// source without its extension, `{ext}` the extension of the output format,
+/// `{variant}` the name of the variant and `{width}` its size in pixels.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Names {
+    pub default: String,
+    pub high: String,
+    pub thumb: String,
+}
+
+impl Default for Names {
+    fn default() -> Self {
+        Names {
+            default: "{stem}.{ext}".to_string(),
+            high: "{stem}_high.{ext}".to_string(),
+            thumb: "{stem}_thumb.{ext}".to_string(),
+        }
+    }
+}
+
+impl Names {
+    pub fn get(&self, variant: Variant) -> &str {
+        match variant {
+            Variant::Default => &self.default,
+            Variant::High => &self.high,
+            Variant::Thumb => &self.thumb,
+        }
+    }
+
+    /// The file name of `variant` for a source named `stem`. The stem and
+    /// extension are kept as they are, even if they aren't valid UTF-8
+    pub fn render(&self, variant: Variant, stem: &OsStr, ext: &OsStr, sizes: &Sizes) -> OsString {
+        let mut name = OsString::new();
+        let mut rest = self.get(variant);
+        while let Some(start) = rest.find('{') {
+            name.push(&rest[..start]);
+            rest = &rest[start..];
+            let placeholder = rest.find('}').map_or(rest, |end| &rest[..=end]);
+            match placeholder {
+                "{stem}" => name.push(stem),
+                "{ext}" => name.push(ext),
+                "{variant}" => name.push(variant.name()),
+                "{width}" => name.push(sizes.get(variant).to_string()),
+                other => name.push(other),
+            }
+            rest = &rest[placeholder.len()..];
+        }
+        name.push(rest);
+        name
+    }
+
+    /// Checks that every template names its source and that no two variants
+    /// end up with the same file name
+    fn validate(&self, sizes: &Sizes) -> Result<()> {
+        const PLACEHOLDERS: [&str; 4] = ["{stem}", "{ext}", "{variant}", "{width}"];
+        let variants = [Variant::Default, Variant::High, Variant::Thumb];
+        for variant in variants {
+            let template = self.get(variant);
+            if !template.contains("{stem}") {
+                return Err(eyre!(
+                    "the {} name \"{template}\" must contain {{stem}}",
+                    variant.name()
+                ));
+            }
+            let rest = PLACEHOLDERS
+                .iter()
+                .fold(template.to_string(), |rest, p| rest.replace(p, ""));
+            if rest.contains(['{', '}', '/', '\\']) {
+                return Err(eyre!(
+                    "the {} name \"{template}\" can only use {}, without path separators",
+                    variant.name(),
+                    PLACEHOLDERS.join(", ")
+                ));
+            }
+        }
+        for (i, a) in variants.iter().enumerate() {
+            for b in &variants[i + 1..] {
+                if self.render(*a, "a".as_ref(), "jpg".as_ref(), sizes)
+                    == self.render(*b, "a".as_ref(), "jpg".as_ref(), sizes)
+                {
+                    return Err(eyre!(
+                        "the {} and {} variants would have the same names",
+                        a.name(),
+                        b.name()
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether every variant is stripped of its metadata, like EXIF, and color
+/// profile. Unstripped variants are larger but keep the camera settings,
+/// copyright and colors of the source, e.g. for high resolution downloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Strip {
+    pub default: bool,
+    pub high: bool,
+    pub thumb: bool,
+}
+
+impl Default for Strip {
+    fn default() -> Self {
+        Strip {
+            default: true,
+            high: true,
+            thumb: true,
+        }
+    }
+}
+
+impl Strip {
+    pub fn get(&self, variant: Variant) -> bool {
+        match variant {
+            Variant::Default => self.default,
+            Variant::High => self.high,
+            Variant::Thumb => self.thumb,
+        }
+    }
+}
+
+/// The largest size of every variant in kB (1000 bytes). ImageMagick finds
+/// the highest quality that fits for JPEG and WebP, while PNG can't be
+/// shrunk to a size.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TargetSizes {
+    pub default: Option<u64>,
+    pub high: Option<u64>,
+    pub thumb: Option<u64>,
+}
+
+impl TargetSizes {
+    /// The target of `variant` in bytes
+    pub fn get(&self, variant: Variant) -> Option<u64> {
+        let kb = match variant {
+            Variant::Default => self.default,
+            Variant::High => self.high,
+            Variant::Thumb => self.thumb,
+        };
+        kb.map(|kb| kb * 1000)
+    }
+
+    /// Sets the targets in a list like `thumb=40,default=300`
+    pub fn parse(&mut self, list: &str) -> Result<()> {
+        for target in list.split(',').map(str::trim).filter(|t| !t.is_empty()) {
+            let (variant, kb) = target
+                .split_once('=')
+                .ok_or_else(|| eyre!("target sizes are like thumb=40, not {target}"))?;
+            let kb = kb
+                .trim()
+                .parse::<u64>()
+                .ok()
+                .filter(|kb| *kb > 0)
+                .ok_or_else(|| eyre!("target sizes are positive numbers of kB, not {kb}"))?;
+            let slot = match variant.trim() {
+                "default" => &mut self.default,
+                "high" => &mut self.high,
+                "thumb" => &mut self.thumb,
+                other => return Err(eyre!("no variant is named {other}")),
+            };
+            *slot = Some(kb);
+        }
+        Ok(())
+    }
+}
+
+/// The longest side in pixels of every variant
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sizes {
+    pub default: u32,
+    pub high: u32,
+    pub thumb: u32,
+}
+
+impl Default for Sizes {
+    fn default() -> Self {
+        Sizes {
+            default: 1920,
+            high: 3840,
+            thumb: 640,
+        }
+    }
+}
+
+impl Sizes {
+    pub fn get(&self, variant: Variant) -> u32 {
+        match variant {
+            Variant::Default => self.default,
+            Variant::High => self.high,
+            Variant::Thumb => self.thumb,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ExtensionRule {
+    pub action: Option<Action>,
+    pub quality: Option<u8>,
+    pub format: Option<Format>,
+    /// The maximum size of copied files with this extension or in this
+    /// category in MiB, instead of the one for all files
+    pub max_file_size: Option<u64>,
+}
+
+/// What is done with a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    /// Resize into every variant
+    Convert,
+    /// Copy as it is if it is smaller than the maximum file size
+    Copy,
+    /// Leave out of the destination
+    Skip,
+}
+
+/// The part of an image that is kept when it is cropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Gravity {
+    Center,
+    North,
+    NorthEast,
+    East,
+    SouthEast,
+    South,
+    SouthWest,
+    West,
+    NorthWest,
+}
+
+impl Gravity {
+    /// The name ImageMagick's `-gravity` takes
+    pub fn imagemagick_name(self) -> &'static str {
+        match self {
+            Gravity::Center => "Center",
+            Gravity::North => "North",
+            Gravity::NorthEast => "NorthEast",
+            Gravity::East => "East",
+            Gravity::SouthEast => "SouthEast",
+            Gravity::South => "South",
+            Gravity::SouthWest => "SouthWest",
+            Gravity::West => "West",
+            Gravity::NorthWest => "NorthWest",
+        }
+    }
+}
+
+/// What images show, which decides how they are best converted. A preset
+/// picks the quality and format unless the config sets them, and how the
+/// images are blurred, sharpened and chroma subsampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Preset {
+    /// Photographs, which hide compression well: JPEG with a slight blur
+    /// before resizing and a slight sharpening after
+    Photo,
+    /// Illustrations and paintings with flat colors and hard edges: WebP
+    /// without blur or chroma subsampling
+    Artwork,
+    /// Screenshots and diagrams with text: lossless PNG
+    Screenshot,
+    /// Scanned documents and prints: JPEG without chroma subsampling,
+    /// sharpened to keep text legible
+    Scan,
+}
+
+impl Preset {
+    pub fn quality(self) -> u8 {
+        match self {
+            Preset::Photo => 82,
+            Preset::Artwork => 90,
+            // The zlib level and filter of PNG
+            Preset::Screenshot => 95,
+            Preset::Scan => 88,
+        }
+    }
+
+    pub fn format(self) -> Format {
+        match self {
+            Preset::Photo | Preset::Scan => Format::Jpg,
+            Preset::Artwork => Format::Webp,
+            Preset::Screenshot => Format::Png,
+        }
+    }
+}
+
+/// An automatic correction of an image, good enough for unedited phone
+/// photos
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Enhancement {
+    /// Stretches the brightest and darkest pixels to white and black,
+    /// brightening dull and dark images without changing their colors
+    AutoLevel,
+    /// Stretches the red, green and blue channels separately, which removes
+    /// a color cast
+    WhiteBalance,
+    /// Increases the contrast, ignoring the brightest and darkest half
+    /// percent of pixels so a few specks don't keep it from working
+    ContrastStretch,
+}
+
+impl Enhancement {
+    /// The ImageMagick arguments applying the correction
+    pub fn imagemagick_args(self) -> &'static [&'static str] {
+        match self {
+            Enhancement::AutoLevel => &["-auto-level"],
+            Enhancement::WhiteBalance => &[
+                "-channel",
+                "R",
+                "-auto-level",
+                "-channel",
+                "G",
+                "-auto-level",
+                "-channel",
+                "B",
+                "-auto-level",
+                "+channel",
+            ],
+            Enhancement::ContrastStretch => &["-contrast-stretch", "0.5%x0.5%"],
+        }
+    }
+}
+
+/// Overrides for a single source, read from a file named like it with
+/// [`SIDECAR_EXTENSION`] appended, e.g. `hero.jpg.toml` for `hero.jpg`. They
+/// take precedence over every config file.
+///
+/// ```toml
+/// # convert, copy or skip, e.g. copy to keep the source untouched
+/// action = "copy"
+/// quality = 95
+/// format = "png"
+/// thumbnails = false
+/// gravity = "north"
+/// preset = "screenshot"
+/// # Listed in the manifest and used by the gallery
+/// alt = "The team on the summit"
+/// ```
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Sidecar {
+    pub action: Option<Action>,
+    pub quality: Option<u8>,
+    pub format: Option<Format>,
+    pub thumbnails: Option<bool>,
+    pub gravity: Option<Gravity>,
+    pub preset: Option<Preset>,
+    /// A description of the image for the `alt` attribute
+    pub alt: Option<String>,
+}
+
+impl Sidecar {
+    pub fn parse(toml: &str) -> Result<Sidecar> {
+        let sidecar: Sidecar = toml::from_str(toml)?;
+        if let Some(quality) = sidecar.quality.filter(|q| !(1..=100).contains(q)) {
+            return Err(eyre!("quality must be between 1 and 100, not {quality}"));
+        }
+        Ok(sidecar)
+    }
+
+    /// The path of the sidecar of `source`
+    pub fn path(source: &Path) -> PathBuf {
+        let mut path = source.as_os_str().to_owned();
+        path.push(".");
+        path.push(SIDECAR_EXTENSION);
+        PathBuf::from(path)
+    }
+
+    /// Whether `path` is the sidecar of a file next to it
+    #[cfg(feature = "native")]
+    pub fn is_sidecar(path: &Path) -> bool {
+        let is_toml = path
+            .extension()
+            .is_some_and(|e| e.eq_ignore_ascii_case(SIDECAR_EXTENSION));
+        is_toml && path.with_extension("").is_file()
+    }
+
+    /// Reads the sidecar of `source` if it has one
+    #[cfg(feature = "native")]
+    pub fn load(source: &Path) -> Result<Option<Sidecar>> {
+        let path = Sidecar::path(source);
+        match std::fs::read_to_string(&path) {
+            Ok(toml) => Sidecar::parse(&toml)
+                .map(Some)
+                .wrap_err_with(|| ConfigError { path }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        }
+    }
+}
+
+/// The format of converted images
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+#[serde(rename_all = "kebab-case")]
+pub enum Format {
+    #[default]
+    Jpg,
+    Webp,
+    Png,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Jpg => "jpg",
+            Format::Webp => "webp",
+            Format::Png => "png",
+        }
+    }
+}
+
+impl Config {
+    pub fn parse(toml: &str) -> Result<Config> {
+        let mut config: Config = toml::from_str(toml)?;
+        config.extensions = std::mem::take(&mut config.extensions)
+            .into_iter()
+            .map(|(extension, rule)| (extension.to_lowercase(), rule))
+            .collect();
+        let qualities = config
+            .extensions
+            .values()
+            .chain(config.categories.values())
+            .map(|rule| rule.quality);
+        if let Some(quality) = qualities
+            .chain([config.quality])
+            .flatten()
+            .find(|quality| !(1..=100).contains(quality))
+        {
+            return Err(eyre!("quality must be between 1 and 100, not {quality}"));
+        }
+        let targets = config.target_size;
+        if [targets.default, targets.high, targets.thumb].contains(&Some(0)) {
+            return Err(eyre!("target sizes must be positive"));
+        }
+        if let Some(denoise) = config.denoise.filter(|d| !(1..=50).contains(d)) {
+            return Err(eyre!("denoise must be between 1 and 50, not {denoise}"));
+        }
+        if let Some(color) = config.flatten_color.as_ref().filter(|c| !is_hex_color(c)) {
+            return Err(eyre!("flatten-color must be like #ffffff, not {color}"));
+        }
+        config.names.validate(&config.sizes)?;
+        Ok(config)
+    }
+
+    /// Reads `path`, or returns the defaults if it doesn't exist
+    #[cfg(feature = "native")]
+    pub fn load(path: &Path) -> Result<Config> {
+        match std::fs::read_to_string(path) {
+            Ok(toml) => Config::parse(&toml).wrap_err_with(|| ConfigError {
+                path: path.to_path_buf(),
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Applies the settings in `overlay` on top of these. Tables like `sizes`,
+    /// `names` and `extensions` are merged key by key, other values are replaced.
+    pub fn merge(&self, mut overlay: toml::Table) -> Result<Config> {
+        if let Some(toml::Value::Table(extensions)) = overlay.get_mut("extensions") {
+            *extensions = std::mem::take(extensions)
+                .into_iter()
+                .map(|(extension, rule)| (extension.to_lowercase(), rule))
+                .collect();
+        }
+        let mut table = toml::Table::try_from(self)?;
+        merge_tables(&mut table, overlay);
+        Config::parse(&table.to_string())
+    }
+
+    /// The config for `path` with the overrides of its sidecar. They are set
+    /// in the rule for its extension, which takes precedence over the others.
+    pub fn with_sidecar(&self, path: &Path, sidecar: &Sidecar) -> Config {
+        let mut config = self.clone();
+        let extension = formats::extension(path).unwrap_or_default();
+        let rule = config.extensions.entry(extension).or_default();
+        rule.action = sidecar.action.or(rule.action);
+        rule.quality = sidecar.quality.or(rule.quality);
+        rule.format = sidecar.format.or(rule.format);
+        config.thumbnails = sidecar.thumbnails.or(config.thumbnails);
+        config.gravity = sidecar.gravity.or(config.gravity);
+        config.preset = sidecar.preset.or(config.preset);
+        config
+    }
+
+    /// The rules for `path`, the one for its extension first
+    fn rules(&self, path: &Path) -> impl Iterator<Item = &ExtensionRule> {
+        let extension = path.extension().map(|e| e.to_string_lossy());
+        let by_extension = extension.and_then(|extension| {
+            self.extensions
+                .iter()
+                .find(|(e, _)| e.eq_ignore_ascii_case(&extension))
+                .map(|(_, rule)| rule)
+        });
+        let by_category = formats::category(path).and_then(|c| self.categories.get(&c));
+        by_extension.into_iter().chain(by_category)
+    }
+
+    /// What is done with `path`. Without a rule, the [image
+    /// formats](formats::IMAGE_FORMATS) are converted and everything else is
+    /// copied.
+    pub fn action(&self, path: &Path) -> Action {
+        match self.rules(path).find_map(|rule| rule.action) {
+            Some(action) => action,
+            None if formats::is_image(path) => Action::Convert,
+            None => Action::Copy,
+        }
+    }
+
+    pub fn quality(&self, path: &Path) -> u8 {
+        self.rules(path)
+            .find_map(|rule| rule.quality)
+            .or(self.quality)
+            .or(self.preset.map(Preset::quality))
+            .unwrap_or(DEFAULT_QUALITY)
+    }
+
+    pub fn format(&self, path: &Path) -> Format {
+        self.rules(path)
+            .find_map(|rule| rule.format)
+            .or(self.format)
+            .or(self.preset.map(Preset::format))
+            .unwrap_or_default()
+    }
+
+    /// The maximum size in bytes of `path` if its extension or category has
+    /// its own
+    pub fn max_file_size(&self, path: &Path) -> Option<u64> {
+        self.rules(path)
+            .find_map(|rule| rule.max_file_size)
+            .map(|mib| mib * MIB)
+    }
+
+    /// Adds the extensions in `block` to the blocked ones and removes those in
+    /// `unblock`, starting from [`BLOCKED_EXTENSIONS`] if the config lists none
+    pub fn change_block(&mut self, block: &[String], unblock: &[String]) {
+        if block.is_empty() && unblock.is_empty() {
+            return;
+        }
+        let mut blocked = self
+            .block
+            .take()
+            .unwrap_or_else(|| BLOCKED_EXTENSIONS.map(String::from).into());
+        blocked.extend(block.iter().cloned());
+        blocked.retain(|e| !is_listed(unblock, e.trim_start_matches('.')));
+        self.block = Some(blocked);
+    }
+
+    /// Whether the extension of `path` is blocked
+    pub fn is_blocked(&self, path: &Path) -> bool {
+        let Some(extension) = formats::extension(path) else {
+            return false;
+        };
+        match &self.block {
+            Some(block) => is_listed(block, &extension),
+            None => BLOCKED_EXTENSIONS.contains(&extension.as_str()),
+        }
+    }
+
+    /// Whether the extension of `path` is in the allowlist, if there is one
+    pub fn is_allowed(&self, path: &Path) -> bool {
+        let extension = formats::extension(path).unwrap_or_default();
+        let allow = self.allow.as_deref();
+        allow.is_none_or(|allow| is_listed(allow, &extension))
+    }
+
+    pub fn thumbnails(&self) -> bool {
+        self.thumbnails.unwrap_or(true)
+    }
+
+    pub fn slug_names(&self) -> bool {
+        self.slug_names.unwrap_or(false)
+    }
+
+    pub fn flatten(&self) -> bool {
+        self.flatten.unwrap_or(false)
+    }
+
+    pub fn keep_originals(&self) -> bool {
+        self.keep_originals.unwrap_or(false)
+    }
+
+    pub fn detect_graphics(&self) -> bool {
+        self.detect_graphics.unwrap_or(false)
+    }
+}
+
+/// Whether `color` is written like `#1a1a2e`
+pub fn is_hex_color(color: &str) -> bool {
+    color
+        .strip_prefix('#')
+        .is_some_and(|hex| hex.len() == 6 && hex.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn merged(base: &str, overlay: &str) -> Config {
+        let base = Config::parse(base).unwrap();
+        base.merge(toml::from_str(overlay).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn overlays_replace_values_and_merge_tables_key_by_key() {
+        let config = merged(
+            r#"
+            quality = 80
+            exclude = ["drafts/**"]
+            [sizes]
+            default = 1600
+            thumb = 400
+            [extensions.PNG]
+            action = "copy"
+            quality = 90
+            "#,
+            r#"
+            quality = 60
+            exclude = ["wip/**"]
+            [sizes]
+            thumb = 300
+            [extensions.png]
+            quality = 70
+            "#,
+        );
+        assert_eq!(config.quality, Some(60));
+        assert_eq!(config.exclude, ["wip/**"]);
+        assert_eq!(config.sizes.default, 1600);
+        assert_eq!(config.sizes.thumb, 300);
+        assert_eq!(config.sizes.high, Sizes::default().high);
+        let rule = &config.extensions["png"];
+        assert_eq!(rule.action, Some(Action::Copy));
+        assert_eq!(rule.quality, Some(70));
+    }
+
+    #[test]
+    fn overlays_keep_what_they_dont_set() {
+        let config = merged("quality = 80\nformat = \"webp\"", "thumbnails = false");
+        assert_eq!(config.quality, Some(80));
+        assert_eq!(config.format, Some(Format::Webp));
+        assert_eq!(config.thumbnails, Some(false));
+    }
+
+    #[test]
+    fn overlays_are_validated() {
+        let base = Config::parse("quality = 80").unwrap();
+        let overlay = toml::from_str("quality = 0").unwrap();
+        assert!(base.merge(overlay).is_err());
+    }
+
+    fn names(default: &str, high: &str, thumb: &str) -> Names {
+        Names {
+            default: default.to_string(),
+            high: high.to_string(),
+            thumb: thumb.to_string(),
+        }
+    }
+
+    #[test]
+    fn names_fill_in_every_placeholder() {
+        let names = names(
+            "{stem}.{ext}",
+            "{stem}-{width}w.{ext}",
+            "{stem}.{variant}.{ext}",
+        );
+        let sizes = Sizes::default();
+        let render = |variant| names.render(variant, "Café".as_ref(), "webp".as_ref(), &sizes);
+        assert_eq!(render(Variant::Default), "Café.webp");
+        assert_eq!(render(Variant::High), "Café-3840w.webp");
+        assert_eq!(render(Variant::Thumb), "Café.thumb.webp");
+    }
+
+    #[test]
+    fn unknown_placeholders_are_kept_as_they_are() {
+        let names = names(
+            "{stem}{size}.{ext}",
+            "{stem}_high.{ext",
+            "{stem}_thumb.{ext}",
+        );
+        let sizes = Sizes::default();
+        let render = |variant| names.render(variant, "a".as_ref(), "jpg".as_ref(), &sizes);
+        assert_eq!(render(Variant::Default), "a{size}.jpg");
+        assert_eq!(render(Variant::High), "a_high.{ext");
+    }
+
+    #[test]
+    fn names_must_contain_the_stem_and_differ() {
+        let sizes = Sizes::default();
+        assert!(Names::default().validate(&sizes).is_ok());
+        assert!(names(
+            "{stem}.{ext}",
+            "{stem}-{width}w.{ext}",
+            "{stem}.{variant}.{ext}"
+        )
+        .validate(&sizes)
+        .is_ok());
+        assert!(
+            names("photo.{ext}", "{stem}_high.{ext}", "{stem}_thumb.{ext}")
+                .validate(&sizes)
+                .is_err()
+        );
+        assert!(names("{stem}.{ext}", "{stem}.{ext}", "{stem}_thumb.{ext}")
+            .validate(&sizes)
+            .is_err());
+        assert!(
+            names("{stem}.{ext}", "high/{stem}.{ext}", "{stem}_thumb.{ext}")
+                .validate(&sizes)
+                .is_err()
+        );
+        assert!(
+            names("{stem}.{ext}", "{stem}_{size}.{ext}", "{stem}_thumb.{ext}")
+                .validate(&sizes)
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn names_with_equal_widths_collide() {
+        let sizes = Sizes {
+            default: 1920,
+            high: 1920,
+            thumb: 640,
+        };
+        let names = names(
+            "{stem}-{width}w.{ext}",
+            "{stem}-{width}w.{ext}",
+            "{stem}_t.{ext}",
+        );
+        assert!(names.validate(&sizes).is_err());
+    }
+
+    #[test]
+    fn target_sizes_are_parsed_in_kb() {
+        let mut targets = TargetSizes::default();
+        targets.parse(" thumb=40, default = 300 ,").unwrap();
+        assert_eq!(targets.get(Variant::Thumb), Some(40_000));
+        assert_eq!(targets.get(Variant::Default), Some(300_000));
+        assert_eq!(targets.get(Variant::High), None);
+        // Later lists only replace the targets they name
+        targets.parse("high=900").unwrap();
+        assert_eq!(targets.get(Variant::Default), Some(300_000));
+        assert_eq!(targets.get(Variant::High), Some(900_000));
+    }
+
+    #[test]
+    fn bad_target_sizes_are_rejected() {
+        for list in ["thumb", "thumb=40kb", "thumb=0", "thumb=-5", "small=40"] {
+            let mut targets = TargetSizes::default();
+            assert!(targets.parse(list).is_err(), "{list} is rejected");
+        }
+        assert!(Config::parse("[target-size]\nthumb = 0").is_err());
+    }
+
+    fn strings(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn design_files_are_blocked_by_default() {
+        let config = Config::default();
+        assert!(config.is_blocked(Path::new("art/Poster.PSD")));
+        assert!(config.is_blocked(Path::new("raw/a.xmp")));
+        assert!(!config.is_blocked(Path::new("photos/a.jpg")));
+        assert!(!config.is_blocked(Path::new("README")));
+        assert!(config.is_allowed(Path::new("README")));
+    }
+
+    #[test]
+    fn the_block_list_of_the_config_replaces_the_default() {
+        let config = Config::parse(r#"block = [".mov", "psd"]"#).unwrap();
+        assert!(config.is_blocked(Path::new("clip.MOV")));
+        assert!(config.is_blocked(Path::new("a.psd")));
+        assert!(!config.is_blocked(Path::new("a.xcf")));
+        let config = Config::parse("block = []").unwrap();
+        assert!(!config.is_blocked(Path::new("a.psd")));
+    }
+
+    #[test]
+    fn unblocking_removes_extensions_from_the_block_list() {
+        let mut config = Config::default();
+        config.change_block(&strings(&["mov"]), &strings(&[".AI", "psd"]));
+        assert!(config.is_blocked(Path::new("clip.mov")));
+        assert!(config.is_blocked(Path::new("a.xcf")));
+        assert!(!config.is_blocked(Path::new("vector.ai")));
+        assert!(!config.is_blocked(Path::new("a.psd")));
+    }
+
+    #[test]
+    fn the_allowlist_leaves_out_everything_else() {
+        let config = Config::parse(r#"allow = ["jpg", ".PNG", "psd"]"#).unwrap();
+        assert!(config.is_allowed(Path::new("a.JPG")));
+        assert!(config.is_allowed(Path::new("a.png")));
+        assert!(!config.is_allowed(Path::new("a.mp4")));
+        assert!(!config.is_allowed(Path::new("README")));
+        // Allowing a blocked extension doesn't unblock it
+        assert!(config.is_allowed(Path::new("a.psd")));
+        assert!(config.is_blocked(Path::new("a.psd")));
+    }
+}
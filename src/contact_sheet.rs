@@ -0,0 +1,178 @@
+//! Contact sheets: a grid of the thumbnails in an output folder with the names
+//! of their sources, rendered with ImageMagick's `montage` for reviewing a
+//! shoot at a glance. A sheet is only rendered again when the thumbnails of
+//! its folder change, which is tracked in a state file.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    process::Command,
+    time::UNIX_EPOCH,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{manifest::url_path, Job, Variant};
+
+/// The name of the contact sheet in every output folder with thumbnails
+pub const FILE_NAME: &str = "contact-sheet.jpg";
+
+/// The name of the state file in the destination folder, mapping every
+/// folder with a contact sheet to a hash of the thumbnails it shows
+pub const STATE_FILE_NAME: &str = ".web_assets_converter_contact_sheets.json";
+
+/// Thumbnails per row
+const COLUMNS: u32 = 6;
+
+/// The size of every tile, which thumbnails are scaled down to fit
+const TILE_SIZE: u32 = 240;
+
+/// Renders the contact sheets of the folders in `destination` whose
+/// thumbnails changed since their sheets were rendered, and removes the
+/// sheets of folders that no longer have any. Returns the number of sheets
+/// rendered.
+pub fn update<'a>(jobs: impl IntoIterator<Item = &'a Job>, destination: &Path) -> Result<usize> {
+    let jobs: Vec<_> = jobs.into_iter().collect();
+    let mut folders: BTreeMap<PathBuf, Vec<(String, &Path)>> = BTreeMap::new();
+    for job in &jobs {
+        let thumb = job
+            .outputs()
+            .into_iter()
+            .find(|(variant, path)| *variant == Variant::Thumb.name() && path.is_file());
+        if let Some((_, path)) = thumb {
+            let label = job.relative.file_name().unwrap_or_default();
+            let folder = path.parent().unwrap_or(destination).to_path_buf();
+            folders
+                .entry(folder)
+                .or_default()
+                .push((label.to_string_lossy().into_owned(), path));
+        }
+    }
+    let outputs: HashSet<&Path> = jobs
+        .iter()
+        .flat_map(|job| job.outputs())
+        .map(|(_, path)| path)
+        .collect();
+    let old_state = load_state(destination)?;
+    let mut state = BTreeMap::new();
+    let mut rendered = 0;
+    for (folder, thumbs) in &folders {
+        let relative = folder.strip_prefix(destination).unwrap_or(folder);
+        let sheet = folder.join(FILE_NAME);
+        // The output of a source called contact-sheet.jpg
+        if outputs.contains(sheet.as_path()) {
+            warn!(
+                "Not writing a contact sheet over the output {}",
+                sheet.display()
+            );
+            continue;
+        }
+        let key = key(thumbs);
+        let folder_key = url_path(relative);
+        if old_state.get(&folder_key) == Some(&key) && sheet.is_file() {
+            debug!("{} is up to date", sheet.display());
+        } else {
+            render(thumbs, &sheet)
+                .wrap_err_with(|| format!("rendering the contact sheet {}", sheet.display()))?;
+            rendered += 1;
+        }
+        state.insert(folder_key, key);
+    }
+    for folder in old_state
+        .keys()
+        .filter(|folder| !state.contains_key(*folder))
+    {
+        let sheet = destination.join(folder).join(FILE_NAME);
+        if let Err(e) = std::fs::remove_file(&sheet) {
+            if e.kind() != std::io::ErrorKind::NotFound {
+                warn!("can't remove the contact sheet {}: {e}", sheet.display());
+            }
+        }
+    }
+    save_state(destination, &state)?;
+    Ok(rendered)
+}
+
+/// The contact sheets listed in the state file of `destination`
+pub fn sheets(destination: &Path) -> Result<Vec<PathBuf>> {
+    Ok(load_state(destination)?
+        .into_keys()
+        .map(|folder| destination.join(folder).join(FILE_NAME))
+        .collect())
+}
+
+/// Changes with the names, sizes and modification times of the thumbnails
+fn key(thumbs: &[(String, &Path)]) -> String {
+    let mut hasher = Sha256::new();
+    for (label, thumb) in thumbs {
+        let metadata = thumb.metadata().ok();
+        let modified = metadata
+            .as_ref()
+            .and_then(|m| m.modified().ok())
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |time| time.as_nanos());
+        hasher.update(label);
+        hasher.update([0]);
+        hasher.update(thumb.as_os_str().as_encoded_bytes());
+        hasher.update([0]);
+        hasher.update(metadata.map_or(0, |m| m.len()).to_le_bytes());
+        hasher.update(modified.to_le_bytes());
+    }
+    format!("{:x}", hasher.finalize())
+}
+
+fn render(thumbs: &[(String, &Path)], sheet: &Path) -> Result<()> {
+    let mut command = Command::new("montage");
+    for (label, thumb) in thumbs {
+        // Percent signs would be read as ImageMagick escapes
+        command
+            .arg("-label")
+            .arg(label.replace('%', "%%"))
+            .arg(thumb);
+    }
+    command
+        .args(["-tile", &format!("{COLUMNS}x")])
+        .args(["-geometry", &format!("{TILE_SIZE}x{TILE_SIZE}>+8+8")])
+        .args(["-background", "white", "-pointsize", "12", "-quality", "85"])
+        .arg(sheet);
+    debug!("running {command:?}");
+    let output = command
+        .output()
+        .map_err(|e| crate::spawn_error("ImageMagick's montage", e))?;
+    if !output.status.success() {
+        let _ = std::fs::remove_file(sheet);
+        return Err(eyre!(
+            "montage failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+fn load_state(destination: &Path) -> Result<BTreeMap<String, String>> {
+    let path = destination.join(STATE_FILE_NAME);
+    match std::fs::read_to_string(&path) {
+        Ok(json) => {
+            serde_json::from_str(&json).wrap_err_with(|| format!("parsing {}", path.display()))
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+    }
+}
+
+fn save_state(destination: &Path, state: &BTreeMap<String, String>) -> Result<()> {
+    let path = destination.join(STATE_FILE_NAME);
+    if state.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| format!("removing {}", path.display()))
+            }
+            _ => Ok(()),
+        };
+    }
+    let json = serde_json::to_string_pretty(state)?;
+    std::fs::write(&path, json).wrap_err_with(|| format!("writing {}", path.display()))
+}
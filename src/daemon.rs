@@ -0,0 +1,168 @@
+//! A small HTTP API for converting uploaded images.
+//!
+//! - `POST /convert?variant=<name>` converts the request body and responds
+//!   with the JPEG of that variant.
+//! - `POST /convert` starts converting every variant in the background and
+//!   responds with `{"job": <id>}`.
+//! - `GET /jobs/<id>` reports the status of a job and the URLs of its variants.
+//! - `GET /jobs/<id>/<variant>` responds with a finished variant.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use clap::ValueEnum;
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde_json::json;
+use tiny_http::{Header, Method, Request, Response, Server};
+use web_assets_converter::{imagemagick, Variant};
+
+const ALL_VARIANTS: [Variant; 3] = [Variant::Default, Variant::High, Variant::Thumb];
+
+enum JobState {
+    Pending,
+    Done(HashMap<&'static str, Vec<u8>>),
+    Failed(String),
+}
+
+#[derive(Default)]
+struct Jobs {
+    next_id: u64,
+    states: HashMap<u64, JobState>,
+}
+
+type SharedJobs = Arc<Mutex<Jobs>>;
+
+pub fn run(listen: &str) -> Result<()> {
+    let server = Server::http(listen)
+        .map_err(|e| eyre!(e))
+        .wrap_err_with(|| format!("listening on {listen}"))?;
+    tracing::info!("Listening on http://{listen}");
+    let jobs = SharedJobs::default();
+    for request in server.incoming_requests() {
+        let jobs = jobs.clone();
+        std::thread::spawn(move || {
+            let method = request.method().clone();
+            let url = request.url().to_string();
+            if let Err(e) = handle(request, &jobs) {
+                tracing::error!("{method} {url}: {e:?}");
+            }
+        });
+    }
+    Ok(())
+}
+
+fn handle(mut request: Request, jobs: &SharedJobs) -> Result<()> {
+    let url = request.url().to_string();
+    let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+    let response = match (request.method(), segments.as_slice()) {
+        (Method::Post, ["convert"]) => {
+            let mut body = Vec::new();
+            request.as_reader().read_to_end(&mut body)?;
+            match query_value(query, "variant") {
+                Some(name) => match Variant::from_str(name, true) {
+                    Ok(variant) => match imagemagick::convert_bytes(&body, variant) {
+                        Ok(data) => jpeg(data),
+                        Err(e) => text(500, format!("{e:#}")),
+                    },
+                    Err(_) => text(400, format!("unknown variant: {name}")),
+                },
+                None => {
+                    let id = start_job(body, jobs);
+                    json_response(202, json!({ "job": id }))
+                }
+            }
+        }
+        (Method::Get, ["jobs", id]) => match id.parse() {
+            Ok(id) => job_status(id, jobs),
+            Err(_) => text(404, "no such job"),
+        },
+        (Method::Get, ["jobs", id, variant]) => {
+            let jobs = jobs.lock().unwrap();
+            let data = id
+                .parse()
+                .ok()
+                .and_then(|id: u64| jobs.states.get(&id))
+                .and_then(|state| match state {
+                    JobState::Done(variants) => variants.get(variant),
+                    _ => None,
+                });
+            match data {
+                Some(data) => jpeg(data.clone()),
+                None => text(404, "no such variant"),
+            }
+        }
+        _ => text(404, "not found"),
+    };
+    request.respond(response)?;
+    Ok(())
+}
+
+/// Converts every variant on a background thread and returns the job id
+fn start_job(body: Vec<u8>, jobs: &SharedJobs) -> u64 {
+    let id = {
+        let mut jobs = jobs.lock().unwrap();
+        jobs.next_id += 1;
+        let id = jobs.next_id;
+        jobs.states.insert(id, JobState::Pending);
+        id
+    };
+    let jobs = jobs.clone();
+    std::thread::spawn(move || {
+        let state = ALL_VARIANTS
+            .iter()
+            .map(|&variant| {
+                imagemagick::convert_bytes(&body, variant).map(|data| (variant.name(), data))
+            })
+            .collect::<Result<HashMap<_, _>>>()
+            .map_or_else(|e| JobState::Failed(format!("{e:#}")), JobState::Done);
+        jobs.lock().unwrap().states.insert(id, state);
+    });
+    id
+}
+
+fn job_status(id: u64, jobs: &SharedJobs) -> Response<std::io::Cursor<Vec<u8>>> {
+    let jobs = jobs.lock().unwrap();
+    match jobs.states.get(&id) {
+        None => text(404, "no such job"),
+        Some(JobState::Pending) => json_response(200, json!({ "status": "pending" })),
+        Some(JobState::Failed(error)) => {
+            json_response(200, json!({ "status": "failed", "error": error }))
+        }
+        Some(JobState::Done(variants)) => {
+            let urls: HashMap<_, _> = variants
+                .keys()
+                .map(|name| (*name, format!("/jobs/{id}/{name}")))
+                .collect();
+            json_response(200, json!({ "status": "done", "variants": urls }))
+        }
+    }
+}
+
+fn query_value<'a>(query: &'a str, key: &str) -> Option<&'a str> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v)
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+fn jpeg(data: Vec<u8>) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_data(data).with_header(header("Content-Type", "image/jpeg"))
+}
+
+fn text(status: u16, message: impl Into<String>) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(message).with_status_code(status)
+}
+
+fn json_response(status: u16, value: serde_json::Value) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(value.to_string())
+        .with_status_code(status)
+        .with_header(header("Content-Type", "application/json"))
+}
@@ -0,0 +1,184 @@
+//! Compares the planned outputs with what is in the destination folder, to
+//! see what a run would change before running it.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use color_eyre::eyre::Result;
+use walkdir::WalkDir;
+
+use crate::{
+    contact_sheet, gallery, hashes, headers, journal, lock, manifest, platform, redirects,
+    remote::sha256_file, Job,
+};
+
+/// Files in the destination folder that are written by the tool itself
+pub(crate) const STATE_FILES: [&str; 9] = [
+    contact_sheet::STATE_FILE_NAME,
+    hashes::FILE_NAME,
+    journal::FILE_NAME,
+    manifest::FILE_NAME,
+    manifest::SKIPPED_FILE_NAME,
+    manifest::LOCATIONS_FILE_NAME,
+    redirects::NETLIFY_FILE_NAME,
+    redirects::NGINX_FILE_NAME,
+    redirects::JSON_FILE_NAME,
+];
+
+/// An output and the source it is made from, both relative to their folders
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub source: PathBuf,
+    pub output: PathBuf,
+}
+
+#[derive(Debug, Default)]
+pub struct Diff {
+    /// Outputs that don't exist yet
+    pub missing: Vec<Output>,
+    /// Outputs whose source was modified after they were written
+    pub stale: Vec<Output>,
+    /// Outputs without any content, e.g. left by a conversion that crashed
+    pub empty: Vec<Output>,
+    pub up_to_date: usize,
+    /// Files in the destination folder that no source produces, relative to it
+    pub orphaned: Vec<PathBuf>,
+}
+
+impl Diff {
+    pub fn is_empty(&self) -> bool {
+        self.is_complete() && self.orphaned.is_empty()
+    }
+
+    /// Whether every output exists, has content and is newer than its source
+    pub fn is_complete(&self) -> bool {
+        self.missing.is_empty() && self.stale.is_empty() && self.empty.is_empty()
+    }
+}
+
+/// Compares the outputs of `jobs` with the files in `destination`
+pub fn diff(jobs: &[Job], destination: &Path) -> Result<Diff> {
+    let mut diff = Diff::default();
+    let mut expected = HashSet::new();
+    for job in jobs {
+        let source_modified = job.source.metadata()?.modified()?;
+        for (_, path) in job.outputs() {
+            expected.insert(path.to_path_buf());
+            let output = Output {
+                source: job.relative.clone(),
+                output: path.strip_prefix(destination).unwrap_or(path).to_path_buf(),
+            };
+            let metadata = path.metadata();
+            match metadata.as_ref().map(|m| (m.len(), m.modified())) {
+                Err(_) => diff.missing.push(output),
+                Ok((0, _)) => diff.empty.push(output),
+                Ok((_, Ok(modified))) if modified < source_modified => diff.stale.push(output),
+                Ok(_) => diff.up_to_date += 1,
+            }
+        }
+    }
+    if destination.is_dir() {
+        for entry in WalkDir::new(destination)
+            .sort_by_file_name()
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let is_state = STATE_FILES.iter().any(|name| entry.file_name() == *name)
+                || entry.file_name() == lock::FILE_NAME
+                || entry.file_name() == contact_sheet::FILE_NAME
+                || entry.file_name() == platform::NOJEKYLL_FILE_NAME
+                || gallery::is_page(entry.path())
+                || headers::is_headers_file(entry.path());
+            if !is_state && !expected.contains(entry.path()) {
+                let relative = entry.path().strip_prefix(destination)?;
+                diff.orphaned.push(relative.to_path_buf());
+            }
+        }
+    }
+    Ok(diff)
+}
+
+/// The planned outputs of `jobs` that already exist in `destination` if it
+/// was never written to by this tool, which is recognized by its state files.
+/// Outputs in a folder it wrote to before are taken to be its own.
+pub fn foreign_outputs(jobs: &[Job], destination: &Path) -> Vec<PathBuf> {
+    let is_own = STATE_FILES
+        .iter()
+        .chain([&lock::FILE_NAME])
+        .any(|name| destination.join(name).is_file());
+    if is_own {
+        return Vec::new();
+    }
+    jobs.iter()
+        .flat_map(|job| job.outputs())
+        .map(|(_, path)| path)
+        .filter(|path| path.is_file())
+        .map(Path::to_path_buf)
+        .collect()
+}
+
+/// The size, modification time and hash of every output in a destination
+/// folder, to find what a run changed
+#[derive(Debug, Default)]
+pub struct Snapshot {
+    files: BTreeMap<PathBuf, (u64, SystemTime, String)>,
+}
+
+impl Snapshot {
+    /// Records the files in `destination`, leaving out the state files
+    pub fn take(destination: &Path) -> Result<Snapshot> {
+        let mut snapshot = Snapshot::default();
+        if !destination.is_dir() {
+            return Ok(snapshot);
+        }
+        for entry in WalkDir::new(destination) {
+            let entry = entry?;
+            let is_state = STATE_FILES.iter().any(|name| entry.file_name() == *name)
+                || entry.file_name() == lock::FILE_NAME;
+            if !entry.file_type().is_file() || is_state {
+                continue;
+            }
+            let metadata = entry.metadata()?;
+            let relative = entry.path().strip_prefix(destination)?;
+            snapshot.files.insert(
+                relative.to_path_buf(),
+                (
+                    metadata.len(),
+                    metadata.modified()?,
+                    sha256_file(entry.path())?,
+                ),
+            );
+        }
+        Ok(snapshot)
+    }
+
+    /// How every file that differs in `after` changed, relative to the
+    /// destination folder
+    pub fn changes(&self, after: &Snapshot) -> Vec<(PathBuf, &'static str)> {
+        let mut changes = Vec::new();
+        for (path, (len, modified, hash)) in &self.files {
+            let change = match after.files.get(path) {
+                None => "removed",
+                Some((_, _, after_hash)) if after_hash != hash => "rewritten with other content",
+                Some((after_len, after_modified, _))
+                    if after_len != len || after_modified != modified =>
+                {
+                    "rewritten"
+                }
+                Some(_) => continue,
+            };
+            changes.push((path.clone(), change));
+        }
+        for path in after.files.keys() {
+            if !self.files.contains_key(path) {
+                changes.push((path.clone(), "added"));
+            }
+        }
+        changes.sort();
+        changes
+    }
+}
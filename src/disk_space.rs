@@ -0,0 +1,90 @@
+//! Estimating whether a run fits in the free space of its destination, so it
+//! fails before writing anything instead of halfway through with a full disk.
+
+use std::path::Path;
+
+use crate::{Job, JobKind};
+
+/// Space left free on top of the estimate, for the filesystem, the manifest
+/// and the temporary files written while converting
+pub const HEADROOM: u64 = 100 * crate::MIB;
+
+/// An upper estimate of the space the outputs of `jobs` take in
+/// `destination`, which is `run_destination` where they are planned or one of
+/// its mirrors. Every output is counted at the size of its source, since
+/// resized images are rarely larger, minus the size of the output it replaces.
+/// Linked copies take no space.
+pub fn required<'a>(
+    jobs: impl IntoIterator<Item = &'a Job>,
+    run_destination: &Path,
+    destination: &Path,
+    linked: bool,
+) -> u64 {
+    jobs.into_iter()
+        .filter(|job| !(linked && matches!(job.kind, JobKind::Copy { .. })))
+        .map(|job| {
+            let size = job.source.metadata().map_or(0, |m| m.len());
+            job.outputs()
+                .into_iter()
+                .map(|(_, output)| {
+                    let output = match output.strip_prefix(run_destination) {
+                        Ok(relative) => destination.join(relative),
+                        Err(_) => output.to_path_buf(),
+                    };
+                    let existing = output.metadata().map_or(0, |m| m.len());
+                    size.saturating_sub(existing)
+                })
+                .sum::<u64>()
+        })
+        .sum()
+}
+
+/// The space available to this user on the filesystem of `path`, or `None`
+/// where it can't be found out
+#[cfg(unix)]
+pub fn available(path: &Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: path is a valid C string and stat is large enough for statvfs
+    // to write into
+    let result = unsafe { libc::statvfs(path.as_ptr(), stat.as_mut_ptr()) };
+    if result != 0 {
+        return None;
+    }
+    // SAFETY: statvfs succeeded, so it filled in stat
+    let stat = unsafe { stat.assume_init() };
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(windows)]
+pub fn available(path: &Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            available: *mut u64,
+            total: *mut u64,
+            free: *mut u64,
+        ) -> i32;
+    }
+    let path: Vec<u16> = path.as_os_str().encode_wide().chain([0]).collect();
+    let mut available = 0;
+    // SAFETY: path is null terminated and the null totals are optional
+    let result = unsafe {
+        GetDiskFreeSpaceExW(
+            path.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    (result != 0).then_some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub fn available(_path: &Path) -> Option<u64> {
+    None
+}
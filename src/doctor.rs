@@ -0,0 +1,127 @@
+use std::process::Command;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// An external program the converter can shell out to
+struct Tool {
+    name: &'static str,
+    /// The executable and the argument that makes it print its version
+    command: &'static str,
+    version_arg: &'static str,
+    /// Whether the current pipeline cannot run without it
+    required: bool,
+    install_hint: &'static str,
+}
+
+const TOOLS: &[Tool] = &[
+    Tool {
+        name: "ImageMagick",
+        command: "convert",
+        version_arg: "-version",
+        required: true,
+        install_hint: "apt install imagemagick | brew install imagemagick | winget install ImageMagick.ImageMagick",
+    },
+    Tool {
+        name: "ffmpeg",
+        command: "ffmpeg",
+        version_arg: "-version",
+        required: false,
+        install_hint: "apt install ffmpeg | brew install ffmpeg | winget install Gyan.FFmpeg",
+    },
+    Tool {
+        name: "cwebp",
+        command: "cwebp",
+        version_arg: "-version",
+        required: false,
+        install_hint: "apt install webp | brew install webp",
+    },
+    Tool {
+        name: "avifenc",
+        command: "avifenc",
+        version_arg: "--version",
+        required: false,
+        install_hint: "apt install libavif-bin | brew install libavif",
+    },
+];
+
+/// ImageMagick formats whose write support depends on optional delegates
+const DELEGATE_FORMATS: &[(&str, &str)] = &[
+    (
+        "WEBP",
+        "rebuild ImageMagick with libwebp, e.g. apt install libwebp-dev",
+    ),
+    (
+        "AVIF",
+        "rebuild ImageMagick with libheif, e.g. apt install libheif-dev",
+    ),
+];
+
+pub fn run() -> Result<()> {
+    let mut missing_required = Vec::new();
+    for tool in TOOLS {
+        match version(tool.command, tool.version_arg) {
+            Some(version) => println!("{:<9} {:<12} {}", "[ok]", tool.name, version),
+            None => {
+                let status = if tool.required {
+                    missing_required.push(tool.name);
+                    "[missing]"
+                } else {
+                    "[absent]"
+                };
+                println!("{status:<9} {:<12} `{}` not found", tool.name, tool.command);
+                println!("          install: {}", tool.install_hint);
+            }
+        }
+    }
+    if !missing_required.contains(&"ImageMagick") {
+        let formats = imagemagick_writable_formats();
+        for (format, hint) in DELEGATE_FORMATS {
+            if formats.iter().any(|f| f == format) {
+                println!("{:<9} ImageMagick can write {format}", "[ok]");
+            } else {
+                println!("{:<9} ImageMagick cannot write {format}", "[absent]");
+                println!("          fix: {hint}");
+            }
+        }
+    }
+    if missing_required.is_empty() {
+        println!("All required tools are available");
+        Ok(())
+    } else {
+        Err(eyre!(
+            "required tools are missing: {}",
+            missing_required.join(", ")
+        ))
+    }
+}
+
+/// Returns the first line of the tool's version output, or None if it can't be run
+fn version(command: &str, version_arg: &str) -> Option<String> {
+    let output = Command::new(command).arg(version_arg).output().ok()?;
+    // Some tools print their version on stderr
+    let text = if output.stdout.is_empty() {
+        output.stderr
+    } else {
+        output.stdout
+    };
+    let text = String::from_utf8_lossy(&text);
+    Some(text.lines().next().unwrap_or_default().trim().to_string())
+}
+
+/// Parses `convert -list format`, whose lines look like
+/// `     WEBP* WEBP      rw+   WebP Image Format (libwebp 1.2.4 [020F])`
+fn imagemagick_writable_formats() -> Vec<String> {
+    let Ok(output) = Command::new("convert").arg("-list").arg("format").output() else {
+        return Vec::new();
+    };
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            let mut columns = line.split_whitespace();
+            let format = columns.next()?.trim_end_matches('*');
+            let _module = columns.next()?;
+            let mode = columns.next()?;
+            mode.contains('w').then(|| format.to_string())
+        })
+        .collect()
+}
@@ -0,0 +1,67 @@
+//! In-process image encoding with the `image` crate, which needs no external
+//! tools and also works on wasm32.
+
+use std::io::Cursor;
+
+use color_eyre::eyre::Result;
+use image::{codecs::jpeg::JpegEncoder, imageops::FilterType, ColorType, DynamicImage};
+
+use crate::Variant;
+
+/// The longest side in pixels of every variant, matching the ImageMagick backend
+pub const VARIANT_SIZES: [(Variant, u32); 3] = [
+    (Variant::Default, 1920),
+    (Variant::High, 3840),
+    (Variant::Thumb, 640),
+];
+
+const JPEG_QUALITY: u8 = 85;
+
+/// A variant encoded as a JPEG
+#[derive(Debug, Clone)]
+pub struct EncodedImage {
+    pub variant: Variant,
+    pub width: u32,
+    pub height: u32,
+    pub data: Vec<u8>,
+}
+
+/// Decodes an image from memory and encodes every variant as a JPEG. The
+/// source is decoded a single time and each variant is scaled down from the
+/// next larger one, which is much cheaper than resizing the full source again.
+pub fn encode_variants(source: &[u8]) -> Result<Vec<EncodedImage>> {
+    let mut current = DynamicImage::ImageRgb8(image::load_from_memory(source)?.into_rgb8());
+    let mut by_size = VARIANT_SIZES;
+    by_size.sort_by_key(|&(_, size)| std::cmp::Reverse(size));
+    let mut encoded = Vec::with_capacity(by_size.len());
+    for (variant, size) in by_size {
+        let resized = current.resize(size, size, FilterType::Lanczos3);
+        encoded.push(encode_jpeg(variant, &resized)?);
+        // Never scale down from an upscaled copy of a small source
+        if resized.width() <= current.width() {
+            current = resized;
+        }
+    }
+    // Keep the order of VARIANT_SIZES
+    encoded.sort_by_key(|image| VARIANT_SIZES.iter().position(|(v, _)| *v == image.variant));
+    Ok(encoded)
+}
+
+fn encode_jpeg(variant: Variant, image: &DynamicImage) -> Result<EncodedImage> {
+    let rgb = image
+        .as_rgb8()
+        .expect("variants are resized from an RGB image");
+    let mut data = Vec::new();
+    JpegEncoder::new_with_quality(&mut Cursor::new(&mut data), JPEG_QUALITY).encode(
+        rgb.as_raw(),
+        rgb.width(),
+        rgb.height(),
+        ColorType::Rgb8,
+    )?;
+    Ok(EncodedImage {
+        variant,
+        width: rgb.width(),
+        height: rgb.height(),
+        data,
+    })
+}
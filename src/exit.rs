@@ -0,0 +1,55 @@
+//! The exit codes of the command line, so scripts and CI steps can tell the
+//! kinds of failure apart. They are listed in the help.
+
+use color_eyre::eyre::Report;
+use web_assets_converter::{config::ConfigError, ToolNotFound};
+
+/// Any other error, like an asset folder that doesn't exist
+pub const ERROR: i32 = 1;
+/// The run finished, but some files failed
+pub const PARTIAL_FAILURE: i32 = 3;
+/// A config file is missing or invalid
+pub const CONFIG: i32 = 4;
+/// ImageMagick, rsync or git isn't installed
+pub const MISSING_TOOL: i32 = 5;
+/// Stopped with Ctrl-C, the same code shells use
+pub const INTERRUPTED: i32 = 130;
+
+/// The exit codes for the help, after clap's 2 for invalid arguments
+pub const HELP: &str = "\
+Exit codes:
+  0    Success
+  1    Any other error
+  2    Invalid arguments
+  3    Some files failed, the others were processed
+  4    A config file is missing or invalid
+  5    ImageMagick, rsync or git isn't installed
+  130  Interrupted with Ctrl-C";
+
+/// Returned when some of the files of a run failed
+#[derive(Debug)]
+pub struct PartialFailure {
+    pub failed: usize,
+    pub total: usize,
+}
+
+impl std::fmt::Display for PartialFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} of {} files failed", self.failed, self.total)
+    }
+}
+
+impl std::error::Error for PartialFailure {}
+
+/// The exit code for the error that ended the program
+pub fn code(report: &Report) -> i32 {
+    if report.downcast_ref::<ToolNotFound>().is_some() {
+        MISSING_TOOL
+    } else if report.downcast_ref::<ConfigError>().is_some() {
+        CONFIG
+    } else if report.downcast_ref::<PartialFailure>().is_some() {
+        PARTIAL_FAILURE
+    } else {
+        ERROR
+    }
+}
@@ -0,0 +1,117 @@
+//! The formats of source images, known by their extension. Extensions are
+//! matched case insensitively, so `photo.Jpg` and `image.JPEG` are JPEGs like
+//! `photo.jpg`.
+//!
+//! Sources in these formats are converted and everything else is copied,
+//! unless an `action` for the extension in the config says otherwise:
+//!
+//! ```toml
+//! [extensions.tif]
+//! action = "convert"
+//! ```
+//!
+//! Other files are sorted into [categories](Category), which the config can
+//! have rules for as well.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// A format of source images that are converted by default
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageFormat {
+    pub name: &'static str,
+    /// Lowercase, without the dot
+    pub extensions: &'static [&'static str],
+}
+
+pub const JPEG: ImageFormat = ImageFormat {
+    name: "jpeg",
+    extensions: &["jpg", "jpeg", "jpe", "jfif"],
+};
+
+pub const PNG: ImageFormat = ImageFormat {
+    name: "png",
+    extensions: &["png"],
+};
+
+/// Every format that is converted by default
+pub const IMAGE_FORMATS: [ImageFormat; 2] = [JPEG, PNG];
+
+/// The lowercased extension of `path`
+pub fn extension(path: &Path) -> Option<String> {
+    path.extension().map(|e| e.to_string_lossy().to_lowercase())
+}
+
+/// The format of `path` if it is an image that is converted by default
+pub fn image_format(path: &Path) -> Option<ImageFormat> {
+    let extension = extension(path)?;
+    IMAGE_FORMATS
+        .into_iter()
+        .find(|format| format.extensions.contains(&extension.as_str()))
+}
+
+pub fn is_image(path: &Path) -> bool {
+    image_format(path).is_some()
+}
+
+pub fn is_jpeg(path: &Path) -> bool {
+    image_format(path) == Some(JPEG)
+}
+
+/// Whether `a` and `b` are in the same format, like `photo.JPG` and
+/// `photo_high.jpeg`
+pub fn is_same_format(a: &Path, b: &Path) -> bool {
+    match (image_format(a), image_format(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => extension(a).is_some_and(|a| Some(a) == extension(b)),
+    }
+}
+
+/// A kind of file that is copied, for rules that apply to several extensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Category {
+    Video,
+    Audio,
+    Document,
+    /// The working files of design tools, which rarely belong on a site
+    Design,
+}
+
+impl Category {
+    pub const ALL: [Category; 4] = [
+        Category::Video,
+        Category::Audio,
+        Category::Document,
+        Category::Design,
+    ];
+
+    /// Lowercase, without the dot
+    pub fn extensions(self) -> &'static [&'static str] {
+        match self {
+            Category::Video => &[
+                "mp4", "m4v", "mov", "webm", "mkv", "avi", "ogv", "mpg", "mpeg", "wmv",
+            ],
+            Category::Audio => &[
+                "mp3", "m4a", "aac", "wav", "ogg", "oga", "opus", "flac", "aif", "aiff",
+            ],
+            Category::Document => &[
+                "pdf", "doc", "docx", "odt", "rtf", "xls", "xlsx", "ods", "ppt", "pptx", "odp",
+                "epub",
+            ],
+            Category::Design => &[
+                "psd", "psb", "ai", "sketch", "fig", "xd", "indd", "xcf", "kra", "afdesign",
+                "afphoto",
+            ],
+        }
+    }
+}
+
+/// The category of `path`, if it is in one
+pub fn category(path: &Path) -> Option<Category> {
+    let extension = extension(path)?;
+    Category::ALL
+        .into_iter()
+        .find(|category| category.extensions().contains(&extension.as_str()))
+}
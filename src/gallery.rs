@@ -0,0 +1,218 @@
+//! Renders a [`Manifest`] as a minimal static gallery: an `index.html` in
+//! every folder of the destination, with lazy-loaded thumbnails linking to the
+//! high resolution variants, so a converted photo folder can be browsed
+//! without a separate frontend.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    manifest::{percent_encode as encode, Manifest},
+    plan::Variant,
+};
+
+/// The name of the page written to every folder of the gallery
+pub const INDEX_FILE_NAME: &str = "index.html";
+
+/// Marks the pages written by the tool, so they can be told apart from an
+/// `index.html` among the assets
+const GENERATOR: &str = concat!(
+    "<meta name=\"generator\" content=\"",
+    env!("CARGO_PKG_NAME"),
+    "\">"
+);
+
+/// The contents of one folder of the gallery
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Page {
+    /// Relative to the destination folder, with forward slashes, and empty for
+    /// the destination folder itself
+    pub folder: String,
+    /// The folders directly inside this one, by name
+    pub folders: BTreeSet<String>,
+    pub images: Vec<Image>,
+    /// Outputs that aren't converted images, like videos and documents
+    pub files: Vec<String>,
+    /// The URLs of the outputs on this page from [`Manifest::urls`], which
+    /// are linked instead of the outputs
+    pub urls: BTreeMap<String, String>,
+}
+
+/// An image of a [`Page`], with its outputs relative to the destination folder
+#[derive(Debug, PartialEq, Eq)]
+pub struct Image {
+    pub name: String,
+    /// From the sidecar of the source, the name is used without one
+    pub alt: Option<String>,
+    /// The thumbnail, or the default variant if there is none
+    pub thumbnail: String,
+    /// The high resolution variant, or the default variant if there is none
+    pub full: String,
+}
+
+/// Groups the outputs of the manifest by the folder they are in. Every folder
+/// up to the destination folder gets a page, so the gallery can be browsed
+/// from the top.
+pub fn pages(manifest: &Manifest) -> Vec<Page> {
+    let mut pages: BTreeMap<String, Page> = BTreeMap::new();
+    pages.entry(String::new()).or_default();
+    for (source, outputs) in &manifest.assets {
+        // The default output is missing if its conversion failed
+        let Some(default) = outputs.get(Variant::Default.name()) else {
+            continue;
+        };
+        let (folder, name) = split(default);
+        let page = pages.entry(folder.to_string()).or_default();
+        let thumbnail = outputs.get(Variant::Thumb.name());
+        let full = outputs.get(Variant::High.name());
+        let urls = [Some(default), thumbnail, full]
+            .into_iter()
+            .flatten()
+            .filter_map(|output| Some((output.clone(), manifest.urls.get(output)?.clone())));
+        page.urls.extend(urls);
+        if thumbnail.is_some() || full.is_some() {
+            page.images.push(Image {
+                name: name.to_string(),
+                alt: manifest.alt.get(source).cloned(),
+                thumbnail: thumbnail.unwrap_or(default).clone(),
+                full: full.unwrap_or(default).clone(),
+            });
+        } else {
+            page.files.push(default.clone());
+        }
+    }
+    // Link every folder from its parent, adding the folders in between that
+    // only hold other folders
+    let mut unlinked = pages.keys().cloned().collect::<Vec<_>>();
+    while let Some(child) = unlinked.pop() {
+        if child.is_empty() {
+            continue;
+        }
+        let (parent, name) = split(&child);
+        let page = pages.entry(parent.to_string()).or_default();
+        if page.folders.insert(name.to_string()) {
+            unlinked.push(parent.to_string());
+        }
+    }
+    pages
+        .into_iter()
+        .map(|(folder, page)| Page { folder, ..page })
+        .collect()
+}
+
+impl Page {
+    /// The path of the page relative to the destination folder
+    pub fn path(&self) -> String {
+        match self.folder.is_empty() {
+            true => INDEX_FILE_NAME.to_string(),
+            false => format!("{}/{INDEX_FILE_NAME}", self.folder),
+        }
+    }
+
+    /// Renders the page, linking to outputs and folders relative to it
+    pub fn to_html(&self) -> String {
+        let title = match self.folder.is_empty() {
+            true => "Gallery".to_string(),
+            false => escape(&self.folder),
+        };
+        let mut html = format!(
+            "<!DOCTYPE html>\n\
+             <html lang=\"en\">\n\
+             <head>\n\
+             <meta charset=\"utf-8\">\n\
+             {GENERATOR}\n\
+             <meta name=\"viewport\" content=\"width=device-width, initial-scale=1\">\n\
+             <title>{title}</title>\n\
+             <style>\n\
+             body {{ font-family: sans-serif; margin: 1rem; }}\n\
+             ul {{ list-style: none; padding: 0; }}\n\
+             .images {{ display: grid; grid-template-columns: repeat(auto-fill, minmax(12rem, 1fr)); gap: 0.5rem; }}\n\
+             .images img {{ width: 100%; height: 12rem; object-fit: cover; display: block; }}\n\
+             </style>\n\
+             </head>\n\
+             <body>\n\
+             <h1>{title}</h1>\n"
+        );
+        let mut folders = Vec::new();
+        if !self.folder.is_empty() {
+            folders.push(("../index.html".to_string(), "..".to_string()));
+        }
+        for name in &self.folders {
+            folders.push((format!("{}/index.html", encode(name)), escape(name)));
+        }
+        if !folders.is_empty() {
+            html.push_str("<ul class=\"folders\">\n");
+            for (href, name) in folders {
+                html.push_str(&format!("<li><a href=\"{href}\">{name}/</a></li>\n"));
+            }
+            html.push_str("</ul>\n");
+        }
+        if !self.images.is_empty() {
+            html.push_str("<ul class=\"images\">\n");
+            for image in &self.images {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\"><img src=\"{}\" alt=\"{}\" loading=\"lazy\" decoding=\"async\"></a></li>\n",
+                    self.link(&image.full),
+                    self.link(&image.thumbnail),
+                    escape(image.alt.as_ref().unwrap_or(&image.name)),
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+        if !self.files.is_empty() {
+            html.push_str("<ul class=\"files\">\n");
+            for file in &self.files {
+                html.push_str(&format!(
+                    "<li><a href=\"{}\">{}</a></li>\n",
+                    self.link(file),
+                    escape(split(file).1),
+                ));
+            }
+            html.push_str("</ul>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    /// A link from this page to `output`, relative to the destination folder,
+    /// or its URL if it has one
+    fn link(&self, output: &str) -> String {
+        if let Some(url) = self.urls.get(output) {
+            return escape(url);
+        }
+        if self.folder.is_empty() {
+            return encode(output);
+        }
+        match output.strip_prefix(&format!("{}/", self.folder)) {
+            Some(relative) => encode(relative),
+            None => "../".repeat(self.folder.split('/').count()) + &encode(output),
+        }
+    }
+}
+
+/// Whether `path` is a gallery page written by the tool
+#[cfg(feature = "native")]
+pub fn is_page(path: &std::path::Path) -> bool {
+    path.file_name().is_some_and(|name| name == INDEX_FILE_NAME)
+        && std::fs::read_to_string(path).is_ok_and(|html| html.contains(GENERATOR))
+}
+
+/// The folder and name of a path with forward slashes
+fn split(path: &str) -> (&str, &str) {
+    path.rsplit_once('/').unwrap_or(("", path))
+}
+
+/// Escapes text for HTML content and attribute values
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
@@ -0,0 +1,53 @@
+use std::{
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Files in `dir` that were added or modified since `rev`, including
+/// uncommitted and untracked files, as sorted paths starting with `dir`
+pub fn changed_files(dir: &Path, rev: &str) -> Result<Vec<PathBuf>> {
+    let mut files = git_paths(
+        dir,
+        &[
+            "diff",
+            "--name-only",
+            "--relative",
+            "--diff-filter=ACMR",
+            "-z",
+            rev,
+            "--",
+        ],
+    )?;
+    files.extend(git_paths(
+        dir,
+        &["ls-files", "--others", "--exclude-standard", "-z"],
+    )?);
+    files.sort();
+    files.dedup();
+    Ok(files.into_iter().map(|file| dir.join(file)).collect())
+}
+
+/// Runs git in `dir` and splits its NUL separated output into paths
+fn git_paths(dir: &Path, args: &[&str]) -> Result<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(args)
+        .output()
+        .map_err(|e| crate::spawn_error("git", e))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(output
+        .stdout
+        .split(|&b| b == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| PathBuf::from(String::from_utf8_lossy(path).into_owned()))
+        .collect())
+}
@@ -0,0 +1,59 @@
+//! Tells screenshots, diagrams and other graphics apart from photos, so they
+//! can be converted with [`Preset::Screenshot`](crate::config::Preset) instead
+//! of into blurred JPEGs that smear their text. Graphics have few colors and
+//! large flat areas whose neighboring pixels are exactly the same, while the
+//! noise of a photo makes almost every pixel differ from its neighbors.
+//!
+//! Only PNG sources are examined: JPEG sources already lost their sharp edges.
+
+use std::{collections::HashSet, path::Path};
+
+use color_eyre::eyre::{Result, WrapErr};
+
+use crate::formats;
+
+/// Images with at most this many colors are graphics
+const MAX_PALETTE: usize = 256;
+
+/// Images with at most this many colors are graphics if enough pixels are
+/// the same as the pixel to their right. Antialiased text adds shades.
+const MAX_COLORS: usize = 4096;
+
+/// The share of pixels that are the same as the one to their right above
+/// which an image is flat
+const MIN_FLAT: f64 = 0.5;
+
+/// The largest number of pixels along each side that are examined. Larger
+/// images are sampled on a grid.
+const SAMPLES: u32 = 512;
+
+/// Whether the image at `path` looks like a screenshot or another graphic.
+/// Always false for sources that aren't PNGs.
+pub fn is_graphic(path: &Path) -> Result<bool> {
+    if formats::image_format(path) != Some(formats::PNG) {
+        return Ok(false);
+    }
+    let image = image::open(path)
+        .wrap_err("decoding the image")?
+        .into_rgba8();
+    let (width, height) = image.dimensions();
+    if width < 2 || height == 0 {
+        return Ok(false);
+    }
+    let step = (width.max(height) / SAMPLES).max(1);
+    let mut colors = HashSet::new();
+    let mut flat = 0;
+    let mut sampled = 0;
+    for y in (0..height).step_by(step as usize) {
+        for x in (0..width - 1).step_by(step as usize) {
+            let pixel = image.get_pixel(x, y);
+            if colors.len() <= MAX_COLORS {
+                colors.insert(pixel.0);
+            }
+            flat += usize::from(pixel == image.get_pixel(x + 1, y));
+            sampled += 1;
+        }
+    }
+    let flat = flat as f64 / sampled as f64;
+    Ok(colors.len() <= MAX_PALETTE || (colors.len() <= MAX_COLORS && flat >= MIN_FLAT))
+}
@@ -0,0 +1,59 @@
+//! Content hashes of the sources of the last successful conversions, used to
+//! skip unchanged files when modification times can't be trusted, and to
+//! recognize converted images that were moved or renamed.
+
+use std::{collections::BTreeMap, path::Path};
+
+use color_eyre::eyre::{Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+/// The name of the state file in the destination folder
+pub const FILE_NAME: &str = ".web_assets_converter_hashes.json";
+
+/// Maps the path of every source inside its source folder to its SHA-256
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct Hashes {
+    pub sources: BTreeMap<String, String>,
+    /// What every converted image was converted into, by the path of the
+    /// image inside its source folder
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub converted: BTreeMap<String, Converted>,
+}
+
+/// The outputs of a converted image, which are moved along with it
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Converted {
+    /// The SHA-256 of the image
+    pub hash: String,
+    /// The size and modification time in nanoseconds of the image when it
+    /// was hashed, so it is only hashed again when they change
+    pub size: u64,
+    pub modified: u64,
+    /// The settings it was converted with, which a moved image must share to
+    /// reuse its outputs
+    pub encoding: String,
+    /// Every variant and its output relative to the destination folder
+    pub outputs: BTreeMap<String, String>,
+}
+
+impl Hashes {
+    /// Reads the state file in `destination`, or returns an empty state if
+    /// there is none yet
+    pub fn load(destination: &Path) -> Result<Hashes> {
+        let path = destination.join(FILE_NAME);
+        match std::fs::read_to_string(&path) {
+            Ok(json) => {
+                serde_json::from_str(&json).wrap_err_with(|| format!("parsing {}", path.display()))
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Hashes::default()),
+            Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        }
+    }
+
+    pub fn save(&self, destination: &Path) -> Result<()> {
+        let path = destination.join(FILE_NAME);
+        std::fs::create_dir_all(destination)?;
+        let json = serde_json::to_string_pretty(self)?;
+        std::fs::write(&path, json).wrap_err_with(|| format!("writing {}", path.display()))
+    }
+}
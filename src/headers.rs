@@ -0,0 +1,125 @@
+//! `Cache-Control` headers for the outputs in a manifest, written for the web
+//! server. Outputs with a content hash in their name never change, so they
+//! are cached for a year without revalidating. Other outputs are cached for
+//! a shorter time, since a changed source overwrites them in place.
+
+use std::collections::BTreeMap;
+
+use crate::manifest::{percent_encode, Manifest};
+
+/// The names of the header files in the destination folder, by format
+pub const NETLIFY_FILE_NAME: &str = "_headers";
+pub const APACHE_FILE_NAME: &str = ".htaccess";
+pub const NGINX_FILE_NAME: &str = "headers.map";
+
+/// The first line of every header file, which marks it as the tool's own
+const GENERATOR: &str = "# Cache-Control headers written by web_assets_converter";
+
+/// The `Cache-Control` of fingerprinted outputs
+pub const IMMUTABLE: &str = "public, max-age=31536000, immutable";
+
+/// The default lifetime in seconds of outputs that aren't fingerprinted
+pub const DEFAULT_MAX_AGE: u64 = 3600;
+
+/// The formats headers are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum HeadersFormat {
+    /// A `_headers` file for Netlify and Cloudflare Pages
+    Netlify,
+    /// An Apache `.htaccess` file, which needs `mod_headers`
+    Apache,
+    /// Entries of an nginx `map` block
+    Nginx,
+}
+
+impl HeadersFormat {
+    /// The name of the file in the destination folder
+    pub fn file_name(self) -> &'static str {
+        match self {
+            HeadersFormat::Netlify => NETLIFY_FILE_NAME,
+            HeadersFormat::Apache => APACHE_FILE_NAME,
+            HeadersFormat::Nginx => NGINX_FILE_NAME,
+        }
+    }
+}
+
+/// Whether a part of the stem of `path` between `.`, `-` or `_` is a hash of
+/// at least 8 hex digits with both digits and letters, like in
+/// `app.3f2a9c1b.css` or its variant `app.3f2a9c1b_thumb.jpg`
+pub fn is_fingerprinted(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let stem = name.rsplit_once('.').map_or(name, |(stem, _)| stem);
+    stem.split(['.', '-', '_']).any(|hash| {
+        hash.len() >= 8
+            && hash.bytes().all(|b| b.is_ascii_hexdigit())
+            && hash.bytes().any(|b| b.is_ascii_digit())
+            && hash.bytes().any(|b| b.is_ascii_alphabetic())
+    })
+}
+
+/// Maps the path of every output in `manifest` below `base`, the path the
+/// destination folder is served at, to its `Cache-Control`
+pub fn cache_control(manifest: &Manifest, base: &str, max_age: u64) -> BTreeMap<String, String> {
+    let base = base.trim_end_matches('/');
+    let short = format!("public, max-age={max_age}");
+    manifest
+        .outputs()
+        .map(|output| {
+            let value = match is_fingerprinted(output) {
+                true => IMMUTABLE.to_string(),
+                false => short.clone(),
+            };
+            (format!("{base}/{output}"), value)
+        })
+        .collect()
+}
+
+/// Writes `headers` in `format`. The paths are percent encoded for Netlify
+/// and kept decoded for Apache and nginx, which match decoded paths.
+pub fn render(headers: &BTreeMap<String, String>, format: HeadersFormat) -> String {
+    let mut text = format!("{GENERATOR}\n");
+    match format {
+        HeadersFormat::Netlify => {
+            for (path, value) in headers {
+                text.push_str(&format!(
+                    "{}\n  Cache-Control: {value}\n",
+                    percent_encode(path)
+                ));
+            }
+        }
+        HeadersFormat::Apache => {
+            text.push_str("<IfModule mod_headers.c>\n");
+            for (path, value) in headers {
+                let path = path.replace('\\', "\\\\").replace('\'', "\\'");
+                text.push_str(&format!(
+                    "  <If \"%{{REQUEST_URI}} == '{path}'\">\n    \
+                     Header set Cache-Control \"{value}\"\n  </If>\n"
+                ));
+            }
+            text.push_str("</IfModule>\n");
+        }
+        HeadersFormat::Nginx => {
+            text.push_str(
+                "# Include in a map block, e.g.\n\
+                 # map $uri $cache_control { include headers.map; }\n\
+                 # with: add_header Cache-Control $cache_control;\n",
+            );
+            for (path, value) in headers {
+                let path = path.replace('\\', "\\\\").replace('"', "\\\"");
+                text.push_str(&format!("\"{path}\" \"{value}\";\n"));
+            }
+        }
+    }
+    text
+}
+
+/// Whether `path` is a header file written by the tool, as opposed to e.g. an
+/// `.htaccess` written by hand
+#[cfg(feature = "native")]
+pub fn is_headers_file(path: &std::path::Path) -> bool {
+    let names = [NETLIFY_FILE_NAME, APACHE_FILE_NAME, NGINX_FILE_NAME];
+    path.file_name()
+        .is_some_and(|name| names.iter().any(|n| name == *n))
+        && std::fs::read_to_string(path).is_ok_and(|text| text.starts_with(GENERATOR))
+}
@@ -0,0 +1,805 @@
+use std::{
+    collections::BTreeSet,
+    ffi::OsString,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::{
+    backup::Backup,
+    config::{Enhancement, Gravity, Preset, Sizes, Strip, TargetSizes, DEFAULT_QUALITY},
+    formats,
+    remote::sha256_file,
+    semaphore::Semaphore,
+    timings::Timings,
+    Curated, ImageVariants, Profile, Variant,
+};
+
+/// The ids of the running `convert` processes, which lead their own process
+/// groups so that a Ctrl-C in the terminal doesn't reach them
+static RUNNING: Mutex<BTreeSet<u32>> = Mutex::new(BTreeSet::new());
+
+/// Set by [`kill_running`], after which no more conversions are started
+static KILLED: AtomicBool = AtomicBool::new(false);
+
+/// The error of conversions stopped by [`kill_running`]
+#[derive(Debug)]
+pub struct Interrupted;
+
+impl std::fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("the conversion was interrupted")
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Stops every running conversion. Their jobs fail with [`Interrupted`] after
+/// removing the outputs they were writing.
+pub fn kill_running() {
+    KILLED.store(true, Ordering::Relaxed);
+    #[cfg(unix)]
+    for &id in RUNNING.lock().unwrap().iter() {
+        // SAFETY: kill only sends a signal, to the group the process leads
+        unsafe { libc::kill(-(id as libc::pid_t), libc::SIGTERM) };
+    }
+}
+
+/// Keeps the id of a `convert` process in [`RUNNING`] while it runs
+struct RunningProcess(u32);
+
+impl RunningProcess {
+    fn start(id: u32) -> Self {
+        RUNNING.lock().unwrap().insert(id);
+        RunningProcess(id)
+    }
+}
+
+impl Drop for RunningProcess {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+/// How a source image is turned into a variant with at most `size` pixels
+/// on the longest side, made of steps that [`Recipe::args`] puts together
+/// into ImageMagick arguments
+struct Recipe<'a> {
+    variant: Variant,
+    profile: Profile,
+    size: u32,
+    quality: u8,
+    /// Thumbnails cover a square of `size` instead and are cropped to it,
+    /// keeping this part of the image
+    gravity: Option<Gravity>,
+    preset: Option<Preset>,
+    /// Removes metadata and color profiles. The dev profile always does
+    strip: bool,
+    /// The background of transparent sources
+    flatten_color: Option<&'a str>,
+    /// Lowers the quality of JPEG and WebP outputs as far as needed to fit
+    /// in this many bytes
+    target_size: Option<u64>,
+}
+
+impl<'a> Recipe<'a> {
+    /// The recipe with every optional step turned off, except stripping
+    fn new(variant: Variant, size: u32) -> Self {
+        Recipe {
+            variant,
+            profile: Profile::Prod,
+            size,
+            quality: DEFAULT_QUALITY,
+            gravity: None,
+            preset: None,
+            strip: true,
+            flatten_color: None,
+            target_size: None,
+        }
+    }
+
+    fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let size = format!("{0}x{0}", self.size);
+        let crop = self.gravity.filter(|_| self.variant == Variant::Thumb);
+        let resize = match crop {
+            Some(_) => format!("{size}^"),
+            None => size.clone(),
+        };
+        if self.profile == Profile::Dev {
+            // -thumbnail samples before resizing, which is much faster for large sources
+            args.extend(["-thumbnail", &resize, "-quality", "70%"].map(String::from));
+        } else {
+            args.extend(self.encode(&resize));
+        }
+        if let Some(gravity) = crop {
+            args.extend(
+                ["-gravity", gravity.imagemagick_name(), "-extent", &size].map(String::from),
+            );
+        }
+        if let Some(color) = self.flatten_color {
+            args.extend(
+                ["-background", color, "-alpha", "remove", "-alpha", "off"].map(String::from),
+            );
+        }
+        args
+    }
+
+    /// The arguments that resize the image to `resize` with the full quality
+    fn encode(&self, resize: &str) -> Vec<String> {
+        let quality = format!("{}%", self.quality);
+        let mut args = Vec::new();
+        if self.strip {
+            args.push("-strip");
+        }
+        args.extend(["-interlace", "Plane"]);
+        if let Some(blur) = blur(self.variant, self.preset) {
+            args.extend(["-gaussian-blur", blur]);
+        }
+        if let Some(sampling) = self.preset.and_then(sampling_factor) {
+            args.extend(["-sampling-factor", sampling]);
+        }
+        args.extend(["-quality", &quality, "-resize", resize]);
+        if let Some(unsharp) = self.preset.and_then(unsharp) {
+            args.extend(["-unsharp", unsharp]);
+        }
+        let mut args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        if let Some(bytes) = self.target_size {
+            // Each format reads its own, so both are given
+            args.extend([
+                "-define".to_string(),
+                format!("jpeg:extent={bytes}"),
+                "-define".to_string(),
+                format!("webp:target-size={bytes}"),
+            ]);
+        }
+        args
+    }
+}
+
+/// The radius of the blur before resizing, which hides noise and JPEG
+/// artifacts of the source. There is none for the high resolution version,
+/// nor for presets whose edges should stay crisp.
+fn blur(variant: Variant, preset: Option<Preset>) -> Option<&'static str> {
+    match (preset, variant) {
+        (Some(Preset::Artwork | Preset::Screenshot | Preset::Scan), _) => None,
+        (_, Variant::Default) => Some("0.05"),
+        (_, Variant::High) => None,
+        (_, Variant::Thumb) => Some("0.01"),
+    }
+}
+
+/// The chroma subsampling of JPEG and WebP outputs. Halving the resolution of
+/// the colors is invisible in photos but smears colored edges and text.
+fn sampling_factor(preset: Preset) -> Option<&'static str> {
+    match preset {
+        Preset::Photo => Some("4:2:0"),
+        Preset::Artwork | Preset::Scan => Some("4:4:4"),
+        // PNG has no chroma subsampling
+        Preset::Screenshot => None,
+    }
+}
+
+/// The sharpening after resizing, which brings back the detail downscaling
+/// softens
+fn unsharp(preset: Preset) -> Option<&'static str> {
+    match preset {
+        Preset::Photo => Some("0x0.75+0.75+0.008"),
+        Preset::Scan => Some("0x1+1+0.02"),
+        Preset::Artwork | Preset::Screenshot => None,
+    }
+}
+
+/// Converts an image held in memory into a JPEG variant without touching the filesystem
+pub fn convert_bytes(source: &[u8], variant: Variant) -> Result<Vec<u8>> {
+    let mut child = Command::new("convert")
+        .arg("-")
+        .args(Recipe::new(variant, Sizes::default().get(variant)).args())
+        .arg("jpg:-")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| crate::spawn_error("ImageMagick's convert", e))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    // Write from another thread so a full stdout pipe can't deadlock us
+    let (written, output) = std::thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(source));
+        let output = child.wait_with_output();
+        (writer.join().expect("writer thread panicked"), output)
+    });
+    let output = output?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "convert failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // A broken pipe only matters if convert claims to have succeeded
+    written?;
+    Ok(output.stdout)
+}
+
+/// Whether `destination` exists and was written after `source` was last modified
+pub(crate) fn is_up_to_date(source: &Path, destination: &Path) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified());
+    match (modified(source), modified(destination)) {
+        (Ok(source), Ok(destination)) => destination >= source,
+        _ => false,
+    }
+}
+
+/// Settings shared by every conversion in a run
+pub(crate) struct ConvertOptions<'a> {
+    /// Reencode outputs even if they are newer than their source
+    pub clean: bool,
+    pub profile: Profile,
+    pub processes: &'a Semaphore,
+    /// A folder of earlier conversions that can be shared between projects
+    pub cache: Option<&'a Path>,
+    /// JPEG sources that already fit a variant and use at most this many
+    /// bytes per pixel are copied instead of reencoded
+    pub skip_optimized: Option<f64>,
+    pub timings: &'a Timings,
+    /// Sources with more pixels than this are converted with bounded memory
+    pub big_image_pixels: u64,
+    pub sizes: Sizes,
+    pub quality: u8,
+    /// Where replaced outputs are moved
+    pub backup: Option<&'a Backup>,
+    pub gravity: Option<Gravity>,
+    pub preset: Option<Preset>,
+    /// The background of transparent sources
+    pub flatten_color: Option<&'a str>,
+    /// Corrections applied before resizing
+    pub enhance: &'a [Enhancement],
+    /// The threshold of the denoise before resizing in percent
+    pub denoise: Option<u8>,
+    /// The variants that are stripped of metadata and color profiles
+    pub strip: Strip,
+    pub target_size: TargetSizes,
+}
+
+impl ConvertOptions<'_> {
+    /// The steps applied once to the source before it is cloned for every
+    /// variant
+    fn prepare(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(threshold) = self.denoise {
+            args.extend(["-wavelet-denoise".to_string(), format!("{threshold}%")]);
+        }
+        // Corrections see the whole image, after the denoise so they don't
+        // stretch the noise
+        let enhance = self.enhance.iter().flat_map(|e| e.imagemagick_args());
+        args.extend(enhance.map(|arg| arg.to_string()));
+        args
+    }
+
+    fn recipe(&self, variant: Variant) -> Vec<String> {
+        Recipe {
+            variant,
+            profile: self.profile,
+            size: self.sizes.get(variant),
+            quality: self.quality,
+            gravity: self.gravity,
+            preset: self.preset,
+            strip: self.strip.get(variant),
+            flatten_color: self.flatten_color,
+            target_size: self.target_size.get(variant),
+        }
+        .args()
+    }
+}
+
+/// Copies the source if it is already optimized, or the output from the
+/// shared cache if the same source was converted with the same arguments
+/// before. Returns false if the variant still has to be converted.
+fn reuse_output(
+    source_path: &Path,
+    destination_path: &Path,
+    variant: Variant,
+    options: &ConvertOptions,
+    source_hash: &mut Option<String>,
+) -> Result<bool> {
+    // Thumbnails are always generated since they are much smaller than any source
+    if variant != Variant::Thumb
+        && formats::is_jpeg(destination_path)
+        && is_already_optimized(source_path, variant, options)
+    {
+        debug!(
+            "{} is already optimized, copying it as {}",
+            source_path.display(),
+            destination_path.display()
+        );
+        let _timer = options.timings.timer("copy optimized source");
+        std::fs::copy(source_path, destination_path).wrap_err_with(|| {
+            format!(
+                "source: {}, destination: {}",
+                source_path.display(),
+                destination_path.display()
+            )
+        })?;
+        return Ok(true);
+    }
+    let Some(cache) = options.cache else {
+        return Ok(false);
+    };
+    if source_hash.is_none() {
+        let _timer = options.timings.timer("hash");
+        *source_hash = Some(sha256_file(source_path)?);
+    }
+    let cached = cache_path(
+        cache,
+        source_hash.as_deref().unwrap(),
+        variant,
+        destination_path,
+        options,
+    );
+    if !cached.is_file() {
+        return Ok(false);
+    }
+    debug!(
+        "copying {} from the shared cache as {}",
+        cached.display(),
+        destination_path.display()
+    );
+    let _timer = options.timings.timer("copy from shared cache");
+    std::fs::copy(&cached, destination_path)
+        .wrap_err_with(|| format!("copying {} from the cache", cached.display()))?;
+    Ok(true)
+}
+
+/// Reencoding a JPEG that is already small and compressed only loses quality
+fn is_already_optimized(source_path: &Path, variant: Variant, options: &ConvertOptions) -> bool {
+    let Some(max_bytes_per_pixel) = options.skip_optimized else {
+        return false;
+    };
+    let (Ok((width, height)), Ok(metadata)) =
+        (image::image_dimensions(source_path), source_path.metadata())
+    else {
+        return false;
+    };
+    let bytes_per_pixel = metadata.len() as f64 / (f64::from(width) * f64::from(height));
+    let target_size = options.target_size.get(variant).unwrap_or(u64::MAX);
+    formats::is_jpeg(source_path)
+        && width.max(height) <= options.sizes.get(variant)
+        && bytes_per_pixel <= max_bytes_per_pixel
+        && metadata.len() <= target_size
+}
+
+fn file_size(path: &Path) -> Result<u64> {
+    let metadata = path
+        .metadata()
+        .wrap_err_with(|| format!("reading the size of {}", path.display()))?;
+    Ok(metadata.len())
+}
+
+/// Writes every output with a single `convert` process once a process slot is
+/// free. The source is decoded once and every variant is made from a clone.
+fn run_convert(
+    source_path: &Path,
+    outputs: &[(Variant, &Path)],
+    options: &ConvertOptions,
+) -> Result<()> {
+    if outputs.is_empty() {
+        return Ok(());
+    }
+    let mut command = Command::new("convert");
+    command.args(convert_args(source_path, outputs, options));
+    let names: Vec<_> = outputs.iter().map(|(variant, _)| variant.name()).collect();
+    let paths: Vec<_> = outputs.iter().map(|(_, path)| *path).collect();
+    run(
+        command,
+        &paths,
+        &format!("imagemagick {}", names.join("+")),
+        options,
+    )
+}
+
+/// The arguments of the `convert` process writing `outputs`, which must not be
+/// empty
+fn convert_args(
+    source_path: &Path,
+    outputs: &[(Variant, &Path)],
+    options: &ConvertOptions,
+) -> Vec<OsString> {
+    let ((last_variant, last_path), others) = outputs.split_last().expect("no outputs");
+    let largest = outputs
+        .iter()
+        .map(|(variant, _)| *variant)
+        .max_by_key(|variant| options.sizes.get(*variant))
+        .unwrap_or(*last_variant);
+    // Without it settings like -define and -background made for one variant
+    // would outlive its parentheses and apply to the next ones
+    let mut args = vec![OsString::from("-respect-parentheses")];
+    args.extend(
+        big_image_args(source_path, largest, options)
+            .into_iter()
+            .map(OsString::from),
+    );
+    args.push(source_path.into());
+    args.extend(options.prepare().into_iter().map(OsString::from));
+    for (variant, path) in others {
+        args.extend(["(", "+clone"].map(OsString::from));
+        args.extend(options.recipe(*variant).into_iter().map(OsString::from));
+        args.extend([OsString::from("-write"), path.into()]);
+        args.extend(["+delete", ")"].map(OsString::from));
+    }
+    args.extend(
+        options
+            .recipe(*last_variant)
+            .into_iter()
+            .map(OsString::from),
+    );
+    args.push(last_path.into());
+    args
+}
+
+/// Runs `command` once a process slot is free, timed as `stage`. If it fails
+/// its partial `outputs` are removed.
+fn run(
+    mut command: Command,
+    outputs: &[&Path],
+    stage: &str,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let _permit = {
+        let _timer = options.timings.timer("wait for a process slot");
+        options.processes.acquire()
+    };
+    let _timer = options.timings.timer(stage);
+    if KILLED.load(Ordering::Relaxed) {
+        return Err(Interrupted.into());
+    }
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut command, 0);
+    debug!("running {command:?}");
+    let child = command
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|e| crate::spawn_error("ImageMagick's convert", e))?;
+    let output = {
+        let _running = RunningProcess::start(child.id());
+        child.wait_with_output()?
+    };
+    if !output.status.success() {
+        // A killed convert leaves truncated files that would look up to date
+        for path in outputs {
+            if let Err(e) = std::fs::remove_file(path) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!("can't remove the partial output {}: {e}", path.display());
+                }
+            }
+        }
+        if KILLED.load(Ordering::Relaxed) {
+            return Err(Interrupted.into());
+        }
+        return Err(eyre!(
+            "convert failed with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+/// Arguments that keep the memory use of ImageMagick bounded for sources
+/// larger than [`ConvertOptions::big_image_pixels`]
+fn big_image_args(source_path: &Path, variant: Variant, options: &ConvertOptions) -> Vec<String> {
+    let Ok((width, height)) = image::image_dimensions(source_path) else {
+        return Vec::new();
+    };
+    if u64::from(width) * u64::from(height) <= options.big_image_pixels {
+        return Vec::new();
+    }
+    // Past these limits the pixel cache is kept in a temporary file on disk
+    let mut args: Vec<String> = ["-limit", "memory", "256MiB", "-limit", "map", "512MiB"]
+        .map(String::from)
+        .into();
+    // Lets the JPEG decoder scale down while reading instead of decoding
+    // every pixel, as long as it stays at least twice the output size
+    let size = options.sizes.get(variant) * 2;
+    args.push("-define".to_string());
+    args.push(format!("jpeg:size={size}x{size}"));
+    args
+}
+
+/// Outputs are keyed by the hash of the source, the ImageMagick arguments and
+/// the output format
+fn cache_path(
+    cache: &Path,
+    source_hash: &str,
+    variant: Variant,
+    destination_path: &Path,
+    options: &ConvertOptions,
+) -> PathBuf {
+    let extension = formats::extension(destination_path).unwrap_or_default();
+    let mut hasher = Sha256::new();
+    hasher.update(source_hash);
+    for arg in options.prepare().into_iter().chain(options.recipe(variant)) {
+        hasher.update([0]);
+        hasher.update(arg);
+    }
+    // Before the output format was configurable every output was a JPEG
+    if extension != "jpg" {
+        hasher.update([0]);
+        hasher.update(&extension);
+    }
+    let key = format!("{:x}", hasher.finalize());
+    cache.join(&key[..2]).join(format!("{key}.{extension}"))
+}
+
+fn add_to_cache(output: &Path, cached: &Path) -> Result<()> {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    if let Some(p) = cached.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    // Other threads and processes may be adding the same file, so write to a
+    // unique name and rename it into place
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    let partial = cached.with_extension(format!("{}-{id}.partial", std::process::id()));
+    std::fs::copy(output, &partial)
+        .wrap_err_with(|| format!("adding {} to the cache", output.display()))?;
+    std::fs::rename(&partial, cached)?;
+    Ok(())
+}
+
+pub(crate) fn convert_image(
+    source_path: &Path,
+    variants: &ImageVariants,
+    options: &ConvertOptions,
+) -> Result<()> {
+    // Only hashed if the shared cache is used
+    let mut source_hash = None;
+    if let Some(p) = variants.default.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    let mut outputs = vec![
+        (Variant::Default, variants.default.as_path()),
+        (Variant::High, variants.high.as_path()),
+    ];
+    if let Some(thumb) = &variants.thumb {
+        outputs.push((Variant::Thumb, thumb.as_path()));
+    }
+    let mut stale = Vec::new();
+    let mut to_convert = Vec::new();
+    for (variant, destination_path) in outputs {
+        if !options.clean && is_up_to_date(source_path, destination_path) {
+            debug!("{} is up to date", destination_path.display());
+        } else {
+            stale.push(variant);
+            if let Some(backup) = options.backup {
+                backup.save(destination_path)?;
+            }
+            if !reuse_output(
+                source_path,
+                destination_path,
+                variant,
+                options,
+                &mut source_hash,
+            )? {
+                to_convert.push((variant, destination_path));
+            }
+        }
+    }
+    run_convert(source_path, &to_convert, options)?;
+    for (variant, destination_path) in &to_convert {
+        let Some(target_size) = options.target_size.get(*variant) else {
+            continue;
+        };
+        let size = file_size(destination_path)?;
+        if size > target_size {
+            warn!(
+                "Not within the target size of {} kB: {} is {} kB",
+                target_size / 1000,
+                destination_path.display(),
+                size.div_ceil(1000)
+            );
+        }
+    }
+    if let (Some(cache), Some(source_hash)) = (options.cache, &source_hash) {
+        for (variant, destination_path) in &to_convert {
+            if destination_path.is_file() {
+                let _timer = options.timings.timer("add to shared cache");
+                let cached = cache_path(cache, source_hash, *variant, destination_path, options);
+                add_to_cache(destination_path, &cached)?;
+            }
+        }
+    }
+    // Smallest wins: a variant that is larger than its source is replaced by
+    // the source, unless that would put the bytes of one format in a file
+    // named for another. Thumbnails are much smaller than any source.
+    let source_size = file_size(source_path)?;
+    for (variant, destination_path) in [
+        (Variant::Default, &variants.default),
+        (Variant::High, &variants.high),
+    ] {
+        if !stale.contains(&variant) || file_size(destination_path)? <= source_size {
+            continue;
+        }
+        if !formats::is_same_format(source_path, destination_path) {
+            debug!(
+                "{} is larger than its source, but the source is in another format",
+                destination_path.display()
+            );
+            continue;
+        }
+        debug!(
+            "{} is larger than its source, using the source instead",
+            destination_path.display()
+        );
+        std::fs::copy(source_path, destination_path).wrap_err_with(|| {
+            format!(
+                "source: {}, destination: {}",
+                source_path.display(),
+                destination_path.display()
+            )
+        })?;
+    }
+    Ok(())
+
+    // convert "$f" \
+    // -strip \
+    // -interlace Plane \
+    // -gaussian-blur 0.05 \
+    // -quality 85% \
+    // -resize 1920x1920\> \
+    // "$f"
+}
+
+/// Converts the source of a [spec entry](crate::spec) into its single output
+pub(crate) fn convert_curated(
+    source_path: &Path,
+    curated: &Curated,
+    options: &ConvertOptions,
+) -> Result<()> {
+    let destination_path = curated.destination.as_path();
+    if !options.clean && is_up_to_date(source_path, destination_path) {
+        debug!("{} is up to date", destination_path.display());
+        return Ok(());
+    }
+    if let Some(backup) = options.backup {
+        backup.save(destination_path)?;
+    }
+    if let Some(p) = destination_path.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    let (resize, quality) = match options.profile {
+        // -thumbnail samples before resizing, which is much faster for large sources
+        Profile::Dev => ("-thumbnail", "70%".to_string()),
+        Profile::Prod => ("-resize", format!("{}%", curated.quality)),
+    };
+    let mut command = Command::new("convert");
+    command
+        .arg(source_path)
+        .args(["-strip", "-interlace", "Plane", "-quality", &quality]);
+    match (curated.width, curated.height) {
+        (Some(width), Some(height)) => {
+            let gravity = curated.gravity.unwrap_or(Gravity::Center);
+            let size = format!("{width}x{height}");
+            command.args([resize, &format!("{size}^")]).args([
+                "-gravity",
+                gravity.imagemagick_name(),
+                "-extent",
+                &size,
+            ]);
+        }
+        (Some(width), None) => {
+            command.args([resize, &width.to_string()]);
+        }
+        (None, Some(height)) => {
+            command.args([resize, &format!("x{height}")]);
+        }
+        (None, None) => {}
+    }
+    command.arg(destination_path);
+    run(command, &[destination_path], "imagemagick curated", options)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The arguments converting every variant of `a.jpg`, with the thumbnail
+    /// first and the high resolution version outside of parentheses
+    fn args(configure: impl FnOnce(&mut ConvertOptions)) -> Vec<String> {
+        let processes = Semaphore::new(1);
+        let timings = Timings::default();
+        let mut options = ConvertOptions {
+            clean: false,
+            profile: Profile::Prod,
+            processes: &processes,
+            cache: None,
+            skip_optimized: None,
+            timings: &timings,
+            big_image_pixels: u64::MAX,
+            sizes: Sizes::default(),
+            quality: DEFAULT_QUALITY,
+            backup: None,
+            gravity: None,
+            preset: None,
+            flatten_color: None,
+            enhance: &[],
+            denoise: None,
+            strip: Strip::default(),
+            target_size: TargetSizes::default(),
+        };
+        configure(&mut options);
+        let outputs = [
+            (Variant::Thumb, Path::new("dist/a_thumb.jpg")),
+            (Variant::Default, Path::new("dist/a.jpg")),
+            (Variant::High, Path::new("dist/a_high.jpg")),
+        ];
+        convert_args(Path::new("a.jpg"), &outputs, &options)
+            .into_iter()
+            .map(|arg| arg.into_string().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn target_sizes_stay_inside_the_parentheses_of_their_variant() {
+        let args = args(|options| {
+            options.target_size = TargetSizes {
+                default: Some(300),
+                high: None,
+                thumb: Some(40),
+            }
+        });
+        assert_eq!(args[0], "-respect-parentheses");
+        let groups: Vec<_> = args.split(|arg| arg == ")").collect();
+        let defines = |group: &[String]| -> Vec<String> {
+            group
+                .windows(2)
+                .filter(|pair| pair[0] == "-define")
+                .map(|pair| pair[1].clone())
+                .collect()
+        };
+        assert_eq!(groups.len(), 3);
+        assert_eq!(
+            defines(groups[0]),
+            ["jpeg:extent=40000", "webp:target-size=40000"]
+        );
+        assert_eq!(
+            defines(groups[1]),
+            ["jpeg:extent=300000", "webp:target-size=300000"]
+        );
+        assert!(defines(groups[2]).is_empty());
+        assert_eq!(groups[2].last().unwrap(), "dist/a_high.jpg");
+    }
+
+    #[test]
+    fn the_source_is_denoised_once_before_it_is_cloned() {
+        let args = args(|options| options.denoise = Some(5));
+        let denoise = args.iter().position(|arg| arg == "-wavelet-denoise");
+        let first_clone = args.iter().position(|arg| arg == "(");
+        assert_eq!(
+            args.iter().filter(|arg| *arg == "-wavelet-denoise").count(),
+            1
+        );
+        assert!(denoise < first_clone);
+        assert_eq!(args[denoise.unwrap() - 1], "a.jpg");
+    }
+
+    #[test]
+    fn enhancements_follow_the_denoise_once() {
+        let args = args(|options| {
+            options.denoise = Some(5);
+            options.enhance = &[Enhancement::AutoLevel];
+        });
+        let source = args.iter().position(|arg| arg == "a.jpg").unwrap();
+        let mut prepare = vec!["-wavelet-denoise", "5%"];
+        prepare.extend(Enhancement::AutoLevel.imagemagick_args());
+        assert_eq!(args[source + 1..source + 1 + prepare.len()], prepare);
+        assert_eq!(args[source + 1 + prepare.len()], "(");
+    }
+}
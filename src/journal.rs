@@ -0,0 +1,70 @@
+//! A record of the jobs finished by a run, so an interrupted run can resume
+//! where it left off.
+
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+
+/// The name of the journal file in the destination folder
+pub const FILE_NAME: &str = ".web_assets_converter_journal";
+
+#[derive(Debug)]
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    finished: HashSet<String>,
+}
+
+impl Journal {
+    /// Opens the journal in `destination`. If `resume` is set the jobs
+    /// finished by an earlier run are kept, otherwise the journal starts empty.
+    pub fn open(destination: &Path, resume: bool) -> Result<Journal> {
+        let path = destination.join(FILE_NAME);
+        std::fs::create_dir_all(destination)?;
+        let mut finished = HashSet::new();
+        if resume {
+            match std::fs::read_to_string(&path) {
+                Ok(text) => finished.extend(text.lines().map(str::to_string)),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("opening {}", path.display()))?;
+        if !resume {
+            file.set_len(0)?;
+        }
+        Ok(Journal {
+            path,
+            file,
+            finished,
+        })
+    }
+
+    pub fn is_finished(&self, source: &Path) -> bool {
+        self.finished.contains(&*source.to_string_lossy())
+    }
+
+    /// Records that the job of `source` is finished. The line is written
+    /// immediately so it survives the process being killed.
+    pub fn record(&mut self, source: &Path) -> Result<()> {
+        writeln!(self.file, "{}", source.to_string_lossy())?;
+        Ok(())
+    }
+
+    /// Removes the journal after a run that wasn't interrupted
+    pub fn complete(self) -> Result<()> {
+        drop(self.file);
+        std::fs::remove_file(&self.path)
+            .wrap_err_with(|| format!("removing {}", self.path.display()))
+    }
+}
@@ -0,0 +1,136 @@
+//! Converts a folder of original assets into web friendly files.
+//!
+//! Images are resized into a default, a high resolution and a thumbnail variant
+//! using ImageMagick, and other files are copied as they are if they are small
+//! enough.
+//!
+//! Planning, manifest, gallery, redirect and header generation and the
+//! in-process [`encode`] backend don't touch the filesystem or spawn
+//! processes, so they build for wasm32 with `--no-default-features`.
+//! Everything else needs the default `native` feature.
+//!
+//! ```no_run
+//! use web_assets_converter::Pipeline;
+//!
+//! let report = Pipeline::builder("assets", "dist/assets").build().run()?;
+//! println!("{} files processed", report.results.len());
+//! # Ok::<(), color_eyre::eyre::Report>(())
+//! ```
+
+#[cfg(feature = "native")]
+pub mod archive;
+#[cfg(feature = "async-copy")]
+mod async_copy;
+#[cfg(feature = "native")]
+pub mod backup;
+#[cfg(feature = "native")]
+pub mod budget;
+#[cfg(feature = "native")]
+pub mod build;
+#[cfg(feature = "native")]
+pub mod clean;
+pub mod config;
+#[cfg(feature = "native")]
+pub mod contact_sheet;
+#[cfg(feature = "native")]
+pub mod diff;
+#[cfg(feature = "native")]
+pub mod disk_space;
+pub mod encode;
+pub mod formats;
+pub mod gallery;
+#[cfg(feature = "native")]
+pub mod git;
+#[cfg(feature = "native")]
+pub mod graphics;
+#[cfg(feature = "native")]
+pub mod hashes;
+pub mod headers;
+#[cfg(feature = "native")]
+pub mod imagemagick;
+#[cfg(feature = "native")]
+pub mod journal;
+#[cfg(feature = "native")]
+pub mod lock;
+pub mod manifest;
+#[cfg(feature = "native")]
+pub mod metadata;
+#[cfg(feature = "native")]
+pub mod metrics;
+pub mod mime;
+#[cfg(feature = "native")]
+mod moves;
+#[cfg(feature = "native")]
+pub mod paths;
+#[cfg(feature = "native")]
+mod pipeline;
+pub mod plan;
+pub mod platform;
+pub mod redirects;
+#[cfg(feature = "native")]
+pub mod references;
+#[cfg(feature = "native")]
+pub mod remote;
+#[cfg(feature = "native")]
+pub mod rsync;
+#[cfg(feature = "native")]
+pub mod s3;
+#[cfg(feature = "native")]
+mod semaphore;
+#[cfg(feature = "native")]
+pub mod similar;
+pub mod slug;
+#[cfg(feature = "native")]
+pub mod spec;
+#[cfg(feature = "native")]
+pub mod stats;
+#[cfg(feature = "native")]
+pub mod timings;
+#[cfg(feature = "native")]
+pub mod verify;
+
+pub use config::Config;
+#[cfg(feature = "native")]
+pub use pipeline::{
+    FollowLinks, JobResult, LinkMode, OnCollision, OnLowSpace, Outcome, Pipeline, PipelineBuilder,
+    Report,
+};
+pub use plan::{Curated, ImageVariants, Job, JobKind, Profile, Variant};
+
+pub const MIB: u64 = 2_u64.pow(20);
+
+/// An external program that isn't installed or not on the `PATH`
+#[cfg(feature = "native")]
+#[derive(Debug)]
+pub struct ToolNotFound(pub &'static str);
+
+#[cfg(feature = "native")]
+impl std::fmt::Display for ToolNotFound {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} isn't installed or not on the PATH", self.0)
+    }
+}
+
+#[cfg(feature = "native")]
+impl std::error::Error for ToolNotFound {}
+
+/// The error for failing to start `tool`, which is [`ToolNotFound`] if it
+/// isn't installed
+#[cfg(feature = "native")]
+pub(crate) fn spawn_error(tool: &'static str, e: std::io::Error) -> color_eyre::Report {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => ToolNotFound(tool).into(),
+        _ => color_eyre::Report::new(e).wrap_err(format!("failed to run {tool}")),
+    }
+}
+
+/// Where downloads and other reusable data are kept between runs:
+/// `$XDG_CACHE_HOME/web_assets_converter` or `~/.cache/web_assets_converter`
+#[cfg(feature = "native")]
+pub fn default_cache_dir() -> std::path::PathBuf {
+    let base = std::env::var_os("XDG_CACHE_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".cache")))
+        .unwrap_or_else(|| ".cache".into());
+    base.join("web_assets_converter")
+}
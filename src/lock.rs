@@ -0,0 +1,75 @@
+//! An advisory lock on the destination folder, so that two runs, like watch
+//! mode and a manual run, don't write the same outputs at the same time.
+
+use std::{
+    fs::{File, OpenOptions, TryLockError},
+    io::{Read, Seek, Write},
+    path::Path,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use tracing::info;
+
+/// The name of the lock file in the destination folder. It is left in place
+/// after the run, since removing it could let two later runs lock different
+/// files.
+pub const FILE_NAME: &str = ".web_assets_converter_lock";
+
+/// Held for as long as the run writes to the destination folder, and released
+/// when dropped
+#[derive(Debug)]
+pub struct DestinationLock {
+    _file: File,
+}
+
+impl DestinationLock {
+    /// Locks `destination`, creating it if needed. If another run holds the
+    /// lock this waits for it to finish with `wait` and fails otherwise.
+    pub fn acquire(destination: &Path, wait: bool) -> Result<DestinationLock> {
+        let path = destination.join(FILE_NAME);
+        std::fs::create_dir_all(destination)?;
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)
+            .wrap_err_with(|| format!("opening {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) if wait => {
+                info!(
+                    "Waiting for the run {} to finish writing to {}",
+                    holder(&mut file),
+                    destination.display()
+                );
+                file.lock()
+                    .wrap_err_with(|| format!("locking {}", path.display()))?;
+            }
+            Err(TryLockError::WouldBlock) => {
+                return Err(eyre!(
+                    "the run {} is writing to {}, wait for it to finish",
+                    holder(&mut file),
+                    destination.display()
+                ));
+            }
+            Err(TryLockError::Error(e)) => {
+                return Err(e).wrap_err_with(|| format!("locking {}", path.display()));
+            }
+        }
+        // Tells the runs that find the folder locked which process holds it
+        file.set_len(0)?;
+        file.rewind()?;
+        write!(file, "{}", std::process::id())?;
+        Ok(DestinationLock { _file: file })
+    }
+}
+
+/// Describes the process holding the lock from the id it wrote
+fn holder(file: &mut File) -> String {
+    let mut id = String::new();
+    match file.read_to_string(&mut id) {
+        Ok(_) if !id.trim().is_empty() => format!("in process {}", id.trim()),
+        _ => "of another process".to_string(),
+    }
+}
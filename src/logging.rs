@@ -0,0 +1,83 @@
+//! Log output on stderr, filtered by `-v`/`-q`, that stays out of the way of
+//! the progress bar.
+
+use std::{io::Write, sync::Mutex};
+
+use indicatif::ProgressBar;
+use tracing::level_filters::LevelFilter;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+use crate::output;
+
+/// The progress bar that is currently drawn, which log lines are printed above
+static PROGRESS_BAR: Mutex<Option<ProgressBar>> = Mutex::new(None);
+
+/// Log lines written while a full screen UI is shown, printed once it closes
+#[cfg(feature = "tui")]
+static HELD: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+
+/// Sets up logging with `info` as the default level, which `verbose` raises
+/// and `quiet` lowers to only warnings and errors. WAC_LOG can set a filter
+/// per module like RUST_LOG, e.g. `web_assets_converter::imagemagick=debug`.
+pub fn init(verbose: u8, quiet: bool) {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .with_env_var("WAC_LOG")
+        .from_env_lossy();
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(Stderr)
+        .with_ansi(output::color_enabled(std::io::stderr()))
+        .without_time()
+        .with_target(false)
+        .init();
+}
+
+/// Log lines are printed above `bar` until it is finished
+pub fn set_progress_bar(bar: Option<ProgressBar>) {
+    *PROGRESS_BAR.lock().unwrap() = bar;
+}
+
+/// Holds log lines back while `hold` is set, then prints them all
+#[cfg(feature = "tui")]
+pub fn hold(hold: bool) {
+    let held = std::mem::replace(&mut *HELD.lock().unwrap(), hold.then(Vec::new));
+    if let Some(held) = held {
+        let _ = std::io::stderr().write_all(&held);
+    }
+}
+
+struct Stderr;
+
+impl<'a> MakeWriter<'a> for Stderr {
+    type Writer = Stderr;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        Stderr
+    }
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        #[cfg(feature = "tui")]
+        if let Some(held) = &mut *HELD.lock().unwrap() {
+            held.extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        match &*PROGRESS_BAR.lock().unwrap() {
+            Some(bar) => bar.suspend(|| std::io::stderr().write_all(buf))?,
+            None => std::io::stderr().write_all(buf)?,
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        std::io::stderr().flush()
+    }
+}
@@ -1,219 +1,1917 @@
 use std::{
-    ffi::{OsStr, OsString},
+    collections::{BTreeMap, HashSet},
+    io::{IsTerminal, Read, Write},
     path::{Path, PathBuf},
-    process::Command,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::RecvTimeoutError,
+        Arc, OnceLock,
+    },
+    time::{Duration, Instant},
 };
 
-use clap::Parser;
-use color_eyre::eyre::{Result, *};
-use walkdir::WalkDir;
+use clap::{builder::BoolishValueParser, CommandFactory, Parser, Subcommand};
+use color_eyre::eyre::{eyre, Report, Result, WrapErr};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use notify_debouncer_mini::{new_debouncer, notify::RecursiveMode};
+use priority::IoPriority;
+use tracing::{error, info, warn};
+use web_assets_converter::{
+    archive::{self, ArchiveFormat},
+    backup::Backup,
+    budget, clean,
+    config::{self, Config, ConfigError, Enhancement, Format, Preset},
+    contact_sheet, default_cache_dir,
+    diff::{self, Snapshot},
+    formats, gallery, git,
+    headers::{self, HeadersFormat},
+    imagemagick,
+    lock::DestinationLock,
+    manifest::{self, url_path, Manifest, SkipReason},
+    metadata::Metadata,
+    metrics, paths,
+    platform::{self, Platform},
+    redirects::{self, RedirectFormat},
+    references, remote, rsync,
+    s3::S3Target,
+    similar, spec, stats,
+    timings::Timings,
+    FollowLinks, Job, JobKind, JobResult, LinkMode, OnCollision, OnLowSpace, Outcome, Pipeline,
+    Profile, Variant, MIB,
+};
+
+mod daemon;
+mod doctor;
+mod exit;
+mod logging;
+mod notification;
+mod output;
+mod priority;
+mod serve;
+#[cfg(feature = "tui")]
+mod tui;
+
+use notification::Status;
+use output::OutputFormat;
 
-/// Simple program to greet a person
+/// Convert a folder of assets into web friendly files
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Path to the folder with original assets
-    #[arg(short, long, default_value = "./")]
-    asset_path: String,
-    /// Path to the destination folder
-    #[arg(short, long, default_value = "../dist/assets/")]
-    destination_path: String,
-    /// The maximum file size of copied non-image files in MiB
-    #[arg(short, long, default_value_t = 20)]
-    max_file_size: u64,
-    /// If false, files that already exist will not be reencoded
-    #[arg(short, long, default_value_t = false)]
+#[command(author, version, about, long_about = None, after_help = exit::HELP)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+    /// Print every processed file and debug details like the exact ImageMagick
+    /// commands and why files are skipped. -vv prints even more
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    verbose: u8,
+    /// Only print warnings and errors
+    #[arg(short, long, global = true, conflicts_with = "verbose", value_parser = BoolishValueParser::new(), env = "WAC_QUIET")]
+    quiet: bool,
+    /// Don't color the output. Also set by NO_COLOR, and colors are only used
+    /// if stdout is a terminal
+    #[arg(long, global = true, value_parser = BoolishValueParser::new(), env = "WAC_NO_COLOR")]
+    no_color: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    /// Convert images and copy other files to the destination folder
+    Convert(ConvertArgs),
+    /// Convert, then keep converting files as they change in the asset folder
+    Watch(ConvertArgs),
+    /// Convert an image read from stdin and write the JPEG result to stdout
+    Pipe(PipeArgs),
+    /// Run an HTTP server that converts uploaded images
+    Daemon(DaemonArgs),
+    /// Serve the destination folder over HTTP for development, converting
+    /// every requested output that is missing or older than its source first
+    Serve(ServeArgs),
+    /// Write a manifest.json listing the outputs of every source file
+    Manifest(ManifestArgs),
+    /// Write the manifest.json and a static index.html in every folder of the
+    /// destination, with thumbnails linking to the high resolution images
+    Gallery(ManifestArgs),
+    /// Show which outputs are missing, stale or orphaned in the destination
+    /// folder without changing anything
+    Plan(SourceArgs),
+    /// Check that every output exists, isn't empty and is newer than its
+    /// source without converting anything, and fail otherwise
+    Validate(SourceArgs),
+    /// Remove the outputs and state files the tool wrote to the destination
+    /// folder, keeping any other files in it
+    Clean(CleanArgs),
+    /// List groups of source images that look nearly the same, like exports
+    /// at different sizes or slight edits, without converting anything
+    Similar(SimilarArgs),
+    /// List the sources that nothing in a site's HTML, templates, styles,
+    /// scripts or Markdown references, and references to missing assets
+    Unused(UnusedArgs),
+    /// Check that the external tools used for conversion are installed
+    Doctor,
+    /// Print a completion script for the shell, e.g.
+    /// `web_assets_converter completions bash > /etc/bash_completion.d/web_assets_converter`
+    #[command(hide = true)]
+    Completions { shell: clap_complete::Shell },
+    /// Print the man page in roff format, e.g.
+    /// `web_assets_converter man > /usr/local/share/man/man1/web_assets_converter.1`
+    #[command(hide = true)]
+    Man,
+}
+
+#[derive(clap::Args, Debug)]
+struct SourceArgs {
+    /// Path to the folder with original assets. Can be given several times to
+    /// merge more folders into the destination, as PREFIX=PATH to place the
+    /// outputs of a folder in PREFIX. The first one holds the config file
+    #[arg(short, long, default_value = "./", env = "WAC_ASSET_PATH")]
+    asset_path: Vec<String>,
+    /// Path to the destination folder, an archive file ending in .tar,
+    /// .tar.gz, .tar.zst or .zip to pack all outputs into, or a
+    /// [user@]host:path to sync the outputs to with rsync over SSH. Can be
+    /// given several times to copy the outputs into more folders in the same
+    /// run, the first is the one the other commands read
+    #[arg(
+        short,
+        long,
+        default_value = "../dist/assets/",
+        env = "WAC_DESTINATION_PATH"
+    )]
+    destination_path: Vec<String>,
+    /// The maximum file size of copied non-image files in MiB, unless the
+    /// config has one for their extension or category. Defaults to 20
+    #[arg(short, long, env = "WAC_MAX_FILE_SIZE")]
+    max_file_size: Option<u64>,
+    /// Read sizes, formats, quality, excludes and per-extension and category rules from
+    /// this file instead of the web_assets_converter.toml in the asset folder
+    #[arg(long, value_name = "FILE", env = "WAC_CONFIG")]
+    config: Option<PathBuf>,
+    /// The format images are converted to, overriding every format in the
+    /// config files. Defaults to jpg
+    #[arg(long, value_enum, env = "WAC_FORMAT")]
+    format: Option<Format>,
+    /// Tune the quality, format, blur, sharpening and chroma subsampling of
+    /// converted images for what they show. Quality and format set in the
+    /// config or with --quality and --format win, and folder configs and
+    /// sidecars can choose another preset
+    #[arg(long, value_enum, env = "WAC_PRESET")]
+    preset: Option<Preset>,
+    /// Convert PNG sources that look like screenshots or diagrams, with few
+    /// colors and flat areas, with the screenshot preset instead of into
+    /// blurred JPEGs. Sources with a preset are left alone
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_DETECT_GRAPHICS")]
+    detect_graphics: bool,
+    /// The #rrggbb background transparent images are put on when they are
+    /// converted to JPEG, e.g. the background color of the site. Folder
+    /// configs can set their own with flatten-color
+    #[arg(long, value_name = "COLOR", env = "WAC_FLATTEN_COLOR")]
+    flatten_color: Option<String>,
+    /// Correct converted images automatically before resizing, e.g.
+    /// auto-level,white-balance for unedited phone photos. Folder configs
+    /// can set their own with enhance
+    #[arg(long, value_enum, value_delimiter = ',', env = "WAC_ENHANCE")]
+    enhance: Vec<Enhancement>,
+    /// Remove the noise of converted images before resizing, with a threshold
+    /// from 1 to 50 percent, e.g. 5 for high-ISO night shots. Folder configs
+    /// can set their own with denoise
+    #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=50), env = "WAC_DENOISE")]
+    denoise: Option<u8>,
+    /// Keep the metadata and color profile of these variants instead of
+    /// stripping them, e.g. high for full resolution downloads. The config
+    /// can choose them in its strip table
+    #[arg(
+        long,
+        value_name = "VARIANTS",
+        value_enum,
+        value_delimiter = ',',
+        env = "WAC_KEEP_METADATA"
+    )]
+    keep_metadata: Vec<Variant>,
+    /// The largest size in kB of every variant, e.g. "thumb=40,default=300",
+    /// lowering the quality of JPEG and WebP outputs as far as needed. The
+    /// config can set them in its target-size table
+    #[arg(long, value_name = "TARGETS", env = "WAC_TARGET_SIZE")]
+    target_size: Option<String>,
+    /// Only process the files listed in this file instead of walking the asset
+    /// folder, one per line or NUL separated. Use - to read from stdin
+    #[arg(long, value_name = "FILE", env = "WAC_FILES_FROM")]
+    files_from: Option<PathBuf>,
+    /// Only process files added or modified since this git revision
+    #[arg(
+        long,
+        value_name = "REV",
+        conflicts_with = "files_from",
+        env = "WAC_CHANGED_SINCE"
+    )]
+    changed_since: Option<String>,
+    /// Also download and process the URLs listed in this file, one per line
+    /// with an optional SHA-256 checksum after the URL
+    #[arg(long, value_name = "FILE", env = "WAC_SOURCES")]
+    sources: Option<PathBuf>,
+    /// Also convert the images listed in this JSON or CSV file, each into an
+    /// output with its own name, size and crop, e.g. for a homepage carousel
+    #[arg(long, value_name = "FILE", env = "WAC_SPEC")]
+    spec: Option<PathBuf>,
+    /// Only convert the images listed in --spec instead of walking the asset
+    /// folder
+    #[arg(long, requires = "spec", value_parser = BoolishValueParser::new(), env = "WAC_SPEC_ONLY")]
+    spec_only: bool,
+    /// Only process files whose path inside the asset folder matches this
+    /// glob, e.g. "photos/**/*.jpg". Can be given several times
+    #[arg(long, visible_alias = "include", value_name = "GLOB", env = "WAC_ONLY")]
+    only: Vec<String>,
+    /// Leave out files whose path inside the asset folder matches this glob,
+    /// in addition to the excludes in the config. Can be given several times
+    #[arg(long, value_name = "GLOB", env = "WAC_EXCLUDE")]
+    exclude: Vec<String>,
+    /// Copy files whose path inside the asset folder matches this glob even
+    /// if they are larger than the maximum file size, in addition to the
+    /// force-copy globs in the config. Can be given several times
+    #[arg(long, value_name = "GLOB", env = "WAC_FORCE_COPY")]
+    force_copy: Vec<String>,
+    /// Never copy or convert files with this extension, in addition to the
+    /// blocked extensions of the config, e.g. psd. Can be given several times
+    #[arg(long, value_name = "EXT", env = "WAC_BLOCK")]
+    block: Vec<String>,
+    /// Let files with this blocked extension through, e.g. ai for
+    /// downloadable vector art. Can be given several times
+    #[arg(long, value_name = "EXT", env = "WAC_UNBLOCK")]
+    unblock: Vec<String>,
+    /// Only copy or convert files with these extensions, e.g.
+    /// jpg,png,svg,webp,mp4,woff2, leaving out and listing the rest.
+    /// Replaces the allow list of the config
+    #[arg(long, value_name = "EXTS", value_delimiter = ',', env = "WAC_ALLOW")]
+    allow: Vec<String>,
+    /// Process at most this many files
+    #[arg(long, value_name = "N", env = "WAC_LIMIT")]
+    limit: Option<usize>,
+    /// Convert identical source files once and copy the outputs of the first
+    /// one for the duplicates
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_DEDUPE")]
+    dedupe: bool,
+    /// Give outputs lowercase ASCII names with dashes instead of spaces,
+    /// e.g. "cafe-menu.jpg" for "Café Menu.JPG". The manifest lists the
+    /// renamed files
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_SLUG_NAMES")]
+    slug_names: bool,
+    /// Which symbolic links in the asset folder are followed. By default only
+    /// links to files and folders inside it are, --follow-links alone follows
+    /// every link. Links that aren't followed are skipped with a warning
+    #[arg(long, value_enum, num_args = 0..=1, require_equals = true, default_value_t = FollowLinks::Inside, default_missing_value = "all", env = "WAC_FOLLOW_LINKS")]
+    follow_links: FollowLinks,
+    /// Only process files this many folders deep in the asset folder, 0 for
+    /// only the files at its top
+    #[arg(long, value_name = "DEPTH", env = "WAC_MAX_DEPTH")]
+    max_depth: Option<usize>,
+    /// Also process hidden files and folders like .git and .DS_Store, and
+    /// junk like Thumbs.db and desktop.ini, which are skipped by default
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_INCLUDE_HIDDEN")]
+    include_hidden: bool,
+    /// What to do when several sources would write the same output, like
+    /// photo.png and photo.jpg both converted to photo.jpg
+    #[arg(long, value_enum, default_value_t = OnCollision::Error, env = "WAC_ON_COLLISION")]
+    on_collision: OnCollision,
+    /// What to do when the outputs may not fit in the free space of the
+    /// destination, estimated from the sizes of the sources
+    #[arg(long, value_enum, default_value_t = OnLowSpace::Error, env = "WAC_ON_LOW_SPACE")]
+    on_low_space: OnLowSpace,
+    /// Put every output directly in the destination folder instead of
+    /// mirroring the asset folder, joining folder names into the file name
+    /// like "photos-rally-a.jpg"
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_FLATTEN")]
+    flatten: bool,
+    /// Also copy the untouched sources of converted images into an
+    /// "originals" folder in the destination, which the manifest leaves out,
+    /// so the destination doubles as a backup of the sources
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_KEEP_ORIGINALS")]
+    keep_originals: bool,
+    /// Where downloaded sources and shared conversions are kept between runs
+    #[arg(long, value_name = "DIR", default_value_os_t = default_cache_dir(), env = "WAC_CACHE_DIR")]
+    cache_dir: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct ConvertArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// If false, outputs newer than their source will not be reencoded
+    #[arg(short, long, default_value_t = false, value_parser = BoolishValueParser::new(), env = "WAC_CLEAN")]
     clean: bool,
+    /// Don't ask for confirmation, e.g. when the asset folder isn't called
+    /// "assets"
+    #[arg(short, long, visible_alias = "non-interactive", value_parser = BoolishValueParser::new(), env = "WAC_YES")]
+    yes: bool,
+    /// What to do with files that outputs would replace in a destination
+    /// folder this tool hasn't written to before. --yes answers the prompt
+    /// with overwrite
+    #[arg(long, value_enum, default_value_t = OnExisting::Prompt, env = "WAC_ON_EXISTING")]
+    on_existing: OnExisting,
+    /// Use dev for fast, lower quality conversions while iterating locally
+    #[arg(long, value_enum, default_value_t = Profile::Prod, env = "WAC_PROFILE")]
+    profile: Profile,
+    /// The quality of converted images, overriding every quality in the config
+    /// files
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=100), env = "WAC_QUALITY")]
+    quality: Option<u8>,
+    /// How many files to process in parallel. Defaults to the number of CPU cores
+    #[arg(short, long, env = "WAC_JOBS")]
+    jobs: Option<usize>,
+    /// How many ImageMagick processes may run at once, to bound memory use.
+    /// Defaults to the number of CPU cores
+    #[arg(long, value_name = "N", env = "WAC_MAX_PROCESSES")]
+    max_processes: Option<usize>,
+    /// Detect changed sources by their content hash instead of modification
+    /// time, e.g. after a fresh git checkout. Hashes are stored in the
+    /// destination folder
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_HASH")]
+    hash: bool,
+    /// Reuse images converted by earlier runs, in this or any other project,
+    /// from the conversions folder in --cache-dir
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_SHARED_CACHE")]
+    shared_cache: bool,
+    /// Skip the files finished by an earlier run that was interrupted
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_RESUME")]
+    resume: bool,
+    /// Wait for another run writing to the destination folder to finish
+    /// instead of failing. Watch mode always waits between its runs
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_WAIT_FOR_LOCK")]
+    wait_for_lock: bool,
+    /// How non-image files are placed in the destination folder. Linking
+    /// saves time and space when it is on the same filesystem as the assets
+    #[arg(long, value_enum, default_value_t = LinkMode::Copy, env = "WAC_LINK_MODE")]
+    link_mode: LinkMode,
+    /// Give outputs the modification time of their source and copied files
+    /// its permissions, e.g. for rsync based deploys and cache validators
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_PRESERVE_ATTRIBUTES")]
+    preserve_attributes: bool,
+    /// Move outputs into a new folder named by the time of the run in this
+    /// folder before replacing them, e.g. to undo a run with a bad quality
+    /// setting
+    #[arg(long, value_name = "DIR", env = "WAC_BACKUP_DIR")]
+    backup_dir: Option<PathBuf>,
+    /// How many runs to keep in --backup-dir, removing the oldest
+    #[arg(
+        long,
+        value_name = "N",
+        requires = "backup_dir",
+        env = "WAC_KEEP_BACKUPS"
+    )]
+    keep_backups: Option<usize>,
+    /// Check that every converted image can be read and has the expected
+    /// size, and fully decode this percentage of them. Sources with broken
+    /// outputs fail and their outputs are removed
+    #[arg(long, value_name = "PERCENT", num_args = 0..=1, require_equals = true, default_missing_value = "10", value_parser = clap::value_parser!(u8).range(0..=100), env = "WAC_VERIFY")]
+    verify: Option<u8>,
+    /// Copy JPEGs that already fit a variant and use at most this many bytes
+    /// per pixel instead of reencoding them, e.g. 0.5. Their metadata is kept
+    #[arg(long, value_name = "BYTES_PER_PIXEL", env = "WAC_SKIP_OPTIMIZED")]
+    skip_optimized: Option<f64>,
+    /// Images with more megapixels than this are converted with bounded memory
+    #[arg(
+        long,
+        value_name = "MEGAPIXELS",
+        default_value_t = 50,
+        env = "WAC_BIG_IMAGE_MEGAPIXELS"
+    )]
+    big_image_megapixels: u64,
+    /// Lower the CPU priority of the conversion, from 0 (normal) to 19 (lowest)
+    #[arg(long, value_name = "NICENESS", value_parser = clap::value_parser!(i32).range(0..=19), env = "WAC_NICE")]
+    nice: Option<i32>,
+    /// Lower the disk priority of the conversion (Linux only)
+    #[arg(long, value_enum, env = "WAC_IO_PRIORITY")]
+    io_priority: Option<IoPriority>,
+    /// Print how much time was spent in every stage at the end
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_BENCH")]
+    bench: bool,
+    /// Write file counts, sizes and timings of the run to this file in the
+    /// Prometheus text format, e.g. for node_exporter's textfile collector
+    #[arg(long, value_name = "PATH", env = "WAC_METRICS")]
+    metrics: Option<PathBuf>,
+    /// Write the source size, the size of every variant, the compression
+    /// ratio and the time of every file to this .csv or .json file
+    #[arg(long, value_name = "PATH", env = "WAC_REPORT")]
+    report: Option<PathBuf>,
+    /// Render a contact-sheet.jpg in every output folder with thumbnails, a
+    /// grid of them with the names of their sources. Sheets are only rendered
+    /// again when the thumbnails in their folder change
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_CONTACT_SHEETS")]
+    contact_sheets: bool,
+    /// Compare the copyright, artist, orientation and color space of every
+    /// converted image with its outputs and warn about the differences
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_CHECK_METADATA")]
+    check_metadata: bool,
+    /// Run the conversion a second time and fail if that changes any output,
+    /// e.g. because outputs are always taken to be stale or aren't
+    /// reproducible
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_CHECK_IDEMPOTENT")]
+    check_idempotent: bool,
+    /// Report the total size of the destination folder after the run and list
+    /// its largest files if it is larger than this, e.g. "500MiB"
+    #[arg(long, value_name = "SIZE", value_parser = budget::parse_size, env = "WAC_BUDGET")]
+    budget: Option<u64>,
+    /// Fail when the destination folder is larger than --budget, before it is
+    /// uploaded, archived or synced
+    #[arg(long, requires = "budget", value_parser = BoolishValueParser::new(), env = "WAC_FAIL_OVER_BUDGET")]
+    fail_over_budget: bool,
+    /// Use json to print one JSON object per processed file on stdout for
+    /// scripts, instead of the progress bar
+    #[arg(long, value_enum, default_value_t = OutputFormat::Human, env = "WAC_OUTPUT_FORMAT")]
+    output_format: OutputFormat,
+    /// Copy non-image files concurrently with async I/O, which is much faster
+    /// for many small files on network filesystems
+    #[cfg(feature = "async-copy")]
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_ASYNC_COPY")]
+    async_copy: bool,
+    /// Show a full screen view of every file, the running jobs and the space
+    /// saved, where the run can be paused and cancelled
+    #[cfg(feature = "tui")]
+    #[arg(long, conflicts_with = "output_format", value_parser = BoolishValueParser::new(), env = "WAC_TUI")]
+    tui: bool,
+    #[command(flatten)]
+    s3: S3Args,
+    #[command(flatten)]
+    notify: NotifyArgs,
+}
+
+#[derive(clap::Args, Debug)]
+struct CleanArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Only list the files that would be removed
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_DRY_RUN")]
+    dry_run: bool,
+}
+
+#[derive(clap::Args, Debug)]
+struct ManifestArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// Also write a locations.geojson placing the outputs of every photo with
+    /// GPS tags on a map. The tags are read from the sources, since outputs
+    /// are stripped of them. Mind that this publishes where they were taken
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_LOCATIONS")]
+    locations: bool,
+    /// Also write redirects from the paths of sources to their outputs where
+    /// these are named differently, so links to the sources keep working:
+    /// _redirects for Netlify, redirects.map for nginx or redirects.json
+    #[arg(long, value_name = "FORMAT", env = "WAC_REDIRECTS")]
+    redirects: Option<RedirectFormat>,
+    /// Also write Cache-Control headers for every output: _headers for
+    /// Netlify and Cloudflare Pages, .htaccess for Apache or headers.map for
+    /// nginx. Outputs with a content hash in their name are cached for a year
+    #[arg(long, value_name = "FORMAT", env = "WAC_HEADERS")]
+    headers: Option<HeadersFormat>,
+    /// How many seconds --headers lets outputs without a content hash in their
+    /// name be cached
+    #[arg(long, default_value_t = headers::DEFAULT_MAX_AGE, env = "WAC_MAX_AGE")]
+    max_age: u64,
+    /// Also list the Subresource Integrity hash of every output in the
+    /// manifest, not only of scripts and stylesheets
+    #[arg(long, value_parser = BoolishValueParser::new(), env = "WAC_INTEGRITY_ALL")]
+    integrity_all: bool,
+    /// A URL template for the outputs, listed in the manifest under "urls" and
+    /// linked from the gallery, e.g. https://cdn.example.com/{path}?v={hash}.
+    /// {path} is the output, {name} its file name and {hash} changes with its
+    /// contents. Overrides output-url in the config
+    #[arg(long, value_name = "TEMPLATE", env = "WAC_OUTPUT_URL")]
+    output_url: Option<String>,
+    /// The URL path the destination folder is served at, which the paths in
+    /// --redirects and --headers start with
+    #[arg(long, default_value = "/", env = "WAC_BASE_PATH")]
+    base_path: String,
+    /// Write the header and redirect files the host reads from the root of
+    /// the site, which the destination folder is taken to be unless
+    /// --base-path says otherwise
+    #[arg(
+        long,
+        conflicts_with_all = ["redirects", "headers"],
+        env = "WAC_PLATFORM"
+    )]
+    platform: Option<Platform>,
+}
+
+#[derive(clap::Args, Debug)]
+struct SimilarArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// How many of the 64 bits of the perceptual hashes of two images may
+    /// differ for them to count as similar. Higher finds more edited copies
+    /// along with more false matches
+    #[arg(long, value_name = "BITS", default_value_t = similar::DEFAULT_MAX_DISTANCE, value_parser = clap::value_parser!(u32).range(0..=64), env = "WAC_MAX_DISTANCE")]
+    max_distance: u32,
 }
 
-fn main() -> Result<()> {
+#[derive(clap::Args, Debug)]
+struct UnusedArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The folder with the site's sources that link to the assets
+    #[arg(long, env = "WAC_SITE")]
+    site: PathBuf,
+}
+
+#[derive(clap::Args, Debug)]
+struct S3Args {
+    /// Also upload the outputs to this S3 compatible bucket. Credentials are
+    /// read from AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY
+    #[arg(long, value_name = "BUCKET", env = "WAC_S3_BUCKET")]
+    s3_bucket: Option<String>,
+    /// The storage endpoint, e.g. https://<account>.r2.cloudflarestorage.com.
+    /// Defaults to AWS S3 in --s3-region
+    #[arg(long, value_name = "URL", env = "WAC_S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+    /// Use auto for Cloudflare R2
+    #[arg(long, default_value = "us-east-1", env = "WAC_S3_REGION")]
+    s3_region: String,
+    /// Prepended to the key of every uploaded object
+    #[arg(long, default_value = "", env = "WAC_S3_PREFIX")]
+    s3_prefix: String,
+    /// The Cache-Control header of uploaded objects
+    #[arg(
+        long,
+        default_value = "public, max-age=86400",
+        env = "WAC_S3_CACHE_CONTROL"
+    )]
+    s3_cache_control: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct NotifyArgs {
+    /// POST a JSON summary of the run to this URL when it ends, whether it
+    /// succeeded, failed or was interrupted. Only convert runs notify, and
+    /// the first run of watch, not its rebuilds or the daemon's
+    #[arg(long, value_name = "URL", env = "WAC_NOTIFY_WEBHOOK")]
+    notify_webhook: Option<String>,
+    /// Run this shell command when the run ends, with the JSON summary on
+    /// stdin and succeeded, failed or interrupted in WAC_STATUS
+    #[arg(long, value_name = "CMD", env = "WAC_NOTIFY_CMD")]
+    notify_cmd: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+struct PipeArgs {
+    /// Which variant of the image to produce
+    #[arg(long, value_enum, default_value_t = Variant::Default, env = "WAC_VARIANT")]
+    variant: Variant,
+}
+
+/// What happens to files in a destination folder the tool hasn't written to
+/// before, when outputs would replace them
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum OnExisting {
+    /// Leave the files alone and don't process their sources
+    Skip,
+    /// Replace every output, even those newer than their source
+    Overwrite,
+    /// Ask whether to replace them
+    Prompt,
+    /// Stop without changing anything
+    Error,
+}
+
+#[derive(clap::Args, Debug)]
+struct DaemonArgs {
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8080", env = "WAC_LISTEN")]
+    listen: String,
+}
+
+#[derive(clap::Args, Debug)]
+struct ServeArgs {
+    #[command(flatten)]
+    source: SourceArgs,
+    /// The address to listen on
+    #[arg(long, default_value = "127.0.0.1:8000", env = "WAC_LISTEN")]
+    listen: String,
+}
+
+fn main() {
+    if let Err(report) = run() {
+        eprintln!("Error: {report:?}");
+        std::process::exit(exit::code(&report));
+    }
+}
+
+fn run() -> Result<()> {
     color_eyre::install()?;
-    let args = Args::parse();
-    println!("Processing files in {}", args.asset_path);
-    let asset_path = std::fs::canonicalize(PathBuf::from(&args.asset_path)).unwrap();
-    if !asset_path.is_dir() {
-        panic!("Asset path is not a directory: {}", asset_path.display());
+    let cli = Cli::parse();
+    output::init_color(cli.no_color);
+    logging::init(cli.verbose, cli.quiet);
+    match cli.command {
+        Commands::Convert(args) => convert(&args),
+        Commands::Watch(args) => watch(&args),
+        Commands::Pipe(args) => pipe(&args),
+        Commands::Daemon(args) => daemon::run(&args.listen),
+        Commands::Serve(args) => serve(&args),
+        Commands::Manifest(args) => write_manifest(&args).map(|_| ()),
+        Commands::Gallery(args) => write_gallery(&args),
+        Commands::Plan(args) => print_plan(&args),
+        Commands::Validate(args) => validate(&args),
+        Commands::Clean(args) => clean(&args),
+        Commands::Similar(args) => print_similar(&args),
+        Commands::Unused(args) => print_unused(&args),
+        Commands::Doctor => doctor::run(),
+        Commands::Completions { shell } => print_completions(shell),
+        Commands::Man => print_man_page(),
     }
-    if asset_path.file_stem().unwrap().to_string_lossy() != "assets" {
-        println!(
-            "Program not started in a directory called \"assets\", do you want to continue? [y/N]"
-        );
-        let mut answer = String::new();
-        std::io::stdin().read_line(&mut answer)?;
-        if answer != "y" && answer != "Y" {
-            return Ok(());
+}
+
+fn print_completions(shell: clap_complete::Shell) -> Result<()> {
+    let mut command = Cli::command();
+    let name = command.get_name().to_string();
+    clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+    Ok(())
+}
+
+fn print_man_page() -> Result<()> {
+    clap_mangen::Man::new(Cli::command()).render(&mut std::io::stdout())?;
+    Ok(())
+}
+
+fn convert(args: &ConvertArgs) -> Result<()> {
+    let start = Instant::now();
+    let destination = args.source.destination_path();
+    let mut results = Vec::new();
+    let converted = convert_files(args, start, &mut results);
+    let status = match converted {
+        Ok(()) => Status::Succeeded,
+        Err(_) => Status::Failed,
+    };
+    args.notify.send(
+        status,
+        destination,
+        &results,
+        converted.as_ref().err(),
+        start,
+    );
+    converted
+}
+
+/// Runs a conversion, leaving the results of the jobs in `results` for the
+/// notifications
+fn convert_files(args: &ConvertArgs, start: Instant, results: &mut Vec<JobResult>) -> Result<()> {
+    args.set_priority()?;
+    let pipeline = args.pipeline(args.clean)?;
+    for (root, _) in pipeline.sources() {
+        info!("Processing files in {}", root.display());
+        let canonical = paths::canonicalize(root)
+            .wrap_err_with(|| format!("asset folder {}", root.display()))?;
+        if !canonical.is_dir() {
+            return Err(eyre!(
+                "the asset path {} is not a folder",
+                canonical.display()
+            ));
         }
     }
-    for entry in WalkDir::new(&args.asset_path)
-        .into_iter()
-        .filter_map(|e| e.ok())
+    if args.check_idempotent && (args.source.archive().is_some() || args.source.remote().is_some())
     {
-        if match entry.path().extension().and_then(OsStr::to_str) {
-            Some("jpg" | "JPG" | "png" | "PNG" | "jpeg") => {
-                println!("{}", entry.path().display());
-                // convert to a smaller file size
-                convert_image(entry.path(), &args)?;
-                // If the original is large, also convert to a 4k file size
-                // Also convert to a thumbnail file size
-                true
-            }
-            _ => false,
-        } {
-            // File was handled
+        return Err(eyre!("--check-idempotent needs a local destination folder"));
+    }
+    let asset_path = paths::canonicalize(pipeline.source())?;
+    let jobs = args.source.plan(&pipeline)?;
+    let is_assets_folder = asset_path.file_stem().is_some_and(|stem| stem == "assets");
+    if !is_assets_folder && !args.yes {
+        if args.source.reads_stdin() {
+            return Err(eyre!(
+                "can't ask whether to continue outside an \"assets\" folder while stdin provides the file list, pass --yes to continue anyway"
+            ));
+        }
+        if !confirm(
+            "Program not started in a directory called \"assets\", do you want to continue?",
+        )? {
+            return Ok(());
+        }
+    }
+    let Some((pipeline, jobs)) = args.handle_existing(pipeline, jobs)? else {
+        return Ok(());
+    };
+    let s3 = args.s3.target()?;
+    let total = jobs.len();
+    *results = {
+        let _timer = pipeline.timings().timer("run jobs (wall time)");
+        args.run_jobs(&pipeline, jobs)?
+    };
+    let results = &*results;
+    if let (Some(dir), Some(keep)) = (&args.backup_dir, args.keep_backups) {
+        Backup::prune(dir, keep)?;
+    }
+    for (relative, size) in pipeline.too_large() {
+        warn!(
+            "left out {} ({:.1} MiB), it is larger than the maximum file size, see --force-copy",
+            relative.display(),
+            size as f64 / MIB as f64
+        );
+    }
+    for (relative, size, reason) in pipeline.blocked() {
+        let why = match reason {
+            SkipReason::NotAllowed => "isn't allowed, see --allow",
+            _ => "is blocked, see --unblock",
+        };
+        warn!(
+            "left out {} ({:.1} MiB), its extension {why}",
+            relative.display(),
+            size as f64 / MIB as f64
+        );
+    }
+    // Before the outputs are moved into an archive
+    if let Some(path) = &args.report {
+        stats::write(path, results)?;
+    }
+    if args.check_metadata {
+        check_metadata(results);
+    }
+    // The files left out by an earlier run are only known if everything is
+    // planned again
+    if !args.source.is_partial() {
+        write_skipped(&pipeline)?;
+    }
+    if pipeline.is_cancelled() {
+        let finished = results
+            .iter()
+            .filter(|result| !result.outcome.is_unfinished())
+            .count();
+        warn!(
+            "Interrupted after {finished} of {total} files, run again with --resume to skip the finished ones"
+        );
+        // Failed files are still worth listing, the exit code says the rest
+        let _ = output::check_failures(results);
+        let destination = args.source.destination_path();
+        args.notify
+            .send(Status::Interrupted, destination, results, None, start);
+        std::process::exit(exit::INTERRUPTED);
+    }
+    if args.contact_sheets {
+        if args.source.is_partial() {
+            warn!("Not updating the contact sheets, only some files were processed");
         } else {
-            if entry.path().is_file() {
-                // File was not handled based on its extension
-                let file_size = entry.path().metadata().unwrap().len();
-                const MIB: u64 = 2_u64.pow(20);
-                if file_size < args.max_file_size * MIB {
-                    // Copy it over
-                    if let Err(e) = copy_file_as_is(entry.path(), &args) {
-                        eprintln!("Error: {:?}", e);
-                    }
+            let _timer = pipeline.timings().timer("contact sheets");
+            let jobs = results.iter().map(|result| &result.job);
+            let rendered = contact_sheet::update(jobs, pipeline.destination())?;
+            info!("Rendered {rendered} contact sheets");
+        }
+    }
+    if args.check_idempotent {
+        output::check_failures(results)?;
+        check_idempotent(args)?;
+    }
+    if let Some(budget) = args.budget {
+        args.check_budget(pipeline.destination(), budget)?;
+    }
+    if s3.is_some() {
+        let _timer = pipeline.timings().timer("upload");
+        upload(s3.as_ref(), &pipeline, results)?;
+    }
+    if let Some(format) = args.source.archive() {
+        let _timer = pipeline.timings().timer("archive");
+        let archive = Path::new(args.source.destination_path());
+        info!("Writing {}", archive.display());
+        if let Some(p) = archive.parent() {
+            std::fs::create_dir_all(p)?;
+        }
+        archive::write_archive(pipeline.destination(), archive, format)?;
+        std::fs::remove_dir_all(pipeline.destination())?;
+    }
+    if let Some(remote) = args.source.remote() {
+        info!("Syncing to {remote}");
+        let _timer = pipeline.timings().timer("rsync");
+        rsync::sync(pipeline.destination(), remote)?;
+    }
+    if let Some(path) = &args.metrics {
+        metrics::write_textfile(path, results, pipeline.timings(), start.elapsed())?;
+    }
+    if args.bench {
+        pipeline
+            .timings()
+            .record("total (wall time)", start.elapsed());
+        print_timings(pipeline.timings());
+    }
+    output::check_failures(results)
+}
+
+/// Asks a yes or no question on the terminal, failing instead of waiting
+/// forever if nobody can answer it, e.g. in CI
+fn confirm(question: &str) -> Result<bool> {
+    if !std::io::stdin().is_terminal() {
+        return Err(eyre!(
+            "{question} Can't ask since stdin is not a terminal, pass --yes to continue anyway"
+        ));
+    }
+    println!("{question} [y/N]");
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y"))
+}
+
+fn print_timings(timings: &Timings) {
+    let stages = timings.stages();
+    let width = stages.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+    println!();
+    println!(
+        "{:<width$} {:>7} {:>11} {:>11}",
+        "Stage", "Count", "Total", "Average"
+    );
+    for (name, stage) in stages {
+        let average = stage.total / stage.count.max(1);
+        println!(
+            "{name:<width$} {:>7} {:>10.2}s {:>9.1}ms",
+            stage.count,
+            stage.total.as_secs_f64(),
+            average.as_secs_f64() * 1000.0
+        );
+    }
+}
+
+/// Uploads the outputs of every successful job
+fn upload(target: Option<&S3Target>, pipeline: &Pipeline, results: &[JobResult]) -> Result<()> {
+    let Some(target) = target else {
+        return Ok(());
+    };
+    for result in results {
+        if result.outcome.is_unfinished() || matches!(result.outcome, Outcome::Unchanged) {
+            continue;
+        }
+        for (_, output) in result.job.outputs() {
+            let Ok(relative) = output.strip_prefix(pipeline.destination()) else {
+                continue;
+            };
+            if output.is_file() {
+                let key = url_path(relative);
+                info!("Uploading {key}");
+                target.upload(output, &key)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn watch(args: &ConvertArgs) -> Result<()> {
+    if args.source.archive().is_some() {
+        return Err(eyre!("watch needs a destination folder, not an archive"));
+    }
+    // The files that failed are listed and tried again when they change, but
+    // a broken config or a missing tool would fail every rebuild as well
+    match convert(args) {
+        Err(e) if e.downcast_ref::<exit::PartialFailure>().is_some() => {
+            warn!("{e}, watching for changes anyway");
+        }
+        result => result?,
+    }
+    // Changed files must be reencoded even though their outputs exist. A
+    // manual run in between only delays the changes
+    let pipeline = args.builder(true)?.wait_for_lock(true).build();
+    let s3 = args.s3.target()?;
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut debouncer = new_debouncer(Duration::from_millis(500), tx)?;
+    for (root, _) in pipeline.sources() {
+        debouncer.watcher().watch(root, RecursiveMode::Recursive)?;
+        info!("Watching {} for changes", root.display());
+    }
+    let cancelled = cancel_flag();
+    while !cancelled.load(Ordering::Relaxed) {
+        let events = match rx.recv_timeout(Duration::from_millis(200)) {
+            Ok(events) => events,
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let result = events
+            .map_err(Report::from)
+            .and_then(|events| pipeline.plan_files(events.into_iter().map(|e| e.path)))
+            .and_then(|jobs| output::run_jobs(&pipeline, jobs, args.output_format))
+            .and_then(|results| {
+                // Publishing half of the changes could leave pages with
+                // missing images
+                if pipeline.is_cancelled() {
+                    return Ok(());
+                }
+                upload(s3.as_ref(), &pipeline, &results)?;
+                match args.source.remote() {
+                    Some(remote) => rsync::sync(pipeline.destination(), remote),
+                    None => Ok(()),
                 }
+            });
+        if let Err(e) = result {
+            error!("{:?}", e);
+        }
+    }
+    Ok(())
+}
+
+fn serve(args: &ServeArgs) -> Result<()> {
+    let source = &args.source;
+    if source.archive().is_some() || source.remote().is_some() {
+        return Err(eyre!("serve needs a local destination folder"));
+    }
+    let pipeline = source
+        .pipeline(source.config()?)?
+        .cancel_flag(cancel_flag())
+        .build();
+    serve::run(&args.listen, pipeline, |pipeline| source.plan(pipeline))
+}
+
+fn pipe(args: &PipeArgs) -> Result<()> {
+    let mut source = Vec::new();
+    std::io::stdin().read_to_end(&mut source)?;
+    let converted = imagemagick::convert_bytes(&source, args.variant)?;
+    std::io::stdout().write_all(&converted)?;
+    Ok(())
+}
+
+/// Runs the conversion again and fails if that changes any output in the
+/// destination folder
+fn check_idempotent(args: &ConvertArgs) -> Result<()> {
+    info!("Running again to check that nothing changes");
+    let pipeline = args.pipeline(false)?;
+    let before = Snapshot::take(pipeline.destination())?;
+    let jobs = args.source.plan(&pipeline)?;
+    let results = pipeline.run_jobs(jobs, |_| {})?;
+    output::check_failures(&results)?;
+    let changes = before.changes(&Snapshot::take(pipeline.destination())?);
+    if changes.is_empty() {
+        info!("The second run changed nothing");
+        return Ok(());
+    }
+    let listing: String = changes
+        .iter()
+        .map(|(path, change)| format!("\n  {}: {change}", path.display()))
+        .collect();
+    Err(eyre!(
+        "the second run changed {} files in {}:{listing}",
+        changes.len(),
+        pipeline.destination().display()
+    ))
+}
+
+/// Warns about every output of a converted image that is shown or credited
+/// differently from its source
+fn check_metadata(results: &[JobResult]) {
+    let mut checked = 0;
+    let mut different = 0;
+    for result in results {
+        if !matches!(result.outcome, Outcome::Converted) {
+            continue;
+        }
+        let JobKind::ConvertImage(_) = &result.job.kind else {
+            continue;
+        };
+        let source = match Metadata::read(&result.job.source) {
+            Ok(source) => source,
+            Err(e) => {
+                warn!("{e:#}");
+                continue;
+            }
+        };
+        for (variant, output) in result.job.outputs() {
+            let differences = match Metadata::read(output) {
+                Ok(output) => source.differences(&output),
+                Err(e) => {
+                    warn!("{e:#}");
+                    continue;
+                }
+            };
+            checked += 1;
+            if !differences.is_empty() {
+                different += 1;
+                warn!(
+                    "the {variant} variant of {} {}",
+                    result.job.relative.display(),
+                    differences.join(", ")
+                );
+            }
+        }
+    }
+    info!("The metadata of {different} of {checked} outputs differs from their sources");
+}
+
+/// Lists the files left out of the destination in [`manifest::SKIPPED_FILE_NAME`],
+/// or removes the list of an earlier run if none were
+fn write_skipped(pipeline: &Pipeline) -> Result<()> {
+    let path = pipeline.destination().join(manifest::SKIPPED_FILE_NAME);
+    let skipped = pipeline.skipped();
+    if skipped.is_empty() {
+        return match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).wrap_err_with(|| format!("removing {}", path.display()))
             }
+            _ => Ok(()),
+        };
+    }
+    let json = serde_json::to_string_pretty(&skipped)?;
+    // Keeps its modification time for tools that watch the destination
+    if std::fs::read_to_string(&path).is_ok_and(|old| old == json) {
+        return Ok(());
+    }
+    std::fs::write(&path, json).wrap_err_with(|| format!("writing {}", path.display()))
+}
+
+/// Writes the manifest of the outputs in the destination folder and returns it
+fn write_manifest(args: &ManifestArgs) -> Result<Manifest> {
+    let source = &args.source;
+    if source.archive().is_some() || source.remote().is_some() {
+        return Err(eyre!(
+            "manifest and gallery need a local destination folder"
+        ));
+    }
+    let config = source.config()?;
+    let output_url = args.output_url.clone().or(config.output_url.clone());
+    let pipeline = source.pipeline(config)?.build();
+    let jobs = source.plan(&pipeline)?;
+    let mut manifest = Manifest::from_jobs(&jobs, pipeline.destination());
+    if let Some(template) = &output_url {
+        manifest.fill_urls(template, pipeline.destination())?;
+    }
+    manifest.fill_integrity(pipeline.destination(), args.integrity_all)?;
+    manifest.too_large = pipeline
+        .too_large()
+        .into_iter()
+        .map(|(relative, size)| (url_path(&relative), size))
+        .collect();
+    let manifest_path = Path::new(source.destination_path()).join(manifest::FILE_NAME);
+    manifest.write(&manifest_path)?;
+    info!(
+        "Wrote {} entries to {}",
+        manifest.assets.len(),
+        manifest_path.display()
+    );
+    if args.locations {
+        write_locations(&manifest, &jobs, Path::new(source.destination_path()))?;
+    }
+    let (redirect_format, headers_format) = match args.platform {
+        Some(platform) => (platform.redirects(), platform.headers()),
+        None => (args.redirects, args.headers),
+    };
+    if let Some(format) = redirect_format {
+        let count = write_redirects(&manifest, format, &args.base_path, source)?;
+        let max = args.platform.and_then(Platform::max_redirects);
+        if let Some(max) = max.filter(|max| count > *max) {
+            warn!("Only the first {max} of the {count} redirects will be used by the host");
         }
     }
+    if let Some(format) = headers_format {
+        let count = write_headers(&manifest, format, args, source)?;
+        let max = args.platform.and_then(Platform::max_headers);
+        if let Some(max) = max.filter(|max| count > *max) {
+            warn!("Only the first {max} of the {count} header rules will be used by the host");
+        }
+    }
+    if args.platform == Some(Platform::GithubPages) {
+        write_nojekyll(Path::new(source.destination_path()))?;
+        let renamed = redirects::redirects(&manifest, &args.base_path).len();
+        if renamed > 0 {
+            warn!("Not redirecting {renamed} renamed sources, which GitHub Pages can't do");
+        }
+    }
+    Ok(manifest)
+}
+
+/// Keeps GitHub Pages from leaving out outputs whose names start with `_`
+fn write_nojekyll(destination: &Path) -> Result<()> {
+    let path = destination.join(platform::NOJEKYLL_FILE_NAME);
+    if !path.exists() {
+        std::fs::write(&path, "").wrap_err_with(|| format!("writing {}", path.display()))?;
+    }
     Ok(())
 }
 
-fn get_destination_path(source: &Path, args: &Args) -> Result<PathBuf> {
-    let relative_file = source.strip_prefix(&args.asset_path)?;
-    let mut new_path = PathBuf::from(&args.destination_path);
-    new_path.push(relative_file);
-    Ok(new_path)
+/// Writes the redirects from sources to the outputs that are named
+/// differently and returns how many there are
+fn write_redirects(
+    manifest: &Manifest,
+    format: RedirectFormat,
+    base: &str,
+    source: &SourceArgs,
+) -> Result<usize> {
+    let redirects = redirects::redirects(manifest, base);
+    let text = redirects::render(&redirects, format);
+    let path = Path::new(source.destination_path()).join(format.file_name());
+    // Keeps its modification time for tools that watch the destination
+    if !std::fs::read_to_string(&path).is_ok_and(|old| old == text) {
+        std::fs::write(&path, text).wrap_err_with(|| format!("writing {}", path.display()))?;
+    }
+    info!("Wrote {} redirects to {}", redirects.len(), path.display());
+    Ok(redirects.len())
+}
+
+/// Writes the Cache-Control headers of the outputs in `manifest` and returns
+/// how many rules there are
+fn write_headers(
+    manifest: &Manifest,
+    format: HeadersFormat,
+    args: &ManifestArgs,
+    source: &SourceArgs,
+) -> Result<usize> {
+    let path = Path::new(source.destination_path()).join(format.file_name());
+    // An .htaccess with other rules, or one of the assets
+    if path.exists() && !headers::is_headers_file(&path) {
+        warn!("Not writing headers over {}", path.display());
+        return Ok(0);
+    }
+    let headers = headers::cache_control(manifest, &args.base_path, args.max_age);
+    let text = headers::render(&headers, format);
+    // Keeps its modification time for tools that watch the destination
+    if !std::fs::read_to_string(&path).is_ok_and(|old| old == text) {
+        std::fs::write(&path, text).wrap_err_with(|| format!("writing {}", path.display()))?;
+    }
+    info!(
+        "Wrote headers for {} outputs to {}",
+        headers.len(),
+        path.display()
+    );
+    Ok(headers.len())
+}
+
+/// Writes the locations of the sources of `jobs` that have GPS tags
+fn write_locations(manifest: &Manifest, jobs: &[Job], destination: &Path) -> Result<()> {
+    let mut locations = BTreeMap::new();
+    for job in jobs {
+        if !formats::is_image(&job.source) {
+            continue;
+        }
+        match Metadata::read_location(&job.source) {
+            Ok(Some(location)) => {
+                locations.insert(url_path(&job.relative), location);
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Not locating {}: {e:#}", job.relative.display()),
+        }
+    }
+    let geojson = manifest.locations_geojson(&locations);
+    let path = destination.join(manifest::LOCATIONS_FILE_NAME);
+    let json = serde_json::to_string_pretty(&geojson)?;
+    std::fs::write(&path, json).wrap_err_with(|| format!("writing {}", path.display()))?;
+    info!("Wrote {} locations to {}", locations.len(), path.display());
+    Ok(())
+}
+
+fn write_gallery(args: &ManifestArgs) -> Result<()> {
+    let manifest = write_manifest(args)?;
+    let destination = Path::new(args.source.destination_path());
+    let pages = gallery::pages(&manifest);
+    for page in &pages {
+        let path = destination.join(page.path());
+        // An index.html among the assets, or written by hand
+        if path.exists() && !gallery::is_page(&path) {
+            warn!("Not writing a gallery page over {}", path.display());
+            continue;
+        }
+        let html = page.to_html();
+        // Keeps its modification time for tools that watch the destination
+        if std::fs::read_to_string(&path).is_ok_and(|old| old == html) {
+            continue;
+        }
+        std::fs::write(&path, html).wrap_err_with(|| format!("writing {}", path.display()))?;
+    }
+    info!("Wrote a gallery of {} folders", pages.len());
+    Ok(())
+}
+
+fn print_plan(args: &SourceArgs) -> Result<()> {
+    if args.archive().is_some() || args.remote().is_some() {
+        return Err(eyre!("plan needs a local destination folder"));
+    }
+    let pipeline = args.pipeline(args.config()?)?.build();
+    let jobs = args.plan(&pipeline)?;
+    let diff = diff::diff(&jobs, pipeline.destination())?;
+    for output in &diff.missing {
+        println!("+ {} (missing)", output.output.display());
+    }
+    for output in &diff.stale {
+        println!(
+            "~ {} ({} changed)",
+            output.output.display(),
+            output.source.display()
+        );
+    }
+    for output in &diff.empty {
+        println!("! {} (empty)", output.output.display());
+    }
+    for path in &diff.orphaned {
+        println!("- {} (orphaned)", path.display());
+    }
+    if !diff.is_empty() {
+        println!();
+    }
+    println!(
+        "{} missing, {} stale, {} empty, {} up to date, {} orphaned",
+        diff.missing.len(),
+        diff.stale.len(),
+        diff.empty.len(),
+        diff.up_to_date,
+        diff.orphaned.len()
+    );
+    Ok(())
 }
 
-fn copy_file_as_is(file: &Path, args: &Args) -> Result<()> {
-    let relative_file = file.strip_prefix(&args.asset_path)?;
-    println!("Copying {}", relative_file.display());
-    let mut new_path = PathBuf::from(&args.destination_path);
-    new_path.push(relative_file);
-    if new_path == *file {
-        // Copying a file to itself can lead to corruption
+fn validate(args: &SourceArgs) -> Result<()> {
+    if args.archive().is_some() || args.remote().is_some() {
+        return Err(eyre!("validate needs a local destination folder"));
+    }
+    let pipeline = args.pipeline(args.config()?)?.build();
+    let jobs = args.plan(&pipeline)?;
+    let diff = diff::diff(&jobs, pipeline.destination())?;
+    let problems = [
+        ("missing", &diff.missing),
+        ("older than its source", &diff.stale),
+        ("empty", &diff.empty),
+    ];
+    for (problem, outputs) in problems {
+        for output in outputs {
+            println!(
+                "{} is {problem}, made from {}",
+                output.output.display(),
+                output.source.display()
+            );
+        }
+    }
+    if !diff.is_complete() {
+        let incomplete = diff.missing.len() + diff.stale.len() + diff.empty.len();
         return Err(eyre!(
-            "source and destination paths are the same: {}",
-            file.display()
+            "{incomplete} of {} outputs are missing, stale or empty",
+            incomplete + diff.up_to_date
         ));
     }
-    if let Some(p) = new_path.parent() {
-        std::fs::create_dir_all(p)?;
+    info!("All {} outputs are up to date", diff.up_to_date);
+    Ok(())
+}
+
+fn clean(args: &CleanArgs) -> Result<()> {
+    if args.source.archive().is_some() || args.source.remote().is_some() {
+        return Err(eyre!("clean needs a local destination folder"));
+    }
+    let pipeline = args.source.pipeline(args.source.config()?)?.build();
+    let jobs = args.source.plan(&pipeline)?;
+    let files = clean::generated_files(&jobs, pipeline.destination())?;
+    if args.dry_run {
+        for file in &files {
+            println!("- {}", file.display());
+        }
+        println!("{} files would be removed", files.len());
+        return Ok(());
     }
-    std::fs::copy(&file, &new_path).wrap_err_with(|| {
-        format!(
-            "source: {}, destination: {}",
-            file.display(),
-            new_path.display()
-        )
-    })?;
+    let _lock = DestinationLock::acquire(pipeline.destination(), false)?;
+    clean::remove(&files, pipeline.destination())?;
+    info!(
+        "Removed {} files from {}",
+        files.len(),
+        pipeline.destination().display()
+    );
     Ok(())
 }
 
-fn convert_image(source_path: &Path, args: &Args) -> Result<()> {
-    let mut destination_path = get_destination_path(source_path, &args)?;
-    let is_png = destination_path
-        .extension()
-        .unwrap()
-        .to_string_lossy()
-        .to_lowercase()
-        == "png";
-    if is_png {
-        destination_path.set_extension("jpg");
-    }
-    if let Some(p) = destination_path.parent() {
-        std::fs::create_dir_all(p)?;
-    }
-    // Create normal quality default version
-    if args.clean || !destination_path.exists() {
-        Command::new("convert")
-            .arg(source_path)
-            .arg("-strip")
-            .arg("-interlace")
-            .arg("Plane")
-            .arg("-gaussian-blur")
-            .arg("0.05")
-            .arg("-quality")
-            .arg("85%")
-            .arg("-resize")
-            .arg("1920x1920")
-            .arg(&destination_path)
-            .output()?;
-    }
-    if destination_path.metadata().unwrap().len() > source_path.metadata().unwrap().len() {
-        // This particular file is smaller as its original size than as a downsized jpg so use the original image
-        let destination_path = get_destination_path(source_path, &args)?;
-        std::fs::copy(&source_path, &destination_path).wrap_err_with(|| {
-            format!(
-                "source: {}, destination: {}",
-                source_path.display(),
-                destination_path.display()
-            )
-        })?;
-    }
-    // Check if it's worth creating a higher res version
-    {
-        let mut destination_path = destination_path.clone();
-        let org_file_name = destination_path.file_stem().unwrap().to_string_lossy();
-        let org_extension = destination_path.extension().unwrap().to_string_lossy();
-        destination_path.set_file_name(format!("{org_file_name}_high.{org_extension}"));
-        println!("high_path: {destination_path:?}");
-        if args.clean || !destination_path.exists() {
-            // let img = image::open(source_path)?;
-            // if img.width() >= 3840 || img.height() >= 3840 {
-            Command::new("convert")
-                .arg(source_path)
-                .arg("-strip")
-                .arg("-interlace")
-                .arg("Plane")
-                // .arg("-gaussian-blur")
-                // .arg("0.02")
-                .arg("-quality")
-                .arg("85%")
-                .arg("-resize")
-                .arg("3840x3840")
-                .arg(&destination_path)
-                .output()?;
-            // Sometimes the resulting file is larger than the original. In that case, copy the original to the new destination instead.
-            if destination_path.metadata().unwrap().len() > source_path.metadata().unwrap().len() {
-                std::fs::copy(&source_path, &destination_path).wrap_err_with(|| {
-                    format!(
-                        "source: {}, destination: {}",
-                        source_path.display(),
-                        destination_path.display()
-                    )
-                })?;
-            }
-            // }
-        }
-    }
-    // Create a thumbnail version
-    let mut destination_path = destination_path.clone();
-    let org_file_name = destination_path.file_stem().unwrap().to_string_lossy();
-    let org_extension = destination_path.extension().unwrap().to_string_lossy();
-    destination_path.set_file_name(format!("{org_file_name}_thumb.{org_extension}"));
-    println!("thumb_path: {destination_path:?}");
-    if args.clean || !destination_path.exists() {
-        Command::new("convert")
-            .arg(source_path)
-            .arg("-strip")
-            .arg("-interlace")
-            .arg("Plane")
-            .arg("-gaussian-blur")
-            .arg("0.01")
-            .arg("-quality")
-            .arg("85%")
-            .arg("-resize")
-            .arg("640x640")
-            .arg(&destination_path)
-            .output()?;
+fn print_similar(args: &SimilarArgs) -> Result<()> {
+    let pipeline = args.source.pipeline(args.source.config()?)?.build();
+    let jobs = args.source.plan(&pipeline)?;
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    let similar = similar::groups(&jobs, args.max_distance, threads);
+    for (job, e) in &similar.failed {
+        warn!("Not comparing {}: {e:#}", job.relative.display());
+    }
+    for group in &similar.groups {
+        let (_, first) = group.images[0];
+        println!("{} similar images:", group.images.len());
+        for (job, hash) in &group.images {
+            let dimensions = image::image_dimensions(&job.source)
+                .map_or_else(|_| "?".to_string(), |(w, h)| format!("{w}x{h}"));
+            let size = job.source.metadata().map_or(0, |m| m.len());
+            println!(
+                "  {} ({dimensions}, {:.1} MiB, {} bits apart)",
+                job.relative.display(),
+                size as f64 / MIB as f64,
+                similar::distance(first, *hash)
+            );
+        }
+        println!();
     }
+    let images: usize = similar.groups.iter().map(|g| g.images.len()).sum();
+    println!(
+        "{} groups of {images} similar images among {} compared",
+        similar.groups.len(),
+        similar.compared
+    );
     Ok(())
+}
+
+fn print_unused(args: &UnusedArgs) -> Result<()> {
+    let source = &args.source;
+    let pipeline = source.pipeline(source.config()?)?.build();
+    let jobs = source.plan(&pipeline)?;
+    let skip: Vec<&Path> = pipeline
+        .sources()
+        .into_iter()
+        .map(|(root, _)| root)
+        .chain([pipeline.destination()])
+        .collect();
+    let usage = references::usage(&jobs, pipeline.destination(), &args.site, &skip)?;
+    if !usage.unreferenced.is_empty() {
+        println!("Not referenced by the site:");
+        for job in &usage.unreferenced {
+            let size = job.source.metadata().map_or(0, |m| m.len());
+            println!(
+                "  {} ({:.1} MiB)",
+                job.relative.display(),
+                size as f64 / MIB as f64
+            );
+        }
+        println!();
+    }
+    if !usage.dangling.is_empty() {
+        println!("References to missing assets:");
+        for reference in &usage.dangling {
+            println!(
+                "  {}:{}: {}",
+                reference.file.display(),
+                reference.line,
+                reference.target
+            );
+        }
+        println!();
+    }
+    let size: u64 = usage
+        .unreferenced
+        .iter()
+        .filter_map(|job| job.source.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    println!(
+        "{} of {} sources ({:.1} MiB) are unreferenced and {} references are missing, in {} files of {}",
+        usage.unreferenced.len(),
+        jobs.len(),
+        size as f64 / MIB as f64,
+        usage.dangling.len(),
+        usage.scanned,
+        args.site.display()
+    );
+    Ok(())
+}
+
+/// Set by the first Ctrl-C so runs stop gracefully and can be resumed. A
+/// second Ctrl-C stops the running conversions and removes their partial
+/// outputs, and a third one exits immediately.
+fn cancel_flag() -> Arc<AtomicBool> {
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    FLAG.get_or_init(|| {
+        let flag = Arc::new(AtomicBool::new(false));
+        let handler_flag = flag.clone();
+        let presses = AtomicUsize::new(0);
+        let installed = ctrlc::set_handler(move || match presses.fetch_add(1, Ordering::Relaxed) {
+            0 => {
+                handler_flag.store(true, Ordering::Relaxed);
+                warn!("Stopping after the running jobs, press Ctrl-C again to stop them");
+            }
+            1 => {
+                warn!("Stopping the running jobs, press Ctrl-C again to exit now");
+                imagemagick::kill_running();
+            }
+            _ => {
+                imagemagick::kill_running();
+                std::process::exit(exit::INTERRUPTED);
+            }
+        });
+        if let Err(e) = installed {
+            warn!("Can't handle Ctrl-C: {e}");
+        }
+        flag
+    })
+    .clone()
+}
+
+impl ConvertArgs {
+    /// Applies `--nice` and `--io-priority` to the current process
+    fn set_priority(&self) -> Result<()> {
+        if let Some(nice) = self.nice {
+            priority::set_nice(nice)?;
+        }
+        if let Some(io_priority) = self.io_priority {
+            priority::set_io_priority(io_priority)?;
+        }
+        Ok(())
+    }
+
+    fn pipeline(&self, clean: bool) -> Result<Pipeline> {
+        Ok(self.builder(clean)?.build())
+    }
+
+    fn builder(&self, clean: bool) -> Result<web_assets_converter::PipelineBuilder> {
+        let mut builder = self
+            .source
+            .pipeline(self.source.config()?)?
+            .clean(clean)
+            .profile(self.profile)
+            .hash_sources(self.hash)
+            .resume(self.resume)
+            .wait_for_lock(self.wait_for_lock)
+            .link_mode(self.link_mode)
+            .preserve_attributes(self.preserve_attributes)
+            .big_image_pixels(self.big_image_megapixels * 1_000_000);
+        if let Some(quality) = self.quality {
+            builder = builder.quality(quality);
+        }
+        if let Some(jobs) = self.jobs {
+            builder = builder.jobs(jobs);
+        }
+        if let Some(processes) = self.max_processes {
+            builder = builder.max_processes(processes);
+        }
+        builder = builder.cancel_flag(cancel_flag());
+        #[cfg(feature = "async-copy")]
+        {
+            builder = builder.async_copy(self.async_copy);
+        }
+        #[cfg(feature = "tui")]
+        {
+            builder = builder.pause_flag(tui::pause_flag());
+        }
+        if let Some(max_bytes_per_pixel) = self.skip_optimized {
+            builder = builder.skip_optimized(max_bytes_per_pixel);
+        }
+        if self.shared_cache {
+            builder = builder.conversion_cache(self.source.cache_dir.join("conversions"));
+        }
+        if let Some(dir) = &self.backup_dir {
+            builder = builder.backup(dir);
+        }
+        if let Some(percent) = self.verify {
+            builder = builder.verify(percent);
+        }
+        for mirror in self.source.mirrors()? {
+            builder = builder.mirror(mirror);
+        }
+        Ok(builder)
+    }
+
+    /// Reports the size of `destination` against --budget, listing its
+    /// largest files if it is over
+    fn check_budget(&self, destination: &Path, budget: u64) -> Result<()> {
+        let usage = budget::measure(destination)?;
+        let mib = |size: u64| size as f64 / MIB as f64;
+        if usage.total <= budget {
+            info!(
+                "{} uses {:.1} MiB of the {:.1} MiB budget",
+                destination.display(),
+                mib(usage.total),
+                mib(budget)
+            );
+            return Ok(());
+        }
+        let largest: String = usage
+            .largest
+            .iter()
+            .map(|(path, size)| format!("\n  {:>9.1} MiB  {}", mib(*size), url_path(path)))
+            .collect();
+        let message = format!(
+            "{} uses {:.1} MiB, {:.1} MiB over the {:.1} MiB budget. The largest files are:{largest}",
+            destination.display(),
+            mib(usage.total),
+            mib(usage.total - budget),
+            mib(budget)
+        );
+        match self.fail_over_budget {
+            true => Err(eyre!(message)),
+            false => {
+                warn!("{message}");
+                Ok(())
+            }
+        }
+    }
+
+    /// Applies --on-existing to the outputs that would replace files this
+    /// tool didn't write. Returns `None` if the run should stop.
+    fn handle_existing(
+        &self,
+        pipeline: Pipeline,
+        jobs: Vec<Job>,
+    ) -> Result<Option<(Pipeline, Vec<Job>)>> {
+        let existing = diff::foreign_outputs(&jobs, pipeline.destination());
+        if existing.is_empty() {
+            return Ok(Some((pipeline, jobs)));
+        }
+        let destination = pipeline.destination().display();
+        let listing = {
+            let mut listing: Vec<_> = existing
+                .iter()
+                .take(10)
+                .map(|path| format!("  {}", path.display()))
+                .collect();
+            if existing.len() > 10 {
+                listing.push(format!("  and {} more", existing.len() - 10));
+            }
+            listing.join("\n")
+        };
+        let on_existing = match self.on_existing {
+            OnExisting::Prompt if self.yes => OnExisting::Overwrite,
+            OnExisting::Prompt if self.source.reads_stdin() => {
+                return Err(eyre!(
+                    "{destination} holds files that weren't written by this tool, can't ask whether to replace them while stdin provides the file list, see --on-existing"
+                ));
+            }
+            on_existing => on_existing,
+        };
+        match on_existing {
+            OnExisting::Error => Err(eyre!(
+                "{destination} holds {} files that weren't written by this tool and would be replaced, see --on-existing:\n{listing}",
+                existing.len()
+            )),
+            OnExisting::Prompt => {
+                println!(
+                    "{destination} holds {} files that weren't written by this tool:\n{listing}",
+                    existing.len()
+                );
+                match confirm("Replace them?")? {
+                    true => Ok(Some((self.builder(self.clean)?.force(existing).build(), jobs))),
+                    false => Ok(None),
+                }
+            }
+            // Nothing in the folder is from an earlier run to compare with, so
+            // the files in the way are replaced even if they look up to date
+            OnExisting::Overwrite => {
+                warn!(
+                    "replacing {} files in {destination} that weren't written by this tool",
+                    existing.len()
+                );
+                Ok(Some((self.builder(self.clean)?.force(existing).build(), jobs)))
+            }
+            OnExisting::Skip => {
+                let existing: HashSet<_> = existing.iter().map(PathBuf::as_path).collect();
+                let (kept, skipped): (Vec<_>, Vec<_>) = jobs.into_iter().partition(|job| {
+                    job.outputs()
+                        .iter()
+                        .all(|(_, path)| !existing.contains(path))
+                });
+                warn!(
+                    "left out {} sources whose outputs would replace files in {destination} that weren't written by this tool",
+                    skipped.len()
+                );
+                Ok(Some((pipeline, kept)))
+            }
+        }
+    }
+
+    fn run_jobs(&self, pipeline: &Pipeline, jobs: Vec<Job>) -> Result<Vec<JobResult>> {
+        #[cfg(feature = "tui")]
+        if self.tui {
+            return tui::run_jobs(pipeline, jobs, cancel_flag());
+        }
+        output::run_jobs(pipeline, jobs, self.output_format)
+    }
+}
+
+impl S3Args {
+    fn target(&self) -> Result<Option<S3Target>> {
+        let Some(bucket) = &self.s3_bucket else {
+            return Ok(None);
+        };
+        let (access_key, secret_key) = S3Target::credentials_from_env()?;
+        Ok(Some(S3Target {
+            endpoint: self
+                .s3_endpoint
+                .clone()
+                .unwrap_or_else(|| format!("https://s3.{}.amazonaws.com", self.s3_region)),
+            bucket: bucket.clone(),
+            region: self.s3_region.clone(),
+            prefix: self.s3_prefix.clone(),
+            cache_control: Some(self.s3_cache_control.clone()),
+            access_key,
+            secret_key,
+        }))
+    }
+}
+
+impl NotifyArgs {
+    fn send(
+        &self,
+        status: Status,
+        destination: &str,
+        results: &[JobResult],
+        error: Option<&Report>,
+        start: Instant,
+    ) {
+        if self.notify_webhook.is_none() && self.notify_cmd.is_none() {
+            return;
+        }
+        let destination = Path::new(destination);
+        let payload = notification::payload(status, destination, results, error, start.elapsed());
+        notification::send(
+            self.notify_webhook.as_deref(),
+            self.notify_cmd.as_deref(),
+            &payload,
+        );
+    }
+}
+
+/// The folder and prefix of an `--asset-path` after the first, which is a
+/// path or PREFIX=PATH. A path to a folder that exists is taken as it is, even
+/// if it contains a `=`.
+fn extra_source(arg: &str) -> (PathBuf, PathBuf) {
+    match arg.split_once('=') {
+        Some((prefix, path)) if !Path::new(arg).exists() => (path.into(), prefix.into()),
+        _ => (arg.into(), PathBuf::new()),
+    }
+}
+
+impl SourceArgs {
+    /// Reads `--config` or the config file in the asset folder
+    fn config(&self) -> Result<Config> {
+        let path = match &self.config {
+            Some(path) => path.clone(),
+            None => Path::new(self.asset_path()).join(config::FILE_NAME),
+        };
+        if self.config.is_some() && !path.is_file() {
+            return Err(eyre!("it doesn't exist")).wrap_err(ConfigError { path });
+        }
+        Config::load(&path)
+    }
+
+    /// The asset folder, which holds the config file
+    fn asset_path(&self) -> &str {
+        &self.asset_path[0]
+    }
+
+    /// The extra sources from the config followed by those given with
+    /// `--asset-path`, as folders with the prefix of their outputs
+    fn extra_sources(&self, config: &Config) -> Result<Vec<(PathBuf, PathBuf)>> {
+        let mut sources: Vec<_> = config
+            .extra_sources
+            .iter()
+            .map(|source| {
+                let root = Path::new(self.asset_path()).join(&source.path);
+                (root, source.prefix.clone())
+            })
+            .collect();
+        sources.extend(self.asset_path[1..].iter().map(|arg| extra_source(arg)));
+        for (_, prefix) in &sources {
+            if prefix.is_absolute() || prefix.components().any(|c| c.as_os_str() == "..") {
+                return Err(eyre!(
+                    "the prefix {} must be a folder inside the destination",
+                    prefix.display()
+                ));
+            }
+        }
+        Ok(sources)
+    }
+
+    fn pipeline(&self, mut config: Config) -> Result<web_assets_converter::PipelineBuilder> {
+        let extra_sources = self.extra_sources(&config)?;
+        config.exclude.extend(self.exclude.iter().cloned());
+        config.force_copy.extend(self.force_copy.iter().cloned());
+        config.change_block(&self.block, &self.unblock);
+        if !self.allow.is_empty() {
+            config.allow = Some(self.allow.clone());
+        }
+        if self.slug_names {
+            config.slug_names = Some(true);
+        }
+        if self.flatten {
+            config.flatten = Some(true);
+        }
+        if self.keep_originals {
+            config.keep_originals = Some(true);
+        }
+        if let Some(preset) = self.preset {
+            config.preset = Some(preset);
+        }
+        if self.detect_graphics {
+            config.detect_graphics = Some(true);
+        }
+        if let Some(color) = &self.flatten_color {
+            if !config::is_hex_color(color) {
+                return Err(eyre!("--flatten-color must be like #ffffff, not {color}"));
+            }
+            config.flatten_color = Some(color.clone());
+        }
+        if !self.enhance.is_empty() {
+            config.enhance = self.enhance.clone();
+        }
+        if let Some(denoise) = self.denoise {
+            config.denoise = Some(denoise);
+        }
+        if let Some(targets) = &self.target_size {
+            config
+                .target_size
+                .parse(targets)
+                .wrap_err("--target-size")?;
+        }
+        for variant in &self.keep_metadata {
+            match variant {
+                Variant::Default => config.strip.default = false,
+                Variant::High => config.strip.high = false,
+                Variant::Thumb => config.strip.thumb = false,
+            }
+        }
+        let max_file_size = self.max_file_size.or(config.max_file_size).unwrap_or(20);
+        let mut builder = Pipeline::builder(self.asset_path(), self.output_dir())
+            .max_file_size(max_file_size * MIB)
+            .config(config);
+        for (root, prefix) in extra_sources {
+            builder = builder.extra_source(root, prefix);
+        }
+        if let Some(depth) = self.max_depth {
+            builder = builder.max_depth(depth);
+        }
+        builder = builder
+            .follow_links(self.follow_links)
+            .include_hidden(self.include_hidden)
+            .on_collision(self.on_collision)
+            .on_low_space(self.on_low_space);
+        Ok(match self.format {
+            Some(format) => builder.format(format),
+            None => builder,
+        })
+    }
+
+    /// The main destination, which holds the journal and the manifest
+    fn destination_path(&self) -> &str {
+        &self.destination_path[0]
+    }
+
+    /// The destination folders after the first, which the outputs are copied
+    /// into
+    fn mirrors(&self) -> Result<Vec<PathBuf>> {
+        self.destination_path[1..]
+            .iter()
+            .map(|path| {
+                if ArchiveFormat::from_path(Path::new(path)).is_some() || rsync::is_remote(path) {
+                    return Err(eyre!(
+                        "only the first destination can be an archive or a remote host, not {path}"
+                    ));
+                }
+                Ok(PathBuf::from(path))
+            })
+            .collect()
+    }
+
+    /// Set if the destination is an archive file instead of a folder
+    fn archive(&self) -> Option<ArchiveFormat> {
+        ArchiveFormat::from_path(Path::new(self.destination_path()))
+    }
+
+    /// Set if the destination is a remote host to sync to
+    fn remote(&self) -> Option<&str> {
+        rsync::is_remote(self.destination_path()).then_some(self.destination_path())
+    }
+
+    /// The folder outputs are written to. For archives this is a hidden
+    /// staging folder next to the archive, which is removed once it is packed.
+    /// For remote hosts it is kept in the cache folder so that only changed
+    /// files are converted and transferred on the next run.
+    fn output_dir(&self) -> PathBuf {
+        if let Some(remote) = self.remote() {
+            let name: String = remote
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+                .collect();
+            return self.cache_dir.join("rsync").join(name);
+        }
+        let destination = PathBuf::from(self.destination_path());
+        if self.archive().is_none() {
+            return destination;
+        }
+        let mut name = std::ffi::OsString::from(".");
+        name.push(destination.file_name().unwrap_or_default());
+        name.push(".staging");
+        destination.with_file_name(name)
+    }
+
+    fn reads_stdin(&self) -> bool {
+        self.files_from.as_deref() == Some(Path::new("-"))
+    }
+
+    /// Whether only some of the files in the asset folder are planned
+    fn is_partial(&self) -> bool {
+        self.files_from.is_some()
+            || self.changed_since.is_some()
+            || self.spec_only
+            || !self.only.is_empty()
+            || self.limit.is_some()
+    }
+
+    /// Plans the files given by `--files-from` or `--changed-since`, or the
+    /// whole asset folder, followed by the images in `--spec` and the files
+    /// from `--sources`, and marks duplicates if `--dedupe` is set
+    fn plan(&self, pipeline: &Pipeline) -> Result<Vec<Job>> {
+        let only = self.only_globs()?;
+        let is_selected = |relative: &Path| only.as_ref().is_none_or(|g| g.is_match(relative));
+        let mut jobs = match self.spec_only {
+            true => Vec::new(),
+            false => self.plan_local(pipeline)?,
+        };
+        if let Some(spec) = &self.spec {
+            jobs.extend(pipeline.plan_spec(&spec::load(spec)?)?);
+            jobs = pipeline.resolve_collisions(jobs)?;
+        }
+        jobs.retain(|job| is_selected(&job.relative));
+        if let Some(sources) = &self.sources {
+            let text = std::fs::read_to_string(sources)
+                .wrap_err_with(|| format!("reading {}", sources.display()))?;
+            let mut sources = remote::parse_sources(&text)?;
+            // Don't download what won't be processed
+            sources.retain(|source| is_selected(&source.path));
+            let cache = self.cache_dir.join("remote");
+            let files = {
+                let _timer = pipeline.timings().timer("download");
+                remote::fetch(&sources, &cache)?
+            };
+            jobs.extend(pipeline.plan_files_in(&cache, files)?);
+            jobs = pipeline.resolve_collisions(jobs)?;
+        }
+        if let Some(limit) = self.limit {
+            jobs.truncate(limit);
+        }
+        if self.dedupe {
+            jobs = pipeline.deduplicate(jobs)?;
+        }
+        Ok(jobs)
+    }
 
-    // convert "$f" \
-    // -strip \
-    // -interlace Plane \
-    // -gaussian-blur 0.05 \
-    // -quality 85% \
-    // -resize 1920x1920\> \
-    // "$f"
+    fn only_globs(&self) -> Result<Option<GlobSet>> {
+        if self.only.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in &self.only {
+            builder.add(Glob::new(pattern).wrap_err_with(|| format!("--only {pattern}"))?);
+        }
+        Ok(Some(builder.build()?))
+    }
+
+    fn plan_local(&self, pipeline: &Pipeline) -> Result<Vec<Job>> {
+        if let Some(rev) = &self.changed_since {
+            let mut files = Vec::new();
+            for (root, _) in pipeline.sources() {
+                files.extend(git::changed_files(root, rev)?);
+            }
+            return pipeline.plan_files(files);
+        }
+        let Some(list) = &self.files_from else {
+            return pipeline.plan();
+        };
+        let mut content = Vec::new();
+        if self.reads_stdin() {
+            std::io::stdin().read_to_end(&mut content)?;
+        } else {
+            content =
+                std::fs::read(list).wrap_err_with(|| format!("reading {}", list.display()))?;
+        }
+        pipeline.plan_files(parse_file_list(&content))
+    }
+}
+
+/// Splits on NUL if the list contains any, as produced by `find -print0`,
+/// and on newlines otherwise
+fn parse_file_list(content: &[u8]) -> Vec<PathBuf> {
+    let separator = if content.contains(&0) { b'\0' } else { b'\n' };
+    content
+        .split(|&b| b == separator)
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    PathBuf::from(std::ffi::OsStr::from_bytes(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(bytes).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extra_sources_are_split_at_the_prefix_unless_they_exist() {
+        assert_eq!(
+            extra_source("shots=../screenshots"),
+            (PathBuf::from("../screenshots"), PathBuf::from("shots"))
+        );
+        assert_eq!(
+            extra_source("../screenshots"),
+            (PathBuf::from("../screenshots"), PathBuf::new())
+        );
+        let dir =
+            std::env::temp_dir().join(format!("web_assets_converter-extra-{}", std::process::id()));
+        let folder = dir.join("a=b");
+        std::fs::create_dir_all(&folder).unwrap();
+        let arg = folder.to_str().unwrap();
+        assert_eq!(extra_source(arg), (folder.clone(), PathBuf::new()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn selecting_files_makes_a_run_partial() {
+        let source = |args: &[&str]| {
+            let cli =
+                Cli::try_parse_from(["web_assets_converter", "plan"].iter().chain(args)).unwrap();
+            match cli.command {
+                Commands::Plan(source) => source,
+                _ => unreachable!(),
+            }
+        };
+        assert!(!source(&[]).is_partial());
+        assert!(source(&["--only", "photos/**"]).is_partial());
+        assert!(source(&["--limit", "10"]).is_partial());
+        assert!(source(&["--changed-since", "HEAD"]).is_partial());
+    }
 }
@@ -0,0 +1,394 @@
+use std::{collections::BTreeMap, path::Path};
+
+use serde::Serialize;
+
+#[cfg(feature = "native")]
+use {
+    crate::{config::Sidecar, metadata::Location, plan, Job, JobKind, Variant},
+    color_eyre::eyre::{eyre, Result, WrapErr},
+};
+
+/// The name of the manifest in the destination folder
+pub const FILE_NAME: &str = "manifest.json";
+
+/// The name of the list of [skipped files](SkippedFile) in the destination
+/// folder
+pub const SKIPPED_FILE_NAME: &str = "skipped.json";
+
+/// The name of the GeoJSON file locating the images that have GPS tags in
+/// the destination folder
+pub const LOCATIONS_FILE_NAME: &str = "locations.geojson";
+
+/// Maps every source file to the outputs it produced in the destination folder
+#[derive(Serialize, Default, Debug)]
+pub struct Manifest {
+    pub assets: BTreeMap<String, BTreeMap<&'static str, String>>,
+    /// Sources whose outputs were renamed by `slug-names`, mapped to their
+    /// default output, so references to them can be updated
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub renamed: BTreeMap<String, String>,
+    /// Sources left out for being larger than the maximum file size, with
+    /// their size in bytes
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub too_large: BTreeMap<String, u64>,
+    /// Sources that were smaller than some of their variants, mapped to those
+    /// variants, which are copies of the source instead of conversions
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub originals: BTreeMap<String, Vec<&'static str>>,
+    /// Sources identical to an earlier source, mapped to it. Their outputs
+    /// are copies of its outputs
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub duplicates: BTreeMap<String, String>,
+    /// The outputs of [spec files](crate::spec), mapped to their sources
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub curated: BTreeMap<String, String>,
+    /// Descriptions of sources from the `alt` of their sidecars
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub alt: BTreeMap<String, String>,
+    /// The [Subresource Integrity](https://www.w3.org/TR/SRI/) hash of every
+    /// script and stylesheet, or of every output, for `integrity` attributes
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub integrity: BTreeMap<String, String>,
+    /// Every output mapped to where it is served, from an `output-url`
+    /// template, see [`output_url`]
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub urls: BTreeMap<String, String>,
+}
+
+impl Manifest {
+    /// Records that `source` produced `output`, both relative to their roots
+    pub fn insert(&mut self, source: &Path, variant: &'static str, output: &Path) {
+        self.assets
+            .entry(url_path(source))
+            .or_default()
+            .insert(variant, url_path(output));
+    }
+
+    /// Lists the outputs of the jobs that exist in the destination folder,
+    /// relative to the destination folder
+    #[cfg(feature = "native")]
+    pub fn from_jobs(jobs: &[Job], destination_root: &Path) -> Manifest {
+        let mut manifest = Manifest::default();
+        for job in jobs {
+            // Only list outputs that have actually been produced, and leave
+            // out the kept originals, which aren't meant for the site
+            for (variant, path) in job.outputs() {
+                if variant == plan::ORIGINAL {
+                    continue;
+                }
+                if variant == plan::CURATED {
+                    if let Ok(relative) = path.strip_prefix(destination_root) {
+                        if path.is_file() {
+                            manifest
+                                .curated
+                                .insert(url_path(relative), url_path(&job.relative));
+                        }
+                    }
+                    continue;
+                }
+                if let Ok(relative) = path.strip_prefix(destination_root) {
+                    if path.is_file() {
+                        manifest.insert(&job.relative, variant, relative);
+                    }
+                    // Only the extension changes if the name isn't slugged
+                    let is_renamed = job.relative.with_extension("") != relative.with_extension("");
+                    if variant == Variant::Default.name() && is_renamed {
+                        manifest
+                            .renamed
+                            .insert(url_path(&job.relative), url_path(relative));
+                    }
+                }
+            }
+            if let JobKind::ConvertImage(_) = &job.kind {
+                let originals: Vec<_> = job
+                    .outputs()
+                    .into_iter()
+                    .filter(|(variant, path)| {
+                        *variant != plan::ORIGINAL && is_copy_of(path, &job.source)
+                    })
+                    .map(|(variant, _)| variant)
+                    .collect();
+                if !originals.is_empty() {
+                    manifest
+                        .originals
+                        .insert(url_path(&job.relative), originals);
+                }
+            }
+            // Planning already failed on invalid sidecars
+            if let Ok(Some(Sidecar { alt: Some(alt), .. })) = Sidecar::load(&job.source) {
+                manifest.alt.insert(url_path(&job.relative), alt);
+            }
+            if let JobKind::Duplicate { of, .. } = &job.kind {
+                manifest
+                    .duplicates
+                    .insert(url_path(&job.relative), url_path(of));
+            }
+        }
+        manifest
+    }
+
+    /// The outputs listed in [`Manifest::assets`] and [`Manifest::curated`]
+    pub fn outputs(&self) -> impl Iterator<Item = &String> {
+        self.assets
+            .values()
+            .flat_map(|outputs| outputs.values())
+            .chain(self.curated.keys())
+    }
+
+    /// Fills in [`Manifest::urls`] from `template`, reading the outputs in
+    /// `destination` if it has a `{hash}`
+    #[cfg(feature = "native")]
+    pub fn fill_urls(&mut self, template: &str, destination: &Path) -> Result<()> {
+        if !template.contains("{path}") && !template.contains("{name}") {
+            return Err(eyre!(
+                "the output URL \"{template}\" must contain {{path}} or {{name}}"
+            ));
+        }
+        let mut urls = BTreeMap::new();
+        for output in self.outputs() {
+            let hash = match template.contains("{hash}") {
+                true => Some(crate::remote::sha256_file(&destination.join(output))?),
+                false => None,
+            };
+            urls.insert(
+                output.clone(),
+                output_url(template, output, hash.as_deref()),
+            );
+        }
+        self.urls = urls;
+        Ok(())
+    }
+
+    /// Fills in [`Manifest::integrity`] for the scripts and stylesheets in
+    /// `destination`, or for every output if `all` is set
+    #[cfg(feature = "native")]
+    pub fn fill_integrity(&mut self, destination: &Path, all: bool) -> Result<()> {
+        let mut integrity = BTreeMap::new();
+        for output in self.outputs() {
+            let extension = crate::formats::extension(Path::new(output)).unwrap_or_default();
+            if all || SUBRESOURCE_EXTENSIONS.contains(&extension.as_str()) {
+                let hash = integrity_hash(&destination.join(output))?;
+                integrity.insert(output.clone(), hash);
+            }
+        }
+        self.integrity = integrity;
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> serde_json::Result<String> {
+        serde_json::to_string_pretty(self)
+    }
+
+    /// A GeoJSON feature collection with a point for every source in
+    /// `locations`, keyed like [`Manifest::assets`], with its outputs as
+    /// properties
+    #[cfg(feature = "native")]
+    pub fn locations_geojson(&self, locations: &BTreeMap<String, Location>) -> serde_json::Value {
+        let features: Vec<_> = locations
+            .iter()
+            .filter_map(|(source, location)| {
+                let outputs = self.assets.get(source)?;
+                let mut coordinates = vec![location.longitude, location.latitude];
+                coordinates.extend(location.altitude);
+                Some(serde_json::json!({
+                    "type": "Feature",
+                    "geometry": { "type": "Point", "coordinates": coordinates },
+                    "properties": { "source": source, "outputs": outputs },
+                }))
+            })
+            .collect();
+        serde_json::json!({ "type": "FeatureCollection", "features": features })
+    }
+
+    #[cfg(feature = "native")]
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let json = self.to_json()?;
+        std::fs::write(path, json).wrap_err_with(|| format!("writing {}", path.display()))
+    }
+}
+
+/// A source that was left out of the destination folder, so the site can link
+/// to where it is hosted instead
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct SkippedFile {
+    /// Relative to the asset folder, with forward slashes
+    pub path: String,
+    /// In bytes
+    pub size: u64,
+    pub reason: SkipReason,
+    /// The limit the file was over, in bytes, if it was too large
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_file_size: Option<u64>,
+    /// Where the file is hosted, from the `external-url` of the config
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    /// Larger than the maximum file size
+    TooLarge,
+    /// Its extension is blocked
+    Blocked,
+    /// Its extension is missing from the allowlist
+    NotAllowed,
+}
+
+/// Fills in the `{path}` and `{name}` of an `external-url` template for the
+/// source at `relative`, percent encoding them
+pub fn external_url(template: &str, relative: &Path) -> String {
+    let path = url_path(relative);
+    let name = path.rsplit('/').next().unwrap_or_default();
+    template
+        .replace("{path}", &percent_encode(&path))
+        .replace("{name}", &percent_encode(name))
+}
+
+/// The extensions of the outputs that get an integrity hash by default, which
+/// are the ones loaded with `<script>` and `<link>`
+#[cfg(feature = "native")]
+const SUBRESOURCE_EXTENSIONS: [&str; 3] = ["js", "mjs", "css"];
+
+/// The `integrity` attribute value of the file at `path`, its SHA-384 in
+/// base64 after `sha384-`
+#[cfg(feature = "native")]
+pub fn integrity_hash(path: &Path) -> Result<String> {
+    use sha2::{Digest, Sha384};
+    let mut file =
+        std::fs::File::open(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    let mut hasher = Sha384::new();
+    std::io::copy(&mut file, &mut hasher)
+        .wrap_err_with(|| format!("reading {}", path.display()))?;
+    Ok(format!("sha384-{}", base64(&hasher.finalize())))
+}
+
+/// Standard base64 with padding
+#[cfg(feature = "native")]
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &byte)| n | u32::from(byte) << (16 - 8 * i));
+        for i in 0..4 {
+            match i <= chunk.len() {
+                true => encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char),
+                false => encoded.push('='),
+            }
+        }
+    }
+    encoded
+}
+
+/// Fills in an `output-url` template for `output`, relative to the
+/// destination folder: `{path}` is the output and `{name}` its file name,
+/// both percent encoded, and `{hash}` the first 16 hex digits of the SHA-256
+/// of its contents, e.g. for `https://cdn.example.com/{path}?v={hash}`
+pub fn output_url(template: &str, output: &str, hash: Option<&str>) -> String {
+    let name = output.rsplit('/').next().unwrap_or_default();
+    let hash = hash.map_or("", |hash| &hash[..hash.len().min(16)]);
+    template
+        .replace("{path}", &percent_encode(output))
+        .replace("{name}", &percent_encode(name))
+        .replace("{hash}", hash)
+}
+
+/// Percent encodes everything in `path` but unreserved URL characters and `/`
+pub(crate) fn percent_encode(path: &str) -> String {
+    let mut encoded = String::with_capacity(path.len());
+    for byte in path.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
+}
+
+/// Decodes `%XX` escapes, keeping invalid ones as they are
+pub fn percent_decode(path: &str) -> String {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// Whether `output` holds the same bytes as `source`. Only files of the same
+/// size are read.
+#[cfg(feature = "native")]
+fn is_copy_of(output: &Path, source: &Path) -> bool {
+    let size = |path: &Path| path.metadata().map(|m| m.len()).ok();
+    if size(output).is_none() || size(output) != size(source) {
+        return false;
+    }
+    match (std::fs::read(output), std::fs::read(source)) {
+        (Ok(output), Ok(source)) => output == source,
+        _ => false,
+    }
+}
+
+/// Joins the components of a relative path with forward slashes, as used in URLs
+pub fn url_path(path: &Path) -> String {
+    path.components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn base64_matches_the_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "Zg=="),
+            ("fo", "Zm8="),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg=="),
+            ("fooba", "Zm9vYmE="),
+            ("foobar", "Zm9vYmFy"),
+        ];
+        for (input, encoded) in vectors {
+            assert_eq!(base64(input.as_bytes()), encoded, "encoding {input:?}");
+        }
+    }
+
+    #[test]
+    fn integrity_hashes_are_sha384_in_base64() {
+        let path = std::env::temp_dir().join(format!(
+            "web_assets_converter-integrity-{}.js",
+            std::process::id()
+        ));
+        std::fs::write(&path, "alert('Hello, world.');").unwrap();
+        let hash = integrity_hash(&path);
+        let _ = std::fs::remove_file(&path);
+        // The example of the Subresource Integrity docs on MDN
+        assert_eq!(
+            hash.unwrap(),
+            "sha384-H8BRh8j48O9oYatfu5AZzq6A9RINhZO5H16dQZngK7T62em8MUt1FLm52t+eX6xO"
+        );
+    }
+}
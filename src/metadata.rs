@@ -0,0 +1,355 @@
+//! Reads the metadata that matters for how an image is shown and credited,
+//! to check what survives the conversion: the copyright and artist, the
+//! orientation and the color space. Only JPEG, PNG and WebP are read, straight
+//! from their containers, so this works without ImageMagick.
+
+use std::{io::Read, path::Path};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+
+/// EXIF tags in the first IFD
+const ORIENTATION: u16 = 0x0112;
+const ARTIST: u16 = 0x013b;
+const COPYRIGHT: u16 = 0x8298;
+/// The offset of the IFD with the GPS tags
+const GPS_INFO: u16 = 0x8825;
+
+/// GPS tags
+const GPS_LATITUDE_REF: u16 = 1;
+const GPS_LATITUDE: u16 = 2;
+const GPS_LONGITUDE_REF: u16 = 3;
+const GPS_LONGITUDE: u16 = 4;
+const GPS_ALTITUDE_REF: u16 = 5;
+const GPS_ALTITUDE: u16 = 6;
+
+/// JPEG keeps its EXIF data before the image data, which is all that is read
+/// to find the location of a JPEG
+const JPEG_HEAD_SIZE: u64 = 256 * 1024;
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Metadata {
+    pub copyright: Option<String>,
+    pub artist: Option<String>,
+    /// The EXIF orientation, from 1 (as stored) to 8
+    pub orientation: Option<u16>,
+    pub color_space: Option<ColorSpace>,
+    /// Whether an ICC color profile is embedded
+    pub icc_profile: bool,
+    /// As stored, before the orientation is applied
+    pub dimensions: Option<(u32, u32)>,
+    /// Where the photo was taken, from its GPS tags
+    pub location: Option<Location>,
+}
+
+/// A position in WGS 84 degrees, as in GPS tags
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Location {
+    /// Negative to the south
+    pub latitude: f64,
+    /// Negative to the west
+    pub longitude: f64,
+    /// In meters, negative below sea level
+    pub altitude: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorSpace {
+    Gray,
+    Rgb,
+    Cmyk,
+}
+
+impl std::fmt::Display for ColorSpace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            ColorSpace::Gray => "grayscale",
+            ColorSpace::Rgb => "RGB",
+            ColorSpace::Cmyk => "CMYK",
+        })
+    }
+}
+
+impl Metadata {
+    /// Reads the metadata of the image at `path`, which is empty for formats
+    /// that can't be read here
+    pub fn read(path: &Path) -> Result<Metadata> {
+        let bytes = std::fs::read(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+        let mut metadata = match bytes.get(..4) {
+            Some([0xff, 0xd8, ..]) => read_jpeg(&bytes),
+            Some([0x89, b'P', b'N', b'G']) => read_png(&bytes),
+            Some(b"RIFF") if bytes.get(8..12) == Some(b"WEBP") => read_webp(&bytes),
+            _ => Ok(Metadata::default()),
+        }
+        .wrap_err_with(|| format!("reading the metadata of {}", path.display()))?;
+        metadata.dimensions = image::image_dimensions(path).ok();
+        Ok(metadata)
+    }
+
+    /// Reads only the location of the image at `path`, which for JPEG only
+    /// needs the start of the file
+    pub fn read_location(path: &Path) -> Result<Option<Location>> {
+        let mut head = Vec::new();
+        std::fs::File::open(path)
+            .and_then(|file| file.take(JPEG_HEAD_SIZE).read_to_end(&mut head))
+            .wrap_err_with(|| format!("reading {}", path.display()))?;
+        match head.get(..2) {
+            Some([0xff, 0xd8]) => read_jpeg(&head)
+                .map(|metadata| metadata.location)
+                .wrap_err_with(|| format!("reading the metadata of {}", path.display())),
+            _ => Ok(Metadata::read(path)?.location),
+        }
+    }
+
+    /// Whether the image is shown turned by a quarter
+    fn is_turned(&self) -> bool {
+        matches!(self.orientation, Some(5..=8))
+    }
+
+    /// Whether the image is shown wider than it is tall
+    fn is_landscape(&self) -> Option<bool> {
+        let (width, height) = self.dimensions?;
+        if width == height {
+            return None;
+        }
+        Some((width > height) != self.is_turned())
+    }
+
+    /// How `output`, converted from this image, is shown or credited
+    /// differently
+    pub fn differences(&self, output: &Metadata) -> Vec<String> {
+        let mut differences = Vec::new();
+        for (name, source, output) in [
+            ("copyright", &self.copyright, &output.copyright),
+            ("artist", &self.artist, &output.artist),
+        ] {
+            match (source, output) {
+                (Some(_), None) => differences.push(format!("lost the {name}")),
+                (Some(a), Some(b)) if a != b => {
+                    differences.push(format!("changed the {name} from \"{a}\" to \"{b}\""))
+                }
+                _ => {}
+            }
+        }
+        match (self.is_landscape(), output.is_landscape()) {
+            (Some(a), Some(b)) if a != b => differences.push(format!(
+                "is shown turned, the orientation {} of the source was dropped without rotating it",
+                self.orientation.unwrap_or(1)
+            )),
+            // Mirroring and turning upside down don't change the shape
+            _ if matches!(self.orientation, Some(2..=4))
+                && output.orientation.unwrap_or(1) == 1 =>
+            {
+                differences.push(format!(
+                    "may be shown mirrored or upside down, the orientation {} of the source was dropped",
+                    self.orientation.unwrap_or(1)
+                ))
+            }
+            _ => {}
+        }
+        if let (Some(a), Some(b)) = (self.color_space, output.color_space) {
+            if a != b {
+                differences.push(format!("changed the color space from {a} to {b}"));
+            }
+        }
+        if self.icc_profile && !output.icc_profile {
+            differences.push("lost the ICC color profile".to_string());
+        }
+        differences
+    }
+}
+
+fn read_jpeg(bytes: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut position = 2;
+    while let Some(&[0xff, marker, high, low]) = bytes.get(position..position + 4) {
+        // The image data follows the start of scan
+        if marker == 0xda {
+            break;
+        }
+        let length = u16::from_be_bytes([high, low]) as usize;
+        let segment = bytes
+            .get(position + 4..position + 2 + length)
+            .ok_or_else(|| eyre!("a JPEG segment is truncated"))?;
+        match marker {
+            0xe1 if segment.starts_with(b"Exif\0\0") => read_exif(&segment[6..], &mut metadata),
+            0xe2 if segment.starts_with(b"ICC_PROFILE\0") => metadata.icc_profile = true,
+            // Start of frame, except the markers in between for huffman and
+            // arithmetic coding tables
+            0xc0..=0xcf if ![0xc4, 0xc8, 0xcc].contains(&marker) => {
+                metadata.color_space = match segment.get(5) {
+                    Some(1) => Some(ColorSpace::Gray),
+                    Some(3) => Some(ColorSpace::Rgb),
+                    Some(4) => Some(ColorSpace::Cmyk),
+                    _ => None,
+                };
+            }
+            _ => {}
+        }
+        position += 2 + length;
+    }
+    Ok(metadata)
+}
+
+fn read_png(bytes: &[u8]) -> Result<Metadata> {
+    let mut metadata = Metadata::default();
+    let mut position = 8;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let length = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let kind = &header[4..];
+        let data = bytes
+            .get(position + 8..position + 8 + length)
+            .ok_or_else(|| eyre!("a PNG chunk is truncated"))?;
+        match kind {
+            b"IHDR" => {
+                metadata.color_space = match data.get(9) {
+                    Some(0 | 4) => Some(ColorSpace::Gray),
+                    Some(2 | 3 | 6) => Some(ColorSpace::Rgb),
+                    _ => None,
+                };
+            }
+            b"iCCP" => metadata.icc_profile = true,
+            b"eXIf" => read_exif(data, &mut metadata),
+            b"tEXt" => {
+                let mut parts = data.splitn(2, |&b| b == 0);
+                if let (Some(keyword), Some(text)) = (parts.next(), parts.next()) {
+                    let text = Some(String::from_utf8_lossy(text).trim().to_string());
+                    match keyword {
+                        b"Copyright" => metadata.copyright = metadata.copyright.take().or(text),
+                        b"Author" => metadata.artist = metadata.artist.take().or(text),
+                        _ => {}
+                    }
+                }
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        // The data is followed by a CRC
+        position += 12 + length;
+    }
+    Ok(metadata)
+}
+
+fn read_webp(bytes: &[u8]) -> Result<Metadata> {
+    // WebP is always RGB
+    let mut metadata = Metadata {
+        color_space: Some(ColorSpace::Rgb),
+        ..Metadata::default()
+    };
+    let mut position = 12;
+    while let Some(header) = bytes.get(position..position + 8) {
+        let length = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+        let data = bytes
+            .get(position + 8..position + 8 + length)
+            .ok_or_else(|| eyre!("a WebP chunk is truncated"))?;
+        match &header[..4] {
+            b"ICCP" => metadata.icc_profile = true,
+            b"EXIF" => read_exif(
+                data.strip_prefix(b"Exif\0\0").unwrap_or(data),
+                &mut metadata,
+            ),
+            _ => {}
+        }
+        // Chunks are padded to an even length
+        position += 8 + length + length % 2;
+    }
+    Ok(metadata)
+}
+
+/// Reads the tags of the first IFD of the TIFF structure in EXIF data.
+/// Malformed data is ignored, like viewers do.
+fn read_exif(tiff: &[u8], metadata: &mut Metadata) {
+    let little_endian = match tiff.get(..2) {
+        Some(b"II") => true,
+        Some(b"MM") => false,
+        _ => return,
+    };
+    let u16_at = |at: usize| {
+        let bytes = tiff.get(at..at + 2)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u16::from_le_bytes(bytes),
+            false => u16::from_be_bytes(bytes),
+        })
+    };
+    let u32_at = |at: usize| {
+        let bytes = tiff.get(at..at + 4)?.try_into().ok()?;
+        Some(match little_endian {
+            true => u32::from_le_bytes(bytes),
+            false => u32::from_be_bytes(bytes),
+        } as usize)
+    };
+    let Some(ifd) = u32_at(4) else {
+        return;
+    };
+    let Some(entries) = u16_at(ifd) else {
+        return;
+    };
+    let mut gps = None;
+    for i in 0..entries as usize {
+        let entry = ifd + 2 + i * 12;
+        let (Some(tag), Some(count)) = (u16_at(entry), u32_at(entry + 4)) else {
+            return;
+        };
+        // Values of up to 4 bytes are stored in place of their offset
+        let text = || {
+            let at = match count <= 4 {
+                true => entry + 8,
+                false => u32_at(entry + 8)?,
+            };
+            let text = String::from_utf8_lossy(tiff.get(at..at + count)?);
+            let text = text.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+            (!text.is_empty()).then(|| text.to_string())
+        };
+        match tag {
+            ORIENTATION => metadata.orientation = u16_at(entry + 8),
+            ARTIST => metadata.artist = text(),
+            COPYRIGHT => metadata.copyright = text(),
+            GPS_INFO => gps = u32_at(entry + 8),
+            _ => {}
+        }
+    }
+    if let Some(gps) = gps {
+        metadata.location = read_gps(gps, u16_at, u32_at);
+    }
+}
+
+/// Reads the location from the GPS IFD at `ifd`, given the readers of the
+/// byte order of the TIFF structure
+fn read_gps(
+    ifd: usize,
+    u16_at: impl Fn(usize) -> Option<u16>,
+    u32_at: impl Fn(usize) -> Option<usize>,
+) -> Option<Location> {
+    let rational = |at: usize| {
+        let (numerator, denominator) = (u32_at(at)?, u32_at(at + 4)?);
+        (denominator != 0).then(|| numerator as f64 / denominator as f64)
+    };
+    // Degrees, minutes and seconds
+    let degrees =
+        |at: usize| Some(rational(at)? + rational(at + 8)? / 60.0 + rational(at + 16)? / 3600.0);
+    let (mut latitude, mut longitude, mut altitude) = (None, None, None);
+    let (mut south, mut west, mut below_sea_level) = (false, false, false);
+    for i in 0..u16_at(ifd)? as usize {
+        let entry = ifd + 2 + i * 12;
+        // References are a single ASCII letter or byte stored in place
+        let reference = u16_at(entry + 8).map(|value| value.to_be_bytes());
+        match u16_at(entry)? {
+            GPS_LATITUDE_REF => south = reference.is_some_and(|r| r.contains(&b'S')),
+            GPS_LONGITUDE_REF => west = reference.is_some_and(|r| r.contains(&b'W')),
+            GPS_ALTITUDE_REF => below_sea_level = reference.is_some_and(|r| r.contains(&1)),
+            GPS_LATITUDE => latitude = u32_at(entry + 8).and_then(degrees),
+            GPS_LONGITUDE => longitude = u32_at(entry + 8).and_then(degrees),
+            GPS_ALTITUDE => altitude = u32_at(entry + 8).and_then(rational),
+            _ => {}
+        }
+    }
+    let (latitude, longitude) = (latitude?, longitude?);
+    if !(-90.0..=90.0).contains(&latitude) || !(-180.0..=180.0).contains(&longitude) {
+        return None;
+    }
+    let sign = |negative: bool| if negative { -1.0 } else { 1.0 };
+    Some(Location {
+        latitude: sign(south) * latitude,
+        longitude: sign(west) * longitude,
+        altitude: altitude.map(|altitude| sign(below_sea_level) * altitude),
+    })
+}
@@ -0,0 +1,164 @@
+//! Run metrics in the Prometheus text format, for node_exporter's textfile
+//! collector, so scheduled conversions can be monitored and alerted on.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+
+use crate::{formats, timings::Timings, JobResult, Outcome};
+
+const PREFIX: &str = "web_assets_converter";
+/// Timing stages that cover a whole job for one source extension
+const FORMAT_STAGE: &str = "format ";
+
+/// The timing stage a job for `source` is recorded under, e.g. `format jpg`
+pub fn format_stage(source: &Path) -> String {
+    let extension = formats::extension(source).unwrap_or_else(|| "none".to_string());
+    format!("{FORMAT_STAGE}{extension}")
+}
+
+/// Label values may hold anything but a backslash, quote or newline unescaped
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Formats the metrics of a finished run
+pub fn render(results: &[JobResult], timings: &Timings, duration: Duration) -> String {
+    let mut files: BTreeMap<&str, u64> = [
+        "converted",
+        "copied",
+        "unchanged",
+        "resumed",
+        "deduplicated",
+        "failed",
+    ]
+    .into_iter()
+    .map(|outcome| (outcome, 0))
+    .collect();
+    let (mut bytes_in, mut bytes_out) = (0, 0);
+    for result in results {
+        *files.entry(result.outcome.name()).or_default() += 1;
+        // Only count the files that were actually read and written in this run
+        if let Outcome::Converted | Outcome::Copied | Outcome::Deduplicated = result.outcome {
+            let size = |path: &Path| path.metadata().map_or(0, |m| m.len());
+            bytes_in += size(&result.job.source);
+            bytes_out += result
+                .job
+                .outputs()
+                .iter()
+                .map(|(_, path)| size(path))
+                .sum::<u64>();
+        }
+    }
+    let finished = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    let mut out = String::new();
+    let mut metric = |name: &str, help: &str, samples: &[(String, f64)]| {
+        writeln!(out, "# HELP {PREFIX}_{name} {help}").unwrap();
+        writeln!(out, "# TYPE {PREFIX}_{name} gauge").unwrap();
+        for (labels, value) in samples {
+            writeln!(out, "{PREFIX}_{name}{labels} {value}").unwrap();
+        }
+    };
+    let unlabelled = |value: f64| [(String::new(), value)];
+    metric(
+        "files",
+        "Source files handled by the last run, by outcome.",
+        &files
+            .iter()
+            .map(|(outcome, count)| (format!("{{outcome=\"{outcome}\"}}"), *count as f64))
+            .collect::<Vec<_>>(),
+    );
+    metric(
+        "failures",
+        "Source files that failed in the last run.",
+        &unlabelled(files["failed"] as f64),
+    );
+    metric(
+        "input_bytes",
+        "Size of the sources converted or copied by the last run.",
+        &unlabelled(bytes_in as f64),
+    );
+    metric(
+        "output_bytes",
+        "Size of the outputs written by the last run.",
+        &unlabelled(bytes_out as f64),
+    );
+    metric(
+        "duration_seconds",
+        "Wall time of the last run.",
+        &unlabelled(duration.as_secs_f64()),
+    );
+    metric(
+        "last_run_timestamp_seconds",
+        "When the last run finished, in seconds since the Unix epoch.",
+        &unlabelled(finished.as_secs_f64()),
+    );
+
+    let (formats, stages): (Vec<_>, Vec<_>) = timings
+        .stages()
+        .into_iter()
+        .partition(|(name, _)| name.starts_with(FORMAT_STAGE));
+    let samples = |stages: &[(String, crate::timings::Stage)], label: &str, seconds: bool| {
+        stages
+            .iter()
+            .map(|(name, stage)| {
+                let name = name.strip_prefix(FORMAT_STAGE).unwrap_or(name);
+                let value = match seconds {
+                    true => stage.total.as_secs_f64(),
+                    false => f64::from(stage.count),
+                };
+                (format!("{{{label}=\"{}\"}}", escape(name)), value)
+            })
+            .collect::<Vec<_>>()
+    };
+    metric(
+        "format_seconds",
+        "Time spent on jobs in the last run, by source extension. Parallel jobs can add up to more than the wall time.",
+        &samples(&formats, "format", true),
+    );
+    metric(
+        "format_jobs",
+        "Jobs run in the last run, by source extension.",
+        &samples(&formats, "format", false),
+    );
+    metric(
+        "stage_seconds",
+        "Time spent in every stage of the last run.",
+        &samples(&stages, "stage", true),
+    );
+    metric(
+        "stage_count",
+        "How many times every stage ran in the last run.",
+        &samples(&stages, "stage", false),
+    );
+    out
+}
+
+/// Writes the metrics of a finished run to `path`. The file is replaced
+/// atomically so a collector never reads half of it.
+pub fn write_textfile(
+    path: &Path,
+    results: &[JobResult],
+    timings: &Timings,
+    duration: Duration,
+) -> Result<()> {
+    if let Some(p) = path.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    let partial = path.with_extension("prom.partial");
+    std::fs::write(&partial, render(results, timings, duration))
+        .wrap_err_with(|| format!("writing metrics to {}", partial.display()))?;
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
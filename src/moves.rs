@@ -0,0 +1,209 @@
+//! Moves the outputs of converted images that were moved or renamed in the
+//! source folder along with them, instead of converting them again and
+//! leaving the old outputs behind. Images are recognized by their content
+//! hash, which is recorded in the [hashes](crate::hashes) state file once they
+//! are converted.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs::File,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use tracing::{debug, info};
+
+use crate::{
+    hashes::{Converted, Hashes},
+    manifest::url_path,
+    pipeline::{encoding, JobResult, Outcome},
+    remote::sha256_file,
+    Job, JobKind,
+};
+
+/// Moves the outputs of the recorded images that no longer exist in `roots`
+/// to the jobs of new images with the same contents, settings and variants,
+/// whose outputs don't exist yet. Returns the number of images moved.
+pub(crate) fn relocate(
+    jobs: &[&Job],
+    destination: &Path,
+    roots: &[&Path],
+    state: &mut Hashes,
+) -> Result<usize> {
+    let planned: HashSet<String> = jobs.iter().map(|job| url_path(&job.relative)).collect();
+    // The recorded images that are gone but whose outputs are all there, by
+    // size, so only new images of the same size are hashed
+    let mut gone: HashMap<u64, Vec<String>> = HashMap::new();
+    for (key, converted) in &state.converted {
+        let exists = planned.contains(key) || roots.iter().any(|root| root.join(key).exists());
+        let outputs_exist = converted
+            .outputs
+            .values()
+            .all(|output| destination.join(output).is_file());
+        if !exists && outputs_exist {
+            gone.entry(converted.size).or_default().push(key.clone());
+        }
+    }
+    if gone.is_empty() {
+        return Ok(0);
+    }
+    let mut moved = 0;
+    for &job in jobs {
+        let key = url_path(&job.relative);
+        let outputs = job.outputs();
+        let is_new = matches!(job.kind, JobKind::ConvertImage(_))
+            && !state.converted.contains_key(&key)
+            && outputs.iter().all(|(_, path)| !path.exists());
+        if !is_new {
+            continue;
+        }
+        let Some((size, _)) = stamp(&job.source) else {
+            continue;
+        };
+        let Some(candidates) = gone.get_mut(&size) else {
+            continue;
+        };
+        let hash = sha256_file(&job.source)?;
+        let encoding = format!("{:?}", encoding(&job.kind));
+        let found = candidates.iter().position(|old| {
+            let converted = &state.converted[old];
+            converted.hash == hash
+                && converted.encoding == encoding
+                && converted.outputs.len() == outputs.len()
+                && outputs
+                    .iter()
+                    .all(|(variant, _)| converted.outputs.contains_key(*variant))
+        });
+        let Some(found) = found else {
+            continue;
+        };
+        let old_key = candidates.swap_remove(found);
+        let old = state.converted.remove(&old_key).expect("recorded");
+        let mut new_outputs = BTreeMap::new();
+        for (variant, path) in &outputs {
+            let old_path = destination.join(&old.outputs[*variant]);
+            move_output(&old_path, path, destination)?;
+            let relative = path.strip_prefix(destination).unwrap_or(path);
+            new_outputs.insert(variant.to_string(), url_path(relative));
+        }
+        debug!("moved the outputs of {old_key} to {key}");
+        if let Some(hash) = state.sources.remove(&old_key) {
+            state.sources.insert(key.clone(), hash);
+        }
+        let (size, modified) = stamp(&job.source).unwrap_or_default();
+        state.converted.insert(
+            key,
+            Converted {
+                size,
+                modified,
+                outputs: new_outputs,
+                ..old
+            },
+        );
+        moved += 1;
+    }
+    if moved > 0 {
+        info!("Moved the outputs of {moved} moved or renamed images");
+    }
+    Ok(moved)
+}
+
+/// Records the images converted by `results` whose record is missing or out
+/// of date, and drops the records of images that are gone along with their
+/// outputs. Returns whether anything changed.
+pub(crate) fn record<'a>(
+    results: impl IntoIterator<Item = &'a JobResult>,
+    destination: &Path,
+    roots: &[&Path],
+    state: &mut Hashes,
+) -> Result<bool> {
+    let mut changed = false;
+    for result in results {
+        let job = &result.job;
+        let is_converted = matches!(result.outcome, Outcome::Converted | Outcome::Unchanged);
+        if !is_converted || !matches!(job.kind, JobKind::ConvertImage(_)) {
+            continue;
+        }
+        let Some((size, modified)) = stamp(&job.source) else {
+            continue;
+        };
+        let key = url_path(&job.relative);
+        let encoding = format!("{:?}", encoding(&job.kind));
+        let outputs: BTreeMap<String, String> = job
+            .outputs()
+            .into_iter()
+            .filter_map(|(variant, path)| {
+                let relative = path.strip_prefix(destination).ok()?;
+                Some((variant.to_string(), url_path(relative)))
+            })
+            .collect();
+        let is_current = state.converted.get(&key).is_some_and(|converted| {
+            converted.size == size
+                && converted.modified == modified
+                && converted.encoding == encoding
+                && converted.outputs == outputs
+        });
+        if is_current {
+            continue;
+        }
+        // Recorded by the next run
+        let Ok(hash) = sha256_file(&job.source) else {
+            continue;
+        };
+        state.converted.insert(
+            key,
+            Converted {
+                hash,
+                size,
+                modified,
+                encoding,
+                outputs,
+            },
+        );
+        changed = true;
+    }
+    let before = state.converted.len();
+    state.converted.retain(|key, converted| {
+        roots.iter().any(|root| root.join(key).exists())
+            || converted
+                .outputs
+                .values()
+                .any(|output| destination.join(output).is_file())
+    });
+    Ok(changed || state.converted.len() != before)
+}
+
+/// Renames `from` to `to`, marks it as written now so it is newer than its
+/// source, and removes the folders `from` leaves empty
+fn move_output(from: &Path, to: &Path, destination: &Path) -> Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)
+            .wrap_err_with(|| format!("creating {}", parent.display()))?;
+    }
+    std::fs::rename(from, to)
+        .wrap_err_with(|| format!("moving {} to {}", from.display(), to.display()))?;
+    File::options()
+        .write(true)
+        .open(to)
+        .and_then(|file| file.set_modified(SystemTime::now()))
+        .wrap_err_with(|| format!("touching {}", to.display()))?;
+    let folders = from
+        .ancestors()
+        .skip(1)
+        .take_while(|folder| folder.starts_with(destination) && *folder != destination);
+    for folder in folders {
+        // Fails if the folder isn't empty
+        if std::fs::remove_dir(folder).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// The size and modification time in nanoseconds of `path`
+fn stamp(path: &Path) -> Option<(u64, u64)> {
+    let metadata = path.metadata().ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some((metadata.len(), modified.as_nanos() as u64))
+}
@@ -0,0 +1,217 @@
+//! Tells a webhook or a command that a run ended, so long runs on a server
+//! don't have to be watched. Both get the same JSON summary of the run:
+//!
+//! ```json
+//! {
+//!   "status": "failed",
+//!   "destination": "dist",
+//!   "duration_seconds": 5123.4,
+//!   "total": 1200,
+//!   "outcomes": { "converted": 1180, "unchanged": 18, "failed": 2 },
+//!   "failed": [{ "source": "raw/a.jpg", "error": "..." }],
+//!   "error": "2 of 1200 files failed"
+//! }
+//! ```
+//!
+//! The status is `succeeded`, `failed` or `interrupted`. Notifications that
+//! can't be delivered are only logged, so they don't change the exit code.
+//! Only `convert` runs notify, including the first one of `watch`, but not
+//! the rebuilds of `watch` or the conversions of the daemon.
+
+use std::{
+    collections::BTreeMap,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    time::Duration,
+};
+
+use color_eyre::eyre::{eyre, Report, Result, WrapErr};
+use serde_json::json;
+use tracing::{info, warn};
+use web_assets_converter::{JobResult, Outcome};
+
+/// How a run ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Succeeded,
+    Failed,
+    Interrupted,
+}
+
+impl Status {
+    fn name(self) -> &'static str {
+        match self {
+            Status::Succeeded => "succeeded",
+            Status::Failed => "failed",
+            Status::Interrupted => "interrupted",
+        }
+    }
+}
+
+/// The JSON sent when a run ends. `results` is empty if the run failed
+/// before any file was processed.
+pub fn payload(
+    status: Status,
+    destination: &Path,
+    results: &[JobResult],
+    error: Option<&Report>,
+    duration: Duration,
+) -> serde_json::Value {
+    let mut outcomes: BTreeMap<&str, usize> = BTreeMap::new();
+    for result in results {
+        *outcomes.entry(result.outcome.name()).or_default() += 1;
+    }
+    let failed: Vec<_> = results
+        .iter()
+        .filter_map(|result| match &result.outcome {
+            Outcome::Failed(e) | Outcome::Corrupt(e) => Some(json!({
+                "source": result.job.relative,
+                "error": format!("{e:#}"),
+            })),
+            _ => None,
+        })
+        .collect();
+    json!({
+        "status": status.name(),
+        "destination": destination,
+        "duration_seconds": duration.as_secs_f64(),
+        "total": results.len(),
+        "outcomes": outcomes,
+        "failed": failed,
+        "error": error.map(|e| format!("{e:#}")),
+    })
+}
+
+/// Posts `payload` to `webhook` and runs `command` with it on stdin, warning
+/// about the ones that fail
+pub fn send(webhook: Option<&str>, command: Option<&str>, payload: &serde_json::Value) {
+    let body = payload.to_string();
+    if let Some(url) = webhook {
+        match post(url, &body) {
+            Ok(()) => info!("Notified {url}"),
+            Err(e) => warn!("Not notifying {url}: {e:#}"),
+        }
+    }
+    if let Some(command) = command {
+        let status = payload["status"].as_str().unwrap_or_default();
+        match run(command, &body, status) {
+            Ok(()) => info!("Ran the notify command"),
+            Err(e) => warn!("Not notifying with {command}: {e:#}"),
+        }
+    }
+}
+
+fn post(url: &str, body: &str) -> Result<()> {
+    ureq::post(url)
+        .timeout(Duration::from_secs(30))
+        .set("Content-Type", "application/json")
+        .send_string(body)?;
+    Ok(())
+}
+
+/// Runs `command` in the shell with the payload on stdin and the status in
+/// `WAC_STATUS`
+fn run(command: &str, body: &str, status: &str) -> Result<()> {
+    let mut shell = match cfg!(windows) {
+        true => {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C");
+            shell
+        }
+        false => {
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            shell
+        }
+    };
+    let mut child = shell
+        .arg(command)
+        .env("WAC_STATUS", status)
+        .stdin(Stdio::piped())
+        .spawn()
+        .wrap_err("starting it")?;
+    // A command that doesn't read its stdin closes it early
+    let _ = child
+        .stdin
+        .take()
+        .expect("piped")
+        .write_all(body.as_bytes());
+    let exit = child.wait()?;
+    match exit.success() {
+        true => Ok(()),
+        false => Err(eyre!("it exited with {exit}")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+    use web_assets_converter::{Job, JobKind};
+
+    fn result(relative: &str, outcome: Outcome) -> JobResult {
+        JobResult {
+            job: Job {
+                source: Path::new("assets").join(relative),
+                relative: PathBuf::from(relative),
+                kind: JobKind::Copy {
+                    destination: Path::new("dist").join(relative),
+                },
+            },
+            outcome,
+            duration: Duration::from_millis(10),
+        }
+    }
+
+    #[test]
+    fn payloads_count_outcomes_and_list_failures() {
+        let results = [
+            result("a.txt", Outcome::Copied),
+            result("b.txt", Outcome::Copied),
+            result("c.txt", Outcome::Unchanged),
+            result("d.jpg", Outcome::Failed(eyre!("convert failed"))),
+            result("e.jpg", Outcome::Corrupt(eyre!("truncated"))),
+        ];
+        let error = eyre!("2 of 5 files failed");
+        let payload = payload(
+            Status::Failed,
+            Path::new("dist"),
+            &results,
+            Some(&error),
+            Duration::from_secs(2),
+        );
+        assert_eq!(payload["status"], "failed");
+        assert_eq!(payload["destination"], "dist");
+        assert_eq!(payload["duration_seconds"], 2.0);
+        assert_eq!(payload["total"], 5);
+        assert_eq!(
+            payload["outcomes"],
+            json!({ "copied": 2, "unchanged": 1, "failed": 1, "corrupt": 1 })
+        );
+        assert_eq!(
+            payload["failed"],
+            json!([
+                { "source": "d.jpg", "error": "convert failed" },
+                { "source": "e.jpg", "error": "truncated" },
+            ])
+        );
+        assert_eq!(payload["error"], "2 of 5 files failed");
+    }
+
+    #[test]
+    fn successful_payloads_have_no_error() {
+        let results = [result("a.txt", Outcome::Copied)];
+        let payload = payload(
+            Status::Succeeded,
+            Path::new("dist"),
+            &results,
+            None,
+            Duration::ZERO,
+        );
+        assert_eq!(payload["status"], "succeeded");
+        assert_eq!(payload["outcomes"], json!({ "copied": 1 }));
+        assert_eq!(payload["failed"], json!([]));
+        assert!(payload["error"].is_null());
+    }
+}
@@ -0,0 +1,199 @@
+//! What the convert and watch commands print about processed files, either
+//! for people with a progress bar and colored status lines or for scripts as
+//! JSON lines.
+
+use std::{
+    collections::BTreeMap,
+    io::IsTerminal,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use anstyle::{AnsiColor, Style};
+use color_eyre::eyre::Result;
+use indicatif::{ProgressBar, ProgressStyle};
+use tracing::{error, warn, Level};
+use web_assets_converter::{Job, JobKind, JobResult, Outcome, Pipeline, ToolNotFound};
+
+use crate::{exit::PartialFailure, logging};
+
+static COLOR: AtomicBool = AtomicBool::new(false);
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    Human,
+    Json,
+}
+
+/// Colors are used unless `no_color` or NO_COLOR is set
+pub fn init_color(no_color: bool) {
+    let no_color_env = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+    COLOR.store(!no_color && !no_color_env, Ordering::Relaxed);
+}
+
+/// Whether to color what is written to `stream`. Only terminals are colored
+pub fn color_enabled(stream: impl IsTerminal) -> bool {
+    COLOR.load(Ordering::Relaxed) && stream.is_terminal()
+}
+
+fn paint(text: &str, style: Style) -> String {
+    match color_enabled(std::io::stdout()) {
+        true => format!("{style}{text}{style:#}"),
+        false => text.to_string(),
+    }
+}
+
+fn outcome_style(outcome: &Outcome) -> Style {
+    match outcome {
+        Outcome::Converted | Outcome::Copied | Outcome::Deduplicated => {
+            AnsiColor::Green.on_default()
+        }
+        Outcome::Unchanged | Outcome::Resumed => AnsiColor::Yellow.on_default(),
+        Outcome::Corrupt(_) => AnsiColor::Magenta.on_default().bold(),
+        Outcome::Interrupted => AnsiColor::Yellow.on_default().bold(),
+        Outcome::Failed(_) => AnsiColor::Red.on_default().bold(),
+    }
+}
+
+/// Runs the jobs with a progress bar, or prints a line for every processed
+/// file with `--verbose` or if there is no terminal to draw the bar on
+pub fn run_jobs(
+    pipeline: &Pipeline,
+    jobs: Vec<Job>,
+    output_format: OutputFormat,
+) -> Result<Vec<JobResult>> {
+    let total = jobs.len();
+    let is_json = output_format == OutputFormat::Json;
+    let is_terminal = std::io::stderr().is_terminal();
+    let is_verbose = tracing::enabled!(Level::DEBUG);
+    let is_quiet = !tracing::enabled!(Level::INFO);
+    let show_lines = !is_json && !is_quiet && (is_verbose || !is_terminal);
+    let bar = match is_json || show_lines || is_quiet {
+        true => ProgressBar::hidden(),
+        false => ProgressBar::new(total as u64).with_style(
+            ProgressStyle::with_template(
+                "{bar:30} {pos}/{len} files, {per_sec}, ETA {eta} {wide_msg}",
+            )
+            .expect("the template is valid"),
+        ),
+    };
+    logging::set_progress_bar(Some(bar.clone()));
+    let mut finished = 0;
+    let results = pipeline.run_jobs(jobs, |result| {
+        let job = &result.job;
+        finished += 1;
+        bar.inc(1);
+        bar.set_message(job.relative.display().to_string());
+        if is_json {
+            println!("{}", json_event(result));
+        }
+        let is_skipped = matches!(result.outcome, Outcome::Unchanged | Outcome::Resumed);
+        if show_lines && (is_verbose || !is_skipped) {
+            let status = format!("{:>12}", result.outcome.name());
+            let mut line = format!(
+                "[{finished}/{total}] {} {}",
+                paint(&status, outcome_style(&result.outcome)),
+                job.relative.display()
+            );
+            if let JobKind::Duplicate { of, .. } = &job.kind {
+                line += &format!(" (copy of {})", of.display());
+            }
+            println!("{line}");
+        }
+        match &result.outcome {
+            Outcome::Failed(e) => error!("{}: {e:#}", job.relative.display()),
+            Outcome::Corrupt(e) => warn!("skipped {}: {e:#}", job.relative.display()),
+            _ => {}
+        }
+    });
+    bar.finish_and_clear();
+    logging::set_progress_bar(None);
+    let results = results?;
+    if !is_json && !is_quiet && !results.is_empty() {
+        println!("{}", summary(&results));
+    }
+    Ok(results)
+}
+
+/// Lists the corrupt sources that were skipped and the files that failed
+/// with their errors, and returns an error if any failed, so that a partially
+/// failed run exits with a failure. Files that failed because ImageMagick
+/// isn't installed fail with [`ToolNotFound`] instead.
+pub fn check_failures(results: &[JobResult]) -> Result<()> {
+    let mut corrupt = Vec::new();
+    let mut failed = Vec::new();
+    for result in results {
+        match &result.outcome {
+            Outcome::Corrupt(e) => corrupt.push((&result.job.relative, e)),
+            Outcome::Failed(e) => failed.push((&result.job.relative, e)),
+            _ => {}
+        }
+    }
+    if !corrupt.is_empty() {
+        eprintln!("Needs attention, these sources are corrupt and were skipped:");
+        for (relative, e) in &corrupt {
+            eprintln!("  {}: {e:#}", relative.display());
+        }
+    }
+    if failed.is_empty() {
+        return Ok(());
+    }
+    eprintln!("Failed files:");
+    for (relative, e) in &failed {
+        eprintln!("  {}: {e:#}", relative.display());
+    }
+    if let Some(tool) = failed
+        .iter()
+        .find_map(|(_, e)| e.downcast_ref::<ToolNotFound>())
+    {
+        return Err(ToolNotFound(tool.0).into());
+    }
+    Err(PartialFailure {
+        failed: failed.len(),
+        total: results.len(),
+    }
+    .into())
+}
+
+/// How many files had every outcome, e.g. "5 converted, 2 unchanged"
+pub fn summary(results: &[JobResult]) -> String {
+    let mut counts: BTreeMap<&str, (usize, Style)> = BTreeMap::new();
+    for result in results {
+        let style = outcome_style(&result.outcome);
+        counts.entry(result.outcome.name()).or_insert((0, style)).0 += 1;
+    }
+    counts
+        .into_iter()
+        .map(|(name, (count, style))| paint(&format!("{count} {name}"), style))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The result of a job as a single line of JSON for `--output-format json`
+fn json_event(result: &JobResult) -> serde_json::Value {
+    let size = |path: &Path| path.metadata().ok().map(|m| m.len());
+    let outputs: Vec<_> = result
+        .job
+        .outputs()
+        .into_iter()
+        .map(|(variant, path)| {
+            serde_json::json!({
+                "variant": variant,
+                "path": path,
+                "size": size(path),
+            })
+        })
+        .collect();
+    let error = match &result.outcome {
+        Outcome::Failed(e) | Outcome::Corrupt(e) => Some(format!("{e:#}")),
+        _ => None,
+    };
+    serde_json::json!({
+        "action": result.outcome.name(),
+        "source": result.job.relative,
+        "source_size": size(&result.job.source),
+        "outputs": outputs,
+        "duration_seconds": result.duration.as_secs_f64(),
+        "error": error,
+    })
+}
@@ -0,0 +1,66 @@
+//! Path handling that behaves the same on every platform.
+
+use std::path::{Component, Path, PathBuf};
+
+/// Like [`std::fs::canonicalize`], but without the `\\?\` prefix Windows adds,
+/// so the result can be compared with and shown like any other path. Paths
+/// that only work with the prefix, e.g. because they are too long, keep it.
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    let canonical = std::fs::canonicalize(path)?;
+    Ok(simplify(canonical))
+}
+
+#[cfg(windows)]
+fn simplify(path: PathBuf) -> PathBuf {
+    use std::path::Prefix;
+    const MAX_PATH: usize = 260;
+    let Some(Component::Prefix(prefix)) = path.components().next() else {
+        return path;
+    };
+    let rest: PathBuf = path.components().skip(1).collect();
+    let simple = match prefix.kind() {
+        Prefix::VerbatimDisk(disk) => PathBuf::from(format!("{}:\\", disk as char))
+            .join(rest.strip_prefix("\\").unwrap_or(&rest)),
+        Prefix::VerbatimUNC(server, share) => {
+            let mut simple = std::ffi::OsString::from(r"\\");
+            simple.push(server);
+            simple.push(r"\");
+            simple.push(share);
+            PathBuf::from(simple).join(rest.strip_prefix("\\").unwrap_or(&rest))
+        }
+        _ => return path,
+    };
+    // Names like CON or trailing dots mean something else without the prefix
+    let is_plain = simple.components().all(|c| match c {
+        Component::Normal(name) => {
+            let name = name.to_string_lossy();
+            !name.ends_with(['.', ' ']) && !is_reserved(&name)
+        }
+        _ => true,
+    });
+    match is_plain && simple.as_os_str().len() < MAX_PATH {
+        true => simple,
+        false => path,
+    }
+}
+
+/// Device names Windows reserves in every folder, with or without an extension
+#[cfg(windows)]
+fn is_reserved(name: &str) -> bool {
+    let stem = name.split('.').next().unwrap_or(name).to_ascii_uppercase();
+    matches!(stem.as_str(), "CON" | "PRN" | "AUX" | "NUL")
+        || (stem.len() == 4
+            && (stem.starts_with("COM") || stem.starts_with("LPT"))
+            && stem.as_bytes()[3].is_ascii_digit())
+}
+
+#[cfg(not(windows))]
+fn simplify(path: PathBuf) -> PathBuf {
+    path
+}
+
+/// Whether joining `path` onto a folder stays inside it: it is relative, has
+/// no `..` and on Windows no drive or root like `C:x` or `\x`
+pub fn is_plain_relative(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
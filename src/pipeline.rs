@@ -0,0 +1,1972 @@
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, HashMap, HashSet},
+    ffi::OsString,
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use tracing::{debug, info, warn};
+use walkdir::WalkDir;
+
+use crate::{
+    backup::Backup,
+    config::{
+        self, Action, Config, ConfigError, Enhancement, Format, Gravity, Preset, Sidecar, Sizes,
+        Strip, TargetSizes,
+    },
+    diff::STATE_FILES,
+    disk_space, graphics,
+    hashes::Hashes,
+    imagemagick::{self, ConvertOptions},
+    journal::Journal,
+    lock::{self, DestinationLock},
+    manifest::{self, url_path, SkipReason, SkippedFile},
+    metrics, moves, paths,
+    plan::{self, Curated, Job, JobKind, Link},
+    remote::sha256_file,
+    semaphore::Semaphore,
+    spec::SpecEntry,
+    timings::Timings,
+    verify, ImageVariants, Profile, MIB,
+};
+
+/// Files at least this large report their progress while being copied
+const PROGRESS_THRESHOLD: u64 = 64 * MIB;
+
+/// Converts or copies every file in an asset folder into a destination folder
+#[derive(Debug, Clone)]
+pub struct Pipeline {
+    source: PathBuf,
+    /// Other folders converted along with the source folder, with the folder
+    /// inside the destination their outputs are placed in
+    extra_sources: Vec<(PathBuf, PathBuf)>,
+    destination: PathBuf,
+    max_file_size: u64,
+    clean: bool,
+    /// Outputs whose jobs run like with `clean`
+    forced: HashSet<PathBuf>,
+    profile: Profile,
+    jobs: usize,
+    processes: Arc<Semaphore>,
+    hash_sources: bool,
+    conversion_cache: Option<PathBuf>,
+    resume: bool,
+    wait_for_lock: bool,
+    /// Folders every output is copied into as well
+    mirrors: Vec<PathBuf>,
+    cancelled: Arc<AtomicBool>,
+    paused: Arc<AtomicBool>,
+    running: Arc<Mutex<Vec<(PathBuf, Instant)>>>,
+    /// Files left out for being larger than the maximum file size, with
+    /// their size and that maximum
+    too_large: Arc<Mutex<BTreeMap<PathBuf, (u64, u64)>>>,
+    /// Files left out for their blocked or not allowed extension, with their
+    /// size and which of the two it was
+    blocked: Arc<Mutex<BTreeMap<PathBuf, (u64, SkipReason)>>>,
+    link_mode: LinkMode,
+    follow_links: FollowLinks,
+    max_depth: Option<usize>,
+    include_hidden: bool,
+    preserve_attributes: bool,
+    on_collision: OnCollision,
+    on_low_space: OnLowSpace,
+    backup: Option<Arc<Backup>>,
+    verify: Option<u8>,
+    skip_optimized: Option<f64>,
+    timings: Arc<Timings>,
+    big_image_pixels: u64,
+    config: Arc<Config>,
+    quality: Option<u8>,
+    format: Option<Format>,
+    #[cfg(feature = "async-copy")]
+    async_copy: bool,
+}
+
+/// How non-image files are placed in the destination folder
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Copy the contents
+    #[default]
+    Copy,
+    /// Hard link to the source, falling back to copying across filesystems
+    Hardlink,
+    /// Share the data blocks of the source on filesystems that support it,
+    /// like Btrfs and XFS, falling back to copying
+    Reflink,
+}
+
+/// Which symbolic links in the source folders are followed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum FollowLinks {
+    /// Only links to files and folders inside the source folder
+    #[default]
+    Inside,
+    /// Every link, wherever it points
+    All,
+}
+
+/// What happens when several sources would write the same output
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OnCollision {
+    /// Fail before converting anything, listing every collision
+    #[default]
+    Error,
+    /// Add -2, -3 and so on to the names of the outputs of all but one source
+    Suffix,
+}
+
+/// What happens when the outputs may not fit in the free space of the
+/// destination, see [`disk_space::required`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum OnLowSpace {
+    /// Fail before writing anything
+    #[default]
+    Error,
+    /// Warn and run anyway
+    Warn,
+}
+
+#[derive(Debug, Clone)]
+pub struct PipelineBuilder {
+    pipeline: Pipeline,
+}
+
+impl PipelineBuilder {
+    /// The maximum size in bytes of non-image files that are copied
+    pub fn max_file_size(mut self, bytes: u64) -> Self {
+        self.pipeline.max_file_size = bytes;
+        self
+    }
+    /// If false, outputs newer than their source will not be reencoded or
+    /// copied again
+    pub fn clean(mut self, clean: bool) -> Self {
+        self.pipeline.clean = clean;
+        self
+    }
+    /// Reencodes or copies again the jobs writing any of these outputs, as
+    /// with [`PipelineBuilder::clean`], leaving the other jobs alone
+    pub fn force(mut self, outputs: impl IntoIterator<Item = PathBuf>) -> Self {
+        self.pipeline.forced.extend(outputs);
+        self
+    }
+    /// Trades quality for speed when converting images
+    pub fn profile(mut self, profile: Profile) -> Self {
+        self.pipeline.profile = profile;
+        self
+    }
+    /// How many files are processed at the same time. Defaults to the number
+    /// of CPU cores.
+    pub fn jobs(mut self, jobs: usize) -> Self {
+        self.pipeline.jobs = jobs.max(1);
+        self
+    }
+    /// How many ImageMagick processes may run at the same time, independent
+    /// of [`PipelineBuilder::jobs`]. Defaults to the number of CPU cores.
+    pub fn max_processes(mut self, processes: usize) -> Self {
+        self.pipeline.processes = Arc::new(Semaphore::new(processes));
+        self
+    }
+    /// Skip sources whose content hash matches the one stored after their last
+    /// successful conversion instead of comparing modification times
+    pub fn hash_sources(mut self, hash_sources: bool) -> Self {
+        self.pipeline.hash_sources = hash_sources;
+        self
+    }
+    /// Reuse converted images from this folder when the same source was
+    /// converted with the same settings before, even by another project
+    pub fn conversion_cache(mut self, cache: impl Into<PathBuf>) -> Self {
+        self.pipeline.conversion_cache = Some(cache.into());
+        self
+    }
+    /// Skip the jobs an earlier, interrupted run finished according to the
+    /// journal it left in the destination folder
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.pipeline.resume = resume;
+        self
+    }
+    /// Wait for another run writing to the destination folder to finish,
+    /// instead of failing
+    pub fn wait_for_lock(mut self, wait: bool) -> Self {
+        self.pipeline.wait_for_lock = wait;
+        self
+    }
+    /// Also copy every output into `destination`, at the same path as in the
+    /// destination folder. Outputs are copied as their jobs finish, if they
+    /// are missing or older there. Can be called several times.
+    pub fn mirror(mut self, destination: impl Into<PathBuf>) -> Self {
+        self.pipeline.mirrors.push(destination.into());
+        self
+    }
+    /// Setting the flag stops the run: no new jobs are started and copies in
+    /// progress are abandoned, while the journal is kept for `resume`. The
+    /// run returns the results of the jobs that finished.
+    pub fn cancel_flag(mut self, cancelled: Arc<AtomicBool>) -> Self {
+        self.pipeline.cancelled = cancelled;
+        self
+    }
+    /// While the flag is set no new jobs are started, but the running ones
+    /// finish
+    pub fn pause_flag(mut self, paused: Arc<AtomicBool>) -> Self {
+        self.pipeline.paused = paused;
+        self
+    }
+    /// Whether non-image files are copied or linked to their source
+    pub fn link_mode(mut self, link_mode: LinkMode) -> Self {
+        self.pipeline.link_mode = link_mode;
+        self
+    }
+
+    /// Which symbolic links are followed when walking the source folders,
+    /// [`FollowLinks::Inside`] by default. The others are skipped with a
+    /// warning.
+    pub fn follow_links(mut self, follow_links: FollowLinks) -> Self {
+        self.pipeline.follow_links = follow_links;
+        self
+    }
+
+    /// Only walks this many folders deep into the source folders, 0 for only
+    /// the files at their top
+    pub fn max_depth(mut self, depth: usize) -> Self {
+        self.pipeline.max_depth = Some(depth);
+        self
+    }
+    /// Copy JPEG sources that already fit a variant and use at most
+    /// `max_bytes_per_pixel` instead of reencoding them. Thumbnails are
+    /// always generated.
+    pub fn skip_optimized(mut self, max_bytes_per_pixel: f64) -> Self {
+        self.pipeline.skip_optimized = Some(max_bytes_per_pixel);
+        self
+    }
+    /// Sources with more pixels than this are converted with bounded memory,
+    /// keeping the pixels on disk. Defaults to 50 megapixels.
+    pub fn big_image_pixels(mut self, pixels: u64) -> Self {
+        self.pipeline.big_image_pixels = pixels;
+        self
+    }
+    /// Sizes, formats, quality, excludes and per-extension and category rules, usually
+    /// read from the [`config::FILE_NAME`] in the source folder. The
+    /// [`config::DIRECTORY_FILE_NAME`] files in the source folder are applied
+    /// on top of it.
+    pub fn config(mut self, config: Config) -> Self {
+        self.pipeline.config = Arc::new(config);
+        self
+    }
+    /// Converts every image with this quality, ignoring the config
+    pub fn quality(mut self, quality: u8) -> Self {
+        self.pipeline.quality = Some(quality);
+        self
+    }
+    /// Converts every image to this format, ignoring the config
+    pub fn format(mut self, format: Format) -> Self {
+        self.pipeline.format = Some(format);
+        self
+    }
+    /// Copy pass-through files concurrently with tokio instead of on the job
+    /// threads. Not used together with `hash_sources` or a `link_mode`.
+    #[cfg(feature = "async-copy")]
+    pub fn async_copy(mut self, async_copy: bool) -> Self {
+        self.pipeline.async_copy = async_copy;
+        self
+    }
+    /// Also converts the files in `root`, as if it was a folder called
+    /// `prefix` in the source folder. An empty `prefix` merges it into the
+    /// source folder.
+    pub fn extra_source(mut self, root: impl Into<PathBuf>, prefix: impl Into<PathBuf>) -> Self {
+        self.pipeline
+            .extra_sources
+            .push((root.into(), prefix.into()));
+        self
+    }
+
+    /// Also processes hidden files and folders like `.git` and junk like
+    /// `Thumbs.db`, see [`plan::is_hidden`]
+    pub fn include_hidden(mut self, include_hidden: bool) -> Self {
+        self.pipeline.include_hidden = include_hidden;
+        self
+    }
+
+    /// Gives every output the modification time of its source, and copied
+    /// files the permissions of their source, so that tools comparing
+    /// timestamps like rsync see unchanged files as unchanged
+    pub fn preserve_attributes(mut self, preserve: bool) -> Self {
+        self.pipeline.preserve_attributes = preserve;
+        self
+    }
+
+    /// Moves outputs into a new folder in `root` before they are replaced,
+    /// see [`Backup`]. Copies are only backed up if their source changed,
+    /// since otherwise they are replaced with the same file.
+    pub fn backup(mut self, root: impl Into<PathBuf>) -> Self {
+        let backup = Backup::new(root, &self.pipeline.destination);
+        self.pipeline.backup = Some(Arc::new(backup));
+        self
+    }
+
+    /// Checks every converted image and fully decodes `full_decode_percent`
+    /// percent of them, see [`verify::verify_outputs`]. The outputs of
+    /// sources that fail are removed so that they aren't shipped.
+    pub fn verify(mut self, full_decode_percent: u8) -> Self {
+        self.pipeline.verify = Some(full_decode_percent.min(100));
+        self
+    }
+
+    pub fn on_collision(mut self, on_collision: OnCollision) -> Self {
+        self.pipeline.on_collision = on_collision;
+        self
+    }
+
+    pub fn on_low_space(mut self, on_low_space: OnLowSpace) -> Self {
+        self.pipeline.on_low_space = on_low_space;
+        self
+    }
+
+    pub fn build(self) -> Pipeline {
+        self.pipeline
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    Converted,
+    Copied,
+    /// The source has the same content hash as when it was last processed
+    Unchanged,
+    /// Already finished by an earlier run that was interrupted
+    Resumed,
+    /// The outputs of an identical source were copied
+    Deduplicated,
+    /// The source image is truncated or can't be decoded, so it was skipped
+    /// instead of producing broken outputs
+    Corrupt(color_eyre::eyre::Report),
+    Failed(color_eyre::eyre::Report),
+    /// The run was cancelled while the job was running, and the outputs it
+    /// was writing were removed
+    Interrupted,
+}
+
+impl Outcome {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Outcome::Converted => "converted",
+            Outcome::Copied => "copied",
+            Outcome::Unchanged => "unchanged",
+            Outcome::Resumed => "resumed",
+            Outcome::Deduplicated => "deduplicated",
+            Outcome::Corrupt(_) => "corrupt",
+            Outcome::Failed(_) => "failed",
+            Outcome::Interrupted => "interrupted",
+        }
+    }
+
+    /// Whether the outputs weren't written, so the job has to run again
+    pub fn is_unfinished(&self) -> bool {
+        matches!(
+            self,
+            Outcome::Failed(_) | Outcome::Corrupt(_) | Outcome::Interrupted
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct JobResult {
+    pub job: Job,
+    pub outcome: Outcome,
+    /// How long the job took, including waiting for a process slot
+    pub duration: Duration,
+}
+
+/// The results of every job in a run, in processing order
+#[derive(Debug, Default)]
+pub struct Report {
+    pub results: Vec<JobResult>,
+}
+
+impl Pipeline {
+    pub fn builder(source: impl Into<PathBuf>, destination: impl Into<PathBuf>) -> PipelineBuilder {
+        let cores = std::thread::available_parallelism().map_or(1, |n| n.get());
+        PipelineBuilder {
+            pipeline: Pipeline {
+                source: source.into(),
+                extra_sources: Vec::new(),
+                destination: destination.into(),
+                max_file_size: 20 * MIB,
+                clean: false,
+                forced: HashSet::new(),
+                profile: Profile::default(),
+                jobs: cores,
+                processes: Arc::new(Semaphore::new(cores)),
+                hash_sources: false,
+                conversion_cache: None,
+                resume: false,
+                wait_for_lock: false,
+                mirrors: Vec::new(),
+                cancelled: Arc::default(),
+                paused: Arc::default(),
+                running: Arc::default(),
+                too_large: Arc::default(),
+                blocked: Arc::default(),
+                link_mode: LinkMode::default(),
+                follow_links: FollowLinks::default(),
+                max_depth: None,
+                include_hidden: false,
+                preserve_attributes: false,
+                on_collision: OnCollision::default(),
+                on_low_space: OnLowSpace::default(),
+                backup: None,
+                verify: None,
+                skip_optimized: None,
+                timings: Arc::default(),
+                big_image_pixels: 50_000_000,
+                config: Arc::default(),
+                quality: None,
+                format: None,
+                #[cfg(feature = "async-copy")]
+                async_copy: false,
+            },
+        }
+    }
+
+    pub fn source(&self) -> &Path {
+        &self.source
+    }
+
+    pub fn destination(&self) -> &Path {
+        &self.destination
+    }
+
+    /// The source folder followed by the extra sources, each with the folder
+    /// inside the destination its outputs are placed in
+    pub fn sources(&self) -> Vec<(&Path, &Path)> {
+        std::iter::once((self.source.as_path(), Path::new("")))
+            .chain(
+                self.extra_sources
+                    .iter()
+                    .map(|(root, prefix)| (root.as_path(), prefix.as_path())),
+            )
+            .collect()
+    }
+
+    /// The time spent in every stage of the runs so far
+    pub fn timings(&self) -> &Timings {
+        &self.timings
+    }
+
+    /// The sources of the jobs that are running right now, relative to the
+    /// source folder, and how long they have been running. Clones of the
+    /// pipeline share them, so another thread can show what the workers do.
+    pub fn running_jobs(&self) -> Vec<(PathBuf, Duration)> {
+        let running = self.running.lock().unwrap();
+        running
+            .iter()
+            .map(|(relative, start)| (relative.clone(), start.elapsed()))
+            .collect()
+    }
+
+    /// The files left out by the plans so far for being larger than the
+    /// maximum file size, relative to the source folder, with their size
+    pub fn too_large(&self) -> Vec<(PathBuf, u64)> {
+        let too_large = self.too_large.lock().unwrap();
+        too_large
+            .iter()
+            .map(|(relative, (size, _))| (relative.clone(), *size))
+            .collect()
+    }
+
+    /// The files left out by the plans so far for their blocked or not
+    /// allowed extension, relative to the source folder, with their size and
+    /// [`SkipReason::Blocked`] or [`SkipReason::NotAllowed`]
+    pub fn blocked(&self) -> Vec<(PathBuf, u64, SkipReason)> {
+        let blocked = self.blocked.lock().unwrap();
+        blocked
+            .iter()
+            .map(|(relative, &(size, reason))| (relative.clone(), size, reason))
+            .collect()
+    }
+
+    /// Like [`Self::too_large`] and [`Self::blocked`], with the reason and the
+    /// external URL from the config for [`manifest::SKIPPED_FILE_NAME`]
+    pub fn skipped(&self) -> Vec<SkippedFile> {
+        let too_large = self.too_large.lock().unwrap();
+        let too_large = too_large.iter().map(|(relative, &(size, max_file_size))| {
+            (relative, size, SkipReason::TooLarge, Some(max_file_size))
+        });
+        let blocked = self.blocked.lock().unwrap();
+        let blocked = blocked
+            .iter()
+            .map(|(relative, &(size, reason))| (relative, size, reason, None));
+        let mut skipped: Vec<_> = too_large
+            .chain(blocked)
+            .map(|(relative, size, reason, max_file_size)| SkippedFile {
+                path: url_path(relative),
+                size,
+                reason,
+                max_file_size,
+                url: self
+                    .config
+                    .external_url
+                    .as_deref()
+                    .map(|template| manifest::external_url(template, relative)),
+            })
+            .collect();
+        skipped.sort_by(|a, b| a.path.cmp(&b.path));
+        skipped
+    }
+
+    /// Walks the source folder and the extra sources and decides what to do
+    /// with every file in them. Jobs are in file name order so that runs are
+    /// reproducible.
+    pub fn plan(&self) -> Result<Vec<Job>> {
+        let _timer = self.timings.timer("walk");
+        let mut jobs = Vec::new();
+        for (root, prefix) in self.sources() {
+            jobs.extend(self.plan_folder(root, prefix)?);
+        }
+        self.resolve_collisions(jobs)
+    }
+
+    fn plan_folder(&self, root: &Path, prefix: &Path) -> Result<Vec<Job>> {
+        let mut layers = Layers::new(root, &self.config, self.format)?;
+        let canonical_root = paths::canonicalize(root)
+            .wrap_err_with(|| format!("source folder {}", root.display()))?;
+        // Converting the outputs of earlier runs again would give names like
+        // a_thumb_thumb.jpg
+        let nested_destination = match paths::canonicalize(&self.destination) {
+            Ok(destination) if destination == canonical_root => {
+                return Err(eyre!(
+                    "the destination {} is the source folder {}, outputs would overwrite their sources",
+                    self.destination.display(),
+                    root.display()
+                ));
+            }
+            Ok(destination) => match destination.strip_prefix(&canonical_root) {
+                Ok(relative) => {
+                    warn!(
+                        "the destination {} is inside the source folder {}, leaving it out",
+                        self.destination.display(),
+                        root.display()
+                    );
+                    Some(root.join(relative))
+                }
+                Err(_) => None,
+            },
+            Err(_) => None,
+        };
+        let mut walk = WalkDir::new(root).sort_by_file_name().follow_links(true);
+        if let Some(depth) = self.max_depth {
+            walk = walk.max_depth(depth + 1);
+        }
+        let mut jobs = Vec::new();
+        for entry in walk
+            .into_iter()
+            .filter_entry(|e| {
+                // Don't walk into hidden folders like .git at all
+                let relative = e.path().strip_prefix(root).unwrap_or(e.path());
+                (self.include_hidden || !plan::is_hidden(relative))
+                    && nested_destination.as_deref() != Some(e.path())
+                    && self.is_followed(e, &canonical_root)
+            })
+            .filter_map(|e| e.map_err(|e| warn!("skipping {e}")).ok())
+            .filter(|e| e.file_type().is_file())
+        {
+            let relative = entry.path().strip_prefix(root)?;
+            if let Some(job) = self.job_for(entry.path(), relative, prefix, &mut layers)? {
+                jobs.push(job);
+            }
+        }
+        Ok(jobs)
+    }
+
+    /// Whether to walk into or plan `entry` if it is a symbolic link
+    fn is_followed(&self, entry: &walkdir::DirEntry, canonical_root: &Path) -> bool {
+        if !entry.path_is_symlink() {
+            return true;
+        }
+        let path = entry.path().display();
+        match self.follow_links {
+            FollowLinks::All => true,
+            FollowLinks::Inside => match paths::canonicalize(entry.path()) {
+                Ok(target) if target.starts_with(canonical_root) => true,
+                Ok(target) => {
+                    warn!(
+                        "skipping the symbolic link {path}, it points outside the source folder to {}, see --follow-links",
+                        target.display()
+                    );
+                    false
+                }
+                Err(e) => {
+                    warn!("skipping the symbolic link {path}: {e}");
+                    false
+                }
+            },
+        }
+    }
+
+    /// Plans the entries of a [spec](crate::spec), whose sources are in the
+    /// source folder
+    pub fn plan_spec(&self, entries: &[SpecEntry]) -> Result<Vec<Job>> {
+        entries
+            .iter()
+            .map(|entry| {
+                let source = self.source.join(&entry.source);
+                if !source.is_file() {
+                    return Err(eyre!("{} from the spec isn't a file", source.display()));
+                }
+                let quality = entry
+                    .quality
+                    .or(self.quality)
+                    .unwrap_or_else(|| self.config.quality(&entry.source));
+                Ok(Job {
+                    source,
+                    relative: entry.source.clone(),
+                    kind: JobKind::Curated(Curated {
+                        destination: self.destination.join(&entry.output),
+                        width: entry.width,
+                        height: entry.height,
+                        gravity: entry.gravity,
+                        quality,
+                    }),
+                })
+            })
+            .collect()
+    }
+
+    /// Plans only the given files instead of walking the source folders.
+    /// Files that don't exist or are outside the source folders are ignored.
+    pub fn plan_files(&self, paths: impl IntoIterator<Item = PathBuf>) -> Result<Vec<Job>> {
+        self.plan_files_under(&self.sources(), paths)
+    }
+
+    /// Like [`Pipeline::plan_files`] for files in another folder, such as a
+    /// download cache, whose outputs are placed as if it was the source folder
+    pub fn plan_files_in(
+        &self,
+        root: &Path,
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<Vec<Job>> {
+        self.plan_files_under(&[(root, Path::new(""))], paths)
+    }
+
+    fn plan_files_under(
+        &self,
+        roots: &[(&Path, &Path)],
+        paths: impl IntoIterator<Item = PathBuf>,
+    ) -> Result<Vec<Job>> {
+        let _timer = self.timings.timer("plan");
+        let mut folders = Vec::new();
+        for &(root, prefix) in roots {
+            let canonical_root = paths::canonicalize(root)
+                .wrap_err_with(|| format!("source folder {}", root.display()))?;
+            let layers = Layers::new(root, &self.config, self.format)?;
+            folders.push((canonical_root, prefix, layers));
+        }
+        let canonical_destination = paths::canonicalize(&self.destination).ok();
+        let mut jobs = Vec::new();
+        for path in paths {
+            let Ok(canonical) = paths::canonicalize(&path) else {
+                continue;
+            };
+            if !canonical.is_file() {
+                continue;
+            }
+            // Outputs written to a destination inside a source folder
+            if canonical_destination
+                .as_ref()
+                .is_some_and(|destination| canonical.starts_with(destination))
+            {
+                continue;
+            }
+            // The innermost folder wins if one source is inside another
+            let folder = folders
+                .iter_mut()
+                .filter(|(canonical_root, _, _)| canonical.starts_with(canonical_root))
+                .max_by_key(|(canonical_root, _, _)| canonical_root.components().count());
+            let Some((canonical_root, prefix, layers)) = folder else {
+                continue;
+            };
+            let relative = canonical.strip_prefix(&*canonical_root)?;
+            let path = layers.root.join(relative);
+            if let Some(job) = self.job_for(&path, relative, prefix, layers)? {
+                jobs.push(job);
+            }
+        }
+        self.resolve_collisions(jobs)
+    }
+
+    /// The job for `path`, which is `relative` inside the source folder of
+    /// `layers`. Its outputs are placed in `prefix` inside the destination.
+    fn job_for(
+        &self,
+        path: &Path,
+        relative: &Path,
+        prefix: &Path,
+        layers: &mut Layers,
+    ) -> Result<Option<Job>> {
+        let is_config = relative == Path::new(config::FILE_NAME)
+            || [config::DIRECTORY_FILE_NAME, config::IGNORE_FILE_NAME]
+                .iter()
+                .any(|name| relative.file_name() == Some(name.as_ref()));
+        if is_config || Sidecar::is_sidecar(path) {
+            return Ok(None);
+        }
+        if !self.include_hidden && plan::is_hidden(relative) {
+            debug!("skipping {}: hidden", relative.display());
+            return Ok(None);
+        }
+        let layer = layers.get(relative.parent().unwrap_or(Path::new("")))?;
+        if layer.is_excluded(relative) {
+            debug!("skipping {}: excluded", relative.display());
+            return Ok(None);
+        }
+        let blocked = if self.config.is_blocked(relative) {
+            Some((SkipReason::Blocked, "blocked"))
+        } else if !self.config.is_allowed(relative) {
+            Some((SkipReason::NotAllowed, "not allowed"))
+        } else {
+            None
+        };
+        if let Some((reason, why)) = blocked {
+            debug!("skipping {}: {why}", relative.display());
+            let size = path.metadata().map_or(0, |m| m.len());
+            let mut blocked = self.blocked.lock().unwrap();
+            blocked.insert(prefix.join(relative), (size, reason));
+            return Ok(None);
+        }
+        let mut config = match Sidecar::load(path)? {
+            Some(sidecar) => Cow::Owned(with_format(
+                layer.config.with_sidecar(relative, &sidecar),
+                layers.format,
+            )),
+            None => Cow::Borrowed(&layer.config),
+        };
+        let detect = config.detect_graphics()
+            && config.preset.is_none()
+            && config.action(relative) == Action::Convert;
+        if detect {
+            match graphics::is_graphic(path) {
+                Ok(true) => {
+                    debug!("converting {} as a graphic", relative.display());
+                    config.to_mut().preset = Some(Preset::Screenshot);
+                }
+                Ok(false) => {}
+                // Fails again with a better error when the job runs
+                Err(e) => debug!("not detecting a graphic in {}: {e:#}", relative.display()),
+            }
+        }
+        // A file that vanished since it was listed, or a broken link, fails
+        // when its job runs and is reported with the other failed files
+        let size = path.metadata().map_or(0, |m| m.len());
+        let max_file_size = match layer.force_copy.is_match(relative) {
+            true => u64::MAX,
+            false => config.max_file_size(relative).unwrap_or(self.max_file_size),
+        };
+        let relative = &prefix.join(relative);
+        let kind = plan::plan_file(relative, size, &self.destination, max_file_size, &config);
+        if kind.is_none() {
+            match config.action(relative) {
+                Action::Skip => debug!("skipping {}: skipped by the config", relative.display()),
+                _ => {
+                    debug!(
+                        "skipping {}: larger than the maximum file size",
+                        relative.display()
+                    );
+                    let mut too_large = self.too_large.lock().unwrap();
+                    too_large.insert(relative.clone(), (size, max_file_size));
+                }
+            }
+        }
+        Ok(kind.map(|kind| Job {
+            source: path.to_path_buf(),
+            relative: relative.to_path_buf(),
+            kind,
+        }))
+    }
+
+    /// Plans and runs every job
+    pub fn run(&self) -> Result<Report> {
+        let results = self.run_jobs(self.plan()?, |_| {})?;
+        Ok(Report { results })
+    }
+
+    /// Runs `jobs` on up to [`PipelineBuilder::jobs`] threads, calling
+    /// `on_result` as each one finishes. The results are returned in the
+    /// order of `jobs`. Files that fail are recorded in their results, while
+    /// any other error stops the jobs that haven't started.
+    ///
+    /// The destination folder is locked while the jobs run, see
+    /// [`PipelineBuilder::wait_for_lock`].
+    ///
+    /// Finished jobs are recorded in a journal in the destination folder,
+    /// which is removed once every job has run. A [cancelled](Self::is_cancelled)
+    /// run returns the results it has so far and keeps the journal.
+    pub fn run_jobs(
+        &self,
+        jobs: Vec<Job>,
+        mut on_result: impl FnMut(&JobResult),
+    ) -> Result<Vec<JobResult>> {
+        let _locks = [&self.destination]
+            .into_iter()
+            .chain(&self.mirrors)
+            .map(|destination| DestinationLock::acquire(destination, self.wait_for_lock))
+            .collect::<Result<Vec<_>>>()?;
+        let mut journal = Journal::open(&self.destination, self.resume)?;
+        let mut results = Vec::new();
+        let mut remaining = Vec::new();
+        for (i, job) in jobs.into_iter().enumerate() {
+            if journal.is_finished(&job.source) {
+                debug!(
+                    "{} was finished by the interrupted run",
+                    job.relative.display()
+                );
+                let result = JobResult {
+                    job,
+                    outcome: Outcome::Resumed,
+                    duration: Duration::ZERO,
+                };
+                on_result(&result);
+                results.push((i, result));
+            } else {
+                remaining.push((i, job));
+            }
+        }
+        let mut state = Hashes::load(&self.destination)?;
+        let roots: Vec<&Path> = self.sources().into_iter().map(|(root, _)| root).collect();
+        let remaining_jobs: Vec<&Job> = remaining.iter().map(|(_, job)| job).collect();
+        let moved = moves::relocate(&remaining_jobs, &self.destination, &roots, &mut state)?;
+        let hashes = match self.hash_sources {
+            true => Some(Mutex::new(std::mem::take(&mut state))),
+            false => None,
+        };
+        self.check_disk_space(remaining.iter().map(|(_, job)| job))?;
+        let (duplicates, remaining): (Vec<_>, Vec<_>) = remaining
+            .into_iter()
+            .partition(|(_, job)| matches!(job.kind, JobKind::Duplicate { .. }));
+        #[cfg(feature = "async-copy")]
+        let (copies, remaining): (Vec<_>, Vec<_>) = remaining.into_iter().partition(|(_, job)| {
+            self.async_copy
+                && hashes.is_none()
+                && self.link_mode == LinkMode::Copy
+                && self.backup.is_none()
+                && self.mirrors.is_empty()
+                && !self.is_forced(job)
+                && matches!(job.kind, JobKind::Copy { .. })
+        });
+        let queue = Mutex::new(remaining.into_iter());
+        let (tx, rx) = mpsc::channel();
+        let mut error = None;
+        std::thread::scope(|scope| {
+            #[cfg(feature = "async-copy")]
+            if !copies.is_empty() {
+                let tx = tx.clone();
+                scope.spawn(move || {
+                    crate::async_copy::copy_all(
+                        copies,
+                        &self.cancelled,
+                        self.clean,
+                        self.preserve_attributes,
+                        tx,
+                    )
+                });
+            }
+            for _ in 0..self.jobs {
+                let tx = tx.clone();
+                let queue = &queue;
+                let hashes = &hashes;
+                scope.spawn(move || loop {
+                    self.wait_while_paused();
+                    if self.is_cancelled() {
+                        break;
+                    }
+                    let Some((i, job)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let result = match hashes {
+                        Some(hashes) => self.run_job_if_changed(job, hashes),
+                        None => self.run_job(job),
+                    }
+                    .map(|result| self.mirror(result));
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(tx);
+            for (i, result) in rx {
+                match result {
+                    Ok(result) => {
+                        if !result.outcome.is_unfinished() {
+                            if let Err(e) = journal.record(&result.job.source) {
+                                error.get_or_insert(e);
+                            }
+                        }
+                        on_result(&result);
+                        results.push((i, result));
+                    }
+                    Err(e) => {
+                        // Empty the queue so the other threads stop
+                        queue.lock().unwrap().by_ref().for_each(drop);
+                        error.get_or_insert(e);
+                    }
+                }
+            }
+        });
+        // Duplicates copy the outputs of their originals, so they run last
+        for (i, job) in duplicates {
+            self.wait_while_paused();
+            if error.is_some() || self.is_cancelled() {
+                break;
+            }
+            let result = match self.run_job(job) {
+                Ok(result) => self.mirror(result),
+                Err(e) => {
+                    error = Some(e);
+                    break;
+                }
+            };
+            if !result.outcome.is_unfinished() {
+                if let Err(e) = journal.record(&result.job.source) {
+                    error.get_or_insert(e);
+                }
+            }
+            on_result(&result);
+            results.push((i, result));
+        }
+        // Save even after an error so the finished jobs aren't redone
+        let hash_sources = hashes.is_some();
+        if let Some(hashes) = hashes {
+            state = hashes.into_inner().unwrap();
+        }
+        let recorded = moves::record(
+            results.iter().map(|(_, result)| result),
+            &self.destination,
+            &roots,
+            &mut state,
+        )?;
+        if hash_sources || recorded || moved > 0 {
+            state.save(&self.destination)?;
+        }
+        if let Some(e) = error {
+            return Err(e);
+        }
+        // The journal is kept so the rest can be resumed
+        if !self.is_cancelled() {
+            journal.complete()?;
+        }
+        results.sort_by_key(|(i, _)| *i);
+        Ok(results.into_iter().map(|(_, result)| result).collect())
+    }
+
+    /// Fails if the outputs of `jobs` may not fit in the free space of the
+    /// destination or a mirror, or only warns with [`OnLowSpace::Warn`]
+    fn check_disk_space<'a>(&self, jobs: impl Iterator<Item = &'a Job>) -> Result<()> {
+        let jobs: Vec<_> = jobs.collect();
+        let linked = self.link_mode == LinkMode::Hardlink;
+        for destination in [&self.destination].into_iter().chain(&self.mirrors) {
+            let Some(available) = disk_space::available(destination) else {
+                debug!("can't find the free space in {}", destination.display());
+                continue;
+            };
+            let required =
+                disk_space::required(jobs.iter().copied(), &self.destination, destination, linked);
+            if required + disk_space::HEADROOM <= available {
+                continue;
+            }
+            let message = format!(
+                "{} needs up to {} MiB but only {} MiB are free",
+                destination.display(),
+                required.div_ceil(crate::MIB),
+                available / crate::MIB
+            );
+            match self.on_low_space {
+                OnLowSpace::Error => {
+                    return Err(eyre!("{message}, see --on-low-space"));
+                }
+                OnLowSpace::Warn => warn!("{message}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Finds outputs that more than one source would write, like `photo.png`
+    /// and `photo.jpg` both converted to `photo.jpg`, or that would replace
+    /// the files the tool writes itself, like the manifest. Either fails or
+    /// renames the outputs of all but one of the sources, depending on
+    /// [`OnCollision`]. A source whose own name is the output name keeps it,
+    /// otherwise the first source by path does, so the result doesn't depend
+    /// on the order of `jobs`.
+    ///
+    /// Names only differing in case collide on macOS and Windows, whose
+    /// filesystems don't tell them apart. Variants of one source that would
+    /// write the same file always fail, since renaming can't separate them.
+    pub fn resolve_collisions(&self, mut jobs: Vec<Job>) -> Result<Vec<Job>> {
+        let mut order: Vec<usize> = (0..jobs.len()).collect();
+        order.sort_by_cached_key(|&i| {
+            let job = &jobs[i];
+            let default = job.outputs().first().map(|(_, path)| path.to_path_buf());
+            let keeps_name =
+                default.is_some_and(|path| path.file_name() == job.relative.file_name());
+            (!keeps_name, job.relative.clone())
+        });
+        let mut writers: HashMap<PathBuf, PathBuf> = STATE_FILES
+            .iter()
+            .chain([&lock::FILE_NAME])
+            .map(|name| {
+                let path = self.destination.join(name);
+                (collision_key(&path), PathBuf::from(env!("CARGO_PKG_NAME")))
+            })
+            .collect();
+        let mut collisions = Vec::new();
+        let mut same_variants = Vec::new();
+        for i in order {
+            let mut own = HashMap::new();
+            for (variant, output) in jobs[i].outputs() {
+                if let Some(other) = own.insert(collision_key(output), variant) {
+                    same_variants.push(format!(
+                        "the {other} and {variant} variants of {} both write {}",
+                        jobs[i].relative.display(),
+                        output.display()
+                    ));
+                }
+            }
+            let collision = jobs[i].outputs().into_iter().find_map(|(_, output)| {
+                writers
+                    .get(&collision_key(output))
+                    .map(|other| (other.clone(), output.to_path_buf()))
+            });
+            if let Some((other, output)) = collision {
+                let job = &mut jobs[i];
+                match self.on_collision {
+                    OnCollision::Error => {
+                        collisions.push(format!(
+                            "{} and {} both write {}",
+                            other.display(),
+                            job.relative.display(),
+                            output.display()
+                        ));
+                        continue;
+                    }
+                    OnCollision::Suffix => {
+                        let renamed = (2..)
+                            .map(|n| {
+                                let mut renamed = job.clone();
+                                renamed.add_suffix(&format!("-{n}"));
+                                renamed
+                            })
+                            .find(|renamed| {
+                                renamed.outputs().iter().all(|(_, output)| {
+                                    !writers.contains_key(&collision_key(output))
+                                })
+                            })
+                            .expect("some suffix is free");
+                        warn!(
+                            "{} and {} both write {}, renamed the outputs of {}",
+                            other.display(),
+                            renamed.relative.display(),
+                            output.display(),
+                            renamed.relative.display()
+                        );
+                        *job = renamed;
+                    }
+                }
+            }
+            let job = &jobs[i];
+            for (_, output) in job.outputs() {
+                writers.insert(collision_key(output), job.relative.clone());
+            }
+        }
+        if !same_variants.is_empty() {
+            return Err(eyre!(
+                "variants would overwrite each other, check the names and sizes in the config:\n  {}",
+                same_variants.join("\n  ")
+            ));
+        }
+        if !collisions.is_empty() {
+            return Err(eyre!(
+                "sources would overwrite each other's outputs or the files of the tool, rename them or pass --on-collision suffix:\n  {}",
+                collisions.join("\n  ")
+            ));
+        }
+        Ok(jobs)
+    }
+
+    /// Turns every job whose source is identical to the source of an earlier
+    /// job of the same kind and encoding into a [`JobKind::Duplicate`] of that job
+    pub fn deduplicate(&self, jobs: Vec<Job>) -> Result<Vec<Job>> {
+        // Only hash the files that share their size with another file
+        let mut sizes: HashMap<(Option<Encoding>, u64), usize> = HashMap::new();
+        let mut keys = Vec::with_capacity(jobs.len());
+        for job in &jobs {
+            let key = (encoding(&job.kind), job.source.metadata()?.len());
+            *sizes.entry(key.clone()).or_default() += 1;
+            keys.push(key);
+        }
+        let mut originals: HashMap<(Option<Encoding>, String), usize> = HashMap::new();
+        let mut deduplicated: Vec<Job> = Vec::with_capacity(jobs.len());
+        for (job, key) in jobs.into_iter().zip(keys) {
+            let can_link = matches!(job.kind, JobKind::ConvertImage(_) | JobKind::Copy { .. });
+            if !can_link || sizes[&key] < 2 {
+                deduplicated.push(job);
+                continue;
+            }
+            let hash = {
+                let _timer = self.timings.timer("hash");
+                (key.0.clone(), sha256_file(&job.source)?)
+            };
+            let Some(&original) = originals.get(&hash) else {
+                originals.insert(hash, deduplicated.len());
+                deduplicated.push(job);
+                continue;
+            };
+            let original = &deduplicated[original];
+            let links = original
+                .outputs()
+                .into_iter()
+                .zip(job.outputs())
+                .map(|((variant, original), (_, destination))| Link {
+                    variant,
+                    original: original.to_path_buf(),
+                    destination: destination.to_path_buf(),
+                })
+                .collect();
+            let of = original.relative.clone();
+            deduplicated.push(Job {
+                kind: JobKind::Duplicate { of, links },
+                ..job
+            });
+        }
+        Ok(deduplicated)
+    }
+
+    /// Whether the run was stopped with the [cancel
+    /// flag](PipelineBuilder::cancel_flag)
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+    }
+
+    fn wait_while_paused(&self) {
+        while self.paused.load(Ordering::Relaxed) && !self.is_cancelled() {
+            std::thread::sleep(Duration::from_millis(100));
+        }
+    }
+
+    /// Runs a single job. A file that can't be converted or copied is
+    /// recorded as [`Outcome::Failed`] in the result instead of an error, so
+    /// the other jobs keep running.
+    pub fn run_job(&self, job: Job) -> Result<JobResult> {
+        let clean = self.clean || self.is_forced(&job);
+        self.run_job_with(job, clean)
+    }
+
+    /// Whether `job` writes one of the outputs given to [`PipelineBuilder::force`]
+    fn is_forced(&self, job: &Job) -> bool {
+        job.outputs()
+            .iter()
+            .any(|(_, path)| self.forced.contains(*path))
+    }
+
+    /// Runs a job unless its source has the hash recorded in `hashes` and all
+    /// of its outputs exist, and records the hash if it succeeds
+    fn run_job_if_changed(&self, job: Job, hashes: &Mutex<Hashes>) -> Result<JobResult> {
+        let start = Instant::now();
+        let key = url_path(&job.relative);
+        let hash = {
+            let _timer = self.timings.timer("hash");
+            sha256_file(&job.source)
+        };
+        let hash = match hash {
+            Ok(hash) => hash,
+            Err(e) => {
+                return Ok(JobResult {
+                    job,
+                    outcome: Outcome::Failed(e),
+                    duration: start.elapsed(),
+                })
+            }
+        };
+        let outputs_exist = job.outputs().iter().all(|(_, path)| path.is_file());
+        if outputs_exist && hashes.lock().unwrap().sources.get(&key) == Some(&hash) {
+            debug!("{} is unchanged", job.relative.display());
+            return Ok(JobResult {
+                job,
+                outcome: Outcome::Unchanged,
+                duration: start.elapsed(),
+            });
+        }
+        // Modification times can't be trusted in this mode, so always reencode
+        let mut result = self.run_job_with(job, true)?;
+        if !result.outcome.is_unfinished() {
+            hashes.lock().unwrap().sources.insert(key, hash);
+        }
+        result.duration = start.elapsed();
+        Ok(result)
+    }
+
+    fn run_job_with(&self, job: Job, clean: bool) -> Result<JobResult> {
+        let start = Instant::now();
+        let _running = Running::start(&self.running, &job.relative);
+        let stage = metrics::format_stage(&job.source);
+        let _timer = self.timings.timer(&stage);
+        let outcome = match &job.kind {
+            JobKind::ConvertImage(variants) => match self.check_source(&job.source) {
+                Err(e) => Outcome::Corrupt(e),
+                Ok(()) => {
+                    let options = ConvertOptions {
+                        clean,
+                        profile: self.profile,
+                        processes: &self.processes,
+                        cache: self.conversion_cache.as_deref(),
+                        skip_optimized: self.skip_optimized,
+                        timings: &self.timings,
+                        big_image_pixels: self.big_image_pixels,
+                        sizes: variants.sizes,
+                        quality: self.quality.unwrap_or(variants.quality),
+                        backup: self.backup.as_deref(),
+                        gravity: variants.gravity,
+                        preset: variants.preset,
+                        flatten_color: variants.flatten_color.as_deref(),
+                        enhance: &variants.enhance,
+                        denoise: variants.denoise,
+                        strip: variants.strip,
+                        target_size: variants.target_size,
+                    };
+                    let converted = imagemagick::convert_image(&job.source, variants, &options)
+                        .and_then(|()| self.verify_outputs(&job, variants))
+                        .and_then(|()| match &variants.original {
+                            Some(original) => self.copy_file_as_is(&job.source, original, clean),
+                            None => Ok(()),
+                        });
+                    match converted {
+                        Ok(()) => Outcome::Converted,
+                        Err(e) if e.is::<imagemagick::Interrupted>() => Outcome::Interrupted,
+                        Err(e) => Outcome::Failed(e),
+                    }
+                }
+            },
+            JobKind::Curated(curated) => match self.check_source(&job.source) {
+                Err(e) => Outcome::Corrupt(e),
+                Ok(()) => {
+                    let options = ConvertOptions {
+                        clean,
+                        profile: self.profile,
+                        processes: &self.processes,
+                        cache: None,
+                        skip_optimized: None,
+                        timings: &self.timings,
+                        big_image_pixels: self.big_image_pixels,
+                        sizes: self.config.sizes,
+                        quality: curated.quality,
+                        backup: self.backup.as_deref(),
+                        gravity: curated.gravity,
+                        preset: None,
+                        flatten_color: None,
+                        enhance: &[],
+                        denoise: None,
+                        strip: Strip::default(),
+                        target_size: TargetSizes::default(),
+                    };
+                    match imagemagick::convert_curated(&job.source, curated, &options) {
+                        Ok(()) => Outcome::Converted,
+                        Err(e) if e.is::<imagemagick::Interrupted>() => Outcome::Interrupted,
+                        Err(e) => Outcome::Failed(e),
+                    }
+                }
+            },
+            JobKind::Copy { destination } => {
+                match self.copy_file_as_is(&job.source, destination, clean) {
+                    Ok(()) => Outcome::Copied,
+                    Err(e) if e.is::<imagemagick::Interrupted>() => Outcome::Interrupted,
+                    Err(e) => Outcome::Failed(e),
+                }
+            }
+            JobKind::Duplicate { links, .. } => {
+                let _timer = self.timings.timer("copy duplicate");
+                let copied = links.iter().try_for_each(|link| {
+                    if !clean && is_copy_up_to_date(&link.original, &link.destination) {
+                        return Ok(());
+                    }
+                    if let Some(backup) = &self.backup {
+                        if !imagemagick::is_up_to_date(&link.original, &link.destination) {
+                            backup.save(&link.destination)?;
+                        }
+                    }
+                    copy_output(link)
+                });
+                match copied {
+                    Ok(()) => Outcome::Deduplicated,
+                    Err(e) => Outcome::Failed(e),
+                }
+            }
+        };
+        let outcome = match outcome {
+            _ if outcome.is_unfinished() => outcome,
+            _ if self.preserve_attributes => match preserve_attributes(&job) {
+                Ok(()) => outcome,
+                Err(e) => Outcome::Failed(e),
+            },
+            _ => outcome,
+        };
+        Ok(JobResult {
+            job,
+            outcome,
+            duration: start.elapsed(),
+        })
+    }
+
+    /// Copies the outputs of a finished job into the [mirrors](PipelineBuilder::mirror)
+    fn mirror(&self, mut result: JobResult) -> JobResult {
+        if self.mirrors.is_empty() || result.outcome.is_unfinished() {
+            return result;
+        }
+        let _timer = self.timings.timer("mirror");
+        let outputs = result.job.outputs();
+        let copied = self.mirrors.iter().try_for_each(|mirror| {
+            for (_, output) in &outputs {
+                let Ok(relative) = output.strip_prefix(&self.destination) else {
+                    continue;
+                };
+                let copy = mirror.join(relative);
+                if !output.is_file() || imagemagick::is_up_to_date(output, &copy) {
+                    continue;
+                }
+                if let Some(p) = copy.parent() {
+                    std::fs::create_dir_all(p)?;
+                }
+                std::fs::copy(output, &copy).wrap_err_with(|| {
+                    format!("copying {} to {}", output.display(), copy.display())
+                })?;
+                if self.preserve_attributes {
+                    let modified = output.metadata()?.modified()?;
+                    std::fs::File::options()
+                        .write(true)
+                        .open(&copy)
+                        .and_then(|file| file.set_modified(modified))?;
+                }
+            }
+            Ok(())
+        });
+        if let Err(e) = copied {
+            result.outcome = Outcome::Failed(e);
+        }
+        result
+    }
+
+    fn check_source(&self, source: &Path) -> Result<()> {
+        let _timer = self.timings.timer("check source");
+        verify::check_source(source)
+    }
+
+    fn verify_outputs(&self, job: &Job, variants: &ImageVariants) -> Result<()> {
+        let Some(full_decode_percent) = self.verify else {
+            return Ok(());
+        };
+        let _timer = self.timings.timer("verify");
+        let verified =
+            verify::verify_outputs(&job.source, variants, &variants.sizes, full_decode_percent);
+        if verified.is_err() {
+            for (_, output) in job.outputs() {
+                let _ = std::fs::remove_file(output);
+            }
+        }
+        verified
+    }
+
+    /// Copies in chunks so large files report progress and can be cancelled
+    fn copy_file_as_is(&self, file: &Path, new_path: &Path, clean: bool) -> Result<()> {
+        if new_path == file {
+            // Copying a file to itself can lead to corruption
+            return Err(eyre!(
+                "source and destination paths are the same: {}",
+                file.display()
+            ));
+        }
+        if let Some(p) = new_path.parent() {
+            std::fs::create_dir_all(p)
+                .wrap_err_with(|| format!("creating the folder {}", p.display()))?;
+        }
+        let _timer = self.timings.timer("copy");
+        if self.link_mode == LinkMode::Hardlink && is_same_file(file, new_path) {
+            return Ok(());
+        }
+        if !clean && is_copy_up_to_date(file, new_path) {
+            return Ok(());
+        }
+        if let Some(backup) = &self.backup {
+            if !imagemagick::is_up_to_date(file, new_path) {
+                backup.save(new_path)?;
+            }
+        }
+        // The destination may be a hard link to the source, which must not be
+        // truncated by writing to it
+        match std::fs::remove_file(new_path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => {}
+        }
+        let linked = match self.link_mode {
+            LinkMode::Copy => false,
+            LinkMode::Hardlink => std::fs::hard_link(file, new_path).is_ok(),
+            LinkMode::Reflink => reflink(file, new_path).is_ok(),
+        };
+        if linked {
+            return Ok(());
+        }
+        let result = self.copy_chunked(file, new_path);
+        if result.is_err() {
+            // Don't leave a truncated file that looks like a finished copy
+            let _ = std::fs::remove_file(new_path);
+        }
+        result.wrap_err_with(|| {
+            format!(
+                "source: {}, destination: {}",
+                file.display(),
+                new_path.display()
+            )
+        })
+    }
+
+    fn copy_chunked(&self, file: &Path, new_path: &Path) -> Result<()> {
+        let mut reader = File::open(file)?;
+        let total = reader.metadata()?.len();
+        let mut writer = File::create(new_path)?;
+        let mut buffer = vec![0; MIB as usize];
+        let mut copied = 0;
+        let mut reported = 0;
+        loop {
+            if self.is_cancelled() {
+                return Err(imagemagick::Interrupted.into());
+            }
+            let read = reader.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            writer.write_all(&buffer[..read])?;
+            copied += read as u64;
+            if total < PROGRESS_THRESHOLD {
+                continue;
+            }
+            // The file may grow while it is copied
+            let percent = (copied * 100 / total).min(100);
+            if percent >= reported + 10 {
+                reported = percent - percent % 10;
+                info!(
+                    "Copying {}: {reported}% of {} MiB",
+                    file.display(),
+                    total / MIB
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (a.metadata(), b.metadata()) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_a: &Path, _b: &Path) -> bool {
+    false
+}
+
+/// Clones `file` into `new_path` with the FICLONE ioctl
+#[cfg(target_os = "linux")]
+fn reflink(file: &Path, new_path: &Path) -> std::io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+    const FICLONE: libc::c_ulong = 0x4004_9409;
+    let source = File::open(file)?;
+    let destination = File::create(new_path)?;
+    // SAFETY: both file descriptors are open for the duration of the call
+    let result = unsafe { libc::ioctl(destination.as_raw_fd(), FICLONE as _, source.as_raw_fd()) };
+    if result != 0 {
+        let error = std::io::Error::last_os_error();
+        drop(destination);
+        let _ = std::fs::remove_file(new_path);
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_file: &Path, _new_path: &Path) -> std::io::Result<()> {
+    Err(std::io::ErrorKind::Unsupported.into())
+}
+
+/// Sets the modification time of every output of `job` to that of its
+/// source, and the permissions of copied files to those of their source
+pub(crate) fn preserve_attributes(job: &Job) -> Result<()> {
+    let metadata = job
+        .source
+        .metadata()
+        .wrap_err_with(|| format!("reading the attributes of {}", job.source.display()))?;
+    let modified = metadata.modified()?;
+    let outputs = job.outputs().into_iter().map(|(_, path)| path);
+    for output in outputs.filter(|path| path.is_file()) {
+        std::fs::File::options()
+            .write(true)
+            .open(output)
+            .and_then(|file| file.set_modified(modified))
+            .wrap_err_with(|| format!("setting the modification time of {}", output.display()))?;
+    }
+    if let JobKind::Copy { destination } = &job.kind {
+        std::fs::set_permissions(destination, metadata.permissions())
+            .wrap_err_with(|| format!("setting the permissions of {}", destination.display()))?;
+    }
+    Ok(())
+}
+
+/// Whether `destination` is a copy of `source` from after it was last
+/// modified, judging by its size and modification time
+pub(crate) fn is_copy_up_to_date(source: &Path, destination: &Path) -> bool {
+    let len = |path: &Path| path.metadata().map(|m| m.len()).ok();
+    imagemagick::is_up_to_date(source, destination) && len(source) == len(destination)
+}
+
+/// Copies the output of the original to the output of the duplicate. They
+/// aren't hard linked since outputs are overwritten in place when reconverted.
+fn copy_output(link: &Link) -> Result<()> {
+    if let Some(p) = link.destination.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    std::fs::copy(&link.original, &link.destination).wrap_err_with(|| {
+        format!(
+            "source: {}, destination: {}",
+            link.original.display(),
+            link.destination.display()
+        )
+    })?;
+    Ok(())
+}
+
+/// What makes the outputs of two images with the same source different
+pub(crate) type Encoding = (
+    Sizes,
+    Strip,
+    TargetSizes,
+    u8,
+    bool,
+    Option<Gravity>,
+    Option<Preset>,
+    Option<String>,
+    Vec<Enhancement>,
+    Option<u8>,
+    Option<OsString>,
+);
+
+/// Unset for jobs that copy their source
+pub(crate) fn encoding(kind: &JobKind) -> Option<Encoding> {
+    match kind {
+        JobKind::ConvertImage(variants) => Some((
+            variants.sizes,
+            variants.strip,
+            variants.target_size,
+            variants.quality,
+            variants.thumb.is_some(),
+            variants.gravity,
+            variants.preset,
+            variants.flatten_color.clone(),
+            variants.enhance.clone(),
+            variants.denoise,
+            variants.default.extension().map(|e| e.to_os_string()),
+        )),
+        _ => None,
+    }
+}
+
+/// The settings for the files in a folder: the config of the pipeline with
+/// the [`config::DIRECTORY_FILE_NAME`] and [`config::IGNORE_FILE_NAME`] of the
+/// folder and its parents applied
+#[derive(Clone)]
+struct Layer {
+    config: Config,
+    /// Files copied whatever their size
+    force_copy: GlobSet,
+    /// Exclude globs and the folder inside the source folder they match in
+    exclude: Vec<(PathBuf, GlobSet)>,
+    /// Ignore files and their folder, the deepest last
+    ignores: Vec<(PathBuf, Gitignore)>,
+}
+
+impl Layer {
+    fn is_excluded(&self, relative: &Path) -> bool {
+        let is_excluded = self.exclude.iter().any(|(dir, globs)| {
+            relative
+                .strip_prefix(dir)
+                .is_ok_and(|relative| globs.is_match(relative))
+        });
+        is_excluded || self.is_ignored(relative)
+    }
+
+    /// Like git, a file is ignored if it or any folder it is in matches,
+    /// checking folders first. For each, the deepest ignore file with a
+    /// matching pattern decides, so a negated pattern can re-include it.
+    fn is_ignored(&self, relative: &Path) -> bool {
+        let mut paths: Vec<_> = relative.ancestors().collect();
+        // From the top folder down to the file itself, skipping the empty path
+        paths.reverse();
+        paths.iter().skip(1).enumerate().any(|(i, path)| {
+            let is_dir = i + 2 < paths.len();
+            let decision = self.ignores.iter().rev().find_map(|(dir, ignore)| {
+                let path = path.strip_prefix(dir).ok()?;
+                match ignore.matched(path, is_dir) {
+                    Match::None => None,
+                    Match::Ignore(_) => Some(true),
+                    Match::Whitelist(_) => Some(false),
+                }
+            });
+            decision == Some(true)
+        })
+    }
+
+    /// The layer for `dir`, a folder inside the source folder with a config
+    fn apply(&self, dir: &Path, toml: &str, format: Option<Format>) -> Result<Layer> {
+        let mut overlay: toml::Table = toml::from_str(toml)?;
+        for key in [
+            "max-file-size",
+            "force-copy",
+            "external-url",
+            "output-url",
+            "extra-sources",
+            "block",
+            "allow",
+        ] {
+            if overlay.contains_key(key) {
+                return Err(eyre!("{key} can only be set for the whole source folder"));
+            }
+        }
+        let mut exclude = self.exclude.clone();
+        if let Some(patterns) = overlay.remove("exclude") {
+            let patterns: Vec<String> = patterns.try_into()?;
+            exclude.push((dir.to_path_buf(), globs("exclude", &patterns)?));
+        }
+        Ok(Layer {
+            config: with_format(self.config.merge(overlay)?, format),
+            force_copy: self.force_copy.clone(),
+            exclude,
+            ignores: self.ignores.clone(),
+        })
+    }
+}
+
+/// The layers of the folders in a source folder, read as they are needed
+struct Layers<'a> {
+    root: &'a Path,
+    /// Overrides the format in every config
+    format: Option<Format>,
+    base: Arc<Layer>,
+    by_dir: HashMap<PathBuf, Arc<Layer>>,
+}
+
+impl<'a> Layers<'a> {
+    fn new(root: &'a Path, config: &Config, format: Option<Format>) -> Result<Self> {
+        let base = Layer {
+            config: with_format(config.clone(), format),
+            force_copy: globs("force-copy", &config.force_copy)?,
+            exclude: vec![(PathBuf::new(), globs("exclude", &config.exclude)?)],
+            ignores: Vec::new(),
+        };
+        Ok(Layers {
+            root,
+            format,
+            base: Arc::new(base),
+            by_dir: HashMap::new(),
+        })
+    }
+
+    /// The layer of `dir`, relative to the source folder
+    fn get(&mut self, dir: &Path) -> Result<Arc<Layer>> {
+        if let Some(layer) = self.by_dir.get(dir) {
+            return Ok(layer.clone());
+        }
+        let parent = match dir.parent() {
+            Some(parent) => self.get(parent)?,
+            None => self.base.clone(),
+        };
+        let path = self.root.join(dir).join(config::DIRECTORY_FILE_NAME);
+        let mut layer = match std::fs::read_to_string(&path) {
+            Ok(toml) => Arc::new(
+                parent
+                    .apply(dir, &toml, self.format)
+                    .wrap_err_with(|| ConfigError { path: path.clone() })?,
+            ),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => parent,
+            Err(e) => return Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        };
+        let path = self.root.join(dir).join(config::IGNORE_FILE_NAME);
+        if path.is_file() {
+            // Paths are matched relative to `dir` by `Layer::is_ignored`
+            let mut builder = GitignoreBuilder::new(".");
+            if let Some(e) = builder.add(&path) {
+                return Err(e).wrap_err_with(|| format!("reading {}", path.display()));
+            }
+            let mut with_ignore = (*layer).clone();
+            with_ignore
+                .ignores
+                .push((dir.to_path_buf(), builder.build()?));
+            layer = Arc::new(with_ignore);
+        }
+        self.by_dir.insert(dir.to_path_buf(), layer.clone());
+        Ok(layer)
+    }
+}
+
+fn with_format(mut config: Config, format: Option<Format>) -> Config {
+    if let Some(format) = format {
+        config.format = Some(format);
+        let rules = config.extensions.values_mut();
+        for rule in rules.chain(config.categories.values_mut()) {
+            rule.format = None;
+        }
+    }
+    config
+}
+
+/// What `path` is compared by to find outputs that would overwrite each other
+fn collision_key(path: &Path) -> PathBuf {
+    if cfg!(any(windows, target_os = "macos")) {
+        PathBuf::from(path.to_string_lossy().to_lowercase())
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// Compiles the globs of a config `setting` like `exclude`
+fn globs(setting: &str, patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).wrap_err_with(|| format!("{setting} {pattern}"))?);
+    }
+    Ok(builder.build()?)
+}
+
+/// Lists a job as running until it is dropped
+struct Running<'a> {
+    running: &'a Mutex<Vec<(PathBuf, Instant)>>,
+    relative: PathBuf,
+}
+
+impl<'a> Running<'a> {
+    fn start(running: &'a Mutex<Vec<(PathBuf, Instant)>>, relative: &Path) -> Self {
+        running
+            .lock()
+            .unwrap()
+            .push((relative.to_path_buf(), Instant::now()));
+        Running {
+            running,
+            relative: relative.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for Running<'_> {
+    fn drop(&mut self) {
+        let mut running = self.running.lock().unwrap();
+        if let Some(i) = running.iter().position(|(r, _)| *r == self.relative) {
+            running.remove(i);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A source folder in the temporary folder with `ignores`, the contents
+    /// of the ignore files by the folder they are in
+    fn source_folder(name: &str, ignores: &[(&str, &str)]) -> PathBuf {
+        let root = std::env::temp_dir().join(format!(
+            "web_assets_converter-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&root);
+        for (dir, patterns) in ignores {
+            std::fs::create_dir_all(root.join(dir)).unwrap();
+            std::fs::write(root.join(dir).join(config::IGNORE_FILE_NAME), patterns).unwrap();
+        }
+        root
+    }
+
+    /// Which of `paths` the ignore files in `root` leave out
+    fn ignored<'a>(root: &Path, paths: &[&'a str]) -> Vec<&'a str> {
+        let mut layers = Layers::new(root, &Config::default(), None).unwrap();
+        let ignored = paths
+            .iter()
+            .filter(|relative| {
+                let relative = Path::new(relative);
+                let layer = layers.get(relative.parent().unwrap()).unwrap();
+                layer.is_ignored(relative)
+            })
+            .copied()
+            .collect();
+        let _ = std::fs::remove_dir_all(root);
+        ignored
+    }
+
+    #[test]
+    fn negated_patterns_re_include_files() {
+        let root = source_folder("negated", &[("", "*.psd\n!keep.psd\n")]);
+        assert_eq!(
+            ignored(
+                &root,
+                &["a.psd", "keep.psd", "photos/b.psd", "photos/keep.psd"]
+            ),
+            ["a.psd", "photos/b.psd"]
+        );
+    }
+
+    #[test]
+    fn files_in_ignored_folders_cant_be_re_included() {
+        let root = source_folder("folders", &[("", "drafts/\n!drafts/keep.jpg\n")]);
+        assert_eq!(
+            ignored(
+                &root,
+                &["drafts/a.jpg", "drafts/keep.jpg", "photos/drafts/b.jpg"]
+            ),
+            ["drafts/a.jpg", "drafts/keep.jpg", "photos/drafts/b.jpg"]
+        );
+    }
+
+    #[test]
+    fn folder_patterns_leave_files_of_that_name_alone() {
+        let root = source_folder("files", &[("", "drafts/\n")]);
+        assert_eq!(
+            ignored(&root, &["drafts", "photos/drafts", "drafts/a.jpg"]),
+            ["drafts/a.jpg"]
+        );
+    }
+
+    #[test]
+    fn anchored_patterns_only_match_in_the_folder_of_the_ignore_file() {
+        let root = source_folder("anchored", &[("", "/build\n"), ("photos", "/raw/\n")]);
+        assert_eq!(
+            ignored(
+                &root,
+                &[
+                    "build/a.js",
+                    "docs/build/b.js",
+                    "photos/raw/c.cr2",
+                    "raw/d.cr2"
+                ]
+            ),
+            ["build/a.js", "photos/raw/c.cr2"]
+        );
+    }
+
+    #[test]
+    fn ignore_files_in_folders_override_their_parents() {
+        let root = source_folder("layers", &[("", "*.raw\n"), ("photos", "!*.raw\n*.txt\n")]);
+        assert_eq!(
+            ignored(
+                &root,
+                &[
+                    "a.raw",
+                    "photos/b.raw",
+                    "photos/rally/c.raw",
+                    "notes.txt",
+                    "photos/notes.txt"
+                ]
+            ),
+            ["a.raw", "photos/notes.txt"]
+        );
+    }
+
+    fn copy(source: &Path, relative: &str, output: &str) -> Job {
+        Job {
+            source: source.join(relative),
+            relative: relative.into(),
+            kind: JobKind::Copy {
+                destination: Path::new("dist").join(output),
+            },
+        }
+    }
+
+    fn destinations(jobs: &[Job]) -> Vec<(String, PathBuf)> {
+        jobs.iter()
+            .map(|job| (url_path(&job.relative), job.outputs()[0].1.to_path_buf()))
+            .collect()
+    }
+
+    #[test]
+    fn colliding_outputs_are_suffixed_in_order_of_their_sources() {
+        let pipeline = Pipeline::builder("assets", "dist")
+            .on_collision(OnCollision::Suffix)
+            .build();
+        let source = Path::new("assets");
+        let jobs = vec![
+            copy(source, "c.txt", "out.txt"),
+            copy(source, "a.txt", "out.txt"),
+            copy(source, "b.txt", "out.txt"),
+        ];
+        let jobs = pipeline.resolve_collisions(jobs).unwrap();
+        assert_eq!(
+            destinations(&jobs),
+            [
+                ("c.txt".into(), PathBuf::from("dist/out-3.txt")),
+                ("a.txt".into(), PathBuf::from("dist/out.txt")),
+                ("b.txt".into(), PathBuf::from("dist/out-2.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn sources_named_like_the_output_keep_it() {
+        let pipeline = Pipeline::builder("assets", "dist")
+            .on_collision(OnCollision::Suffix)
+            .build();
+        let source = Path::new("assets");
+        let jobs = vec![
+            copy(source, "a.txt", "out.txt"),
+            copy(source, "z/out.txt", "out.txt"),
+        ];
+        let jobs = pipeline.resolve_collisions(jobs).unwrap();
+        assert_eq!(
+            destinations(&jobs),
+            [
+                ("a.txt".into(), PathBuf::from("dist/out-2.txt")),
+                ("z/out.txt".into(), PathBuf::from("dist/out.txt")),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates_link_to_the_first_identical_source() {
+        let root = source_folder("dedupe", &[]);
+        std::fs::create_dir_all(&root).unwrap();
+        for (name, contents) in [("a.txt", "same"), ("b.txt", "same"), ("c.txt", "diff")] {
+            std::fs::write(root.join(name), contents).unwrap();
+        }
+        let pipeline = Pipeline::builder(&root, "dist").build();
+        let jobs = vec![
+            copy(&root, "b.txt", "b.txt"),
+            copy(&root, "a.txt", "a.txt"),
+            copy(&root, "c.txt", "c.txt"),
+        ];
+        let jobs = pipeline.deduplicate(jobs);
+        let _ = std::fs::remove_dir_all(&root);
+        let jobs = jobs.unwrap();
+        assert!(matches!(jobs[0].kind, JobKind::Copy { .. }));
+        assert!(matches!(jobs[2].kind, JobKind::Copy { .. }));
+        let JobKind::Duplicate { of, links } = &jobs[1].kind else {
+            panic!("a.txt is a duplicate, planned as {:?}", jobs[1].kind);
+        };
+        assert_eq!(of, Path::new("b.txt"));
+        assert_eq!(links.len(), 1);
+        assert_eq!(links[0].original, Path::new("dist/b.txt"));
+        assert_eq!(links[0].destination, Path::new("dist/a.txt"));
+    }
+}
@@ -0,0 +1,571 @@
+use std::{
+    ffi::{OsStr, OsString},
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    config::{Action, Config, Enhancement, Format, Gravity, Preset, Sizes, Strip, TargetSizes},
+    formats, slug,
+};
+
+/// A single source file and what will be done with it
+#[derive(Debug, Clone)]
+pub struct Job {
+    pub source: PathBuf,
+    /// The path of the source inside its source folder
+    pub relative: PathBuf,
+    pub kind: JobKind,
+}
+
+#[derive(Debug, Clone)]
+pub enum JobKind {
+    ConvertImage(ImageVariants),
+    Copy {
+        destination: PathBuf,
+    },
+    /// The source is identical to another source, so the outputs of that one
+    /// are copied instead of converting it again
+    Duplicate {
+        /// The path of the original inside its source folder
+        of: PathBuf,
+        links: Vec<Link>,
+    },
+    /// An output listed in a [spec file](crate::spec)
+    Curated(Curated),
+}
+
+/// An image converted to a single output with its own size and name
+#[derive(Debug, Clone)]
+pub struct Curated {
+    pub destination: PathBuf,
+    /// With both a width and a height the image covers them and is cropped,
+    /// with one of them it keeps its proportions
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// The part of the image kept when it is cropped
+    pub gravity: Option<Gravity>,
+    pub quality: u8,
+}
+
+/// An output of a duplicate source and the output of the original it is copied from
+#[derive(Debug, Clone)]
+pub struct Link {
+    pub variant: &'static str,
+    pub original: PathBuf,
+    pub destination: PathBuf,
+}
+
+/// The sizes every source image is converted into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum Variant {
+    /// At most 1920 pixels on the longest side
+    Default,
+    /// At most 3840 pixels on the longest side
+    High,
+    /// At most 640 pixels on the longest side
+    Thumb,
+}
+
+impl Variant {
+    pub fn name(self) -> &'static str {
+        match self {
+            Variant::Default => "default",
+            Variant::High => "high",
+            Variant::Thumb => "thumb",
+        }
+    }
+}
+
+/// How much effort goes into each conversion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum Profile {
+    /// Fast, lower quality conversions for local iteration
+    Dev,
+    /// Full quality outputs for deployment
+    #[default]
+    Prod,
+}
+
+/// Destination paths of the variants generated for a source image and how
+/// they are encoded
+#[derive(Debug, Clone)]
+pub struct ImageVariants {
+    pub default: PathBuf,
+    pub high: PathBuf,
+    /// Unset if thumbnails are turned off
+    pub thumb: Option<PathBuf>,
+    /// The name of the source without its extension as it is used in the
+    /// names of the variants, after `slug-names` and `flatten`
+    pub stem: OsString,
+    /// An untouched copy of the source in [`ORIGINALS_DIR`], with
+    /// `keep-originals` in the config
+    pub original: Option<PathBuf>,
+    pub sizes: Sizes,
+    /// The variants that are stripped of metadata and color profiles
+    pub strip: Strip,
+    pub target_size: TargetSizes,
+    pub quality: u8,
+    /// Crops the thumbnail to a square keeping this part of the image
+    pub gravity: Option<Gravity>,
+    pub preset: Option<Preset>,
+    /// The background of transparent sources, set for JPEG outputs
+    pub flatten_color: Option<String>,
+    pub enhance: Vec<Enhancement>,
+    /// The threshold of the denoise in percent
+    pub denoise: Option<u8>,
+}
+
+/// The folder in the destination that `keep-originals` copies the sources of
+/// converted images into, with the same paths as in the source folder
+pub const ORIGINALS_DIR: &str = "originals";
+
+/// The name of the output that is an untouched copy of the source, see
+/// [`ImageVariants::original`]
+pub const ORIGINAL: &str = "original";
+
+/// The name of the output of a [`JobKind::Curated`]
+pub const CURATED: &str = "curated";
+
+/// Decides what to do with a source file without touching the filesystem.
+///
+/// `relative` is the path of the file inside the source folder and the
+/// returned paths are `destination` joined with the output names, so an empty
+/// `destination` gives paths relative to the destination folder.
+///
+/// With `flatten` in the config the folders are joined into the file name
+/// with dashes, so `photos/rally/a.jpg` becomes `photos-rally-a.jpg`.
+pub fn plan_file(
+    relative: &Path,
+    size: u64,
+    destination: &Path,
+    max_file_size: u64,
+    config: &Config,
+) -> Option<JobKind> {
+    let flattened;
+    let relative_output = match config.flatten() {
+        true => {
+            let mut name = OsString::new();
+            for (i, component) in relative.iter().enumerate() {
+                if i > 0 {
+                    name.push("-");
+                }
+                name.push(component);
+            }
+            flattened = PathBuf::from(name);
+            &flattened
+        }
+        false => relative,
+    };
+    let destination_path = match config.slug_names() {
+        true => destination.join(slug::slug_path(relative_output)),
+        false => destination.join(relative_output),
+    };
+    match config.action(relative) {
+        Action::Convert => {
+            let mut variants = image_variants(destination_path, relative, config);
+            variants.original = config
+                .keep_originals()
+                .then(|| destination.join(ORIGINALS_DIR).join(relative));
+            return Some(JobKind::ConvertImage(variants));
+        }
+        Action::Skip => return None,
+        Action::Copy => {}
+    }
+    // File was not handled based on its extension
+    if size >= max_file_size {
+        return None;
+    }
+    Some(JobKind::Copy {
+        destination: destination_path,
+    })
+}
+
+/// Files that operating systems and tools leave in folders, which are never
+/// meant to be published. Matched case insensitively
+const JUNK_FILE_NAMES: [&str; 4] = ["thumbs.db", "ehthumbs.db", "desktop.ini", "__macosx"];
+
+/// Whether `relative` or a folder it is in is hidden, like `.git` or
+/// `.DS_Store`, or is junk like `Thumbs.db` or an editor backup ending in `~`
+pub fn is_hidden(relative: &Path) -> bool {
+    relative.iter().any(|c| {
+        let name = c.to_string_lossy();
+        (name.starts_with('.') && name != "." && name != "..")
+            || name.ends_with('~')
+            || name == "Icon\r"
+            || JUNK_FILE_NAMES
+                .iter()
+                .any(|junk| name.eq_ignore_ascii_case(junk))
+    })
+}
+
+/// Inserts `suffix` after the first `stem` in `name`, or at the end if `stem`
+/// isn't in it
+fn insert_after(name: &OsStr, stem: &OsStr, suffix: &str) -> OsString {
+    let bytes = name.as_encoded_bytes();
+    let stem = stem.as_encoded_bytes();
+    let end = (!stem.is_empty())
+        .then(|| bytes.windows(stem.len()).position(|w| w == stem))
+        .flatten()
+        .map(|start| start + stem.len());
+    let Some(end) = end else {
+        let mut name = name.to_owned();
+        name.push(suffix);
+        return name;
+    };
+    // SAFETY: the stem is a whole OsStr, so it starts and ends on boundaries
+    // of the encoding and both halves are valid on their own
+    let (head, tail) = unsafe {
+        (
+            OsStr::from_encoded_bytes_unchecked(&bytes[..end]),
+            OsStr::from_encoded_bytes_unchecked(&bytes[end..]),
+        )
+    };
+    let mut name = head.to_owned();
+    name.push(suffix);
+    name.push(tail);
+    name
+}
+
+fn image_variants(
+    mut destination_path: PathBuf,
+    relative: &Path,
+    config: &Config,
+) -> ImageVariants {
+    let stem = destination_path.file_stem().unwrap_or_default().to_owned();
+    let format = config.format(relative);
+    // JPEG sources keep the spelling of their extension
+    if format != Format::Jpg || !formats::is_jpeg(&destination_path) {
+        destination_path.set_extension(format.extension());
+    }
+    let extension = destination_path.extension().unwrap_or_default();
+    let named = |variant: Variant| {
+        destination_path.with_file_name(config.names.render(
+            variant,
+            &stem,
+            extension,
+            &config.sizes,
+        ))
+    };
+    ImageVariants {
+        default: named(Variant::Default),
+        high: named(Variant::High),
+        thumb: config.thumbnails().then(|| named(Variant::Thumb)),
+        stem: stem.clone(),
+        original: None,
+        sizes: config.sizes,
+        strip: config.strip,
+        target_size: config.target_size,
+        quality: config.quality(relative),
+        gravity: config.gravity,
+        preset: config.preset,
+        flatten_color: config
+            .flatten_color
+            .clone()
+            .filter(|_| format == Format::Jpg),
+        enhance: config.enhance.clone(),
+        denoise: config.denoise,
+    }
+}
+
+impl Job {
+    /// Inserts `suffix` after the name of the source in the names of every
+    /// output, so `photo_high.jpg` becomes `photo-2_high.jpg` for `-2`
+    pub fn add_suffix(&mut self, suffix: &str) {
+        let with_suffix = |path: &mut PathBuf, stem: &OsStr| {
+            let name = insert_after(path.file_name().unwrap_or_default(), stem, suffix);
+            path.set_file_name(name);
+        };
+        match &mut self.kind {
+            JobKind::ConvertImage(variants) => {
+                with_suffix(&mut variants.default, &variants.stem);
+                with_suffix(&mut variants.high, &variants.stem);
+                if let Some(thumb) = &mut variants.thumb {
+                    with_suffix(thumb, &variants.stem);
+                }
+                if let Some(original) = &mut variants.original {
+                    let stem = original.file_stem().unwrap_or_default().to_owned();
+                    with_suffix(original, &stem);
+                }
+                variants.stem.push(suffix);
+            }
+            JobKind::Copy { destination } => {
+                let stem = destination.file_stem().unwrap_or_default().to_owned();
+                with_suffix(destination, &stem);
+            }
+            JobKind::Duplicate { links, .. } => {
+                for link in links {
+                    let stem = link.destination.file_stem().unwrap_or_default().to_owned();
+                    with_suffix(&mut link.destination, &stem);
+                }
+            }
+            JobKind::Curated(curated) => {
+                let stem = curated
+                    .destination
+                    .file_stem()
+                    .unwrap_or_default()
+                    .to_owned();
+                with_suffix(&mut curated.destination, &stem);
+            }
+        }
+    }
+
+    /// Every destination path this job writes, named by variant
+    pub fn outputs(&self) -> Vec<(&'static str, &Path)> {
+        match &self.kind {
+            JobKind::ConvertImage(variants) => {
+                let mut outputs = vec![
+                    (Variant::Default.name(), variants.default.as_path()),
+                    (Variant::High.name(), variants.high.as_path()),
+                ];
+                if let Some(thumb) = &variants.thumb {
+                    outputs.push((Variant::Thumb.name(), thumb.as_path()));
+                }
+                if let Some(original) = &variants.original {
+                    outputs.push((ORIGINAL, original.as_path()));
+                }
+                outputs
+            }
+            JobKind::Copy { destination } => {
+                vec![(Variant::Default.name(), destination.as_path())]
+            }
+            JobKind::Duplicate { links, .. } => links
+                .iter()
+                .map(|link| (link.variant, link.destination.as_path()))
+                .collect(),
+            JobKind::Curated(curated) => vec![(CURATED, curated.destination.as_path())],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Sidecar, MIB};
+
+    fn convert(relative: &str, config: &Config) -> ImageVariants {
+        match plan_file(Path::new(relative), 0, Path::new("dist"), 0, config) {
+            Some(JobKind::ConvertImage(variants)) => variants,
+            other => panic!("{relative} was planned as {other:?}"),
+        }
+    }
+
+    fn copy(relative: &Path, config: &Config) -> PathBuf {
+        match plan_file(relative, 0, Path::new("dist"), u64::MAX, config) {
+            Some(JobKind::Copy { destination }) => destination,
+            other => panic!("{} was planned as {other:?}", relative.display()),
+        }
+    }
+
+    #[test]
+    fn unicode_and_spaces_are_kept() {
+        let variants = convert("fotos/Café Menü (2).jpg", &Config::default());
+        assert_eq!(variants.default, Path::new("dist/fotos/Café Menü (2).jpg"));
+        assert_eq!(
+            variants.high,
+            Path::new("dist/fotos/Café Menü (2)_high.jpg")
+        );
+        assert_eq!(
+            variants.thumb.as_deref(),
+            Some(Path::new("dist/fotos/Café Menü (2)_thumb.jpg"))
+        );
+    }
+
+    #[test]
+    fn only_the_last_extension_is_replaced() {
+        let variants = convert("a.b.c.png", &Config::default());
+        assert_eq!(variants.default, Path::new("dist/a.b.c.jpg"));
+        assert_eq!(variants.high, Path::new("dist/a.b.c_high.jpg"));
+    }
+
+    #[test]
+    fn extensions_are_matched_case_insensitively() {
+        let config = Config::default();
+        assert_eq!(
+            convert("photo.Jpg", &config).default,
+            Path::new("dist/photo.Jpg")
+        );
+        assert_eq!(
+            convert("image.JPEG", &config).high,
+            Path::new("dist/image_high.JPEG")
+        );
+        assert_eq!(
+            convert("scan.jfif", &config).thumb.unwrap(),
+            Path::new("dist/scan_thumb.jfif")
+        );
+        assert_eq!(
+            convert("logo.Png", &config).default,
+            Path::new("dist/logo.jpg")
+        );
+        assert_eq!(
+            copy(Path::new("clip.GIF"), &config),
+            Path::new("dist/clip.GIF")
+        );
+    }
+
+    #[test]
+    fn extension_rules_take_precedence_over_category_rules() {
+        let config = Config::parse(
+            r#"
+            max-file-size = 20
+            [categories.video]
+            max-file-size = 500
+            [categories.design]
+            action = "skip"
+            [extensions.mov]
+            max-file-size = 50
+            [extensions.psb]
+            action = "copy"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.max_file_size(Path::new("intro.MP4")),
+            Some(500 * MIB)
+        );
+        assert_eq!(config.max_file_size(Path::new("raw.mov")), Some(50 * MIB));
+        assert_eq!(config.max_file_size(Path::new("notes.pdf")), None);
+        assert_eq!(config.action(Path::new("cover.psd")), Action::Skip);
+        assert_eq!(config.action(Path::new("poster.psb")), Action::Copy);
+        let skipped = plan_file(Path::new("cover.psd"), 0, Path::new("dist"), 0, &config);
+        assert!(skipped.is_none());
+    }
+
+    #[test]
+    fn sidecars_take_precedence_over_every_config() {
+        let config = Config::parse(
+            r#"
+            thumbnails = false
+            [extensions.jpg]
+            quality = 70
+            "#,
+        )
+        .unwrap();
+        let sidecar = Sidecar::parse(
+            r#"
+            quality = 95
+            thumbnails = true
+            gravity = "north-east"
+            "#,
+        )
+        .unwrap();
+        let hero = convert(
+            "hero.jpg",
+            &config.with_sidecar(Path::new("hero.JPG"), &sidecar),
+        );
+        assert_eq!(hero.quality, 95);
+        assert!(hero.thumb.is_some());
+        assert_eq!(hero.gravity, Some(Gravity::NorthEast));
+        assert_eq!(convert("other.jpg", &config).quality, 70);
+        let copied = Sidecar::parse(r#"action = "copy""#).unwrap();
+        let config = config.with_sidecar(Path::new("logo.png"), &copied);
+        assert_eq!(config.action(Path::new("logo.png")), Action::Copy);
+        assert_eq!(
+            Sidecar::path(Path::new("photos/hero.jpg")),
+            Path::new("photos/hero.jpg.toml")
+        );
+    }
+
+    #[test]
+    fn kept_originals_keep_the_source_path() {
+        let config = Config::parse("keep-originals = true\nflatten = true").unwrap();
+        let variants = convert("photos/Rally.PNG", &config);
+        assert_eq!(variants.default, Path::new("dist/photos-Rally.jpg"));
+        assert_eq!(
+            variants.original.as_deref(),
+            Some(Path::new("dist/originals/photos/Rally.PNG"))
+        );
+        assert_eq!(convert("a.png", &Config::default()).original, None);
+    }
+
+    #[test]
+    fn extensionless_files_are_copied_as_they_are() {
+        let destination = copy(Path::new("docs/README"), &Config::default());
+        assert_eq!(destination, Path::new("dist/docs/README"));
+    }
+
+    #[test]
+    fn suffixes_follow_the_stem() {
+        let mut job = Job {
+            source: "a.b.c.png".into(),
+            relative: "a.b.c.png".into(),
+            kind: JobKind::ConvertImage(convert("a.b.c.png", &Config::default())),
+        };
+        job.add_suffix("-2");
+        let outputs: Vec<_> = job.outputs().into_iter().map(|(_, path)| path).collect();
+        assert_eq!(
+            outputs,
+            [
+                Path::new("dist/a.b.c-2.jpg"),
+                Path::new("dist/a.b.c-2_high.jpg"),
+                Path::new("dist/a.b.c-2_thumb.jpg")
+            ]
+        );
+
+        let mut job = Job {
+            source: "README".into(),
+            relative: "README".into(),
+            kind: JobKind::Copy {
+                destination: copy(Path::new("README"), &Config::default()),
+            },
+        };
+        job.add_suffix("-2");
+        assert_eq!(job.outputs()[0].1, Path::new("dist/README-2"));
+    }
+
+    #[test]
+    fn flattened_names_keep_unicode() {
+        let config = Config::parse("flatten = true").unwrap();
+        let variants = convert("Fotos/Rallye Süd/a.jpg", &config);
+        assert_eq!(variants.default, Path::new("dist/Fotos-Rallye Süd-a.jpg"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_names_are_not_mangled() {
+        use std::os::unix::ffi::OsStrExt;
+
+        // "caf\xe9" in Latin-1
+        let stem = OsStr::from_bytes(b"caf\xe9");
+        let mut relative = stem.to_owned();
+        relative.push(".jpg");
+        let Some(JobKind::ConvertImage(variants)) = plan_file(
+            Path::new(&relative),
+            0,
+            Path::new("dist"),
+            0,
+            &Config::default(),
+        ) else {
+            panic!("not converted");
+        };
+        assert_eq!(
+            variants.default.file_name().unwrap().as_bytes(),
+            b"caf\xe9.jpg"
+        );
+        assert_eq!(
+            variants.high.file_name().unwrap().as_bytes(),
+            b"caf\xe9_high.jpg"
+        );
+
+        let mut job = Job {
+            source: relative.clone().into(),
+            relative: relative.into(),
+            kind: JobKind::ConvertImage(variants),
+        };
+        job.add_suffix("-2");
+        assert_eq!(
+            job.outputs()[1].1.file_name().unwrap().as_bytes(),
+            b"caf\xe9-2_high.jpg"
+        );
+
+        let destination = copy(
+            Path::new(OsStr::from_bytes(b"notes \xff.txt")),
+            &Config::default(),
+        );
+        assert_eq!(
+            destination.file_name().unwrap().as_bytes(),
+            b"notes \xff.txt"
+        );
+    }
+}
@@ -0,0 +1,52 @@
+//! Presets for static hosts, picking the header and redirect files each one
+//! reads from the root of the site.
+
+use crate::{headers::HeadersFormat, redirects::RedirectFormat};
+
+/// An empty file that stops GitHub Pages from running Jekyll, which leaves
+/// out files whose names start with `_` or `.`
+pub const NOJEKYLL_FILE_NAME: &str = ".nojekyll";
+
+/// The hosts there are presets for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum Platform {
+    /// `_headers` and `_redirects`
+    Netlify,
+    /// `_headers` and `_redirects`, with Cloudflare's limits on their rules
+    CloudflarePages,
+    /// A `.nojekyll`. GitHub Pages can't set headers or redirect
+    GithubPages,
+}
+
+impl Platform {
+    pub fn headers(self) -> Option<HeadersFormat> {
+        match self {
+            Platform::Netlify | Platform::CloudflarePages => Some(HeadersFormat::Netlify),
+            Platform::GithubPages => None,
+        }
+    }
+
+    pub fn redirects(self) -> Option<RedirectFormat> {
+        match self {
+            Platform::Netlify | Platform::CloudflarePages => Some(RedirectFormat::Netlify),
+            Platform::GithubPages => None,
+        }
+    }
+
+    /// The number of header rules the host reads, ignoring the rest
+    pub fn max_headers(self) -> Option<usize> {
+        match self {
+            Platform::CloudflarePages => Some(100),
+            _ => None,
+        }
+    }
+
+    /// The number of redirects the host reads, ignoring the rest
+    pub fn max_redirects(self) -> Option<usize> {
+        match self {
+            Platform::CloudflarePages => Some(2000),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,56 @@
+//! Lowering the CPU and I/O priority of the converter. Threads and child
+//! processes started afterwards inherit the priority of the main thread.
+
+use color_eyre::eyre::{eyre, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum IoPriority {
+    /// Only use the disk when no other process needs it
+    Idle,
+    /// The lowest priority of the normal scheduling class
+    Low,
+}
+
+/// Sets the niceness of the process, from 0 (normal) to 19 (lowest)
+#[cfg(unix)]
+pub fn set_nice(nice: i32) -> Result<()> {
+    // SAFETY: setpriority has no memory safety requirements
+    let result = unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) };
+    if result != 0 {
+        return Err(eyre!(
+            "setting the priority to {nice}: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn set_nice(_nice: i32) -> Result<()> {
+    Err(eyre!("--nice is only supported on Unix"))
+}
+
+#[cfg(target_os = "linux")]
+pub fn set_io_priority(priority: IoPriority) -> Result<()> {
+    const IOPRIO_WHO_PROCESS: libc::c_long = 1;
+    const IOPRIO_CLASS_SHIFT: libc::c_long = 13;
+    let value = match priority {
+        IoPriority::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        // The best effort class at its lowest level
+        IoPriority::Low => (2 << IOPRIO_CLASS_SHIFT) | 7,
+    };
+    // SAFETY: ioprio_set only takes integer arguments
+    let result = unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, value) };
+    if result != 0 {
+        return Err(eyre!(
+            "setting the I/O priority: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_io_priority(_priority: IoPriority) -> Result<()> {
+    Err(eyre!("--io-priority is only supported on Linux"))
+}
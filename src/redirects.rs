@@ -0,0 +1,81 @@
+//! Redirects from the paths of sources to the outputs that replace them, for
+//! sources whose outputs are named differently, e.g. by a new extension,
+//! `slug-names` or `flatten`. Links to the sources keep working when the
+//! redirects are added to the web server.
+
+use std::collections::BTreeMap;
+
+use crate::manifest::{percent_encode, Manifest};
+use crate::Variant;
+
+/// The names of the redirect files in the destination folder, by format
+pub const NETLIFY_FILE_NAME: &str = "_redirects";
+pub const NGINX_FILE_NAME: &str = "redirects.map";
+pub const JSON_FILE_NAME: &str = "redirects.json";
+
+/// The formats redirects are written in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "native", derive(clap::ValueEnum))]
+pub enum RedirectFormat {
+    /// A Netlify `_redirects` file, which Cloudflare Pages reads too
+    Netlify,
+    /// Entries of an nginx `map` block
+    Nginx,
+    /// A JSON object of old to new paths, as in the manifest
+    Json,
+}
+
+impl RedirectFormat {
+    /// The name of the file in the destination folder
+    pub fn file_name(self) -> &'static str {
+        match self {
+            RedirectFormat::Netlify => NETLIFY_FILE_NAME,
+            RedirectFormat::Nginx => NGINX_FILE_NAME,
+            RedirectFormat::Json => JSON_FILE_NAME,
+        }
+    }
+}
+
+/// Maps the path of every source whose default output is named differently
+/// to that output, both below `base`, the path the destination folder is
+/// served at
+pub fn redirects(manifest: &Manifest, base: &str) -> BTreeMap<String, String> {
+    let base = base.trim_end_matches('/');
+    manifest
+        .assets
+        .iter()
+        .filter_map(|(source, outputs)| {
+            let output = outputs.get(Variant::Default.name())?;
+            (output != source).then(|| (format!("{base}/{source}"), format!("{base}/{output}")))
+        })
+        .collect()
+}
+
+/// Writes `redirects` in `format`. The paths are percent encoded but for the
+/// old paths for nginx, which decodes `$uri` before matching it, and JSON,
+/// which keeps them as in the manifest.
+pub fn render(redirects: &BTreeMap<String, String>, format: RedirectFormat) -> String {
+    match format {
+        RedirectFormat::Netlify => redirects
+            .iter()
+            .map(|(old, new)| format!("{} {} 301\n", percent_encode(old), percent_encode(new)))
+            .collect(),
+        RedirectFormat::Nginx => {
+            let mut map = String::from(
+                "# Include in a map block, e.g.\n\
+                 # map $uri $redirect_uri { include redirects.map; }\n\
+                 # with: if ($redirect_uri) { return 301 $redirect_uri; }\n",
+            );
+            for (old, new) in redirects {
+                let old = old.replace('\\', "\\\\").replace('"', "\\\"");
+                map.push_str(&format!("\"{old}\" \"{}\";\n", percent_encode(new)));
+            }
+            map
+        }
+        RedirectFormat::Json => {
+            let mut json = serde_json::to_string_pretty(redirects).unwrap_or_default();
+            json.push('\n');
+            json
+        }
+    }
+}
@@ -0,0 +1,192 @@
+//! Compares the assets with what a site's sources reference: HTML, templates,
+//! stylesheets, scripts and Markdown are scanned for paths, which match a
+//! source when they end in the path of the source or of one of its outputs.
+//! Sources nothing matches can likely be pruned, and references to asset
+//! files that match nothing are likely broken.
+//!
+//! References are found by splitting the text at quotes, brackets, commas and
+//! whitespace, so paths with unencoded spaces or commas are missed.
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{Result, WrapErr};
+use walkdir::WalkDir;
+
+use crate::{
+    formats,
+    manifest::{percent_decode, url_path},
+    Job,
+};
+
+/// The extensions of the files in a site that are scanned for references
+pub const SITE_EXTENSIONS: [&str; 24] = [
+    "html", "htm", "css", "scss", "sass", "less", "md", "markdown", "mdx", "js", "mjs", "jsx",
+    "ts", "tsx", "vue", "svelte", "astro", "njk", "hbs", "liquid", "erb", "php", "twig", "yml",
+];
+
+/// A path in a file of the site
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Reference {
+    /// Relative to the site folder
+    pub file: PathBuf,
+    /// Counted from 1
+    pub line: usize,
+    /// As it is written
+    pub target: String,
+}
+
+/// What [`usage`] found
+#[derive(Debug, Default)]
+pub struct Usage<'a> {
+    /// The jobs of the sources no reference matches
+    pub unreferenced: Vec<&'a Job>,
+    /// References to files with the extension of an asset that match no
+    /// source and no file in the site
+    pub dangling: Vec<Reference>,
+    /// The number of files of the site that were scanned
+    pub scanned: usize,
+}
+
+/// Scans the site at `site` and matches its references against the sources
+/// of `jobs` and their outputs in `destination`. Files inside `skip`, like the
+/// asset and destination folders, aren't scanned.
+pub fn usage<'a>(
+    jobs: &'a [Job],
+    destination: &Path,
+    site: &Path,
+    skip: &[&Path],
+) -> Result<Usage<'a>> {
+    // The paths every job is known by, indexed by file name to find the
+    // candidates for a reference quickly
+    let mut by_name: HashMap<String, Vec<(String, usize)>> = HashMap::new();
+    let mut extensions = HashSet::new();
+    for (i, job) in jobs.iter().enumerate() {
+        let outputs = job.outputs();
+        let outputs = outputs
+            .iter()
+            .filter_map(|(_, path)| path.strip_prefix(destination).ok());
+        for path in std::iter::once(job.relative.as_path()).chain(outputs) {
+            extensions.extend(formats::extension(path));
+            let path = url_path(path);
+            let name = path.rsplit('/').next().unwrap_or_default().to_string();
+            by_name.entry(name).or_default().push((path, i));
+        }
+    }
+    let mut usage = Usage::default();
+    let mut referenced = vec![false; jobs.len()];
+    let files = site_files(site, skip);
+    usage.scanned = files.len();
+    for file in files {
+        for reference in scan(site, &file)? {
+            let Some(target) = normalize(&reference.target) else {
+                continue;
+            };
+            let name = target.rsplit('/').next().unwrap_or_default();
+            let mut matched = false;
+            for (path, i) in by_name.get(name).into_iter().flatten() {
+                if target == *path || target.ends_with(&format!("/{path}")) {
+                    referenced[*i] = true;
+                    matched = true;
+                }
+            }
+            let is_asset = formats::extension(Path::new(&target))
+                .is_some_and(|extension| extensions.contains(&extension));
+            if !matched && is_asset && !exists_in_site(site, &file, &reference.target) {
+                usage.dangling.push(reference);
+            }
+        }
+    }
+    usage.unreferenced = jobs
+        .iter()
+        .zip(referenced)
+        .filter(|(_, referenced)| !referenced)
+        .map(|(job, _)| job)
+        .collect();
+    Ok(usage)
+}
+
+/// The files of the site with one of the [`SITE_EXTENSIONS`], leaving out
+/// hidden folders like `.git`, `node_modules` and the folders in `skip`
+fn site_files(site: &Path, skip: &[&Path]) -> Vec<PathBuf> {
+    let skip: Vec<PathBuf> = skip
+        .iter()
+        .filter_map(|path| path.canonicalize().ok())
+        .collect();
+    WalkDir::new(site)
+        .sort_by_file_name()
+        .into_iter()
+        .filter_entry(|entry| {
+            let name = entry.file_name().to_string_lossy();
+            let is_hidden = entry.depth() > 0 && name.starts_with('.');
+            let is_skipped = entry
+                .path()
+                .canonicalize()
+                .is_ok_and(|path| skip.contains(&path));
+            !is_hidden && name != "node_modules" && !is_skipped
+        })
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.file_type().is_file())
+        .filter(|entry| {
+            formats::extension(entry.path())
+                .is_some_and(|extension| SITE_EXTENSIONS.contains(&extension.as_str()))
+        })
+        .map(|entry| entry.into_path())
+        .collect()
+}
+
+/// The paths with an extension in `file`
+fn scan(site: &Path, file: &Path) -> Result<Vec<Reference>> {
+    let bytes = std::fs::read(file).wrap_err_with(|| format!("reading {}", file.display()))?;
+    let text = String::from_utf8_lossy(&bytes);
+    let relative = file.strip_prefix(site).unwrap_or(file).to_path_buf();
+    let mut references = Vec::new();
+    for (i, line) in text.lines().enumerate() {
+        let tokens = line.split(|c: char| c.is_whitespace() || "\"'`()<>[]{},;|=".contains(c));
+        for token in tokens {
+            let token = token.trim_end_matches(['.', ':', '!', '?', '*']);
+            let name = token.rsplit('/').next().unwrap_or_default();
+            let has_extension = name
+                .rsplit_once('.')
+                .is_some_and(|(stem, extension)| !stem.is_empty() && !extension.is_empty());
+            if has_extension {
+                references.push(Reference {
+                    file: relative.clone(),
+                    line: i + 1,
+                    target: token.to_string(),
+                });
+            }
+        }
+    }
+    Ok(references)
+}
+
+/// The path of a reference without its query, fragment, `.` and `..`, or
+/// `None` for URLs of other sites and data URLs
+fn normalize(target: &str) -> Option<String> {
+    let path = target.split(['?', '#']).next().unwrap_or_default();
+    let scheme = path.split_once(':').map(|(scheme, _)| scheme);
+    if path.starts_with("//") || scheme.is_some_and(|scheme| !scheme.contains('/')) {
+        return None;
+    }
+    let path = percent_decode(path);
+    let components: Vec<&str> = path
+        .split('/')
+        .filter(|c| !c.is_empty() && *c != "." && *c != "..")
+        .collect();
+    (!components.is_empty()).then(|| components.join("/"))
+}
+
+/// Whether `target` is a file of the site, relative to `file` or to the
+/// site folder
+fn exists_in_site(site: &Path, file: &Path, target: &str) -> bool {
+    let path = percent_decode(target.split(['?', '#']).next().unwrap_or_default());
+    let from_file = match path.starts_with('/') {
+        true => None,
+        false => file.parent().map(|folder| folder.join(&path)),
+    };
+    from_file.is_some_and(|path| path.is_file())
+        || site.join(path.trim_start_matches('/')).is_file()
+}
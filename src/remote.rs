@@ -0,0 +1,127 @@
+//! Source assets that live on a web server or in object storage.
+//!
+//! A sources file lists one URL per line, optionally followed by the SHA-256 of
+//! the file in hex. Empty lines and lines starting with `#` are ignored:
+//!
+//! ```text
+//! https://bucket.example.com/photos/2023/beach.jpg 9f86d081884c7d65...
+//! https://bucket.example.com/videos/intro.mp4
+//! ```
+//!
+//! Files are downloaded into a cache folder at the path of the URL, so the
+//! example above is converted as if `photos/2023/beach.jpg` was in the asset
+//! folder.
+
+use std::{
+    fs::File,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+
+use crate::paths;
+
+#[derive(Debug, Clone)]
+pub struct RemoteSource {
+    pub url: String,
+    pub sha256: Option<String>,
+    /// Where the file is placed inside the cache folder
+    pub path: PathBuf,
+}
+
+pub fn parse_sources(text: &str) -> Result<Vec<RemoteSource>> {
+    text.lines()
+        .enumerate()
+        .map(|(i, line)| (i, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(i, line)| {
+            let mut columns = line.split_whitespace();
+            let url = columns.next().expect("line is not empty").to_string();
+            let sha256 = columns.next().map(|s| s.to_lowercase());
+            let path = path_from_url(&url)
+                .ok_or_else(|| eyre!("line {}: can't derive a file path from {url}", i + 1))?;
+            Ok(RemoteSource { url, sha256, path })
+        })
+        .collect()
+}
+
+/// `https://host/a/b.jpg?x=1` becomes `a/b.jpg`
+fn path_from_url(url: &str) -> Option<PathBuf> {
+    let (_, rest) = url.split_once("://")?;
+    let path = rest.split(['?', '#']).next()?;
+    let (_host, path) = path.split_once('/')?;
+    let path = PathBuf::from(path);
+    // Don't let a URL escape the cache folder
+    (paths::is_plain_relative(&path) && path.file_name().is_some()).then_some(path)
+}
+
+/// Downloads every source that isn't already in `cache` and returns the
+/// paths of all of them inside `cache`
+pub fn fetch(sources: &[RemoteSource], cache: &Path) -> Result<Vec<PathBuf>> {
+    sources
+        .iter()
+        .map(|source| {
+            let path = cache.join(&source.path);
+            if !is_cached(source, &path)? {
+                download(source, &path).wrap_err_with(|| format!("downloading {}", source.url))?;
+            }
+            Ok(path)
+        })
+        .collect()
+}
+
+fn is_cached(source: &RemoteSource, path: &Path) -> Result<bool> {
+    if !path.is_file() {
+        return Ok(false);
+    }
+    match &source.sha256 {
+        Some(expected) => Ok(sha256_file(path)? == *expected),
+        None => Ok(true),
+    }
+}
+
+fn download(source: &RemoteSource, path: &Path) -> Result<()> {
+    tracing::info!("Downloading {}", source.url);
+    if let Some(p) = path.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    // Download next to the destination so a partial file is never mistaken for a cached one
+    let partial = path.with_extension("partial");
+    let mut reader = ureq::get(&source.url).call()?.into_reader();
+    let mut file = File::create(&partial)?;
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        file.write_all(&buffer[..read])?;
+    }
+    drop(file);
+    let actual = hex(&hasher.finalize());
+    if let Some(expected) = &source.sha256 {
+        if actual != *expected {
+            std::fs::remove_file(&partial)?;
+            return Err(eyre!(
+                "checksum mismatch: expected {expected}, got {actual}"
+            ));
+        }
+    }
+    std::fs::rename(&partial, path)?;
+    Ok(())
+}
+
+pub fn sha256_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hex(&hasher.finalize()))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
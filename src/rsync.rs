@@ -0,0 +1,39 @@
+//! Syncing a converted folder to a remote host with rsync over SSH.
+
+use std::{path::Path, process::Command};
+
+use color_eyre::eyre::{eyre, Result};
+
+use crate::lock;
+
+/// Whether `destination` is an rsync style `[user@]host:path` rather than a
+/// local path. Like rsync, a colon before the first slash makes it remote.
+pub fn is_remote(destination: &str) -> bool {
+    match destination.split_once(':') {
+        // A single letter before the colon is a Windows drive, and paths like
+        // \\?\C:\dist have one after a backslash
+        Some((host, _)) => host.len() > 1 && !host.contains(['/', '\\']),
+        None => false,
+    }
+}
+
+/// Copies the contents of `dir` to `remote`. rsync only transfers files whose
+/// size or modification time differ from the copy on the remote, and never
+/// deletes remote files since `dir` may only hold part of the assets.
+pub fn sync(dir: &Path, remote: &str) -> Result<()> {
+    // The trailing slash syncs the contents of the folder rather than the folder itself
+    let mut source = dir.as_os_str().to_owned();
+    source.push("/");
+    let status = Command::new("rsync")
+        .args(["--archive", "--compress"])
+        .arg("--rsh=ssh")
+        .arg(format!("--exclude=/{}", lock::FILE_NAME))
+        .arg(&source)
+        .arg(remote)
+        .status()
+        .map_err(|e| crate::spawn_error("rsync", e))?;
+    if !status.success() {
+        return Err(eyre!("rsync to {remote} failed: {status}"));
+    }
+    Ok(())
+}
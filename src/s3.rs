@@ -0,0 +1,155 @@
+//! Uploads to S3 compatible object storage (AWS S3, Cloudflare R2, Google Cloud
+//! Storage with HMAC keys, MinIO, ...) using path style requests signed with
+//! AWS Signature Version 4.
+
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use sha2::{Digest, Sha256};
+
+use crate::mime::content_type;
+
+#[derive(Debug, Clone)]
+pub struct S3Target {
+    /// E.g. `https://s3.eu-north-1.amazonaws.com` or
+    /// `https://<account>.r2.cloudflarestorage.com`
+    pub endpoint: String,
+    pub bucket: String,
+    /// `auto` for R2
+    pub region: String,
+    /// Prepended to every object key
+    pub prefix: String,
+    pub cache_control: Option<String>,
+    pub access_key: String,
+    pub secret_key: String,
+}
+
+impl S3Target {
+    /// Reads the credentials from `AWS_ACCESS_KEY_ID` and `AWS_SECRET_ACCESS_KEY`
+    pub fn credentials_from_env() -> Result<(String, String)> {
+        let access_key =
+            std::env::var("AWS_ACCESS_KEY_ID").wrap_err("AWS_ACCESS_KEY_ID is not set")?;
+        let secret_key =
+            std::env::var("AWS_SECRET_ACCESS_KEY").wrap_err("AWS_SECRET_ACCESS_KEY is not set")?;
+        Ok((access_key, secret_key))
+    }
+
+    /// Uploads a local file to `<prefix><key>`
+    pub fn upload(&self, file: &Path, key: &str) -> Result<()> {
+        let body = std::fs::read(file).wrap_err_with(|| format!("reading {}", file.display()))?;
+        let key = format!("{}{key}", self.prefix);
+        let path = format!("/{}/{}", self.bucket, uri_encode_path(&key));
+        let host = self
+            .endpoint
+            .split_once("://")
+            .map_or(self.endpoint.as_str(), |(_, host)| host)
+            .trim_end_matches('/');
+        let payload_hash = hex(&Sha256::digest(&body));
+        let (date, timestamp) = amz_date(SystemTime::now());
+
+        let signed_headers = "host;x-amz-content-sha256;x-amz-date";
+        let canonical_request = format!(
+            "PUT\n{path}\n\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\n{signed_headers}\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(&Sha256::digest(canonical_request.as_bytes()))
+        );
+        let mut key_material = hmac_sha256(
+            format!("AWS4{}", self.secret_key).as_bytes(),
+            date.as_bytes(),
+        );
+        for part in [self.region.as_str(), "s3", "aws4_request"] {
+            key_material = hmac_sha256(&key_material, part.as_bytes());
+        }
+        let signature = hex(&hmac_sha256(&key_material, string_to_sign.as_bytes()));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={signature}",
+            self.access_key
+        );
+
+        let url = format!("{}{path}", self.endpoint.trim_end_matches('/'));
+        let mut request = ureq::put(&url)
+            .set("Authorization", &authorization)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &timestamp)
+            .set("Content-Type", content_type(file));
+        if let Some(cache_control) = &self.cache_control {
+            request = request.set("Cache-Control", cache_control);
+        }
+        match request.send_bytes(&body) {
+            Ok(_) => Ok(()),
+            Err(ureq::Error::Status(status, response)) => Err(eyre!(
+                "uploading {key} failed with {status}: {}",
+                response.into_string().unwrap_or_default()
+            )),
+            Err(e) => Err(e).wrap_err_with(|| format!("uploading {key}")),
+        }
+    }
+}
+
+/// Percent encodes everything except unreserved characters and `/`
+fn uri_encode_path(path: &str) -> String {
+    path.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{b:02X}"),
+        })
+        .collect()
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK_SIZE: usize = 64;
+    let mut block = [0u8; BLOCK_SIZE];
+    if key.len() > BLOCK_SIZE {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(inner.finalize());
+    outer.finalize().to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Returns (`YYYYMMDD`, `YYYYMMDDTHHMMSSZ`) in UTC
+pub(crate) fn amz_date(time: SystemTime) -> (String, String) {
+    let seconds = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (seconds / 86400) as i64;
+    let time_of_day = seconds % 86400;
+    // Converts days since 1970-01-01 to a civil date, from Howard Hinnant's algorithms
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    let date = format!("{year:04}{month:02}{day:02}");
+    let timestamp = format!(
+        "{date}T{:02}{:02}{:02}Z",
+        time_of_day / 3600,
+        time_of_day % 3600 / 60,
+        time_of_day % 60
+    );
+    (date, timestamp)
+}
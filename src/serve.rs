@@ -0,0 +1,190 @@
+//! A development server over the destination folder. A requested output that
+//! is missing or older than its source is converted before it is served, so
+//! pages can link to the right URLs without converting everything first.
+//! Files that aren't outputs of a source, like the manifest, are served as
+//! they are, and folders serve their index.html.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use tiny_http::{Header, Method, Request, Response, Server};
+use tracing::{debug, info, warn};
+use web_assets_converter::{
+    manifest::{percent_decode, url_path},
+    mime, paths, Job, JobKind, Outcome, Pipeline,
+};
+
+struct Outputs<P> {
+    pipeline: Pipeline,
+    plan: P,
+    /// The job writing every output, by its path in the destination folder
+    jobs: Mutex<HashMap<String, Job>>,
+    /// Held while a source is converted, so parallel requests for its
+    /// variants convert it once
+    converting: Mutex<HashMap<PathBuf, Arc<Mutex<()>>>>,
+}
+
+/// Serves the destination of `pipeline` on `listen` until the process is
+/// stopped, planning the jobs with `plan`
+pub fn run<P>(listen: &str, pipeline: Pipeline, plan: P) -> Result<()>
+where
+    P: Fn(&Pipeline) -> Result<Vec<Job>> + Sync,
+{
+    let outputs = Outputs {
+        pipeline,
+        plan,
+        jobs: Mutex::default(),
+        converting: Mutex::default(),
+    };
+    outputs.replan()?;
+    let server = Server::http(listen)
+        .map_err(|e| eyre!(e))
+        .wrap_err_with(|| format!("listening on {listen}"))?;
+    info!(
+        "Serving {} on http://{listen}",
+        outputs.pipeline.destination().display()
+    );
+    std::thread::scope(|scope| {
+        for request in server.incoming_requests() {
+            let outputs = &outputs;
+            scope.spawn(move || {
+                let method = request.method().clone();
+                let url = request.url().to_string();
+                if let Err(e) = outputs.handle(request) {
+                    tracing::error!("{method} {url}: {e:?}");
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+impl<P> Outputs<P>
+where
+    P: Fn(&Pipeline) -> Result<Vec<Job>> + Sync,
+{
+    fn handle(&self, request: Request) -> Result<()> {
+        if !matches!(request.method(), Method::Get | Method::Head) {
+            return Ok(request.respond(text(405, "only GET and HEAD are supported"))?);
+        }
+        let url = request.url();
+        let path = url.split(['?', '#']).next().unwrap_or_default();
+        let relative = percent_decode(path.trim_start_matches('/'));
+        if !relative.is_empty() && !paths::is_plain_relative(Path::new(&relative)) {
+            return Ok(request.respond(text(400, "bad path"))?);
+        }
+        if let Err(e) = self.update(relative.trim_end_matches('/')) {
+            warn!("Not converting {relative}: {e:#}");
+            return Ok(request.respond(text(500, format!("{e:#}")))?);
+        }
+        let mut path = self.pipeline.destination().join(&relative);
+        if path.is_dir() {
+            path.push("index.html");
+        }
+        match std::fs::File::open(&path) {
+            Ok(file) => {
+                let response = Response::from_file(file)
+                    .with_header(header("Content-Type", mime::content_type(&path)))
+                    .with_header(header("Cache-Control", "no-cache"));
+                Ok(request.respond(response)?)
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                Ok(request.respond(text(404, "not found"))?)
+            }
+            Err(e) => Err(e).wrap_err_with(|| format!("reading {}", path.display())),
+        }
+    }
+
+    /// Converts the source of the output at `relative` if the output is
+    /// missing or stale. Sources added since the last request are planned
+    /// when nothing writes it.
+    fn update(&self, relative: &str) -> Result<()> {
+        if relative.is_empty() {
+            return Ok(());
+        }
+        let mut job = self.jobs.lock().unwrap().get(relative).cloned();
+        if job.is_none() && !self.pipeline.destination().join(relative).exists() {
+            self.replan()?;
+            job = self.jobs.lock().unwrap().get(relative).cloned();
+        }
+        match job {
+            Some(job) => self.run_job(job),
+            None => Ok(()),
+        }
+    }
+
+    fn replan(&self) -> Result<()> {
+        let jobs = (self.plan)(&self.pipeline)?;
+        let destination = self.pipeline.destination();
+        let jobs: HashMap<_, _> = jobs
+            .into_iter()
+            .flat_map(|job| {
+                job.outputs()
+                    .into_iter()
+                    .filter_map(|(_, path)| path.strip_prefix(destination).ok())
+                    .map(|path| (url_path(path), job.clone()))
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        debug!("planned {} outputs", jobs.len());
+        *self.jobs.lock().unwrap() = jobs;
+        Ok(())
+    }
+
+    fn run_job(&self, job: Job) -> Result<()> {
+        let lock = self
+            .converting
+            .lock()
+            .unwrap()
+            .entry(job.source.clone())
+            .or_default()
+            .clone();
+        let _converting = lock.lock().unwrap();
+        if is_up_to_date(&job) {
+            return Ok(());
+        }
+        // The outputs of the original are copied, so it goes first
+        if let JobKind::Duplicate { links, .. } = &job.kind {
+            let destination = self.pipeline.destination();
+            let originals: Vec<Job> = links
+                .iter()
+                .filter_map(|link| link.original.strip_prefix(destination).ok())
+                .filter_map(|path| self.jobs.lock().unwrap().get(&url_path(path)).cloned())
+                .collect();
+            if let Some(original) = originals.into_iter().next() {
+                self.run_job(original)?;
+            }
+        }
+        info!("Converting {}", job.relative.display());
+        let result = self.pipeline.run_job(job)?;
+        match result.outcome {
+            Outcome::Failed(e) | Outcome::Corrupt(e) => Err(e),
+            Outcome::Interrupted => Err(eyre!("interrupted")),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Whether every output of `job` exists and was written after its source
+/// was last modified
+fn is_up_to_date(job: &Job) -> bool {
+    let modified = |path: &Path| path.metadata().and_then(|m| m.modified());
+    let Ok(source) = modified(&job.source) else {
+        return false;
+    };
+    job.outputs()
+        .iter()
+        .all(|(_, path)| modified(path).is_ok_and(|output| output >= source))
+}
+
+fn header(name: &str, value: &str) -> Header {
+    Header::from_bytes(name, value).expect("valid header")
+}
+
+fn text(status: u16, message: impl Into<String>) -> Response<std::io::Cursor<Vec<u8>>> {
+    Response::from_string(message).with_status_code(status)
+}
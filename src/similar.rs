@@ -0,0 +1,126 @@
+//! Finds source images that look nearly the same, like exports of one photo
+//! at different sizes or with slight edits, by comparing perceptual hashes.
+//! Unlike the exact duplicates found by hashing the bytes, these are all
+//! converted, so they are only reported.
+
+use std::{
+    path::Path,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use color_eyre::eyre::{Report, Result, WrapErr};
+use image::imageops::FilterType;
+
+use crate::{formats, Job};
+
+/// The default of the largest number of differing bits between the hashes of
+/// images that are reported as similar, out of 64
+pub const DEFAULT_MAX_DISTANCE: u32 = 6;
+
+/// The difference hash of the image at `path`: it is shrunk to 9 by 8 gray
+/// pixels, and each bit tells whether a pixel is brighter than the one to its
+/// right. Resizing, recompressing and small edits change few of the bits.
+pub fn perceptual_hash(path: &Path) -> Result<u64> {
+    let image = image::open(path).wrap_err("decoding the image")?;
+    let pixels = image.resize_exact(9, 8, FilterType::Triangle).into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = pixels.get_pixel(x, y)[0] > pixels.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
+    }
+    Ok(hash)
+}
+
+/// The number of bits two hashes differ in
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+/// Images whose hashes are within the maximum distance of another in the group
+#[derive(Debug)]
+pub struct Group<'a> {
+    /// The jobs of the images with their hashes, in the order of the jobs
+    pub images: Vec<(&'a Job, u64)>,
+}
+
+/// What [`groups`] found
+#[derive(Debug, Default)]
+pub struct Similar<'a> {
+    pub groups: Vec<Group<'a>>,
+    /// Images that couldn't be decoded, with the error
+    pub failed: Vec<(&'a Job, Report)>,
+    /// The number of images that were compared
+    pub compared: usize,
+}
+
+/// Hashes the source images of `jobs` on `threads` threads and groups the
+/// ones that are at most `max_distance` bits apart, also through other images
+/// of the group
+pub fn groups(jobs: &[Job], max_distance: u32, threads: usize) -> Similar<'_> {
+    let images: Vec<&Job> = jobs
+        .iter()
+        .filter(|job| formats::is_image(&job.source))
+        .collect();
+    let next = AtomicUsize::new(0);
+    let mut hashes: Vec<(usize, Result<u64>)> = std::thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads.max(1))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut hashes = Vec::new();
+                    loop {
+                        let i = next.fetch_add(1, Ordering::Relaxed);
+                        let Some(job) = images.get(i) else {
+                            return hashes;
+                        };
+                        hashes.push((i, perceptual_hash(&job.source)));
+                    }
+                })
+            })
+            .collect();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().expect("hashing thread panicked"))
+            .collect()
+    });
+    hashes.sort_by_key(|(i, _)| *i);
+    let mut similar = Similar::default();
+    let mut hashed = Vec::new();
+    for (i, hash) in hashes {
+        match hash {
+            Ok(hash) => hashed.push((images[i], hash)),
+            Err(e) => similar.failed.push((images[i], e)),
+        }
+    }
+    similar.compared = hashed.len();
+    // Union-find over every pair that is close enough
+    let mut parents: Vec<usize> = (0..hashed.len()).collect();
+    fn root(parents: &mut [usize], mut i: usize) -> usize {
+        while parents[i] != i {
+            parents[i] = parents[parents[i]];
+            i = parents[i];
+        }
+        i
+    }
+    for a in 0..hashed.len() {
+        for b in a + 1..hashed.len() {
+            if distance(hashed[a].1, hashed[b].1) <= max_distance {
+                let (root_a, root_b) = (root(&mut parents, a), root(&mut parents, b));
+                parents[root_b.max(root_a)] = root_a.min(root_b);
+            }
+        }
+    }
+    let mut groups: Vec<Vec<usize>> = vec![Vec::new(); hashed.len()];
+    for i in 0..hashed.len() {
+        groups[root(&mut parents, i)].push(i);
+    }
+    similar.groups = groups
+        .into_iter()
+        .filter(|group| group.len() > 1)
+        .map(|group| Group {
+            images: group.into_iter().map(|i| hashed[i]).collect(),
+        })
+        .collect();
+    similar
+}
@@ -0,0 +1,70 @@
+//! Web safe output names, for sources named by people and cameras.
+
+use std::path::{Path, PathBuf};
+
+/// Lowercases `name`, transliterates it to ASCII, replaces whitespace with
+/// dashes and drops everything but letters, digits, dashes, underscores and
+/// dots, e.g. `Café Menu (2).JPG` becomes `cafe-menu-2.jpg`
+pub fn slug(name: &str) -> String {
+    let mut slug = String::with_capacity(name.len());
+    for c in deunicode::deunicode(name).chars() {
+        match c {
+            'a'..='z' | '0'..='9' | '_' | '.' => slug.push(c),
+            'A'..='Z' => slug.push(c.to_ascii_lowercase()),
+            '-' | ' ' | '\t' if !slug.is_empty() && !slug.ends_with('-') => slug.push('-'),
+            _ => {}
+        }
+    }
+    // Dashes before the extension are left from dropped characters
+    while let Some(i) = slug.find("-.") {
+        slug.remove(i);
+    }
+    let slug = slug.trim_end_matches('-');
+    match slug.is_empty() {
+        true => "file".to_string(),
+        false => slug.to_string(),
+    }
+}
+
+/// Slugs every component of a relative path
+pub fn slug_path(path: &Path) -> PathBuf {
+    path.iter().map(|c| slug(&c.to_string_lossy())).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accents_are_transliterated() {
+        assert_eq!(slug("Café Menu (2).JPG"), "cafe-menu-2.jpg");
+        assert_eq!(slug("Ångström über"), "angstrom-uber");
+    }
+
+    #[test]
+    fn separators_are_collapsed_and_trimmed() {
+        assert_eq!(slug("  --Hello -- World--.png"), "hello-world.png");
+        assert_eq!(slug("a\t\tb"), "a-b");
+        assert_eq!(slug("trailing - "), "trailing");
+    }
+
+    #[test]
+    fn extensions_are_lowercased() {
+        assert_eq!(slug("IMG_0042.JPEG"), "img_0042.jpeg");
+        assert_eq!(slug("archive.Tar.GZ"), "archive.tar.gz");
+    }
+
+    #[test]
+    fn names_without_anything_left_become_file() {
+        assert_eq!(slug("()!?"), "file");
+        assert_eq!(slug(" - "), "file");
+    }
+
+    #[test]
+    fn every_component_of_a_path_is_slugged() {
+        assert_eq!(
+            slug_path(Path::new("Summer Trip/Café Menu.JPG")),
+            Path::new("summer-trip/cafe-menu.jpg")
+        );
+    }
+}
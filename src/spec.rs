@@ -0,0 +1,173 @@
+//! Spec files list single images with an output of their own, for curated
+//! sets like a homepage carousel that need exact sizes, crops and names. They
+//! are processed along with the asset folder, or instead of it.
+//!
+//! A spec is a JSON array, or a CSV file with a header naming the same fields:
+//!
+//! ```json
+//! [
+//!   {
+//!     "source": "photos/summit.jpg",
+//!     "output": "home/carousel-1.webp",
+//!     "width": 1600,
+//!     "height": 900,
+//!     "gravity": "north"
+//!   },
+//!   { "source": "photos/team.png", "output": "home/team.jpg", "width": 800, "quality": 90 }
+//! ]
+//! ```
+//!
+//! ```csv
+//! source,output,width,height,gravity,quality
+//! photos/summit.jpg,home/carousel-1.webp,1600,900,north,
+//! photos/team.png,home/team.jpg,800,,,90
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Deserialize;
+
+use crate::{
+    config::{Format, Gravity},
+    formats, paths,
+};
+
+/// One output of a spec
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SpecEntry {
+    /// Relative to the first asset folder
+    pub source: PathBuf,
+    /// Relative to the destination folder. Its extension picks the format
+    pub output: PathBuf,
+    /// With only a width or a height the image is resized to it keeping its
+    /// proportions. With both it is resized to cover them and cropped.
+    #[serde(default)]
+    pub width: Option<u32>,
+    #[serde(default)]
+    pub height: Option<u32>,
+    /// The part of the image kept when it is cropped. Defaults to the center
+    #[serde(default)]
+    pub gravity: Option<Gravity>,
+    /// Defaults to the quality of the source in the config
+    #[serde(default)]
+    pub quality: Option<u8>,
+}
+
+impl SpecEntry {
+    /// The format of the output, from its extension
+    pub fn format(&self) -> Option<Format> {
+        match formats::extension(&self.output)?.as_str() {
+            "jpg" | "jpeg" => Some(Format::Jpg),
+            "webp" => Some(Format::Webp),
+            "png" => Some(Format::Png),
+            _ => None,
+        }
+    }
+
+    fn validate(&self) -> Result<()> {
+        for path in [&self.source, &self.output] {
+            if !paths::is_plain_relative(path) || path.file_name().is_none() {
+                return Err(eyre!(
+                    "{} must be a relative path to a file",
+                    path.display()
+                ));
+            }
+        }
+        if self.format().is_none() {
+            return Err(eyre!(
+                "{} must end in .jpg, .webp or .png",
+                self.output.display()
+            ));
+        }
+        if self.width == Some(0) || self.height == Some(0) {
+            return Err(eyre!("the width and height must be larger than 0"));
+        }
+        if let Some(quality) = self.quality.filter(|q| !(1..=100).contains(q)) {
+            return Err(eyre!("quality must be between 1 and 100, not {quality}"));
+        }
+        Ok(())
+    }
+}
+
+/// Reads the spec at `path`, as CSV if it ends in .csv and as JSON otherwise
+pub fn load(path: &Path) -> Result<Vec<SpecEntry>> {
+    let text =
+        std::fs::read_to_string(path).wrap_err_with(|| format!("reading {}", path.display()))?;
+    let is_csv = formats::extension(path).as_deref() == Some("csv");
+    match is_csv {
+        true => parse_csv(&text),
+        false => parse_json(&text),
+    }
+    .wrap_err_with(|| format!("spec {}", path.display()))
+}
+
+pub fn parse_json(text: &str) -> Result<Vec<SpecEntry>> {
+    let entries: Vec<SpecEntry> = serde_json::from_str(text)?;
+    for (i, entry) in entries.iter().enumerate() {
+        entry
+            .validate()
+            .wrap_err_with(|| format!("entry {}", i + 1))?;
+    }
+    Ok(entries)
+}
+
+/// Empty cells are left unset, and cells can be quoted like `"a, b.jpg"`
+pub fn parse_csv(text: &str) -> Result<Vec<SpecEntry>> {
+    let mut lines = text
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    let Some((_, header)) = lines.next() else {
+        return Ok(Vec::new());
+    };
+    let header = split_csv_line(header);
+    lines
+        .map(|(i, line)| {
+            let cells = split_csv_line(line);
+            if cells.len() > header.len() {
+                return Err(eyre!("line {}: more cells than in the header", i + 1));
+            }
+            let fields = header
+                .iter()
+                .zip(cells)
+                .filter(|(_, cell)| !cell.is_empty())
+                .map(|(name, cell)| {
+                    let is_number = ["width", "height", "quality"].contains(&name.as_str());
+                    let value = match cell.parse::<u64>() {
+                        Ok(number) if is_number => serde_json::Value::from(number),
+                        _ => serde_json::Value::from(cell),
+                    };
+                    (name.clone(), value)
+                })
+                .collect();
+            let entry: SpecEntry = serde_json::from_value(serde_json::Value::Object(fields))
+                .wrap_err_with(|| format!("line {}", i + 1))?;
+            entry
+                .validate()
+                .wrap_err_with(|| format!("line {}", i + 1))?;
+            Ok(entry)
+        })
+        .collect()
+}
+
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut cells = Vec::new();
+    let mut cell = String::new();
+    let mut quoted = false;
+    let mut chars = line.trim_end_matches('\r').chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                cell.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => cells.push(std::mem::take(&mut cell).trim().to_string()),
+            _ => cell.push(c),
+        }
+    }
+    cells.push(cell.trim().to_string());
+    cells
+}
@@ -0,0 +1,113 @@
+//! Per-file statistics of a run as CSV or JSON, to track how large the assets
+//! and their outputs grow over time.
+
+use std::{fmt::Write as _, path::Path};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use serde::Serialize;
+
+use crate::{formats, manifest::url_path, JobResult, Outcome, Variant};
+
+#[derive(Debug, Serialize)]
+pub struct FileStats {
+    /// The path of the source inside the asset folder
+    pub source: String,
+    pub action: &'static str,
+    pub source_size: u64,
+    pub default_size: Option<u64>,
+    pub high_size: Option<u64>,
+    pub thumb_size: Option<u64>,
+    /// How many times smaller the default variant is than the source
+    pub compression_ratio: Option<f64>,
+    pub duration_seconds: f64,
+    /// Why the file failed or was skipped as corrupt
+    pub error: Option<String>,
+}
+
+impl FileStats {
+    pub fn new(result: &JobResult) -> FileStats {
+        let size = |path: &Path| path.metadata().ok().map(|m| m.len());
+        let outputs = result.job.outputs();
+        let output_size = |variant: Variant| {
+            outputs
+                .iter()
+                .find(|(name, _)| *name == variant.name())
+                .and_then(|(_, path)| size(path))
+        };
+        let source_size = size(&result.job.source).unwrap_or(0);
+        let default_size = output_size(Variant::Default);
+        FileStats {
+            source: url_path(&result.job.relative),
+            action: result.outcome.name(),
+            source_size,
+            default_size,
+            high_size: output_size(Variant::High),
+            thumb_size: output_size(Variant::Thumb),
+            compression_ratio: default_size
+                .filter(|size| *size > 0)
+                .map(|size| source_size as f64 / size as f64),
+            duration_seconds: result.duration.as_secs_f64(),
+            error: match &result.outcome {
+                Outcome::Failed(e) | Outcome::Corrupt(e) => Some(format!("{e:#}")),
+                _ => None,
+            },
+        }
+    }
+}
+
+const CSV_HEADER: &str = "source,action,source_size,default_size,high_size,thumb_size,compression_ratio,duration_seconds,error";
+
+/// Quotes a CSV field if it contains a separator, quote or line break
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn to_csv(stats: &[FileStats]) -> String {
+    let optional = |value: Option<u64>| value.map(|v| v.to_string()).unwrap_or_default();
+    let mut out = format!("{CSV_HEADER}\n");
+    for file in stats {
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{}",
+            csv_field(&file.source),
+            file.action,
+            file.source_size,
+            optional(file.default_size),
+            optional(file.high_size),
+            optional(file.thumb_size),
+            file.compression_ratio
+                .map(|ratio| format!("{ratio:.3}"))
+                .unwrap_or_default(),
+            file.duration_seconds,
+            csv_field(file.error.as_deref().unwrap_or_default()),
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Writes the statistics of every result to `path`, as JSON if it ends in
+/// `.json` and as CSV if it ends in `.csv`
+pub fn write(path: &Path, results: &[JobResult]) -> Result<()> {
+    let stats: Vec<_> = results.iter().map(FileStats::new).collect();
+    let extension = formats::extension(path).unwrap_or_default();
+    let contents = match extension.as_str() {
+        "csv" => to_csv(&stats),
+        "json" => serde_json::to_string_pretty(&stats)?,
+        _ => {
+            return Err(eyre!(
+                "don't know how to write statistics to {}, use a .csv or .json file",
+                path.display()
+            ))
+        }
+    };
+    if let Some(p) = path.parent() {
+        std::fs::create_dir_all(p)?;
+    }
+    std::fs::write(path, contents)
+        .wrap_err_with(|| format!("writing statistics to {}", path.display()))
+}
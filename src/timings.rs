@@ -0,0 +1,63 @@
+//! Time spent in every stage of a run, for finding out where the bottleneck is.
+
+use std::{
+    collections::BTreeMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Stage {
+    /// How many times the stage ran
+    pub count: u32,
+    /// The sum of the durations. With parallel jobs this can exceed the wall time.
+    pub total: Duration,
+}
+
+pub struct Timer<'a> {
+    timings: &'a Timings,
+    stage: &'a str,
+    start: Instant,
+}
+
+impl Drop for Timer<'_> {
+    fn drop(&mut self) {
+        self.timings.record(self.stage, self.start.elapsed());
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Timings {
+    stages: Mutex<BTreeMap<String, Stage>>,
+}
+
+impl Timings {
+    pub fn record(&self, stage: &str, duration: Duration) {
+        let mut stages = self.stages.lock().unwrap();
+        let entry = stages.entry(stage.to_string()).or_default();
+        entry.count += 1;
+        entry.total += duration;
+    }
+
+    /// Starts timing `stage`, which is recorded when the timer is dropped
+    pub fn timer<'a>(&'a self, stage: &'a str) -> Timer<'a> {
+        Timer {
+            timings: self,
+            stage,
+            start: Instant::now(),
+        }
+    }
+
+    /// Every stage that ran, the slowest first
+    pub fn stages(&self) -> Vec<(String, Stage)> {
+        let mut stages: Vec<_> = self
+            .stages
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(name, stage)| (name.clone(), *stage))
+            .collect();
+        stages.sort_by_key(|(_, stage)| std::cmp::Reverse(stage.total));
+        stages
+    }
+}
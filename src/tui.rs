@@ -0,0 +1,290 @@
+//! A full screen view of a conversion for `--tui`: the source files as a tree
+//! with the status of every file, what every worker is doing and how much
+//! smaller the outputs are. Jobs can be paused and the run cancelled.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+
+use color_eyre::eyre::Result;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style, Stylize},
+    text::{Line, Span},
+    widgets::{Block, Gauge, List, ListItem, ListState, Paragraph},
+    Frame,
+};
+use web_assets_converter::{stats::FileStats, Job, JobResult, Outcome, Pipeline, MIB};
+
+use crate::{logging, output};
+
+/// Set while the user has paused the run
+pub fn pause_flag() -> Arc<AtomicBool> {
+    static FLAG: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+    FLAG.get_or_init(Arc::default).clone()
+}
+
+enum Row {
+    Folder { name: String, depth: usize },
+    File { name: String, depth: usize },
+}
+
+/// What the UI thread draws, updated as jobs finish
+struct State {
+    rows: Vec<Row>,
+    /// The row of every source, relative to the source folder
+    row_of: HashMap<PathBuf, usize>,
+    outcomes: HashMap<usize, &'static str>,
+    last_finished: Option<usize>,
+    finished: usize,
+    failed: usize,
+    source_bytes: u64,
+    output_bytes: u64,
+    start: Instant,
+}
+
+impl State {
+    fn new(jobs: &[Job]) -> State {
+        let mut relatives: Vec<_> = jobs.iter().map(|job| job.relative.as_path()).collect();
+        relatives.sort();
+        let mut rows = Vec::new();
+        let mut row_of = HashMap::new();
+        let mut folder: Vec<String> = Vec::new();
+        for relative in relatives {
+            let parent: Vec<String> = relative
+                .parent()
+                .unwrap_or(Path::new(""))
+                .iter()
+                .map(|c| c.to_string_lossy().into_owned())
+                .collect();
+            let shared = folder
+                .iter()
+                .zip(&parent)
+                .take_while(|(a, b)| a == b)
+                .count();
+            for (depth, name) in parent.iter().enumerate().skip(shared) {
+                rows.push(Row::Folder {
+                    name: name.clone(),
+                    depth,
+                });
+            }
+            row_of.insert(relative.to_path_buf(), rows.len());
+            rows.push(Row::File {
+                name: relative
+                    .file_name()
+                    .map(|n| n.to_string_lossy().into_owned())
+                    .unwrap_or_default(),
+                depth: parent.len(),
+            });
+            folder = parent;
+        }
+        State {
+            rows,
+            row_of,
+            outcomes: HashMap::new(),
+            last_finished: None,
+            finished: 0,
+            failed: 0,
+            source_bytes: 0,
+            output_bytes: 0,
+            start: Instant::now(),
+        }
+    }
+
+    fn record(&mut self, result: &JobResult) {
+        self.finished += 1;
+        if let Some(&row) = self.row_of.get(&result.job.relative) {
+            self.outcomes.insert(row, result.outcome.name());
+            self.last_finished = Some(row);
+        }
+        match result.outcome {
+            Outcome::Failed(_) => self.failed += 1,
+            Outcome::Converted => {
+                let stats = FileStats::new(result);
+                self.source_bytes += stats.source_size;
+                self.output_bytes += stats.default_size.unwrap_or(0);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Runs the jobs while showing their progress full screen. `p` or space
+/// pauses and resumes starting new jobs and `q` or Ctrl-C cancels the run.
+/// The pipeline has to be built with [`pause_flag`] and `cancelled`.
+pub fn run_jobs(
+    pipeline: &Pipeline,
+    jobs: Vec<Job>,
+    cancelled: Arc<AtomicBool>,
+) -> Result<Vec<JobResult>> {
+    let total = jobs.len();
+    let state = Mutex::new(State::new(&jobs));
+    let done = AtomicBool::new(false);
+    let paused = pause_flag();
+    logging::hold(true);
+    let mut terminal = ratatui::init();
+    let results = std::thread::scope(|scope| {
+        let ui = scope.spawn(|| -> Result<()> {
+            let mut list = ListState::default();
+            while !done.load(Ordering::Relaxed) {
+                terminal.draw(|frame| {
+                    let state = state.lock().unwrap();
+                    draw(
+                        frame, &state, pipeline, total, &paused, &cancelled, &mut list,
+                    )
+                })?;
+                if !event::poll(Duration::from_millis(100))? {
+                    continue;
+                }
+                let Event::Key(key) = event::read()? else {
+                    continue;
+                };
+                if key.kind != KeyEventKind::Press {
+                    continue;
+                }
+                match key.code {
+                    KeyCode::Char('p') | KeyCode::Char(' ') => {
+                        paused.fetch_xor(true, Ordering::Relaxed);
+                    }
+                    KeyCode::Char('q') | KeyCode::Esc => cancelled.store(true, Ordering::Relaxed),
+                    KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                        cancelled.store(true, Ordering::Relaxed)
+                    }
+                    _ => {}
+                }
+            }
+            Ok(())
+        });
+        let results = pipeline.run_jobs(jobs, |result| state.lock().unwrap().record(result));
+        done.store(true, Ordering::Relaxed);
+        let ui = ui.join().expect("the UI thread doesn't panic");
+        results.and_then(|results| ui.map(|()| results))
+    });
+    ratatui::restore();
+    logging::hold(false);
+    let results = results?;
+    if !results.is_empty() {
+        println!("{}", output::summary(&results));
+    }
+    Ok(results)
+}
+
+fn draw(
+    frame: &mut Frame,
+    state: &State,
+    pipeline: &Pipeline,
+    total: usize,
+    paused: &AtomicBool,
+    cancelled: &AtomicBool,
+    list: &mut ListState,
+) {
+    let [header, body, help] = Layout::vertical([
+        Constraint::Length(3),
+        Constraint::Min(0),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+    let [files, workers] =
+        Layout::horizontal([Constraint::Percentage(60), Constraint::Percentage(40)]).areas(body);
+
+    let title = match (
+        cancelled.load(Ordering::Relaxed),
+        paused.load(Ordering::Relaxed),
+    ) {
+        (true, _) => " Cancelling, waiting for the running jobs ".red(),
+        (false, true) => " Paused, the running jobs finish ".yellow(),
+        (false, false) => " Converting ".green(),
+    };
+    let saved = state.source_bytes.saturating_sub(state.output_bytes);
+    let saved_percent = match state.source_bytes {
+        0 => 0.0,
+        bytes => saved as f64 * 100.0 / bytes as f64,
+    };
+    let label = format!(
+        "{}/{total} files, {} failed, {:.1} MiB saved ({saved_percent:.0}%), {}s",
+        state.finished,
+        state.failed,
+        saved as f64 / MIB as f64,
+        state.start.elapsed().as_secs()
+    );
+    frame.render_widget(
+        Gauge::default()
+            .block(Block::bordered().title(title))
+            .gauge_style(Style::new().fg(Color::Green))
+            .ratio(match total {
+                0 => 1.0,
+                total => state.finished as f64 / total as f64,
+            })
+            .label(label),
+        header,
+    );
+
+    let running = pipeline.running_jobs();
+    let running_rows: Vec<_> = running
+        .iter()
+        .filter_map(|(relative, _)| state.row_of.get(relative))
+        .collect();
+    let items: Vec<_> = state
+        .rows
+        .iter()
+        .enumerate()
+        .map(|(i, row)| match row {
+            Row::Folder { name, depth } => {
+                ListItem::new(format!("{}{name}/", "  ".repeat(*depth))).bold()
+            }
+            Row::File { name, depth } => {
+                let (mark, color) = match state.outcomes.get(&i) {
+                    _ if running_rows.contains(&&i) => ("▶", Color::Cyan),
+                    None => ("·", Color::DarkGray),
+                    Some(&"failed") => ("✗", Color::Red),
+                    Some(&"corrupt") => ("!", Color::Magenta),
+                    Some(&"interrupted") => ("-", Color::Yellow),
+                    Some(&"unchanged") | Some(&"resumed") => ("=", Color::Yellow),
+                    Some(_) => ("✓", Color::Green),
+                };
+                let outcome = state.outcomes.get(&i).copied().unwrap_or("");
+                ListItem::new(Line::from(vec![
+                    Span::raw("  ".repeat(*depth)),
+                    Span::styled(format!("{mark} "), Style::new().fg(color)),
+                    Span::raw(name.as_str()),
+                    Span::styled(format!(" {outcome}"), Style::new().fg(Color::DarkGray)),
+                ]))
+            }
+        })
+        .collect();
+    list.select(state.last_finished);
+    frame.render_stateful_widget(
+        List::new(items)
+            .block(Block::bordered().title(" Files "))
+            .highlight_style(Style::new().reversed()),
+        files,
+        list,
+    );
+
+    let items: Vec<_> = running
+        .iter()
+        .map(|(relative, elapsed)| {
+            ListItem::new(format!(
+                "{:>6.1}s {}",
+                elapsed.as_secs_f64(),
+                relative.display()
+            ))
+        })
+        .collect();
+    frame.render_widget(
+        List::new(items).block(Block::bordered().title(format!(" Running ({}) ", running.len()))),
+        workers,
+    );
+
+    frame.render_widget(
+        Paragraph::new(" p pause/resume · q cancel").fg(Color::DarkGray),
+        help,
+    );
+}
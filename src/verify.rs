@@ -0,0 +1,128 @@
+//! Checks source images before they are converted, since ImageMagick turns
+//! truncated files into garbage, and converted images before they are
+//! shipped, since ImageMagick can leave truncated or empty files behind when
+//! it is killed or runs out of disk space.
+
+use std::{
+    io::{Read, Seek, SeekFrom},
+    path::Path,
+};
+
+use color_eyre::eyre::{eyre, Result, WrapErr};
+use image::ImageError;
+use tracing::debug;
+
+use crate::{config::Sizes, ImageVariants, Variant};
+
+/// How far from the end of a JPEG its end marker may be, for cameras that
+/// append data after the image
+const JPEG_TRAILER_BYTES: u64 = 1024;
+
+/// Checks that a source image can be decoded and isn't truncated, by reading
+/// its header and checking that a JPEG or PNG ends with its end marker. This
+/// is much faster than decoding it. Formats that can't be read here pass.
+pub fn check_source(source: &Path) -> Result<()> {
+    match image::image_dimensions(source) {
+        Ok(_) => {}
+        Err(ImageError::Unsupported(_)) => return Ok(()),
+        Err(e) => return Err(e).wrap_err("it can't be decoded"),
+    }
+    let mut file = std::fs::File::open(source)?;
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    let length = file.metadata()?.len();
+    let tail_length = match magic {
+        [0xff, 0xd8, ..] => JPEG_TRAILER_BYTES,
+        [0x89, b'P', b'N', b'G'] => 12,
+        _ => return Ok(()),
+    };
+    file.seek(SeekFrom::Start(length.saturating_sub(tail_length)))?;
+    let mut tail = Vec::new();
+    file.read_to_end(&mut tail)?;
+    let end_marker: &[u8] = match magic[0] {
+        0xff => &[0xff, 0xd9],
+        _ => b"IEND",
+    };
+    if !tail.windows(end_marker.len()).any(|w| w == end_marker) {
+        return Err(eyre!("it is truncated, its end marker is missing"));
+    }
+    Ok(())
+}
+
+/// Checks the header of every output of a source and fully decodes the
+/// outputs in a sample of `full_decode_percent` percent of them. Each output
+/// must be as large as its variant or as the source, if the source was copied
+/// instead. Outputs in formats that can't be read here, like WebP, are
+/// skipped.
+pub fn verify_outputs(
+    source: &Path,
+    variants: &ImageVariants,
+    sizes: &Sizes,
+    full_decode_percent: u8,
+) -> Result<()> {
+    let source_dimensions = image::image_dimensions(source).ok();
+    let mut outputs = vec![
+        (Variant::Default, variants.default.as_path()),
+        (Variant::High, variants.high.as_path()),
+    ];
+    if let Some(thumb) = &variants.thumb {
+        outputs.push((Variant::Thumb, thumb.as_path()));
+    }
+    for (variant, output) in outputs {
+        verify_output(
+            output,
+            sizes.get(variant),
+            source_dimensions,
+            full_decode_percent,
+        )
+        .wrap_err_with(|| format!("verifying {}", output.display()))?;
+    }
+    Ok(())
+}
+
+fn verify_output(
+    output: &Path,
+    size: u32,
+    source_dimensions: Option<(u32, u32)>,
+    full_decode_percent: u8,
+) -> Result<()> {
+    let (width, height) = match image::image_dimensions(output) {
+        Ok(dimensions) => dimensions,
+        Err(ImageError::Unsupported(_)) => {
+            debug!(
+                "can't verify {}, its format isn't supported",
+                output.display()
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let longest = width.max(height);
+    // ImageMagick rounds the shorter side, which can make the longer one a
+    // pixel off for some aspect ratios
+    let is_resized = longest.abs_diff(size) <= 1;
+    let is_source = source_dimensions == Some((width, height));
+    let fits = source_dimensions.is_none() && longest <= size + 1;
+    if !is_resized && !is_source && !fits {
+        return Err(eyre!(
+            "it is {width}x{height} pixels, expected {size} pixels on the longest side"
+        ));
+    }
+    if is_sampled(output, full_decode_percent) {
+        image::open(output).wrap_err("decoding it")?;
+    }
+    Ok(())
+}
+
+/// Picks the same outputs on every run, by a hash of their path
+fn is_sampled(output: &Path, percent: u8) -> bool {
+    // FNV-1a, which is stable across Rust versions unlike the std hasher
+    let hash = output
+        .as_os_str()
+        .as_encoded_bytes()
+        .iter()
+        .fold(0xcbf29ce484222325_u64, |hash, byte| {
+            (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+        });
+    hash % 100 < u64::from(percent)
+}
@@ -0,0 +1,188 @@
+//! Path handling that differs between platforms, run on every CI platform.
+//! None of these need ImageMagick.
+
+use std::path::{Path, PathBuf};
+
+use web_assets_converter::{
+    clean, manifest::url_path, paths, rsync::is_remote, Job, JobKind, Pipeline,
+};
+
+/// A fresh folder in the temporary folder, removed when dropped
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(name: &str) -> TempDir {
+        let path = std::env::temp_dir().join(format!(
+            "web_assets_converter-{name}-{}",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn write(&self, relative: &str, contents: &[u8]) -> PathBuf {
+        let path = self.0.join(relative);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+fn outputs(jobs: &[web_assets_converter::Job]) -> Vec<(String, Vec<PathBuf>)> {
+    let mut outputs: Vec<_> = jobs
+        .iter()
+        .map(|job| {
+            let paths = job.outputs().into_iter().map(|(_, p)| p.to_path_buf());
+            (url_path(&job.relative), paths.collect())
+        })
+        .collect();
+    outputs.sort();
+    outputs
+}
+
+#[test]
+fn local_paths_are_not_remote() {
+    for path in [
+        "../dist/assets/",
+        "dist",
+        r"C:\dist\assets",
+        "C:/dist/assets",
+        r"\\?\C:\dist\assets",
+        r"\\server\share\dist",
+        r"\\?\UNC\server\share\dist",
+        "/srv/www:assets",
+    ] {
+        assert!(!is_remote(path), "{path} is local");
+    }
+    for path in ["host:dist", "user@host:/srv/www", "host.example.com:assets"] {
+        assert!(is_remote(path), "{path} is remote");
+    }
+}
+
+#[test]
+fn url_paths_use_forward_slashes() {
+    let path = Path::new("photos").join("rally").join("a.jpg");
+    assert_eq!(url_path(&path), "photos/rally/a.jpg");
+}
+
+#[test]
+fn canonical_paths_compare_with_their_folders() {
+    let dir = TempDir::new("canonical");
+    let file = dir.write("sub/a.txt", b"a");
+    let root = paths::canonicalize(&dir.0).unwrap();
+    let canonical = paths::canonicalize(&file).unwrap();
+    assert_eq!(
+        canonical.strip_prefix(&root).unwrap(),
+        Path::new("sub").join("a.txt")
+    );
+    #[cfg(windows)]
+    assert!(!canonical.to_string_lossy().starts_with(r"\\?\"));
+}
+
+#[test]
+fn walked_and_listed_files_are_planned_the_same() {
+    let dir = TempDir::new("plan");
+    let source = dir.0.join("assets");
+    dir.write("assets/photos/rally/a.png", b"png");
+    dir.write("assets/docs/notes.v2.txt", b"text");
+    dir.write("assets/README", b"readme");
+    let pipeline = Pipeline::builder(&source, dir.0.join("dist").join("assets")).build();
+    let walked = pipeline.plan().unwrap();
+    // Like watch mode, which gets absolute paths that may be spelled
+    // differently from the source folder
+    let listed = pipeline
+        .plan_files(walked.iter().map(|job| source.join(&job.relative)))
+        .unwrap();
+    assert_eq!(outputs(&walked), outputs(&listed));
+
+    let destination = dir.0.join("dist").join("assets");
+    let by_source: Vec<_> = outputs(&walked);
+    assert_eq!(by_source[0].0, "README");
+    assert_eq!(by_source[0].1, [destination.join("README")]);
+    assert_eq!(by_source[1].0, "docs/notes.v2.txt");
+    assert_eq!(
+        by_source[1].1,
+        [destination.join("docs").join("notes.v2.txt")]
+    );
+    assert_eq!(by_source[2].0, "photos/rally/a.png");
+    assert_eq!(
+        by_source[2].1[0],
+        destination.join("photos").join("rally").join("a.jpg")
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn only_links_inside_the_source_are_followed_by_default() {
+    let dir = TempDir::new("links");
+    let source = dir.0.join("assets");
+    let inside = dir.write("assets/notes.txt", b"notes");
+    let outside = dir.write("secrets.txt", b"secret");
+    std::os::unix::fs::symlink(&inside, source.join("linked.txt")).unwrap();
+    std::os::unix::fs::symlink(&outside, source.join("outside.txt")).unwrap();
+    let pipeline = Pipeline::builder(&source, dir.0.join("dist")).build();
+    let planned: Vec<_> = outputs(&pipeline.plan().unwrap())
+        .into_iter()
+        .map(|(relative, _)| relative)
+        .collect();
+    assert_eq!(planned, ["linked.txt", "notes.txt"]);
+}
+
+#[test]
+fn relative_destinations_above_the_source_are_planned() {
+    let dir = TempDir::new("relative");
+    dir.write("assets/a.png", b"png");
+    let source = dir.0.join("assets");
+    // Like the default destination ../dist/assets/
+    let destination = source.join("..").join("dist").join("assets");
+    let pipeline = Pipeline::builder(&source, &destination).build();
+    let jobs = pipeline.plan().unwrap();
+    let JobKind::ConvertImage(variants) = &jobs[0].kind else {
+        panic!("a.png is converted");
+    };
+    assert_eq!(variants.default, destination.join("a.jpg"));
+}
+
+#[test]
+fn outputs_differing_in_case_collide_where_the_filesystem_ignores_case() {
+    let destination = Path::new("dist");
+    let job = |name: &str| Job {
+        source: Path::new("assets").join(name),
+        relative: name.into(),
+        kind: JobKind::Copy {
+            destination: destination.join(name),
+        },
+    };
+    let pipeline = Pipeline::builder("assets", destination).build();
+    let result = pipeline.resolve_collisions(vec![job("Notes.txt"), job("notes.txt")]);
+    let ignores_case = cfg!(any(windows, target_os = "macos"));
+    assert_eq!(result.is_err(), ignores_case);
+}
+
+#[test]
+fn clean_stays_inside_the_destination() {
+    let dir = TempDir::new("clean");
+    let destination = dir.0.join("dist");
+    let outside = dir.write("outside.txt", b"keep");
+    dir.write("dist/inside.txt", b"remove");
+    let manifest = serde_json::json!({
+        "assets": {
+            "a": { "default": "inside.txt" },
+            "b": { "default": "../outside.txt" },
+            "c": { "default": outside },
+            "d": { "default": r"\outside.txt" },
+            "e": { "default": "C:outside.txt" },
+        }
+    });
+    dir.write("dist/manifest.json", manifest.to_string().as_bytes());
+    let files = clean::generated_files(&[], &destination).unwrap();
+    assert!(files.contains(&destination.join("inside.txt")));
+    assert!(files.iter().all(|file| file.starts_with(&destination)));
+}